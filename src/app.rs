@@ -0,0 +1,738 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Running the container's application in the VM: the container image's
+//! config (entrypoint, command, environment, working directory and user)
+//! turned into a service that starts on boot, a systemd unit or, on Alpine,
+//! an OpenRC service. The environment can also be given to every login.
+//! A HEALTHCHECK restarts the service after as many failed checks in a row
+//! as docker would have allowed.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::builder::Flavor;
+use crate::firewall::Port;
+use crate::*;
+
+/// What the generated service is called
+pub const CONTAINER_SERVICE: &str = "container";
+
+/// What the systemd service and timer running the health check are called
+pub const HEALTH_SERVICE: &str = "container-health";
+
+/// The parts of a container image's config (`docker image inspect`'s
+/// `.Config`) that say how to run it. Docker writes null for what isn't
+/// set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct ContainerConfig {
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    /// KEY=VALUE
+    pub env: Option<Vec<String>>,
+    pub working_dir: String,
+    /// A name or UID, optionally with a group or GID after a colon. Empty
+    /// for root.
+    pub user: String,
+    /// PORT/PROTOCOL, each to an empty object
+    pub exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
+    pub healthcheck: Option<Healthcheck>,
+}
+
+/// A HEALTHCHECK. The durations are in nanoseconds, 0 for docker's default.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Healthcheck {
+    /// ["CMD", args...], ["CMD-SHELL", command] or ["NONE"]
+    pub test: Option<Vec<String>>,
+    pub interval: u64,
+    pub timeout: u64,
+    pub start_period: u64,
+    pub retries: u64,
+}
+
+/// Nanoseconds rounded up to whole seconds, or `default` for 0
+fn seconds(nanoseconds: u64, default: u64) -> u64 {
+    if nanoseconds == 0 {
+        default
+    } else {
+        nanoseconds.div_ceil(1_000_000_000)
+    }
+}
+
+impl Healthcheck {
+    /// The command to run, unless checking is turned off
+    pub fn command(&self) -> Option<Vec<String>> {
+        let test = self.test.as_deref().unwrap_or_default();
+
+        match test.first().map(String::as_str) {
+            Some("CMD") if test.len() > 1 => Some(test[1..].to_vec()),
+            Some("CMD-SHELL") if test.len() == 2 => {
+                Some(vec!["/bin/sh".into(), "-c".into(), test[1].clone()])
+            }
+            _ => None,
+        }
+    }
+
+    pub fn interval(&self) -> u64 {
+        seconds(self.interval, 30)
+    }
+
+    pub fn timeout(&self) -> u64 {
+        seconds(self.timeout, 30)
+    }
+
+    pub fn start_period(&self) -> u64 {
+        seconds(self.start_period, 0)
+    }
+
+    pub fn retries(&self) -> u64 {
+        if self.retries == 0 {
+            3
+        } else {
+            self.retries
+        }
+    }
+}
+
+impl ContainerConfig {
+    pub fn parse(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// The entrypoint followed by the command, as `docker run` would run
+    /// them. Shell form instructions were already made into `/bin/sh -c`.
+    pub fn command(&self) -> Vec<String> {
+        self.entrypoint
+            .iter()
+            .chain(self.cmd.iter())
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// The environment as (key, value), skipping anything malformed
+    pub fn env_vars(&self) -> Vec<(&str, &str)> {
+        self.env
+            .iter()
+            .flatten()
+            .filter_map(|x| x.split_once('='))
+            .collect()
+    }
+
+    /// The user and group to run as, if not root
+    fn user_and_group(&self) -> (Option<&str>, Option<&str>) {
+        let (user, group) = match self.user.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (self.user.as_str(), None),
+        };
+
+        (
+            Some(user).filter(|x| !x.is_empty()),
+            group.filter(|x| !x.is_empty()),
+        )
+    }
+
+    /// The ports EXPOSEd
+    pub fn exposed_ports(&self) -> Result<Vec<Port>> {
+        self.exposed_ports
+            .iter()
+            .flatten()
+            .map(|(port, _)| port.parse())
+            .collect()
+    }
+
+    /// The health check, if it has one that isn't turned off
+    pub fn health_check(&self) -> Option<(&Healthcheck, Vec<String>)> {
+        let healthcheck = self.healthcheck.as_ref()?;
+        Some((healthcheck, healthcheck.command()?))
+    }
+
+    fn working_dir(&self) -> &str {
+        if self.working_dir.is_empty() {
+            "/"
+        } else {
+            &self.working_dir
+        }
+    }
+}
+
+/// Read the config of the local image `image_name`
+pub fn inspect_config(image_name: &str) -> Result<ContainerConfig> {
+    let output = run(
+        "docker".into(),
+        &[
+            "image".into(),
+            "inspect".into(),
+            "--format".into(),
+            "{{json .Config}}".into(),
+            image_name.into(),
+        ],
+    )?;
+
+    ContainerConfig::parse(&output_stdout_string(&output))
+}
+
+/// `x` quoted for a systemd setting, where % starts a specifier
+fn systemd_quote(x: &str) -> String {
+    format!(
+        "\"{}\"",
+        x.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%")
+    )
+}
+
+/// `x` quoted to go inside double quotes in a shell script
+fn double_quote(x: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in x.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A systemd unit running `config`'s command
+pub fn systemd_unit(image_name: &str, config: &ContainerConfig) -> String {
+    let mut unit = format!(
+        "[Unit]\n\
+         Description=Entrypoint and command of {}\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n",
+        image_name
+    );
+
+    unit.push_str(&format!("ExecStart={}\n", exec_line(&config.command())));
+    unit.push_str(&process_settings(config));
+    unit.push_str("Restart=on-failure\n\n[Install]\nWantedBy=multi-user.target\n");
+    unit
+}
+
+/// `command` for ExecStart=. Command lines also expand $VARIABLES, which
+/// docker doesn't.
+fn exec_line(command: &[String]) -> String {
+    let words: Vec<String> = command
+        .iter()
+        .map(|x| systemd_quote(&x.replace('$', "$$")))
+        .collect();
+    words.join(" ")
+}
+
+/// The [Service] settings that make a command run like in the container:
+/// its working directory, user and environment
+fn process_settings(config: &ContainerConfig) -> String {
+    let mut settings = format!(
+        "WorkingDirectory={}\n",
+        config.working_dir().replace('%', "%%")
+    );
+
+    let (user, group) = config.user_and_group();
+    if let Some(user) = user {
+        settings.push_str(&format!("User={}\n", user));
+    }
+    if let Some(group) = group {
+        settings.push_str(&format!("Group={}\n", group));
+    }
+
+    for (key, value) in config.env_vars() {
+        settings.push_str(&format!(
+            "Environment={}\n",
+            systemd_quote(&format!("{}={}", key, value))
+        ));
+    }
+
+    settings
+}
+
+/// Counts failed checks in a row in `failures`, and once there are
+/// `retries` of them runs `restart` and starts counting again. $1 is the
+/// check's result.
+fn count_failures(failures: &str, retries: u64, restart: &str) -> String {
+    format!(
+        "if [ \"$1\" = success ]; then rm -f {failures}; exit 0; fi; \
+         n=$(( $(cat {failures} 2>/dev/null || echo 0) + 1 )); \
+         if [ $n -lt {retries} ]; then echo $n > {failures}; exit 0; fi; \
+         rm -f {failures}; {restart}",
+    )
+}
+
+/// A oneshot service running `config`'s health check the way the container
+/// would, and a timer starting it every interval. After the check has
+/// failed `retries` times in a row, the container's service is restarted.
+pub fn systemd_health_units(
+    image_name: &str,
+    config: &ContainerConfig,
+) -> Option<(String, String)> {
+    let (healthcheck, command) = config.health_check()?;
+
+    // ExecStopPost= runs whether or not the check succeeded, and with +
+    // as root, to be able to restart the service
+    let on_result = count_failures(
+        &format!("/run/{}.failures", HEALTH_SERVICE),
+        healthcheck.retries(),
+        &format!("systemctl restart {}.service", CONTAINER_SERVICE),
+    );
+
+    let mut service = format!(
+        "[Unit]\n\
+         Description=Health check of {image_name}\n\
+         Requisite={container}.service\n\
+         After={container}.service\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={exec_start}\n\
+         TimeoutStartSec={timeout}\n",
+        container = CONTAINER_SERVICE,
+        exec_start = exec_line(&command),
+        timeout = healthcheck.timeout(),
+    );
+    service.push_str(&process_settings(config));
+    service.push_str(&format!(
+        "ExecStopPost=+/bin/sh -c {} health $SERVICE_RESULT\n",
+        systemd_quote(&on_result.replace('$', "$$"))
+    ));
+
+    let timer = format!(
+        "[Unit]\n\
+         Description=Run the health check of {image_name} every {interval}s\n\
+         \n\
+         [Timer]\n\
+         OnActiveSec={first}\n\
+         OnUnitActiveSec={interval}\n\
+         AccuracySec=1s\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        first = healthcheck.start_period().max(healthcheck.interval()),
+        interval = healthcheck.interval(),
+    );
+
+    Some((service, timer))
+}
+
+/// An OpenRC service running `config`'s command. openrc-run evals
+/// command_args, so they are quoted once for that and again for the
+/// assignment.
+///
+/// A health check needs supervise-daemon, which restarts the service when
+/// healthcheck() fails. It runs as root.
+pub fn openrc_service(image_name: &str, config: &ContainerConfig) -> String {
+    let command = config.command();
+    let command_args: Vec<String> = command[1..].iter().map(|x| shell_quote(x)).collect();
+
+    let mut service = format!(
+        "#!/sbin/openrc-run\n\
+         \n\
+         description={}\n\
+         command={}\n\
+         command_args={}\n",
+        double_quote(&format!("Entrypoint and command of {}", image_name)),
+        double_quote(&command[0]),
+        double_quote(&command_args.join(" ")),
+    );
+
+    let health_check = config.health_check();
+    match &health_check {
+        Some((healthcheck, _)) => service.push_str(&format!(
+            "supervisor=supervise-daemon\n\
+             healthcheck_delay={}\n\
+             healthcheck_timer={}\n",
+            healthcheck.start_period().max(healthcheck.interval()),
+            healthcheck.interval(),
+        )),
+        None => service.push_str("command_background=true\n"),
+    }
+
+    service.push_str(&format!(
+        "pidfile=\"/run/${{RC_SVCNAME}}.pid\"\n\
+         directory={}\n",
+        double_quote(config.working_dir()),
+    ));
+
+    if let (Some(user), group) = config.user_and_group() {
+        let command_user = match group {
+            Some(group) => format!("{}:{}", user, group),
+            None => user.to_string(),
+        };
+        service.push_str(&format!("command_user={}\n", double_quote(&command_user)));
+    }
+
+    if !config.env_vars().is_empty() {
+        service.push('\n');
+        for (key, value) in config.env_vars() {
+            service.push_str(&format!("export {}={}\n", key, double_quote(value)));
+        }
+    }
+
+    service.push_str("\ndepend() {\n\tneed net\n}\n");
+
+    if let Some((healthcheck, command)) = health_check {
+        let command: Vec<String> = command.iter().map(|x| shell_quote(x)).collect();
+        let failures = "\"/run/${RC_SVCNAME}.failures\"";
+
+        // Failing makes supervise-daemon restart the service, so that is
+        // left until the last retry
+        service.push_str(&format!(
+            "\n\
+             healthcheck() {{\n\
+             \tif (cd {directory} && timeout {timeout} {command}); then\n\
+             \t\trm -f {failures}\n\
+             \t\treturn 0\n\
+             \tfi\n\
+             \tfailures=$(( $(cat {failures} 2>/dev/null || echo 0) + 1 ))\n\
+             \tif [ $failures -lt {retries} ]; then\n\
+             \t\techo $failures > {failures}\n\
+             \t\treturn 0\n\
+             \tfi\n\
+             \trm -f {failures}\n\
+             \treturn 1\n\
+             }}\n",
+            directory = double_quote(config.working_dir()),
+            timeout = healthcheck.timeout(),
+            command = command.join(" "),
+            retries = healthcheck.retries(),
+        ));
+    }
+
+    service
+}
+
+/// `value` as pam_env reads it from `/etc/environment`: as is, in double
+/// quotes if it has whitespace or a `#`. pam_env has no escapes and drops
+/// every quote, so `None` for values it can't represent.
+fn pam_env_value(value: &str) -> Option<String> {
+    if value.contains(['"', '\'', '\n']) {
+        None
+    } else if value.contains(|c: char| c.is_whitespace() || c == '#') {
+        Some(format!("\"{}\"", value))
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// `/etc/environment` with `config`'s environment, replacing variables
+/// that `existing` already sets and keeping the rest. pam_env reads it for
+/// every login.
+pub fn environment_file(existing: &str, config: &ContainerConfig) -> String {
+    let env_vars = config.env_vars();
+
+    let mut contents: String = existing
+        .lines()
+        .filter(|line| {
+            let key = line.split_once('=').map(|(key, _)| key.trim());
+            !env_vars.iter().any(|(x, _)| Some(*x) == key)
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+
+    for (key, value) in env_vars {
+        match pam_env_value(value) {
+            Some(value) => contents.push_str(&format!("{}={}\n", key, value)),
+            None => warn!(
+                "leaving {} out of /etc/environment, pam_env can't read quotes or newlines in a value",
+                key
+            ),
+        }
+    }
+
+    contents
+}
+
+/// Give every login in the image at `root` `config`'s environment: in
+/// /etc/environment, or on Alpine, which doesn't use pam_env, a profile.d
+/// script
+pub fn install_environment(root: &str, flavor: &Flavor, config: &ContainerConfig) -> Result<()> {
+    match flavor {
+        Flavor::Debian | Flavor::Ubuntu => {
+            let path = format!("{}/etc/environment", root);
+            let existing = match std::fs::read_to_string(&path) {
+                Ok(existing) => existing,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e.into()),
+            };
+
+            std::fs::write(&path, environment_file(&existing, config))?;
+        }
+
+        Flavor::Alpine => {
+            let script: String = config
+                .env_vars()
+                .iter()
+                .map(|(key, value)| format!("export {}={}\n", key, double_quote(value)))
+                .collect();
+
+            std::fs::create_dir_all(format!("{}/etc/profile.d", root))?;
+            std::fs::write(format!("{}/etc/profile.d/container-env.sh", root), script)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the service for `config` into the image at `root` and enable it
+pub fn install_service(
+    root: &str,
+    flavor: &Flavor,
+    image_name: &str,
+    config: &ContainerConfig,
+) -> Result<()> {
+    if config.command().is_empty() {
+        return Err(Error::InvalidImage {
+            image: image_name.into(),
+            reason: "it has neither an entrypoint nor a command to run".into(),
+        }
+        .into());
+    }
+
+    match flavor {
+        Flavor::Debian | Flavor::Ubuntu => {
+            std::fs::write(
+                format!("{}/etc/systemd/system/{}.service", root, CONTAINER_SERVICE),
+                systemd_unit(image_name, config),
+            )?;
+
+            run(
+                "chroot".into(),
+                &[
+                    root.into(),
+                    "systemctl".into(),
+                    "enable".into(),
+                    format!("{}.service", CONTAINER_SERVICE),
+                ],
+            )?;
+
+            if let Some((service, timer)) = systemd_health_units(image_name, config) {
+                let unit = format!("{}/etc/systemd/system/{}", root, HEALTH_SERVICE);
+                std::fs::write(format!("{}.service", unit), service)?;
+                std::fs::write(format!("{}.timer", unit), timer)?;
+
+                run(
+                    "chroot".into(),
+                    &[
+                        root.into(),
+                        "systemctl".into(),
+                        "enable".into(),
+                        format!("{}.timer", HEALTH_SERVICE),
+                    ],
+                )?;
+            }
+        }
+
+        Flavor::Alpine => {
+            let path = format!("{}/etc/init.d/{}", root, CONTAINER_SERVICE);
+            std::fs::write(&path, openrc_service(image_name, config))?;
+            std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+
+            run(
+                "chroot".into(),
+                &[
+                    root.into(),
+                    "rc-update".into(),
+                    "add".into(),
+                    CONTAINER_SERVICE.into(),
+                    "default".into(),
+                ],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_config() -> ContainerConfig {
+    ContainerConfig::parse(
+        r#"{
+            "Hostname": "",
+            "Env": ["PATH=/usr/local/bin:/usr/bin:/bin", "GREETING=hello \"world\" 100%"],
+            "Cmd": ["serve", "--port", "8080", "$HOME"],
+            "Entrypoint": ["/docker-entrypoint.sh"],
+            "WorkingDir": "/srv/app",
+            "User": "app:app",
+            "ExposedPorts": {"8080/tcp": {}, "9090/udp": {}},
+            "Labels": null
+        }"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_container_config() -> Result<()> {
+    let config = test_config();
+    assert_eq!(
+        config.command(),
+        ["/docker-entrypoint.sh", "serve", "--port", "8080", "$HOME"]
+    );
+    assert_eq!(config.env_vars()[1], ("GREETING", "hello \"world\" 100%"));
+    assert_eq!(
+        config
+            .exposed_ports()?
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        ["8080/tcp", "9090/udp"]
+    );
+
+    // Shell form CMD, nothing else
+    let config = ContainerConfig::parse(
+        r#"{"Cmd": ["/bin/sh", "-c", "nginx -g 'daemon off;'"], "Entrypoint": null, "Env": null, "WorkingDir": ""}"#,
+    )?;
+    assert_eq!(config.command()[2], "nginx -g 'daemon off;'");
+    assert_eq!(config.working_dir(), "/");
+    assert_eq!(config.user_and_group(), (None, None));
+
+    let config = ContainerConfig::parse(r#"{"User": "1000"}"#)?;
+    assert_eq!(config.user_and_group(), (Some("1000"), None));
+    assert!(config.env_vars().is_empty());
+    assert!(config.exposed_ports()?.is_empty());
+
+    assert!(ContainerConfig::parse("{}")?.command().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_systemd_unit() {
+    assert_eq!(
+        systemd_unit("tester", &test_config()),
+        r#"[Unit]
+Description=Entrypoint and command of tester
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+ExecStart="/docker-entrypoint.sh" "serve" "--port" "8080" "$$HOME"
+WorkingDirectory=/srv/app
+User=app
+Group=app
+Environment="PATH=/usr/local/bin:/usr/bin:/bin"
+Environment="GREETING=hello \"world\" 100%%"
+Restart=on-failure
+
+[Install]
+WantedBy=multi-user.target
+"#
+    );
+}
+
+#[test]
+fn test_openrc_service() {
+    assert_eq!(
+        openrc_service("tester", &test_config()),
+        r#"#!/sbin/openrc-run
+
+description="Entrypoint and command of tester"
+command="/docker-entrypoint.sh"
+command_args="serve --port 8080 '\$HOME'"
+command_background=true
+pidfile="/run/${RC_SVCNAME}.pid"
+directory="/srv/app"
+command_user="app:app"
+
+export PATH="/usr/local/bin:/usr/bin:/bin"
+export GREETING="hello \"world\" 100%"
+
+depend() {
+	need net
+}
+"#
+    );
+}
+
+#[test]
+fn test_environment_file() {
+    let existing = "PATH=\"/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin\"\nLANG=C.UTF-8\n";
+
+    assert_eq!(
+        environment_file(existing, &test_config()),
+        r#"LANG=C.UTF-8
+PATH=/usr/local/bin:/usr/bin:/bin
+"#
+    );
+
+    let config = ContainerConfig::parse(
+        r#"{"Env": ["HOME=$HOME/app", "MOTD=hi there # not a comment", "EMPTY="]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        environment_file("", &config),
+        r#"HOME=$HOME/app
+MOTD="hi there # not a comment"
+EMPTY=
+"#
+    );
+}
+
+#[test]
+fn test_health_check() -> Result<()> {
+    let config = ContainerConfig::parse(
+        r#"{
+            "Cmd": ["nginx", "-g", "daemon off;"],
+            "Healthcheck": {
+                "Test": ["CMD-SHELL", "curl -f http://localhost/ || exit 1"],
+                "Interval": 10000000000,
+                "Timeout": 2500000000,
+                "Retries": 5
+            }
+        }"#,
+    )?;
+
+    let (healthcheck, command) = config.health_check().unwrap();
+    assert_eq!(command[2], "curl -f http://localhost/ || exit 1");
+    assert_eq!(healthcheck.interval(), 10);
+    assert_eq!(healthcheck.timeout(), 3);
+    assert_eq!(healthcheck.start_period(), 0);
+    assert_eq!(healthcheck.retries(), 5);
+
+    let (service, timer) = systemd_health_units("tester", &config).unwrap();
+    assert_eq!(
+        service,
+        r#"[Unit]
+Description=Health check of tester
+Requisite=container.service
+After=container.service
+
+[Service]
+Type=oneshot
+ExecStart="/bin/sh" "-c" "curl -f http://localhost/ || exit 1"
+TimeoutStartSec=3
+WorkingDirectory=/
+ExecStopPost=+/bin/sh -c "if [ \"$$1\" = success ]; then rm -f /run/container-health.failures; exit 0; fi; n=$$(( $$(cat /run/container-health.failures 2>/dev/null || echo 0) + 1 )); if [ $$n -lt 5 ]; then echo $$n > /run/container-health.failures; exit 0; fi; rm -f /run/container-health.failures; systemctl restart container.service" health $SERVICE_RESULT
+"#
+    );
+    assert!(timer.contains("OnActiveSec=10\nOnUnitActiveSec=10\n"));
+
+    let service = openrc_service("tester", &config);
+    assert!(service.contains("supervisor=supervise-daemon\nhealthcheck_delay=10\n"));
+    assert!(!service.contains("command_background"));
+    assert!(service.contains(
+        "\tif (cd \"/\" && timeout 3 /bin/sh -c 'curl -f http://localhost/ || exit 1'); then\n"
+    ));
+    assert!(service.contains("\tif [ $failures -lt 5 ]; then\n"));
+
+    // Turned off, here or in a base image
+    for test in [r#"["NONE"]"#, "null", "[]"] {
+        let config = ContainerConfig::parse(&format!(
+            r#"{{"Cmd": ["x"], "Healthcheck": {{"Test": {}}}}}"#,
+            test
+        ))?;
+        assert!(config.health_check().is_none());
+        assert!(systemd_health_units("tester", &config).is_none());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,315 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! An audit log of everything done to the host: each command run, and each
+//! mount, loop device and unpacked archive, as a line of JSON written as
+//! soon as it finishes. Read-only queries, like probing a filesystem, are
+//! left out.
+//!
+//! What's fed to a command on stdin, like a password for chpasswd, is never
+//! written, only its length.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{ExitStatus, Output};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::probe::Filesystem;
+use crate::*;
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    /// Seconds since the Unix epoch
+    pub started: f64,
+    pub duration_secs: f64,
+    /// The effective UID it ran as
+    pub uid: u32,
+    pub exe: &'a str,
+    pub args: &'a [String],
+    /// Set for the command on top of the inherited environment
+    pub env: &'a [(String, String)],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdin_bytes: Option<usize>,
+    /// Missing if it was killed by a signal, or never ran
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    /// Why it failed to run at all, or failed without an exit status
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Passes everything through to another executor, appending an
+/// `AuditRecord` to a file for each action
+pub struct AuditExecutor {
+    inner: Rc<dyn Executor>,
+    log: File,
+}
+
+/// How an action ended
+enum Outcome {
+    Exited(ExitStatus),
+    Done,
+    Failed(String),
+}
+
+impl<T> From<&Result<T>> for Outcome {
+    fn from(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Done,
+            Err(e) => Outcome::Failed(format!("{:#}", e)),
+        }
+    }
+}
+
+impl AuditExecutor {
+    /// Append to the log at `path`, creating it readable only by its owner
+    /// as the environment may hold credentials
+    pub fn create(path: &Path, inner: Rc<dyn Executor>) -> Result<Self> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+
+        Ok(Self { inner, log })
+    }
+
+    fn record(
+        &self,
+        exe: &str,
+        args: &[String],
+        env: &[(String, String)],
+        stdin: Option<&str>,
+        start: (SystemTime, Instant),
+        outcome: Outcome,
+    ) {
+        let (exit_code, signal, error) = match outcome {
+            Outcome::Exited(status) => (status.code(), status.signal(), None),
+            Outcome::Done => (Some(0), None, None),
+            Outcome::Failed(e) => (None, None, Some(e)),
+        };
+
+        let record = AuditRecord {
+            started: start
+                .0
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_secs_f64(),
+            duration_secs: start.1.elapsed().as_secs_f64(),
+            uid: unsafe { libc::geteuid() },
+            exe,
+            args,
+            env,
+            stdin_bytes: stdin.map(str::len),
+            exit_code,
+            signal,
+            error,
+        };
+
+        // One write per line, so lines from a crashed build are whole
+        let written = serde_json::to_string(&record)
+            .map_err(std::io::Error::from)
+            .and_then(|line| (&self.log).write_all(format!("{}\n", line).as_bytes()));
+
+        if let Err(e) = written {
+            warn!("could not write to the audit log: {}", e);
+        }
+    }
+}
+
+fn now() -> (SystemTime, Instant) {
+    (SystemTime::now(), Instant::now())
+}
+
+impl Executor for AuditExecutor {
+    fn execute(
+        &self,
+        exe: &str,
+        args: &[String],
+        env_vars: &[(String, String)],
+        stdin: Option<&str>,
+        stop: &StopWhen,
+    ) -> Result<Output> {
+        let start = now();
+        let result = self.inner.execute(exe, args, env_vars, stdin, stop);
+
+        let outcome = match &result {
+            Ok(output) => Outcome::Exited(output.status),
+            Err(e) => Outcome::Failed(format!("{:#}", e)),
+        };
+        self.record(exe, args, env_vars, stdin, start, outcome);
+
+        result
+    }
+
+    fn execute_interactive(&self, exe: &str, args: &[String]) -> Result<ExitStatus> {
+        let start = now();
+        let result = self.inner.execute_interactive(exe, args);
+
+        let outcome = match &result {
+            Ok(status) => Outcome::Exited(*status),
+            Err(e) => Outcome::Failed(format!("{:#}", e)),
+        };
+        self.record(exe, args, &[], None, start, outcome);
+
+        result
+    }
+
+    fn execute_until(
+        &self,
+        exe: &str,
+        args: &[String],
+        marker: &str,
+        timeout: Duration,
+    ) -> Result<(bool, String)> {
+        let start = now();
+        let result = self.inner.execute_until(exe, args, marker, timeout);
+        self.record(exe, args, &[], None, start, (&result).into());
+        result
+    }
+
+    fn mount(&self, source: &str, target: &str, options: &MountOptions) -> Result<()> {
+        let start = now();
+        let result = self.inner.mount(source, target, options);
+        let args = options.mount_args(source, target);
+        self.record("mount", &args, &[], None, start, (&result).into());
+        result
+    }
+
+    fn umount(&self, target: &str) -> Result<()> {
+        let start = now();
+        let result = self.inner.umount(target);
+        self.record(
+            "umount",
+            &[target.into()],
+            &[],
+            None,
+            start,
+            (&result).into(),
+        );
+        result
+    }
+
+    fn umount_lazy(&self, target: &str) -> Result<()> {
+        let start = now();
+        let result = self.inner.umount_lazy(target);
+        let args = ["-l".into(), target.into()];
+        self.record("umount", &args, &[], None, start, (&result).into());
+        result
+    }
+
+    fn attach_loop(&self, image: &str, options: &LoopOptions) -> Result<String> {
+        let start = now();
+        let result = self.inner.attach_loop(image, options);
+        let args = options.losetup_args(image);
+        self.record("losetup", &args, &[], None, start, (&result).into());
+        result
+    }
+
+    fn detach_loop(&self, device: &str) -> Result<()> {
+        let start = now();
+        let result = self.inner.detach_loop(device);
+        let args = ["-d".into(), device.into()];
+        self.record("losetup", &args, &[], None, start, (&result).into());
+        result
+    }
+
+    fn unpack_tar(&self, archive: &str, dest: &str) -> Result<()> {
+        let start = now();
+        let result = self.inner.unpack_tar(archive, dest);
+        let args = tar_args(archive, dest);
+        self.record("tar", &args, &[], None, start, (&result).into());
+        result
+    }
+
+    fn find_free_loop(&self) -> Result<String> {
+        self.inner.find_free_loop()
+    }
+
+    fn partition_device(&self, device: &str, number: u32) -> Result<String> {
+        self.inner.partition_device(device, number)
+    }
+
+    fn probe_filesystem(&self, device: &str) -> Result<Filesystem> {
+        self.inner.probe_filesystem(device)
+    }
+
+    fn capabilities(&self) -> Result<u64> {
+        self.inner.capabilities()
+    }
+
+    fn mounts_of(&self, device: &str) -> Result<Vec<String>> {
+        self.inner.mounts_of(device)
+    }
+}
+
+#[test]
+fn test_audit_log() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("audit.jsonl");
+
+    let recorder: Rc<dyn Executor> = Rc::new(RecordingExecutor::new(|exe, _| match exe {
+        "false" => anyhow::bail!("no such thing"),
+        _ => Ok("done".into()),
+    }));
+    let audit = Rc::new(AuditExecutor::create(&path, recorder)?);
+
+    let previous = set_executor(audit);
+    let result = run_with_env(
+        "apt".into(),
+        &["update".into()],
+        &[("DEBIAN_FRONTEND".into(), "noninteractive".into())],
+    )
+    .and_then(|_| run_with_stdin("chpasswd".into(), &[], "root:secret".into()))
+    .and_then(|_| mount_fs("/dev/loop0p3", "/mnt", &MountOptions::default()));
+    let failed = run("false".into(), &[]);
+    set_executor(previous);
+    result?;
+    assert!(failed.is_err());
+
+    let log = std::fs::read_to_string(&path)?;
+    assert!(!log.contains("secret"));
+
+    let records: Vec<serde_json::Value> = log
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(records.len(), 4);
+
+    assert_eq!(records[0]["exe"], "apt");
+    assert_eq!(records[0]["args"], serde_json::json!(["update"]));
+    assert_eq!(
+        records[0]["env"],
+        serde_json::json!([["DEBIAN_FRONTEND", "noninteractive"]])
+    );
+    assert_eq!(records[0]["exit_code"], 0);
+    assert!(records[0]["started"].as_f64().unwrap() > 0.0);
+
+    assert_eq!(records[1]["exe"], "chpasswd");
+    assert_eq!(records[1]["stdin_bytes"], 11);
+
+    assert_eq!(records[2]["exe"], "mount");
+    assert_eq!(
+        records[2]["args"].as_array().unwrap().last().unwrap(),
+        "/mnt"
+    );
+
+    assert_eq!(records[3]["exe"], "false");
+    assert!(records[3]["exit_code"].is_null());
+    assert_eq!(records[3]["error"], "no such thing");
+
+    Ok(())
+}
@@ -4,511 +4,3572 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
 
-use std::fs::File;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::path::PathBuf;
-use std::process::Command;
-
-use anyhow::{bail, Result};
-use rand::{distributions::Alphanumeric, Rng};
-
-use clap::{Parser, ValueEnum};
-
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use tracing::{debug, info, info_span, warn, Level};
+
+use clap::{CommandFactory, Parser, ValueEnum};
+
+use docker_to_uefi_bootable_image::audit::AuditExecutor;
+use docker_to_uefi_bootable_image::builder::*;
+use docker_to_uefi_bootable_image::console::Console;
+use docker_to_uefi_bootable_image::datapart::DataPartition;
+use docker_to_uefi_bootable_image::diff;
+use docker_to_uefi_bootable_image::error::Error;
+use docker_to_uefi_bootable_image::events::BuildEvent;
+use docker_to_uefi_bootable_image::firewall::{Firewall, Port};
+use docker_to_uefi_bootable_image::fstab::FstabOptions;
+use docker_to_uefi_bootable_image::gpt::{Gpt, PartitionChange};
+use docker_to_uefi_bootable_image::image::{self, ChecksumAlgorithm, Signer};
+use docker_to_uefi_bootable_image::matrix::{self, Axis, Variant};
+use docker_to_uefi_bootable_image::ovmf::Ovmf;
+use docker_to_uefi_bootable_image::qemu::{self, QemuOptions};
+use docker_to_uefi_bootable_image::sbom::{self, SbomFormat};
+use docker_to_uefi_bootable_image::upload::{
+    self, GceImage, OxideImage, ProxmoxTemplate, S3Options, S3Url,
+};
 use docker_to_uefi_bootable_image::*;
 
 #[derive(Debug, Parser)]
 #[clap(about = "docker to uefi bootable image")]
-enum Args {
-    Create {
-        #[clap(short, long)]
-        image_name: String,
+struct Args {
+    // More output: -v shows commands, -vv their output
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    // Less output: -q shows only warnings, -qq only errors
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+
+    // Log format
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
+
+    // Append a line of JSON for every command run and every mount, loop
+    // device and unpacked archive to this file: arguments, environment,
+    // exit status and duration
+    #[clap(long, global = true)]
+    audit_log: Option<PathBuf>,
+
+    // Write everything to this file too, every command and its output,
+    // whatever -v or -q show on the console
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    Create(Box<CreateArgs>),
+
+    // Mount an image's root and EFI partitions for inspection
+    Mount(MountArgs),
+
+    // Undo `mount`
+    Umount(UmountArgs),
+
+    // Boot an image in QEMU with the serial console on this terminal
+    Boot(BootArgs),
 
-        #[clap(short, long)]
-        output_file: PathBuf,
+    // Shrink an image's root filesystem and partition to fit its contents
+    Shrink(ShrinkArgs),
 
-        // Disk size in GB
-        #[clap(short, long, default_value = "8")]
-        disk_size: usize,
+    // Create a Google Compute Engine image from an image
+    UploadGce(UploadGceArgs),
 
-        // Optional root password
-        #[clap(short, long)]
-        root_passwd: Option<String>,
+    // Import an image into an Oxide silo
+    UploadOxide(UploadOxideArgs),
 
-        #[clap(short, long, value_delimiter = ',')]
-        extra_packages: Vec<String>,
+    // Make a Proxmox VE VM template from an image
+    UploadProxmox(UploadProxmoxArgs),
 
-        // OS flavor (debian, ubuntu, ...)
-        #[clap(short, long)]
-        flavor: OsFlavor,
-    },
+    // Check an image's partition tables, ESP and root filesystem, without
+    // booting it
+    Verify(VerifyArgs),
+
+    // List what building an image changed: files added, removed or
+    // modified, between the container image and the disk's root
+    Diff(DiffArgs),
+
+    // Check that this host has everything `create` needs
+    Doctor(DoctorArgs),
+
+    // Remove containers left behind by builds that crashed or were killed
+    Cleanup(CleanupArgs),
 }
 
 #[derive(Debug, Clone, ValueEnum)]
-enum OsFlavor {
-    Debian,
-    Ubuntu,
-    Alpine,
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, clap::Args)]
+struct CreateArgs {
+    // TOML file with any of the options below, e.g. `flavor = "debian"`.
+    // Options given on the command line take precedence.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    // A value for {{KEY}} in the config file's strings. Keys not given are
+    // taken from the environment. Can be given more than once.
+    #[clap(long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
+    #[clap(short, long)]
+    image_name: String,
+
+    #[clap(short, long)]
+    output_file: PathBuf,
+
+    // Disk size in GB
+    #[clap(short, long, default_value = "8")]
+    disk_size: usize,
+
+    // GPT disk GUID to use, from which the partition GUIDs, ESP volume ID
+    // and root filesystem UUID are derived, for reproducible builds. Don't
+    // attach two images with the same one to a host at once.
+    #[clap(long, value_name = "UUID")]
+    disk_guid: Option<uuid::Uuid>,
+
+    // Make up new GUIDs and UUIDs for this build, as is the default. Given
+    // on the command line, it overrides a disk_guid from --config.
+    #[clap(long, conflicts_with = "disk_guid")]
+    randomize_guids: bool,
+
+    // Optional root password. If neither this nor --root-passwd-hash is
+    // given, a random one is generated and written next to the output file.
+    #[clap(short, long, conflicts_with = "root_passwd_hash")]
+    root_passwd: Option<String>,
+
+    // Root password hash (as in /etc/shadow) to set instead
+    #[clap(long)]
+    root_passwd_hash: Option<String>,
+
+    // Print the generated root password
+    #[clap(long)]
+    show_password: bool,
+
+    // Lock the root account instead of setting a password, for images only
+    // reachable with SSH keys or cloud-init
+    #[clap(long, conflicts_with_all = ["root_passwd", "root_passwd_hash"])]
+    lock_root: bool,
+
+    // Turn off sshd password and keyboard-interactive authentication
+    #[clap(long)]
+    disable_ssh_password_auth: bool,
+
+    #[clap(short, long, value_delimiter = ',')]
+    extra_packages: Vec<String>,
+
+    // Kernels to install and hold instead of the metapackage's: releases
+    // like 6.1.0-18-amd64 on Debian and Ubuntu, each with a GRUB entry, or
+    // one linux-lts version like 6.6.14-r0 on Alpine
+    #[clap(long, value_delimiter = ',')]
+    kernel_version: Vec<String>,
+
+    // OS flavor (debian, ubuntu, ...)
+    #[clap(short, long)]
+    flavor: Flavor,
+
+    // Install chrony and use the PTP KVM clock (/dev/ptp0) as a refclock
+    #[clap(long)]
+    chrony_phc: bool,
+
+    // Kernel clocksource (kvm-clock, tsc, ...), defaults to kvm-clock
+    // when --chrony-phc is used
+    #[clap(long)]
+    clocksource: Option<String>,
+
+    // A console to boot with, like ttyS0,115200, tty0 or hvc0; repeat for
+    // more. The first is the primary one, where boot messages go. GRUB and
+    // a login prompt go on each. Defaults to ttyS0,115200.
+    #[clap(long, value_name = "CONSOLE")]
+    console: Vec<Console>,
+
+    // Install firmware packages if any installed kernel module needs
+    // firmware that isn't already in /lib/firmware
+    #[clap(long)]
+    include_firmware: bool,
+
+    // Ignition config whose files stage (files, units, users) is applied on
+    // first boot, once root is up. The container image must provide
+    // /usr/bin/ignition. Configs that partition or format disks are refused.
+    #[clap(long)]
+    ignition: Option<PathBuf>,
+
+    // Hostname, defaults to one derived from the image name
+    #[clap(long)]
+    hostname: Option<String>,
+
+    // Network configuration for eth0
+    #[clap(long, value_enum, default_value = "dhcp")]
+    network: NetworkMode,
+
+    // Static address in CIDR notation, e.g. 192.168.1.10/24
+    #[clap(long, required_if_eq("network", "static"))]
+    address: Option<String>,
+
+    // Static default gateway
+    #[clap(long)]
+    gateway: Option<String>,
+
+    // Static DNS servers
+    #[clap(long, value_delimiter = ',')]
+    dns: Vec<String>,
+
+    // Mount the root read-only and keep changes in memory, for appliances
+    // that lose power: /etc and /var get a tmpfs overlay (on Alpine, the
+    // whole root does)
+    #[clap(long)]
+    read_only_root: bool,
+
+    // For live USB sticks: a read-only root whose changes are kept on a
+    // persistence partition of this many GB at the end of the disk
+    #[clap(long, value_name = "GB")]
+    persistence: Option<usize>,
+
+    // Add a partition after root, like /data:size=20,fs=xfs,source=seed.tar,
+    // mounted on its mountpoint and filled from a directory or tar archive.
+    // ext4 unless fs=xfs. Can be given more than once.
+    #[clap(long, value_name = "MOUNTPOINT:OPTIONS")]
+    data_partition: Vec<DataPartition>,
+
+    // Add a second ESP with the same files, for firmware to fall back to
+    // if the first is corrupted. It's in fstab as noauto on /boot/efi2.
+    #[clap(long)]
+    esp_mirror: bool,
+
+    // Partition with systemd-repart instead of writing the GPT directly;
+    // it also grows root on boot on Debian and Ubuntu
+    #[clap(long, value_enum, default_value = "native")]
+    partition_backend: PartitionBackend,
+
+    // Build without root or loop devices: as root in a user namespace,
+    // mapped to the subordinate IDs in /etc/subuid and /etc/subgid
+    #[clap(long)]
+    rootless: bool,
+
+    // Run the build in a privileged container of the builder image, which
+    // has every tool it needs, so the host only needs docker
+    #[clap(long)]
+    in_container: bool,
+
+    // The image --in-container runs the build in
+    #[clap(long, value_name = "IMAGE", default_value = relaunch::BUILDER_IMAGE)]
+    builder_image: String,
+
+    // Build a variant for every combination of these options' values, like
+    // flavor=debian,ubuntu. Each output file is named after its values, and
+    // --manifest gets one for every variant.
+    #[clap(long, value_name = "OPTION=VALUE,...")]
+    matrix: Vec<Axis>,
+
+    // Unpack this `docker export` of the image instead of exporting it
+    #[clap(long, value_name = "FILE")]
+    rootfs_tar: Option<PathBuf>,
+
+    // Change a mountpoint's fstab options, like /:noatime,-discard: each
+    // is added, or with a leading - removed. Can be given more than once.
+    #[clap(long = "fstab-opt", value_name = "MOUNTPOINT:OPTIONS")]
+    fstab_opt: Vec<FstabOptions>,
+
+    // Change a partition's type, name or attributes, like
+    // 3:type=root-x86-64,name=root,attribute=no-automount. Types and
+    // attributes are names or GUIDs and bit numbers. Can be given more
+    // than once.
+    #[clap(long, value_name = "NUMBER:CHANGES")]
+    gpt_partition: Vec<PartitionChange>,
+
+    // Trim root weekly and mount it noatime,discard, for thin-provisioned
+    // disks
+    #[clap(long)]
+    fstrim: bool,
+
+    // Install and enable the guest agent for the hypervisor the image will
+    // run on
+    #[clap(long, value_enum, default_value = "none")]
+    guest_tools: GuestTools,
+
+    // Services to enable, disable, or mask (systemctl, or rc-update on
+    // Alpine, which can't mask)
+    #[clap(long, value_delimiter = ',')]
+    enable_service: Vec<String>,
+
+    #[clap(long, value_delimiter = ',')]
+    disable_service: Vec<String>,
+
+    #[clap(long, value_delimiter = ',')]
+    mask_service: Vec<String>,
+
+    // Run the container's entrypoint and command on boot, with its
+    // environment, working directory and user, as container.service (or
+    // /etc/init.d/container on Alpine). A HEALTHCHECK restarts it once
+    // the check fails as many times in a row as docker would allow.
+    #[clap(long)]
+    container_service: bool,
+
+    // Give logins the container's environment variables, in
+    // /etc/environment (or /etc/profile.d on Alpine)
+    #[clap(long)]
+    container_env: bool,
+
+    // Install this firewall and let in only the ports the container image
+    // EXPOSEs (ufw and firewalld aren't available on Alpine)
+    #[clap(long, value_enum)]
+    firewall: Option<Firewall>,
+
+    // More ports to let through the firewall, as PORT[/tcp|udp], e.g. 22
+    // for ssh
+    #[clap(long, value_delimiter = ',', requires = "firewall")]
+    allow_port: Vec<Port>,
+
+    // Install docker and compose, save the images of this compose file's
+    // services onto the disk, and start each service on boot after those
+    // it depends on. Services have to have an image; nothing is built. Not
+    // with --firewall, which would drop what docker forwards to them.
+    #[clap(long, conflicts_with = "firewall")]
+    compose: Option<PathBuf>,
+
+    // Shell command to run in the chroot, optionally prefixed with the hook
+    // point (post-extract:, post-packages:, pre-umount:). Defaults to
+    // post-packages.
+    #[clap(long, value_parser = parse_chroot_command)]
+    run_in_chroot: Vec<(HookPoint, String)>,
+
+    // Directory with post-extract/, post-packages/ and pre-umount/
+    // subdirectories of executables to run in the chroot, in name order
+    #[clap(long)]
+    hook_dir: Option<PathBuf>,
+
+    // Drop into a shell chrooted into the image at this point, and resume
+    // when it exits
+    #[clap(long, value_enum)]
+    pause: Vec<HookPoint>,
+
+    // Keep machine-id, SSH host keys, package caches and logs instead of
+    // resetting them so clones of the image get their own
+    #[clap(long)]
+    no_clean: bool,
+
+    // A smaller root for appliances: no recommended packages, docs, man
+    // pages, translations, package caches or lists
+    #[clap(long)]
+    minimal: bool,
+
+    // Install the SELinux policy and enable SELinux on the kernel command
+    // line (Debian and Ubuntu only)
+    #[clap(long)]
+    selinux: bool,
+
+    // For an image that won't boot: boot verbosely, and leave a shell on
+    // the serial console if the initramfs or systemd fail, even with root
+    // locked
+    #[clap(long)]
+    debug_shell: bool,
+
+    // Reboot this many seconds after a kernel panic or initramfs failure
+    #[clap(long, value_name = "SECS")]
+    panic: Option<u32>,
+
+    // Extra kernel modules to include in the initramfs, e.g.
+    // virtio_blk,virtio_scsi,nvme
+    #[clap(long, value_delimiter = ',')]
+    initramfs_modules: Vec<String>,
+
+    // Compress the initramfs with this instead of the flavor's default
+    #[clap(long, value_enum)]
+    initramfs_compression: Option<InitramfsCompression>,
+
+    // Which modules initramfs-tools includes: most, or dep for only the
+    // host's, which then needs the VM's disk drivers in --initramfs-modules
+    // (Debian and Ubuntu only)
+    #[clap(long, value_enum)]
+    initramfs_module_set: Option<InitramfsModuleSet>,
+
+    // sysctl settings (key=value) for /etc/sysctl.d
+    #[clap(long)]
+    sysctl: Vec<String>,
+
+    // Files to copy into /etc/sysctl.d
+    #[clap(long)]
+    sysctl_file: Vec<PathBuf>,
+
+    // Kernel modules to blacklist in /etc/modprobe.d
+    #[clap(long, value_delimiter = ',')]
+    blacklist_module: Vec<String>,
+
+    // Files to copy into /etc/modprobe.d
+    #[clap(long)]
+    modprobe_file: Vec<PathBuf>,
+
+    // Package mirror base URL, replacing deb.debian.org/debian,
+    // archive.ubuntu.com/ubuntu or dl-cdn.alpinelinux.org/alpine
+    #[clap(long)]
+    mirror: Option<String>,
+
+    // HTTP(S) proxy for package downloads during the build
+    #[clap(long)]
+    pkg_proxy: Option<String>,
+
+    // Keep downloaded packages here, in a directory per flavor, so later
+    // builds don't download them again
+    #[clap(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    // PEM CA certificates to add to the image's trust store
+    #[clap(long)]
+    ca_cert: Vec<PathBuf>,
+
+    // Print every command the build would run, without touching loop
+    // devices, mounts, or docker
+    #[clap(long)]
+    dry_run: bool,
+
+    // Write a JSON description of the built image (UUIDs, kernel, checksum,
+    // sizes) here
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    // Also write the summary printed at the end of the build here
+    #[clap(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    // Write a bill of materials listing the packages installed in the image
+    // and the container image's digest, next to the output file as
+    // <output-file>.spdx.json or <output-file>.cdx.json
+    #[clap(long, value_name = "FORMAT")]
+    sbom: Option<SbomFormat>,
+
+    // Write the image's checksum next to it, e.g. <output-file>.sha256, in
+    // the format sha256sum -c checks
+    #[clap(long, value_name = "ALGORITHM")]
+    checksum: Option<ChecksumAlgorithm>,
+
+    // Sign the image with this key, writing a detached signature next to it:
+    // <output-file>.asc for gpg (a key ID or email), <output-file>.sig for
+    // cosign (a key file or KMS URI)
+    #[clap(long, value_name = "KEY")]
+    sign_key: Option<String>,
+
+    // What signs the image for --sign-key
+    #[clap(long, value_enum, default_value_t = Signer::Gpg, requires = "sign_key")]
+    signer: Signer,
+
+    // After building, boot the image headless in QEMU and fail unless the
+    // serial console shows --verify-boot-marker in time
+    #[clap(long)]
+    verify_boot: bool,
+
+    // What a successful boot prints on the serial console
+    #[clap(long, default_value = "login:")]
+    verify_boot_marker: String,
+
+    // Seconds to wait for --verify-boot-marker
+    #[clap(long, default_value_t = 300)]
+    verify_boot_timeout: u64,
+
+    // Firmware for --verify-boot
+    #[clap(flatten)]
+    ovmf: OvmfArgs,
+
+    // Where to upload the finished image
+    #[clap(flatten)]
+    s3: S3Args,
+
+    // Keep the working directory (disk image, docker export, mount points)
+    // after the build. It is always kept if the build fails.
+    #[clap(long)]
+    keep_workdir: bool,
+
+    // Build in a tmpfs of this size (like 16G, or 50% of RAM) rather than
+    // under the temporary directory, so only the final copy touches disk.
+    // It must hold the docker export and the disk's used blocks.
+    #[clap(long, value_name = "SIZE")]
+    workdir_tmpfs: Option<String>,
+
+    // Write a tarball for bug reports with the build log, every command run
+    // and its output, and the fstab and GRUB config generated in the image
+    #[clap(long)]
+    diagnostics: Option<PathBuf>,
+
+    // Continue a failed build from the working directory it left behind,
+    // skipping the steps that already completed
+    #[clap(long, value_name = "WORKDIR")]
+    resume: Option<PathBuf>,
+
+    // Kill any command that runs longer than this many seconds (a hung apt
+    // update, say) and fail the build
+    #[clap(long, value_name = "SECS")]
+    command_timeout: Option<u64>,
+
+    // Retry package index updates and image pulls that fail, and unmounts
+    // and loop detaches that find the device busy, this many times
+    #[clap(long, default_value_t = 3)]
+    retries: u32,
+
+    // Seconds before the first retry, doubling after each one
+    #[clap(long, value_name = "SECS", default_value_t = 2)]
+    retry_delay: u64,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(Debug, clap::Args)]
+struct MountArgs {
+    // Image to mount
+    image: PathBuf,
 
-    match args {
-        Args::Create {
-            image_name,
-            output_file,
-            disk_size,
-            root_passwd,
-            extra_packages,
-            flavor,
-        } => {
-            println!(
-                "Creating a bootable image {:?} out of {:?}",
-                output_file, image_name,
+    // Where to mount the root partition. The EFI partition is mounted at
+    // boot/efi under it.
+    dir: PathBuf,
+
+    // Attach and mount the image read-only
+    #[clap(long)]
+    read_only: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct UmountArgs {
+    // Directory given to `mount`
+    dir: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+struct VerifyArgs {
+    // Image to check
+    image: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+struct DiffArgs {
+    // Container image the disk was built from
+    image_name: String,
+
+    // Disk image to compare with it
+    image: PathBuf,
+
+    // Include what every build changes, like the kernel, GRUB and the
+    // package database
+    #[clap(long)]
+    all: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct DoctorArgs {
+    // Disk size in GB to check for free space
+    #[clap(short, long, default_value = "8")]
+    disk_size: usize,
+
+    // Check for a build with --rootless
+    #[clap(long)]
+    rootless: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct CleanupArgs {
+    // List what would be removed without removing it
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct ShrinkArgs {
+    // Image to shrink, in place
+    image: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+struct UploadGceArgs {
+    // Image to upload
+    image: PathBuf,
+
+    // Name of the Compute Engine image to create
+    #[clap(long)]
+    name: String,
+
+    // Cloud Storage bucket to stage the image's tarball in, as gs://BUCKET
+    // or gs://BUCKET/PREFIX
+    #[clap(long)]
+    bucket: String,
+
+    // Project to create the image in, if not gcloud's configured one
+    #[clap(long)]
+    project: Option<String>,
+
+    // Image family, so instances can ask for the latest image in it
+    #[clap(long)]
+    family: Option<String>,
+
+    #[clap(long)]
+    description: Option<String>,
+
+    // Labels for the image, as KEY=VALUE
+    #[clap(long, value_delimiter = ',')]
+    labels: Vec<String>,
+
+    // Guest OS features besides UEFI_COMPATIBLE, such as GVNIC or
+    // VIRTIO_SCSI_MULTIQUEUE
+    #[clap(long, value_delimiter = ',')]
+    guest_os_features: Vec<String>,
+
+    // Region or multi-region to store the image in, like "us"
+    #[clap(long)]
+    storage_location: Option<String>,
+
+    // Leave the tarball in the bucket after the image is created
+    #[clap(long)]
+    keep_tarball: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct UploadOxideArgs {
+    // Image to upload
+    image: PathBuf,
+
+    // Project to create the image in
+    #[clap(long)]
+    project: String,
+
+    // Name of the Oxide image to create
+    #[clap(long)]
+    name: String,
+
+    #[clap(long, default_value = "")]
+    description: String,
+
+    // Operating system of the image, e.g. "debian"
+    #[clap(long)]
+    os: String,
+
+    // Version of the operating system, e.g. "12"
+    #[clap(long)]
+    version: String,
+
+    // Make the image available to every project in the silo
+    #[clap(long)]
+    promote: bool,
+
+    // Profile from the oxide CLI's config. Without it, the default profile
+    // or OXIDE_HOST and OXIDE_TOKEN are used.
+    #[clap(long)]
+    profile: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct UploadProxmoxArgs {
+    // Image to upload
+    image: PathBuf,
+
+    // ID of the VM to create and turn into a template
+    #[clap(long)]
+    vmid: u32,
+
+    // Name of the template
+    #[clap(long)]
+    name: String,
+
+    // Storage for the template's disks
+    #[clap(long, default_value = "local-lvm")]
+    storage: String,
+
+    // The node's name in the cluster, if it isn't its hostname
+    #[clap(long)]
+    node: Option<String>,
+
+    // ssh destination of the node, like root@pve1. Without it, this
+    // machine is the node.
+    #[clap(long)]
+    host: Option<String>,
+
+    // Memory for VMs cloned from the template, in MiB
+    #[clap(long, default_value_t = 2048)]
+    memory: u32,
+
+    #[clap(long, default_value_t = 2)]
+    cores: u32,
+
+    // Bridge for the VM's network interface
+    #[clap(long, default_value = "vmbr0")]
+    bridge: String,
+}
+
+#[derive(Debug, clap::Args)]
+struct BootArgs {
+    // Image to boot
+    image: PathBuf,
+
+    // Guest memory, in QEMU's syntax
+    #[clap(long, default_value = "2G")]
+    memory: String,
+
+    // Guest CPUs
+    #[clap(long, default_value_t = 2)]
+    cpus: usize,
+
+    #[clap(flatten)]
+    ovmf: OvmfArgs,
+
+    // Forward a host TCP port to the guest as HOST:GUEST, e.g. 2222:22
+    #[clap(long, value_parser = parse_port_forward)]
+    forward: Vec<(u16, u16)>,
+
+    // Keep changes the guest makes to the image. By default they are
+    // discarded when QEMU exits.
+    #[clap(long)]
+    persist: bool,
+
+    // Use software emulation instead of KVM
+    #[clap(long)]
+    no_kvm: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct S3Args {
+    // Once the image is built (and booted, with --verify-boot), upload it
+    // here, along with its checksum, signature and SBOM under the same key
+    // plus their suffixes
+    #[clap(long, value_name = "s3://BUCKET/KEY")]
+    upload: Option<S3Url>,
+
+    // S3-compatible storage to upload to instead of AWS, e.g.
+    // http://minio.internal:9000
+    #[clap(long, value_name = "URL", requires = "upload")]
+    s3_endpoint: Option<String>,
+
+    #[clap(long, requires = "upload")]
+    s3_region: Option<String>,
+
+    // Profile from the aws CLI's config to upload as. Without it (or
+    // --s3-credentials-file) the aws CLI's usual credentials are used,
+    // such as AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+    #[clap(long, requires = "upload")]
+    s3_profile: Option<String>,
+
+    // Credentials file to use instead of ~/.aws/credentials
+    #[clap(long, value_name = "FILE", requires = "upload")]
+    s3_credentials_file: Option<PathBuf>,
+
+    // Size of each part of the upload, in MiB
+    #[clap(long, value_name = "MIB", default_value_t = 64,
+           value_parser = clap::value_parser!(u64).range(5..), requires = "upload")]
+    s3_part_size: u64,
+}
+
+impl S3Args {
+    fn options(&self) -> S3Options {
+        S3Options {
+            endpoint_url: self.s3_endpoint.clone(),
+            region: self.s3_region.clone(),
+            profile: self.s3_profile.clone(),
+            credentials_file: self.s3_credentials_file.clone(),
+            part_size: self.s3_part_size * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct OvmfArgs {
+    // UEFI firmware for QEMU. Found in the usual distro locations if not
+    // given.
+    #[clap(long)]
+    ovmf: Option<PathBuf>,
+
+    // UEFI variable store template to go with --ovmf. Without it, --ovmf is
+    // used as a combined firmware image.
+    #[clap(long, requires = "ovmf")]
+    ovmf_vars: Option<PathBuf>,
+
+    // If no OVMF is installed, download it from here: a Debian ovmf .deb or
+    // a combined OVMF.fd
+    #[clap(long, requires = "ovmf_sha256")]
+    ovmf_url: Option<String>,
+
+    // SHA-256 that the --ovmf-url download must have
+    #[clap(long, requires = "ovmf_url")]
+    ovmf_sha256: Option<String>,
+}
+
+impl OvmfArgs {
+    fn resolve(&self) -> Result<Ovmf> {
+        if let Some(code) = &self.ovmf {
+            return Ok(Ovmf {
+                code: code.clone(),
+                vars: self.ovmf_vars.clone(),
+            });
+        }
+
+        if let Some(ovmf) = Ovmf::find(Path::new("/")) {
+            debug!("found OVMF at {:?}", ovmf.code);
+            return Ok(ovmf);
+        }
+
+        if let (Some(url), Some(sha256)) = (&self.ovmf_url, &self.ovmf_sha256) {
+            return Ovmf::download(url, sha256, &cache_dir()?.join("ovmf"));
+        }
+
+        bail!("no OVMF firmware found, install it (e.g. the ovmf or edk2-ovmf package) or use --ovmf or --ovmf-url");
+    }
+}
+
+/// Per-user cache for downloads
+fn cache_dir() -> Result<PathBuf> {
+    let base = match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+        (Some(cache), _) => PathBuf::from(cache),
+        (None, Some(home)) => PathBuf::from(home).join(".cache"),
+        (None, None) => bail!("neither XDG_CACHE_HOME nor HOME is set"),
+    };
+
+    Ok(base.join("docker_to_uefi_bootable_image"))
+}
+
+fn parse_port_forward(value: &str) -> Result<(u16, u16)> {
+    let Some((host, guest)) = value.split_once(':') else {
+        bail!("expected HOST:GUEST, got {:?}", value);
+    };
+
+    Ok((host.parse()?, guest.parse()?))
+}
+
+fn parse_chroot_command(value: &str) -> Result<(HookPoint, String), String> {
+    if let Some((point, command)) = value.split_once(':') {
+        if let Ok(point) = HookPoint::from_str(point, true) {
+            return Ok((point, command.to_string()));
+        }
+    }
+
+    Ok((HookPoint::PostPackages, value.to_string()))
+}
+
+/// Cancelled by Ctrl-C or SIGTERM once `cancel_on_signals` is called
+fn interrupt_token() -> &'static CancelToken {
+    static TOKEN: OnceLock<CancelToken> = OnceLock::new();
+    TOKEN.get_or_init(CancelToken::new)
+}
+
+/// The signal that cancelled the build, or 0
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Make Ctrl-C and SIGTERM cancel the build: the running command is
+/// killed, and everything is unmounted, detached and removed on the way
+/// out rather than left behind. A second signal quits right away.
+fn cancel_on_signals() {
+    extern "C" fn on_signal(signal: libc::c_int) {
+        let token = interrupt_token();
+        if token.is_cancelled() {
+            // SAFETY: _exit is async-signal-safe
+            unsafe { libc::_exit(128 + signal) };
+        }
+        SIGNAL.store(signal, Ordering::SeqCst);
+        token.cancel();
+    }
+
+    // Initialize outside of the handler
+    interrupt_token();
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only touches atomics, or exits
+        unsafe {
+            libc::signal(
+                signal,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
             );
+        }
+    }
+}
+
+/// Exit like a process killed by the signal that cancelled the build did
+/// (130 for Ctrl-C, 143 for SIGTERM), once everything has been cleaned up.
+/// Does nothing if the build wasn't cancelled by a signal.
+fn exit_if_signalled(result: &Result<()>) {
+    let signal = SIGNAL.load(Ordering::SeqCst);
+
+    if let (Err(e), true) = (result, signal != 0) {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(128 + signal);
+    }
+}
 
-            println!("> Creating {} GB blank disk", disk_size);
-            let blank_disk = LoopbackDisk::new(disk_size)?;
+/// Exit codes for the kinds of failure automation might handle differently.
+/// Anything else is 1, and clap's usage errors are 2 as well.
+mod exit_code {
+    pub const FAILURE: i32 = 1;
+    /// Options that are wrong or contradict each other, or a missing file
+    pub const USAGE: i32 = 2;
+    /// The host can't build: not root, or a tool or capability missing
+    pub const PREFLIGHT: i32 = 3;
+    /// The image couldn't be pulled, inspected or exported
+    pub const DOCKER: i32 = 4;
+    /// Installing or configuring packages in the chroot failed
+    pub const PACKAGES: i32 = 5;
+    /// GRUB or the initramfs couldn't be installed
+    pub const BOOTLOADER: i32 = 6;
+    /// --verify-boot didn't see the image boot, or `verify` found it broken
+    pub const VERIFICATION: i32 = 7;
+}
+
+/// The phase the last build failed in, from its events
+static FAILED_PHASE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Keep track of the phase a build fails in, for its exit code
+fn record_failed_phase(event: &BuildEvent) {
+    if let BuildEvent::PhaseFinished {
+        phase,
+        succeeded: false,
+        ..
+    } = event
+    {
+        *FAILED_PHASE.lock().unwrap() = Some(phase.clone());
+    }
+}
 
-            println!("> Creating partitioned disk");
-            let partitioned_disk = PartitionedLoopbackDisk::from(blank_disk)?;
+/// The exit code for `e`, by what it is or else the phase it happened in
+fn exit_code_for(e: &anyhow::Error, failed_phase: Option<&str>) -> i32 {
+    let tool_missing = e
+        .root_cause()
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|x| x.kind() == std::io::ErrorKind::NotFound);
+
+    match e.downcast_ref::<Error>() {
+        Some(
+            Error::InvalidOptions(_) | Error::UnsupportedFlavor { .. } | Error::MissingFile(_),
+        ) => exit_code::USAGE,
+        Some(Error::PreflightFailed(_) | Error::NotRoot) => exit_code::PREFLIGHT,
+        Some(Error::InvalidImage { .. }) => exit_code::DOCKER,
+        Some(Error::CommandFailed { cmd, .. }) if cmd.starts_with("docker ") => exit_code::DOCKER,
+        Some(Error::BootFailed { .. } | Error::VerifyFailed(_)) => exit_code::VERIFICATION,
+        _ if tool_missing => exit_code::PREFLIGHT,
+        _ => match failed_phase {
+            Some("packages") => exit_code::PACKAGES,
+            Some("bootloader" | "initramfs") => exit_code::BOOTLOADER,
+            _ => exit_code::FAILURE,
+        },
+    }
+}
+
+fn main() {
+    if let Err(e) = run_command() {
+        eprintln!("Error: {:?}", e);
+        let failed_phase = FAILED_PHASE.lock().unwrap().take();
+        std::process::exit(exit_code_for(&e, failed_phase.as_deref()));
+    }
+}
 
-            println!("> Main disk at {}", partitioned_disk.path());
+fn run_command() -> Result<()> {
+    let argv = with_config_args(std::env::args().collect())?;
+    let args = Args::parse_from(matrix_base_argv(&argv)?);
+
+    // The build in the container writes the log file itself
+    let log_file = match &args.command {
+        Command::Create(create) if create.in_container => None,
+        _ => args.log_file.as_deref(),
+    };
+    init_logging(args.verbose, args.quiet, &args.log_format, log_file)?;
+
+    // unshare needs the process to still be single threaded
+    match &args.command {
+        Command::Create(create) if create.rootless && !create.dry_run && !create.in_container => {
+            rootless::enter_user_namespace()?
+        }
+        Command::Doctor(doctor) if doctor.rootless => rootless::enter_user_namespace()?,
+        _ => {}
+    }
 
-            let root_device_partition_2 = format!("{}{}", partitioned_disk.path(), "p2");
-            let root_device_partition_3 = format!("{}{}", partitioned_disk.path(), "p3");
+    // Before anything runs, so it also covers signing, booting and uploading
+    // after a build
+    if let Some(path) = &args.audit_log {
+        set_executor(Rc::new(AuditExecutor::create(path, executor())?));
+    }
 
-            println!("> Format partitions");
-            run(
-                "mkfs.vfat".into(),
-                &["-F".into(), "32".into(), root_device_partition_2.clone()],
+    match args.command {
+        Command::Create(create) if create.in_container => {
+            let dirs = in_container_dirs(
+                &create,
+                &[args.audit_log.as_deref(), args.log_file.as_deref()],
+            );
+            let status = relaunch::relaunch(&create.builder_image, &argv, &dirs)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Command::Create(create) if !create.matrix.is_empty() => matrix_build(*create, &argv),
+        Command::Create(args) if args.dry_run => dry_run(*args),
+        Command::Create(args) => {
+            cancel_on_signals();
+            let result = create(*args);
+            exit_if_signalled(&result);
+            result
+        }
+        Command::Mount(args) => {
+            let dir = image::mount(
+                &args.image,
+                &args.dir,
+                args.read_only,
+                Path::new(image::MOUNT_STATE_PATH),
             )?;
+            println!("{} mounted at {}", args.image.display(), dir.display());
+            Ok(())
+        }
+        Command::Umount(args) => image::umount(&args.dir, Path::new(image::MOUNT_STATE_PATH)),
+        Command::Boot(args) => qemu::boot(&qemu_options(&args), &args.ovmf.resolve()?),
+        Command::Shrink(args) => image::shrink(&args.image),
+        Command::UploadGce(args) => upload::upload_gce(
+            &args.image,
+            &GceImage {
+                name: args.name,
+                bucket: args.bucket,
+                project: args.project,
+                family: args.family,
+                description: args.description,
+                labels: args.labels,
+                guest_os_features: args.guest_os_features,
+                storage_location: args.storage_location,
+                keep_tarball: args.keep_tarball,
+            },
+        ),
+        Command::UploadOxide(args) => upload::upload_oxide(
+            &args.image,
+            &OxideImage {
+                project: args.project,
+                name: args.name,
+                description: args.description,
+                os: args.os,
+                version: args.version,
+                promote: args.promote,
+                profile: args.profile,
+            },
+        ),
+        Command::UploadProxmox(args) => upload::upload_proxmox(
+            &args.image,
+            &ProxmoxTemplate {
+                vmid: args.vmid,
+                name: args.name,
+                storage: args.storage,
+                node: args.node,
+                host: args.host,
+                memory: args.memory,
+                cores: args.cores,
+                bridge: args.bridge,
+            },
+        ),
+        Command::Verify(args) => verify(args),
+        Command::Diff(args) => diff(args),
+        Command::Doctor(args) => doctor(args),
+        Command::Cleanup(args) => cleanup_leftovers(args),
+    }
+}
 
-            run("mkfs.ext4".into(), &[root_device_partition_3.clone()])?;
+fn qemu_options(args: &BootArgs) -> QemuOptions {
+    QemuOptions {
+        image: args.image.clone(),
+        memory: args.memory.clone(),
+        cpus: args.cpus,
+        forward: args.forward.clone(),
+        persist: args.persist,
+        no_kvm: args.no_kvm,
+    }
+}
 
-            println!("> Mount partitions");
+/// What `create --in-container` mounts: the current directory, for relative
+/// paths, and wherever else the build reads or writes
+fn in_container_dirs(args: &CreateArgs, logs: &[Option<&Path>]) -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("."), relaunch::parent_dir(&args.output_file)];
+
+    dirs.extend(args.manifest.as_deref().map(relaunch::parent_dir));
+    dirs.extend(logs.iter().flatten().map(|x| relaunch::parent_dir(x)));
+    dirs.extend(args.report.as_deref().map(relaunch::parent_dir));
+    dirs.extend(args.cache_dir.clone());
+    dirs.extend(
+        args.data_partition
+            .iter()
+            .filter_map(|x| x.source.as_deref())
+            .map(relaunch::parent_dir),
+    );
+
+    dirs
+}
 
-            let mount_root_path = {
-                let mut path = partitioned_disk.working_dir().path().to_path_buf();
-                path.push("mnt");
-                path.into_os_string().into_string().unwrap()
-            };
+fn cleanup_leftovers(args: CleanupArgs) -> Result<()> {
+    let leftovers = Container::leftovers()?;
 
-            let mount_partition_3 =
-                Mount::new(root_device_partition_3.clone(), mount_root_path.clone())?;
+    if leftovers.is_empty() {
+        println!("no leftover containers");
+    }
 
-            let mount_partition_2 = Mount::new(
-                root_device_partition_2.clone(),
-                format!("{}/boot/efi", mount_root_path),
-            )?;
+    for name in leftovers {
+        if args.dry_run {
+            println!("would remove container {}", name);
+        } else {
+            Container::remove_leftover(&name)?;
+            println!("removed container {}", name);
+        }
+    }
 
-            run(
-                "mkdir".into(),
-                &[
-                    "-p".into(),
-                    format!("{}/boot/efi/EFI/BOOT/", mount_root_path),
-                ],
-            )?;
+    Ok(())
+}
 
-            println!("> Copy docker image contents to directory");
+fn verify(args: VerifyArgs) -> Result<()> {
+    let checks = image::verify(&args.image)?;
 
-            let tempname: String = uuid::Uuid::new_v4().to_string();
+    for check in &checks {
+        let status = if check.ok { "ok" } else { "FAIL" };
+        println!("{:4} {:12} {}", status, check.name, check.detail);
+    }
 
-            let export_path = {
-                let mut path = partitioned_disk.working_dir().path().to_path_buf();
-                path.push("export.tar");
-                path.into_os_string().into_string().unwrap()
-            };
+    let failed: Vec<String> = checks
+        .iter()
+        .filter(|x| !x.ok)
+        .map(|x| format!("{}: {}", x.name, x.detail))
+        .collect();
+    if !failed.is_empty() {
+        return Err(Error::VerifyFailed(failed).into());
+    }
 
-            run(
-                "docker".into(),
-                &[
-                    "run".into(),
-                    "-d".into(),
-                    "--entrypoint=/bin/sh".into(),
-                    "--name".into(),
-                    tempname.clone(),
-                    image_name,
-                ],
-            )?;
-            run(
-                "docker".into(),
-                &[
-                    "export".into(),
-                    "-o".into(),
-                    export_path.clone(),
-                    tempname.clone(),
-                ],
-            )?;
-            run("docker".into(), &["stop".into(), tempname.clone()])?;
-            run("docker".into(), &["rm".into(), tempname])?;
-
-            run(
-                "tar".into(),
-                &[
-                    "--sparse".into(),
-                    "-C".into(),
-                    mount_partition_3.dest(),
-                    "-xf".into(),
-                    export_path,
-                ],
-            )?;
+    Ok(())
+}
 
-            println!("> install extra packages in container to support UEFI boot");
+fn diff(args: DiffArgs) -> Result<()> {
+    let differences = diff::diff(&args.image_name, &args.image)?;
 
-            std::fs::copy(
-                "/etc/resolv.conf",
-                format!("{}/etc/resolv.conf", mount_partition_3.dest()),
-            )?;
+    let (shown, hidden): (Vec<_>, Vec<_>) = differences
+        .iter()
+        .partition(|x| args.all || !diff::is_expected(&x.path));
 
-            let bind_dev = Mount::bind("/dev".into(), format!("{}/dev", mount_partition_3.dest()))?;
-            let bind_proc =
-                Mount::bind("/proc".into(), format!("{}/proc", mount_partition_3.dest()))?;
-            let bind_sys = Mount::bind("/sys".into(), format!("{}/sys", mount_partition_3.dest()))?;
-
-            // Update package repos
-            match flavor {
-                OsFlavor::Debian | OsFlavor::Ubuntu => {
-                    run(
-                        "chroot".into(),
-                        &[
-                            mount_partition_3.dest(),
-                            "apt".into(),
-                            "update".into(),
-                            "-y".into(),
-                        ],
-                    )?;
-                }
+    for difference in &shown {
+        println!("{}", difference);
+    }
 
-                OsFlavor::Alpine => {
-                    run(
-                        "chroot".into(),
-                        &[mount_partition_3.dest(), "apk".into(), "update".into()],
-                    )?;
-                }
-            }
+    if !hidden.is_empty() {
+        info!(
+            "{} more where every build makes changes, like the kernel and package database; --all shows them",
+            hidden.len()
+        );
+    }
 
-            // stop to manually chroot and debug
-            //println!("> Enter some text when done");
-            //let mut s = String::new();
-            //std::io::stdin().read_line(&mut s).expect("Not a string?");
-
-            // Install necessary installer packages for EFI
-            match flavor {
-                OsFlavor::Debian | OsFlavor::Ubuntu => {
-                    let kernel_pkg = match flavor {
-                        OsFlavor::Debian => "linux-image-amd64",
-                        OsFlavor::Ubuntu => "linux-image-generic",
-                        _ => panic!("wat"),
-                    };
-
-                    run(
-                        "chroot".into(),
-                        &[
-                            mount_partition_3.dest(),
-                            "apt".into(),
-                            "install".into(),
-                            "-y".into(),
-                            kernel_pkg.into(),
-                            "systemd-sysv".into(),
-                            "grub2-common".into(),
-                            "grub-efi-amd64-bin".into(),
-                            "initramfs-tools".into(),
-                        ],
-                    )?;
-
-                    // If Debian or Ubuntu, install extra packages - there isn't
-                    // separate disk like Alpine.
-                    if !extra_packages.is_empty() {
-                        println!("> install extra packages");
-
-                        let mut args = vec![
-                            mount_partition_3.dest(),
-                            "apt".into(),
-                            "install".into(),
-                            "-y".into(),
-                        ];
-                        args.extend_from_slice(&extra_packages[..]);
-
-                        run("chroot".into(), &args)?;
-                    }
-                }
+    Ok(())
+}
 
-                OsFlavor::Alpine => {
-                    run(
-                        "chroot".into(),
-                        &[
-                            mount_partition_3.dest(),
-                            "apk".into(),
-                            "add".into(),
-                            "grub-efi".into(),
-                            "mkinitfs".into(),
-                            "alpine-conf".into(),
-                            "linux-lts".into(),
-                        ],
-                    )?;
-
-                    // Populate /answers for setup-alpine
-                    let mut answers =
-                        File::create(format!("{}/answers", mount_partition_3.dest()))?;
-
-                    writeln!(
-                        answers,
-                        r##"
-KEYMAPOPTS="us us"
-HOSTNAMEOPTS="-n alpine"
-DEVDOPTS="mdev"
-INTERFACESOPTS="auto lo
-iface lo inet loopback
-
-auto eth0
-iface eth0 inet dhcp
-    hostname alpine
-"
-DNSOPTS="-d example.com 8.8.8.8"
-TIMEZONEOPTS="-z UTC"
-APKREPOSOPTS="-1"
-SSHDOPTS="-c openssh"
-NTPOPTS="-c openntpd"
-DISKOPTS="-m sys /"
-"##
-                    )?;
-
-                    drop(answers);
-
-                    // Run setup-alpine
-                    run_with_env(
-                        "chroot".into(),
-                        &[
-                            mount_partition_3.dest(),
-                            "setup-alpine".into(),
-                            "-q".into(),
-                            "-f".into(),
-                            "/answers".into(),
-                        ],
-                        &[("USE_EFI".into(), "1".into())],
-                    )?;
-
-                    run(
-                        "chroot".into(),
-                        &[mount_partition_3.dest(), "rm".into(), "/answers".into()],
-                    )?;
-                }
+fn doctor(args: DoctorArgs) -> Result<()> {
+    let checks = preflight_checks(args.disk_size, None, None, false, args.rootless);
+
+    for check in &checks {
+        let status = if check.ok { "ok" } else { "FAIL" };
+        println!("{:4} {:12} {}", status, check.name, check.detail);
+    }
+
+    let failed = checks.iter().filter(|x| !x.ok).count();
+    if failed > 0 {
+        bail!("{} check(s) failed", failed);
+    }
+
+    Ok(())
+}
+
+/// The level logged to the console, which --log-file doesn't change
+static CONSOLE_LEVEL: OnceLock<Level> = OnceLock::new();
+
+fn init_logging(
+    verbose: u8,
+    quiet: u8,
+    log_format: &LogFormat,
+    log_file: Option<&Path>,
+) -> Result<()> {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
+
+    let level = match i16::from(verbose) - i16::from(quiet) {
+        i16::MIN..=-2 => Level::ERROR,
+        -1 => Level::WARN,
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let _ = CONSOLE_LEVEL.set(level);
+
+    let console = tracing_subscriber::fmt::layer().with_writer(|| LogWriter);
+    let console = match log_format {
+        LogFormat::Text => console.boxed(),
+        LogFormat::Json => console.json().boxed(),
+    };
+
+    let file = match log_file {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| format!("creating log file {}", path.display()))?;
+            let layer = tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .with_filter(LevelFilter::TRACE);
+            Some(layer)
+        }
+        None => None,
+    };
+
+    // Progress bars would only get in the way of quiet or machine read output
+    set_progress_enabled(quiet == 0 && matches!(log_format, LogFormat::Text));
+
+    tracing_subscriber::registry()
+        .with(console.with_filter(LevelFilter::from_level(level)))
+        .with(file)
+        .init();
+
+    Ok(())
+}
+
+/// Run `create` against a simulated host, then print the plan
+fn dry_run(args: CreateArgs) -> Result<()> {
+    let unreadable = Rc::new(RefCell::new(None));
+    let executor = Rc::new(RecordingExecutor::new(dry_run_host(
+        args.initramfs_modules.clone(),
+        unreadable.clone(),
+    )));
+
+    // What comes from the image's config, if it can't be read
+    let image_name = args.image_name.clone();
+    let from_config: Vec<&str> = [
+        (
+            args.container_service,
+            "the container's service and health check",
+        ),
+        (args.container_env, "the container's environment"),
+        (args.firewall.is_some(), "the firewall's ports"),
+    ]
+    .into_iter()
+    .filter(|(given, _)| *given)
+    .map(|(_, what)| what)
+    .collect();
+
+    // Nothing really runs, so there is no progress to show
+    set_progress_enabled(false);
+
+    let previous = set_executor(executor.clone());
+    let result = create(args);
+    set_executor(previous);
+    result?;
+
+    println!();
+    println!("The build would run:");
+    println!();
+
+    for command in executor.commands() {
+        println!("{}", command);
+    }
+
+    if let Some(e) = unreadable.borrow().as_ref() {
+        if !from_config.is_empty() {
+            println!();
+            println!(
+                "Depends on image config, which docker couldn't read for {}: {}",
+                image_name, e
+            );
+            println!();
+
+            for what in from_config {
+                println!("{}", what);
             }
+        }
+    }
 
-            println!("> write fstab");
+    Ok(())
+}
 
-            let mut fstab = File::create(format!("{}/etc/fstab", mount_partition_3.dest()))?;
+/// Run a read-only query, like `docker image inspect`, on the real host from
+/// inside a dry run
+fn query_host(exe: &str, args: &[String]) -> Result<String> {
+    let previous = set_executor(Rc::new(HostExecutor));
+    let output = run(exe.into(), args);
+    set_executor(previous);
 
-            let p3_fs_uuid: String = output_stdout_string(&run(
-                "blkid".into(),
-                &["-o".into(), "export".into(), root_device_partition_3],
-            )?)
-            .split('\n')
-            .filter(|x| x.starts_with("UUID="))
-            .collect();
+    Ok(output_stdout_string(&output?))
+}
 
-            writeln!(fstab, "{} / ext4 errors=remount-ro 0 1", p3_fs_uuid)?;
+/// Answer commands like the host would for a dry run, creating just enough
+/// of a root filesystem (with placeholder kernel modules) for the pipeline's
+/// own file reads and writes to succeed. What docker says about the image
+/// and compose file comes from the real host; if it can't read the image's
+/// config, why goes in `unreadable` and the build leaves out what needs it.
+fn dry_run_host(
+    initramfs_modules: Vec<String>,
+    unreadable: Rc<RefCell<Option<String>>>,
+) -> impl Fn(&str, &[String]) -> Result<String> {
+    move |exe, args| {
+        match exe {
+            "losetup" if args.contains(&"--find".to_string()) => Ok("/dev/loop0".into()),
+
+            "id" => Ok("0".into()),
+
+            "grep" if args[0] == "^CapEff:" => Ok("CapEff:\t000001ffffffffff".into()),
+
+            "docker" if args.starts_with(&["image".into(), "inspect".into()]) => {
+                query_host(exe, args).inspect_err(|e| {
+                    unreadable.replace(Some(format!("{:#}", e)));
+                })
+            }
 
-            let p2_fs_uuid: String = output_stdout_string(&run(
-                "blkid".into(),
-                &["-o".into(), "export".into(), root_device_partition_2],
-            )?)
-            .split('\n')
-            .filter(|x| x.starts_with("UUID="))
-            .collect();
+            "docker"
+                if args[0] == "compose" && args.join(" ").ends_with("config --format json") =>
+            {
+                query_host(exe, args)
+            }
 
-            writeln!(fstab, "{} /boot/efi vfat defaults 0 2", p2_fs_uuid)?;
+            "df" => Ok("   Avail\n1099511627776".into()),
 
-            drop(fstab);
+            // Partitions like the native backend would, so the build can read
+            // the table back
+            "systemd-repart" => {
+                let image = Path::new(args.last().unwrap());
+                Gpt::default_layout(std::fs::metadata(image)?.len())?.write(image)?;
+                Ok(String::new())
+            }
 
-            run(
-                "cat".into(),
-                &[format!("{}/etc/fstab", mount_partition_3.dest())],
-            )?;
+            "gpg" | "cosign" => {
+                let output = args
+                    .iter()
+                    .position(|x| x == "--output" || x == "--output-signature")
+                    .unwrap();
+                std::fs::write(&args[output + 1], "signature")?;
+                Ok(String::new())
+            }
 
-            println!("> install grub");
+            "aws" if args.contains(&"create-multipart-upload".to_string()) => {
+                Ok(r#"{"UploadId": "dry-run"}"#.into())
+            }
 
-            run(
-                "mkdir".into(),
-                &[
-                    "-p".into(),
-                    format!("{}/boot/grub/", mount_partition_3.dest()),
-                ],
-            )?;
+            "aws" if args.contains(&"upload-part".to_string()) => Ok(r#"{"ETag": "\"0\""}"#.into()),
+
+            "blkid" => {
+                if args.last().unwrap().ends_with("p2") {
+                    Ok("DEVNAME=/dev/loop0p2\nUUID=0000-0000\nTYPE=vfat\nPARTUUID=00000000-0000-0000-0000-000000000002".into())
+                } else if args.last().unwrap().ends_with("p4") {
+                    Ok("DEVNAME=/dev/loop0p4\nUUID=0000-0004\nTYPE=vfat\nPARTUUID=00000000-0000-0000-0000-000000000004".into())
+                } else {
+                    Ok(
+                    "DEVNAME=/dev/loop0p3\nUUID=00000000-0000-0000-0000-000000000000\nTYPE=ext4"
+                        .into(),
+                )
+                }
+            }
 
-            let mut device_map =
-                File::create(format!("{}/boot/grub/device.map", mount_partition_3.dest()))?;
-            writeln!(device_map, "(hd0) {}", partitioned_disk.path())?;
-            drop(device_map);
-
-            run(
-                "mkdir".into(),
-                &[
-                    "-p".into(),
-                    format!("{}/etc/default/", mount_partition_3.dest()),
-                ],
-            )?;
+            "mkdir" => {
+                std::fs::create_dir_all(args.last().unwrap())?;
+                Ok(String::new())
+            }
 
-            let mut grub_file =
-                File::create(format!("{}/etc/default/grub", mount_partition_3.dest()))?;
-            writeln!(grub_file, "GRUB_DEVICE={}", p3_fs_uuid)?;
-            writeln!(grub_file, "GRUB_TERMINAL=\"serial console\"")?;
-            writeln!(
-                grub_file,
-                "{}",
-                match flavor {
-                    OsFlavor::Debian | OsFlavor::Ubuntu =>
-                        "GRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash console=ttyS0,115200 init=/lib/systemd/systemd-bootchart\"",
+            "tar" if args.contains(&"-xf".to_string()) => {
+                let root = &args[args.iter().position(|x| x == "-C").unwrap() + 1];
+                let modules = format!("{}/lib/modules/0.0.0-dry-run/kernel", root);
+
+                for dir in [
+                    "boot",
+                    "etc/apk",
+                    "etc/apt/sources.list.d",
+                    "etc/chrony",
+                    "etc/init.d",
+                    "etc/initramfs-tools",
+                    "etc/mkinitfs/features.d",
+                    "etc/network",
+                    "etc/ssh",
+                    "etc/systemd/system",
+                    "etc/ufw",
+                    "lib/apk/db",
+                    "tmp",
+                    "usr/bin",
+                    "var/lib/dpkg",
+                ] {
+                    std::fs::create_dir_all(format!("{}/{}", root, dir))?;
+                }
+                std::fs::create_dir_all(&modules)?;
+
+                for file in [
+                    // The kernel as Debian names it, and as Alpine does, by the
+                    // last part of its version
+                    "boot/vmlinuz-0.0.0-dry-run",
+                    "boot/vmlinuz-run",
+                    "etc/apk/repositories",
+                    "etc/apt/sources.list.d/debian.sources",
+                    "etc/apt/sources.list.d/ubuntu.sources",
+                    "etc/chrony/chrony.conf",
+                    "etc/inittab",
+                    "etc/machine-id",
+                    "usr/bin/ignition",
+                ] {
+                    File::create(format!("{}/{}", root, file))?;
+                }
 
-                    OsFlavor::Alpine =>
-                        "GRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash console=ttyS0,115200 rootfstype=ext4 modules=sd-mod,usb-storage,nvme,ext4\"",
+                for module in &initramfs_modules {
+                    File::create(format!("{}/{}.ko", modules, module))?;
                 }
+
+                std::fs::write(
+                format!("{}/var/lib/dpkg/status", root),
+                "Package: base-files\nStatus: install ok installed\nArchitecture: amd64\nVersion: 0.0.0\n",
             )?;
-            drop(grub_file);
-
-            run(
-                "grub-install".into(),
-                &[
-                    "--target=x86_64-efi".into(),
-                    format!("--efi-directory={}/boot/efi/", mount_partition_3.dest()),
-                    format!("--root-directory={}", mount_partition_3.dest()),
-                    "--no-floppy".into(),
-                    partitioned_disk.path(),
-                ],
-            )?;
-            run(
-                "chroot".into(),
-                &[
-                    mount_partition_3.dest(),
-                    "grub-mkconfig".into(),
-                    "-o".into(),
-                    "/boot/grub/grub.cfg".into(),
-                ],
-            )?;
+                std::fs::write(
+                    format!("{}/lib/apk/db/installed", root),
+                    "P:alpine-baselayout\nV:0.0.0-r0\nA:x86_64\n",
+                )?;
+
+                Ok(String::new())
+            }
 
-            println!("> no loop necessary in final image");
-            run(
-                "chroot".into(),
-                &[
-                    mount_partition_3.dest(),
-                    "rm".into(),
-                    "/boot/grub/device.map".into(),
-                ],
+            // What grub-mkconfig would make of the kernel the tar put in /boot
+            "chroot" if args.get(1).is_some_and(|x| x == "grub-mkconfig") => {
+                let root = &args[0];
+                let default = std::fs::read_to_string(format!("{}/etc/default/grub", root))?;
+                let device = default
+                    .lines()
+                    .find_map(|x| x.strip_prefix("GRUB_DEVICE="))
+                    .unwrap_or_default();
+                std::fs::write(
+                format!("{}/boot/grub/grub.cfg", root),
+                format!(
+                    "menuentry 'Linux 0.0.0-dry-run' {{\n\tlinux /boot/vmlinuz-0.0.0-dry-run root={} ro\n}}\n",
+                    device
+                ),
             )?;
+                Ok(String::new())
+            }
 
-            //println!("> Enter some text when done");
-            //let mut s = String::new();
-            //std::io::stdin().read_line(&mut s).expect("Not a string?");
-
-            match flavor {
-                OsFlavor::Debian | OsFlavor::Ubuntu => {
-                    println!("> update-initramfs");
-                    run(
-                        "chroot".into(),
-                        &[
-                            mount_partition_3.dest(),
-                            "update-initramfs".into(),
-                            "-u".into(),
-                        ],
-                    )?;
-                }
+            _ => Ok(String::new()),
+        }
+    }
+}
+
+/// If `create --config` was given, append the config file's options to the
+/// command line, skipping the ones that were given explicitly.
+fn with_config_args(mut argv: Vec<String>) -> Result<Vec<String>> {
+    let config_path = argv.iter().enumerate().find_map(|(i, x)| {
+        if x == "--config" {
+            argv.get(i + 1).cloned()
+        } else {
+            x.strip_prefix("--config=").map(String::from)
+        }
+    });
+
+    let Some(config_path) = config_path else {
+        return Ok(argv);
+    };
+
+    let mut config: toml::Table = std::fs::read_to_string(&config_path)?.parse()?;
+
+    let variables = set_variables(&argv)?;
+    for (key, value) in config.iter_mut() {
+        expand_variables(value, &variables)
+            .with_context(|| format!("{:?} in {}", key, config_path))?;
+    }
+
+    let command = Args::command();
+    let create_command = command.find_subcommand("create").unwrap();
+
+    let mut extra_args: Vec<String> = vec![];
+
+    for (key, value) in config {
+        let id = key.replace('-', "_");
+
+        let Some(arg) = create_command
+            .get_arguments()
+            .find(|x| x.get_id() == id.as_str() && x.get_long().is_some())
+        else {
+            bail!("unknown option {:?} in {}", key, config_path);
+        };
 
-                OsFlavor::Alpine => {
-                    // by default, mkinitfs will use the docker host's kernel version
-                    println!("> get kernel version");
+        if id == "config" {
+            bail!("config files can't include other config files");
+        }
+
+        if id == "set" {
+            bail!("set is only for the command line");
+        }
+
+        let long = format!("--{}", arg.get_long().unwrap());
+
+        let on_command_line = |arg: &clap::Arg| {
+            let Some(long) = arg.get_long().map(|x| format!("--{}", x)) else {
+                return false;
+            };
+
+            argv.iter().any(|x| {
+                *x == long
+                    || x.starts_with(&format!("{}=", long))
+                    || arg.get_short().is_some_and(|s| *x == format!("-{}", s))
+            })
+        };
+
+        // An option that conflicts with one on the command line, like
+        // disk_guid with --randomize-guids, is overridden by it too
+        let conflicts = |other: &clap::Arg| {
+            create_command
+                .get_arg_conflicts_with(arg)
+                .iter()
+                .any(|x| x.get_id() == other.get_id())
+                || create_command
+                    .get_arg_conflicts_with(other)
+                    .iter()
+                    .any(|x| x.get_id() == arg.get_id())
+        };
+
+        if on_command_line(arg)
+            || create_command
+                .get_arguments()
+                .any(|x| conflicts(x) && on_command_line(x))
+        {
+            continue;
+        }
 
-                    let mut kernelversion: Vec<String> =
-                        std::fs::read_dir(format!("{}/lib/modules/", mount_partition_3.dest()))?
-                            .collect::<Result<Vec<std::fs::DirEntry>, std::io::Error>>()?
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+
+        for value in values {
+            match value {
+                // [matrix] with an array of values for each option
+                toml::Value::Table(axes) if id == "matrix" => {
+                    for (option, values) in axes {
+                        let values = match values {
+                            toml::Value::Array(values) => values,
+                            value => vec![value],
+                        };
+                        let values: Vec<String> = values
                             .into_iter()
-                            .map(|x| {
-                                let full_path = x.path();
-                                let last_part = full_path.file_name().unwrap();
-                                last_part.to_os_string().into_string().unwrap()
+                            .map(|x| match x {
+                                toml::Value::String(x) => x,
+                                x => x.to_string(),
                             })
                             .collect();
-
-                    println!("detected kernel versions {:?}", kernelversion);
-                    if kernelversion.len() != 1 {
-                        bail!("incorrect number of kernel vers");
+                        extra_args
+                            .extend([long.clone(), format!("{}={}", option, values.join(","))]);
                     }
-
-                    let kernelversion: String = kernelversion.pop().unwrap();
-
-                    println!("> mkinitfs");
-                    run(
-                        "chroot".into(),
-                        &[
-                            mount_partition_3.dest(),
-                            "mkinitfs".into(),
-                            "-c".into(),
-                            "/etc/mkinitfs/mkinitfs.conf".into(),
-                            "-b".into(),
-                            "/".into(),
-                            kernelversion,
-                        ],
-                    )?;
                 }
+                toml::Value::Boolean(true) => extra_args.push(long.clone()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(x) => extra_args.extend([long.clone(), x]),
+                toml::Value::Integer(x) => extra_args.extend([long.clone(), x.to_string()]),
+                value => bail!(
+                    "unsupported value {} for {:?} in {}",
+                    value,
+                    key,
+                    config_path
+                ),
             }
+        }
+    }
 
-            // alpine requires changing /etc/inittab for a login console on
-            // ttyS0
-            if matches!(flavor, OsFlavor::Alpine) {
-                run(
-                    "chroot".into(),
-                    &[
-                        mount_partition_3.dest(),
-                        "sed".into(),
-                        "-i".into(),
-                        "-e".into(),
-                        "s/^#ttyS0/ttyS0/g".into(),
-                        "/etc/inittab".into(),
-                    ],
-                )?;
-            }
+    argv.extend(extra_args);
 
-            let root_passwd: String = if let Some(v) = root_passwd {
-                v
-            } else {
-                rand::thread_rng()
-                    .sample_iter(&Alphanumeric)
-                    .take(16)
-                    .map(char::from)
-                    .collect()
-            };
+    Ok(argv)
+}
 
-            println!("> set root password as {}", root_passwd);
+/// The `--set KEY=VALUE`s in `argv`
+fn set_variables(argv: &[String]) -> Result<BTreeMap<String, String>> {
+    let mut variables = BTreeMap::new();
+    let mut iter = argv.iter();
+
+    while let Some(x) = iter.next() {
+        let value = match x.strip_prefix("--set") {
+            Some("") => iter.next().cloned().unwrap_or_default(),
+            Some(x) if x.starts_with('=') => x[1..].to_string(),
+            _ => continue,
+        };
+
+        let Some((key, value)) = value.split_once('=') else {
+            bail!("--set takes KEY=VALUE, not {:?}", value);
+        };
+        variables.insert(key.to_string(), value.to_string());
+    }
 
-            let mut passwd = Command::new("chroot")
-                .stdin(std::process::Stdio::piped())
-                .arg(mount_partition_3.dest())
-                .arg("passwd")
-                .spawn()?;
+    Ok(variables)
+}
 
-            {
-                let passwd_stdin = passwd.stdin.as_mut().unwrap();
-                writeln!(passwd_stdin, "{}", root_passwd)?;
-                writeln!(passwd_stdin, "{}", root_passwd)?;
+/// Replace each {{KEY}} in `value`'s strings with its value in `variables`,
+/// or the environment variable KEY
+fn expand_variables(value: &mut toml::Value, variables: &BTreeMap<String, String>) -> Result<()> {
+    match value {
+        toml::Value::String(x) => {
+            let mut expanded = String::new();
+            let mut rest = x.as_str();
+
+            while let Some(start) = rest.find("{{") {
+                let Some(end) = rest[start..].find("}}") else {
+                    bail!("unterminated {{{{ in {:?}", x);
+                };
+                let key = rest[start + 2..start + end].trim();
+
+                let Some(value) = variables
+                    .get(key)
+                    .cloned()
+                    .or_else(|| std::env::var(key).ok())
+                else {
+                    bail!("{{{{{}}}}} isn't set with --set or in the environment", key);
+                };
+
+                expanded.push_str(&rest[..start]);
+                expanded.push_str(&value);
+                rest = &rest[start + end + 2..];
             }
 
-            passwd.wait_with_output()?;
+            expanded.push_str(rest);
+            *x = expanded;
+        }
+        toml::Value::Array(values) => {
+            for x in values {
+                expand_variables(x, variables)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, x) in table.iter_mut() {
+                expand_variables(x, variables)?;
+            }
+        }
+        _ => {}
+    }
 
-            println!("> Clean up");
-            drop(bind_dev);
-            drop(bind_proc);
-            drop(bind_sys);
-            drop(mount_partition_2);
-            drop(mount_partition_3);
+    Ok(())
+}
 
-            println!(
-                "> Copy {:?} to {:?}",
-                partitioned_disk.img_path(),
-                output_file
-            );
-            std::fs::copy(partitioned_disk.img_path(), output_file)?;
+/// Options a matrix can't vary, as each variant gets its own or none
+const UNVARIED_OPTIONS: &[&str] = &[
+    "matrix",
+    "output-file",
+    "manifest",
+    "config",
+    "set",
+    "in-container",
+    "builder-image",
+    "rootfs-tar",
+];
+
+/// `argv` with each of `options` given the value it has, replacing any it
+/// already had, and without any of `removed`
+fn with_options(
+    argv: &[String],
+    options: &[(String, String)],
+    removed: &[&str],
+) -> Result<Vec<String>> {
+    let command = Args::command();
+    let create_command = command.find_subcommand("create").unwrap();
+    let arg_for = |long: &str| {
+        create_command
+            .get_arguments()
+            .find(|x| x.get_long() == Some(long))
+            .ok_or_else(|| anyhow::anyhow!("--matrix {}: no such option", long))
+    };
+
+    let replaced: Vec<&clap::Arg> = options
+        .iter()
+        .map(|(option, _)| option.as_str())
+        .chain(removed.iter().copied())
+        .map(arg_for)
+        .collect::<Result<_>>()?;
+
+    let mut new_argv = vec![];
+    let mut iter = argv.iter();
+
+    while let Some(x) = iter.next() {
+        let is = |arg: &&&clap::Arg| {
+            let long = format!("--{}", arg.get_long().unwrap());
+            *x == long
+                || x.starts_with(&format!("{}=", long))
+                || arg.get_short().is_some_and(|s| *x == format!("-{}", s))
+        };
+
+        match replaced.iter().find(is) {
+            Some(arg) if !x.contains('=') && arg.get_action().takes_values() => {
+                iter.next();
+            }
+            Some(_) => {}
+            None => new_argv.push(x.clone()),
         }
     }
 
-    Ok(())
+    for (option, value) in options {
+        let long = format!("--{}", option);
+
+        if arg_for(option)?.get_action().takes_values() {
+            new_argv.extend([long, value.clone()]);
+        } else {
+            match value.as_str() {
+                "true" => new_argv.push(long),
+                "false" => {}
+                _ => bail!("{} takes true or false, not {:?}", long, value),
+            }
+        }
+    }
+
+    Ok(new_argv)
+}
+
+/// `argv` for one variant of a matrix build: without --matrix, with the
+/// variant's options in place of any given, and its own output file and
+/// manifest
+fn variant_argv(
+    argv: &[String],
+    variant: &Variant,
+    output_file: &Path,
+    manifest: &Path,
+) -> Result<Vec<String>> {
+    let mut variant_argv = with_options(
+        argv,
+        &variant.options,
+        &["matrix", "output-file", "manifest"],
+    )?;
+
+    variant_argv.extend([
+        "--output-file".into(),
+        output_file.display().to_string(),
+        "--manifest".into(),
+        manifest.display().to_string(),
+    ]);
+
+    Ok(variant_argv)
+}
+
+/// `argv` with its first variant's values, if it has a --matrix, so options
+/// a build needs, like --flavor, can come from the matrix alone
+fn matrix_base_argv(argv: &[String]) -> Result<Vec<String>> {
+    let axes: Vec<Axis> = Args::command()
+        .ignore_errors(true)
+        .try_get_matches_from(argv)
+        .ok()
+        .and_then(|x| {
+            x.subcommand_matches("create")?
+                .get_many::<Axis>("matrix")
+                .map(|x| x.cloned().collect())
+        })
+        .unwrap_or_default();
+
+    match axes.is_empty() {
+        true => Ok(argv.to_vec()),
+        false => with_options(argv, &matrix::variants(&axes)?[0].options, &[]),
+    }
+}
+
+/// Build every variant of `args.matrix` in turn, each from one export of
+/// its image, and write a manifest of them all. A variant that fails
+/// doesn't stop the rest.
+fn matrix_build(args: CreateArgs, argv: &[String]) -> Result<()> {
+    for axis in &args.matrix {
+        if UNVARIED_OPTIONS.contains(&axis.option.as_str()) {
+            bail!("--matrix can't vary --{}", axis.option);
+        }
+    }
+
+    let variants = matrix::variants(&args.matrix)?;
+
+    if !args.dry_run {
+        cancel_on_signals();
+    }
+
+    let exports = tempfile::tempdir()?;
+    let mut rootfs_tars: BTreeMap<String, PathBuf> = BTreeMap::new();
+
+    let mut built = vec![];
+    let mut failed = vec![];
+
+    for variant in &variants {
+        let _span = info_span!("variant", variant = variant.name()).entered();
+        info!("Building variant {}", variant.name());
+
+        let output_file = variant.path(&args.output_file);
+        let manifest = output_file.with_extension("json");
+
+        let result = (|| {
+            let _ = std::fs::remove_file(&manifest);
+
+            let mut argv = variant_argv(argv, variant, &output_file, &manifest)?;
+
+            if let Some(report) = &args.report {
+                let report = variant.path(report).display().to_string();
+                argv = with_options(&argv, &[("report".into(), report)], &[])?;
+            }
+
+            // The simulated host of a dry run exports nothing
+            if args.rootfs_tar.is_none() && !args.dry_run {
+                let image_name = variant
+                    .options
+                    .iter()
+                    .find(|(option, _)| option == "image-name")
+                    .map_or(args.image_name.clone(), |(_, value)| value.clone());
+
+                if !rootfs_tars.contains_key(&image_name) {
+                    let path = exports.path().join(format!("{}.tar", rootfs_tars.len()));
+                    info!("export {} once for every variant", image_name);
+                    export_image(&image_name, &path, None)?;
+                    rootfs_tars.insert(image_name.clone(), path);
+                }
+
+                argv.extend([
+                    "--rootfs-tar".into(),
+                    rootfs_tars[&image_name].display().to_string(),
+                ]);
+            }
+
+            let Command::Create(variant_args) = Args::try_parse_from(argv)?.command else {
+                unreachable!();
+            };
+
+            if variant_args.dry_run {
+                dry_run(*variant_args)
+            } else {
+                create(*variant_args)
+            }
+        })();
+        exit_if_signalled(&result);
+
+        let manifest = match std::fs::read_to_string(&manifest) {
+            Ok(contents) => Some(serde_json::from_str(&contents)?),
+            Err(_) => None,
+        };
+
+        if let Err(e) = &result {
+            warn!("variant {} failed: {:#}", variant.name(), e);
+            failed.push(variant.name());
+        }
+
+        built.push(MatrixVariant {
+            options: variant.options.iter().cloned().collect(),
+            output_file,
+            manifest,
+            error: result.err().map(|e| format!("{:#}", e)),
+        });
+    }
+
+    let manifest = args
+        .manifest
+        .unwrap_or_else(|| args.output_file.with_extension("matrix.json"));
+    info!("write matrix manifest {:?}", manifest);
+    std::fs::write(
+        &manifest,
+        serde_json::to_string_pretty(&MatrixManifest { variants: built })?,
+    )?;
+
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} variants failed: {}",
+            failed.len(),
+            variants.len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Written by a matrix build, with each variant's own manifest
+#[derive(Debug, Serialize)]
+struct MatrixManifest {
+    variants: Vec<MatrixVariant>,
+}
+
+#[derive(Debug, Serialize)]
+struct MatrixVariant {
+    options: BTreeMap<String, String>,
+    output_file: PathBuf,
+    manifest: Option<serde_json::Value>,
+    error: Option<String>,
+}
+
+fn create(args: CreateArgs) -> Result<()> {
+    let CreateArgs {
+        image_name,
+        output_file,
+        disk_size,
+        disk_guid,
+        randomize_guids: _,
+        root_passwd,
+        root_passwd_hash,
+        show_password,
+        lock_root,
+        disable_ssh_password_auth,
+        extra_packages,
+        kernel_version,
+        flavor,
+        chrony_phc,
+        clocksource,
+        console,
+        include_firmware,
+        ignition,
+        hostname,
+        network,
+        address,
+        gateway,
+        dns,
+        read_only_root,
+        persistence,
+        data_partition,
+        esp_mirror,
+        partition_backend,
+        rootless,
+        in_container: _,
+        builder_image: _,
+        matrix: _,
+        rootfs_tar,
+        fstab_opt,
+        gpt_partition,
+        fstrim,
+        guest_tools,
+        enable_service,
+        container_service,
+        container_env,
+        firewall,
+        allow_port,
+        compose,
+        disable_service,
+        mask_service,
+        run_in_chroot,
+        hook_dir,
+        pause,
+        no_clean,
+        minimal,
+        selinux,
+        debug_shell,
+        panic,
+        initramfs_modules,
+        initramfs_compression,
+        initramfs_module_set,
+        sysctl,
+        sysctl_file,
+        blacklist_module,
+        modprobe_file,
+        mirror,
+        pkg_proxy,
+        cache_dir,
+        ca_cert,
+        config: _,
+        set: _,
+        dry_run,
+        manifest,
+        report,
+        sbom,
+        checksum,
+        sign_key,
+        signer,
+        verify_boot,
+        verify_boot_marker,
+        verify_boot_timeout,
+        ovmf,
+        s3,
+        keep_workdir,
+        workdir_tmpfs,
+        diagnostics,
+        resume,
+        command_timeout,
+        retries,
+        retry_delay,
+    } = args;
+
+    if verify_boot && !console.is_empty() && !console.iter().any(|x| x.device == "ttyS0") {
+        warn!("--verify-boot watches ttyS0, which none of the --console options is");
+    }
+
+    let mut builder = ImageBuilder::new(image_name)
+        .output_file(&output_file)
+        .disk_size_gb(disk_size)
+        .lock_root(lock_root)
+        .disable_ssh_password_auth(disable_ssh_password_auth)
+        .extra_packages(extra_packages)
+        .kernel_version(kernel_version)
+        .flavor(flavor)
+        .chrony_phc(chrony_phc)
+        .include_firmware(include_firmware)
+        .network(network)
+        .dns(dns)
+        .read_only_root(read_only_root)
+        .persistence(persistence)
+        .data_partitions(data_partition)
+        .esp_mirror(esp_mirror)
+        .partition_backend(partition_backend)
+        .rootless(rootless)
+        .fstab_options(fstab_opt)
+        .gpt_partitions(gpt_partition)
+        .fstrim(fstrim)
+        .guest_tools(guest_tools)
+        .enable_service(enable_service)
+        .disable_service(disable_service)
+        .mask_service(mask_service)
+        .container_service(container_service)
+        .container_env(container_env)
+        .allow_port(allow_port)
+        .consoles(console)
+        .no_clean(no_clean)
+        .minimal(minimal)
+        .selinux(selinux)
+        .debug_shell(debug_shell)
+        .initramfs_modules(initramfs_modules)
+        .sysctl(sysctl)
+        .sysctl_file(sysctl_file)
+        .blacklist_module(blacklist_module)
+        .modprobe_file(modprobe_file)
+        .ca_cert(ca_cert)
+        .dry_run(dry_run)
+        .keep_workdir(keep_workdir)
+        .list_packages(sbom.is_some());
+
+    for (point, command) in run_in_chroot {
+        builder = builder.run_in_chroot(point, command);
+    }
+
+    for point in pause {
+        builder = builder.pause(point);
+    }
+
+    // For the report; a generated password's file is only known after
+    let mut root_passwd_note = if lock_root {
+        "locked".to_string()
+    } else if root_passwd.is_some() {
+        "set with --root-passwd".to_string()
+    } else if root_passwd_hash.is_some() {
+        "set with --root-passwd-hash".to_string()
+    } else {
+        String::new()
+    };
+
+    if let Some(root_passwd) = root_passwd {
+        builder = builder.root_passwd(root_passwd);
+    }
+
+    if let Some(root_passwd_hash) = root_passwd_hash {
+        builder = builder.root_passwd_hash(root_passwd_hash);
+    }
+
+    if let Some(compression) = initramfs_compression {
+        builder = builder.initramfs_compression(compression);
+    }
+
+    if let Some(module_set) = initramfs_module_set {
+        builder = builder.initramfs_module_set(module_set);
+    }
+
+    if let Some(clocksource) = clocksource {
+        builder = builder.clocksource(clocksource);
+    }
+
+    if let Some(panic) = panic {
+        builder = builder.panic(panic);
+    }
+
+    if let Some(hostname) = hostname {
+        builder = builder.hostname(hostname);
+    }
+
+    if let Some(address) = address {
+        builder = builder.address(address);
+    }
+
+    if let Some(gateway) = gateway {
+        builder = builder.gateway(gateway);
+    }
+
+    if let Some(mirror) = mirror {
+        builder = builder.mirror(mirror);
+    }
+
+    if let Some(pkg_proxy) = pkg_proxy {
+        builder = builder.pkg_proxy(pkg_proxy);
+    }
+
+    if let Some(cache_dir) = cache_dir {
+        builder = builder.cache_dir(cache_dir);
+    }
+
+    if let Some(rootfs_tar) = rootfs_tar {
+        builder = builder.rootfs_tar(rootfs_tar);
+    }
+
+    if let Some(ignition) = ignition {
+        builder = builder.ignition(ignition);
+    }
+
+    if let Some(hook_dir) = hook_dir {
+        builder = builder.hook_dir(hook_dir);
+    }
+
+    if let Some(disk_guid) = disk_guid {
+        builder = builder.disk_guid(disk_guid);
+    }
+
+    if let Some(firewall) = firewall {
+        builder = builder.firewall(firewall);
+    }
+
+    if let Some(compose) = compose {
+        builder = builder.compose(compose);
+    }
+
+    if let Some(workdir_tmpfs) = workdir_tmpfs {
+        builder = builder.workdir_tmpfs(workdir_tmpfs);
+    }
+
+    if let Some(diagnostics) = diagnostics {
+        builder = builder.diagnostics(diagnostics);
+    }
+
+    if let Some(resume) = resume {
+        builder = builder.resume(resume);
+    }
+
+    if let Some(command_timeout) = command_timeout {
+        builder = builder.command_timeout(Duration::from_secs(command_timeout));
+    }
+
+    builder = builder
+        .retries(retries)
+        .retry_delay(Duration::from_secs(retry_delay));
+
+    builder = builder
+        .cancel_token(interrupt_token().clone())
+        .events(record_failed_phase);
+
+    let image = builder.build()?;
+
+    for line in phase_report(&image.phases).lines() {
+        info!("{}", line);
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    let mut root_passwd_file = None;
+
+    if let Some(root_passwd) = &image.generated_root_passwd {
+        let passwd_path = root_passwd_path(&output_file);
+
+        write_root_passwd(&passwd_path, root_passwd)?;
+
+        if show_password {
+            println!("root password is {}", root_passwd);
+        } else {
+            info!("root password written to {:?}", passwd_path);
+        }
+
+        root_passwd_note = format!("generated, in {} (mode 600)", passwd_path.display());
+        root_passwd_file = Some(passwd_path);
+    }
+
+    // Hashing a large image takes a while, so it's done at most once
+    let sha256 = if manifest.is_some() || checksum.is_some() {
+        Some(sha256_file(&output_file)?)
+    } else {
+        None
+    };
+
+    let mut checksum_file = None;
+
+    if let (Some(algorithm), Some(sha256)) = (checksum, &sha256) {
+        let path = image::write_checksum(&output_file, algorithm, sha256)?;
+        info!("checksum written to {:?}", path);
+        checksum_file = Some(path);
+    }
+
+    let mut signature_file = None;
+
+    if let Some(sign_key) = sign_key {
+        signature_file = Some(image::sign(&output_file, signer, &sign_key)?);
+    }
+
+    let mut sbom_file = None;
+
+    if let Some(format) = sbom {
+        let sbom_path = format.path(&output_file);
+        info!("write SBOM {:?}", sbom_path);
+        sbom::write(format, &image, &sbom_path)?;
+        sbom_file = Some(sbom_path);
+    }
+
+    // Uploaded along with the image
+    let sidecar_files: Vec<PathBuf> = [&checksum_file, &signature_file, &sbom_file]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+
+    let metadata = std::fs::metadata(&output_file)?;
+
+    let partition = |partition: &BuiltPartition| ManifestPartition {
+        number: partition.number,
+        mountpoint: partition.mountpoint.map(Into::into),
+        partuuid: partition.partuuid.to_string(),
+        fs_type: partition.filesystem.fs_type.to_string(),
+        fs_uuid: partition.filesystem.uuid.to_string(),
+    };
+
+    let contents = Manifest {
+        image_name: image.image_name,
+        flavor: image.flavor,
+        output_file: output_file.canonicalize()?,
+        size_bytes: metadata.len(),
+        allocated_bytes: metadata.blocks() * 512,
+        sha256: sha256.unwrap_or_default(),
+        kernel_versions: image.kernel_versions,
+        partitions: std::iter::once(&image.esp)
+            .chain(image.esp_mirror.as_ref())
+            .chain(std::iter::once(&image.root))
+            .map(partition)
+            .collect(),
+        root_passwd_file,
+        sbom_file,
+        checksum_file,
+        signature_file,
+        phases: image
+            .phases
+            .iter()
+            .map(|x| ManifestPhase {
+                phase: x.phase.into(),
+                seconds: x.elapsed.as_secs_f64(),
+                bytes: x.bytes,
+            })
+            .collect(),
+    };
+
+    if let Some(manifest) = &manifest {
+        info!("write manifest {:?}", manifest);
+        std::fs::write(manifest, serde_json::to_string_pretty(&contents)?)?;
+    }
+
+    let bootloader = match (rootless, &image.esp_mirror) {
+        (true, _) => "GRUB, made with grub-mkimage, at EFI/BOOT/BOOTX64.EFI on the ESP",
+        (false, None) => "GRUB, installed with grub-install, at EFI/BOOT/BOOTX64.EFI on the ESP",
+        (false, Some(_)) => {
+            "GRUB, installed with grub-install, at EFI/BOOT/BOOTX64.EFI on both ESPs"
+        }
+    };
+    let report_text = build_report(
+        &contents,
+        &root_passwd_note,
+        bootloader,
+        manifest.as_deref(),
+    );
+
+    // Quiet with -q, like the rest of the build's output
+    if CONSOLE_LEVEL.get().is_some_and(|x| *x >= Level::INFO) {
+        print!("{}", report_text);
+    }
+
+    if let Some(report) = &report {
+        std::fs::write(report, &report_text)?;
+    }
+
+    if verify_boot {
+        let _phase = info_span!("phase", phase = "verify").entered();
+        qemu::verify_boots(
+            &QemuOptions::new(&output_file),
+            &ovmf.resolve()?,
+            &verify_boot_marker,
+            Duration::from_secs(verify_boot_timeout),
+        )?;
+    }
+
+    if let Some(url) = &s3.upload {
+        let _phase = info_span!("phase", phase = "upload").entered();
+        let options = s3.options();
+
+        upload::upload_s3(&output_file, url, &options)?;
+
+        let output_path = output_file.to_string_lossy();
+        for file in sidecar_files {
+            let suffix = file.to_string_lossy();
+            let suffix = suffix.strip_prefix(&*output_path).unwrap_or_default();
+            upload::upload_s3(&file, &url.with_suffix(suffix), &options)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Written by --manifest after a successful build
+#[derive(Debug, Serialize)]
+struct Manifest {
+    image_name: String,
+    flavor: Flavor,
+    output_file: PathBuf,
+    size_bytes: u64,
+    allocated_bytes: u64,
+    sha256: String,
+    kernel_versions: Vec<String>,
+    partitions: Vec<ManifestPartition>,
+    root_passwd_file: Option<PathBuf>,
+    sbom_file: Option<PathBuf>,
+    checksum_file: Option<PathBuf>,
+    signature_file: Option<PathBuf>,
+    phases: Vec<ManifestPhase>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestPartition {
+    number: u32,
+    mountpoint: Option<String>,
+    partuuid: String,
+    fs_type: String,
+    fs_uuid: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestPhase {
+    phase: String,
+    seconds: f64,
+    bytes: Option<u64>,
+}
+
+/// What the build made and where everything went, in one place at the end
+/// of `create` rather than scattered through its log
+fn build_report(
+    manifest: &Manifest,
+    root_passwd: &str,
+    bootloader: &str,
+    manifest_file: Option<&Path>,
+) -> String {
+    let gib = |bytes: u64| format!("{:.1} GiB", bytes as f64 / 1073741824.0);
+
+    let mut lines = vec![
+        ("image", manifest.output_file.display().to_string()),
+        (
+            "from",
+            format!("{} ({:?})", manifest.image_name, manifest.flavor),
+        ),
+        (
+            "size",
+            format!(
+                "{}, {} allocated",
+                gib(manifest.size_bytes),
+                gib(manifest.allocated_bytes)
+            ),
+        ),
+    ];
+
+    if !manifest.sha256.is_empty() {
+        lines.push(("sha256", manifest.sha256.clone()));
+    }
+
+    lines.push(("kernel", manifest.kernel_versions.join(", ")));
+    lines.push(("bootloader", bootloader.into()));
+
+    if !root_passwd.is_empty() {
+        lines.push(("root password", root_passwd.into()));
+    }
+
+    for partition in &manifest.partitions {
+        lines.push((
+            "partition",
+            format!(
+                "{} {} {} PARTUUID={} UUID={}",
+                partition.number,
+                partition.mountpoint.as_deref().unwrap_or("-"),
+                partition.fs_type,
+                partition.partuuid,
+                partition.fs_uuid
+            ),
+        ));
+    }
+
+    for (name, file) in [
+        ("checksum", &manifest.checksum_file),
+        ("signature", &manifest.signature_file),
+        ("sbom", &manifest.sbom_file),
+        ("manifest", &manifest_file.map(Path::to_path_buf)),
+    ] {
+        if let Some(file) = file {
+            lines.push((name, file.display().to_string()));
+        }
+    }
+
+    let mut report = String::from("Build report\n");
+    for (name, value) in lines {
+        report += &format!("  {:<14} {}\n", name, value);
+    }
+
+    report
+}
+
+/// A table of how long each phase took, and how fast the ones that moved
+/// data went, so it's clear whether apt, extraction or the copy dominates
+fn phase_report(phases: &[PhaseTiming]) -> String {
+    let mut report = format!(
+        "{:<12} {:>9} {:>10} {:>12}\n",
+        "phase", "time", "data", "rate"
+    );
+
+    for phase in phases {
+        let (data, rate) = match phase.bytes {
+            Some(bytes) => (
+                format!("{:.1} MiB", bytes as f64 / 1048576.0),
+                format!(
+                    "{:.1} MiB/s",
+                    bytes as f64 / 1048576.0 / phase.elapsed.as_secs_f64().max(0.001)
+                ),
+            ),
+            None => (String::new(), String::new()),
+        };
+
+        let line = format!(
+            "{:<12} {:>8.1}s {:>10} {:>12}",
+            phase.phase,
+            phase.elapsed.as_secs_f64(),
+            data,
+            rate
+        );
+        report += line.trim_end();
+        report += "\n";
+    }
+
+    let total: Duration = phases.iter().map(|x| x.elapsed).sum();
+    report += &format!("{:<12} {:>8.1}s\n", "total", total.as_secs_f64());
+
+    report
+}
+
+#[cfg(test)]
+mod phase_report_tests {
+    use super::*;
+
+    #[test]
+    fn phase_report_lines_up() {
+        let report = phase_report(&[
+            PhaseTiming {
+                phase: "extract",
+                elapsed: Duration::from_secs(4),
+                bytes: Some(512 * 1048576),
+            },
+            PhaseTiming {
+                phase: "packages",
+                elapsed: Duration::from_millis(61300),
+                bytes: None,
+            },
+        ]);
+
+        assert_eq!(
+            report,
+            "\
+phase             time       data         rate
+extract           4.0s  512.0 MiB  128.0 MiB/s
+packages         61.3s
+total            65.3s
+"
+        );
+    }
+}
+
+#[cfg(test)]
+mod exit_code_tests {
+    use super::*;
+
+    use std::os::unix::process::ExitStatusExt;
+
+    fn command_failed(cmd: &str) -> anyhow::Error {
+        Error::CommandFailed {
+            cmd: cmd.into(),
+            status: std::process::ExitStatus::from_raw(256),
+            stderr: String::new(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn exit_codes_by_error() {
+        let missing = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound))
+            .context("running mkfs.vfat");
+
+        let cases = [
+            (Error::InvalidOptions("x".into()).into(), exit_code::USAGE),
+            (Error::MissingFile("x".into()).into(), exit_code::USAGE),
+            (Error::NotRoot.into(), exit_code::PREFLIGHT),
+            (Error::PreflightFailed(vec![]).into(), exit_code::PREFLIGHT),
+            (missing, exit_code::PREFLIGHT),
+            (
+                Error::InvalidImage {
+                    image: "x".into(),
+                    reason: "x".into(),
+                }
+                .into(),
+                exit_code::DOCKER,
+            ),
+            (command_failed("docker export 0123"), exit_code::DOCKER),
+            (
+                Error::BootFailed {
+                    image: "x".into(),
+                    marker: "x".into(),
+                    timeout: Duration::from_secs(1),
+                    console_tail: String::new(),
+                }
+                .into(),
+                exit_code::VERIFICATION,
+            ),
+            (Error::VerifyFailed(vec![]).into(), exit_code::VERIFICATION),
+            (command_failed("mount /dev/loop0p3"), exit_code::FAILURE),
+        ];
+
+        for (e, code) in cases {
+            assert_eq!(exit_code_for(&e, None), code, "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn exit_codes_by_phase() {
+        let e = command_failed("chroot /mnt apt-get install -y linux-image-amd64");
+        assert_eq!(exit_code_for(&e, Some("packages")), exit_code::PACKAGES);
+        assert_eq!(exit_code_for(&e, Some("bootloader")), exit_code::BOOTLOADER);
+        assert_eq!(exit_code_for(&e, Some("initramfs")), exit_code::BOOTLOADER);
+        assert_eq!(exit_code_for(&e, Some("extract")), exit_code::FAILURE);
+
+        // What the error is wins over where it happened
+        let e = Error::InvalidOptions("x".into()).into();
+        assert_eq!(exit_code_for(&e, Some("packages")), exit_code::USAGE);
+    }
+}
+
+#[cfg(test)]
+mod build_report_tests {
+    use super::*;
+
+    #[test]
+    fn build_report_lists_everything() {
+        let manifest = Manifest {
+            image_name: "debian:12".into(),
+            flavor: Flavor::Debian,
+            output_file: "/out/debian.img".into(),
+            size_bytes: 8 * 1073741824,
+            allocated_bytes: 1288490189,
+            sha256: String::new(),
+            kernel_versions: vec!["6.1.0-18-amd64".into()],
+            partitions: vec![ManifestPartition {
+                number: 3,
+                mountpoint: Some("/".into()),
+                partuuid: "0b7c...".into(),
+                fs_type: "ext4".into(),
+                fs_uuid: "9f2e...".into(),
+            }],
+            root_passwd_file: Some("/out/debian.img.root-passwd".into()),
+            sbom_file: None,
+            checksum_file: Some("/out/debian.img.sha256".into()),
+            signature_file: None,
+            phases: vec![],
+        };
+
+        assert_eq!(
+            build_report(
+                &manifest,
+                "generated, in /out/debian.img.root-passwd (mode 600)",
+                "GRUB",
+                Some(Path::new("/out/debian.json")),
+            ),
+            "\
+Build report
+  image          /out/debian.img
+  from           debian:12 (Debian)
+  size           8.0 GiB, 1.2 GiB allocated
+  kernel         6.1.0-18-amd64
+  bootloader     GRUB
+  root password  generated, in /out/debian.img.root-passwd (mode 600)
+  partition      3 / ext4 PARTUUID=0b7c... UUID=9f2e...
+  checksum       /out/debian.img.sha256
+  manifest       /out/debian.json
+"
+        );
+    }
+}
+
+/// Where a generated root password is stored: next to the output image, e.g.
+/// debian.img.root-passwd
+fn root_passwd_path(output_file: &Path) -> PathBuf {
+    let mut path = output_file.as_os_str().to_os_string();
+    path.push(".root-passwd");
+    PathBuf::from(path)
+}
+
+/// Write `root_passwd` to `path`, readable only by its owner. A file left by
+/// an earlier run is removed first, since opening it would keep its mode.
+fn write_root_passwd(path: &Path, root_passwd: &str) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let mut passwd_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(passwd_file, "{}", root_passwd)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod root_passwd_tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_write_root_passwd_replaces_looser_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = root_passwd_path(&dir.path().join("debian.img"));
+
+        std::fs::write(&path, "an old, longer password\n")?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+
+        write_root_passwd(&path, "hunter2")?;
+
+        assert_eq!(std::fs::metadata(&path)?.mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read_to_string(&path)?, "hunter2\n");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn command_line_overrides_config() -> Result<()> {
+        let dir = tempdir()?;
+        let config = dir.path().join("build.toml");
+
+        std::fs::write(
+            &config,
+            r##"
+image-name = "debian:12"
+output_file = "debian.img"
+flavor = "debian"
+disk_size = 4
+extra_packages = ["vim", "curl"]
+chrony_phc = true
+"##,
+        )?;
+
+        let argv = with_config_args(
+            [
+                "docker_to_uefi_bootable_image",
+                "create",
+                "--config",
+                config.to_str().unwrap(),
+                "-d",
+                "16",
+                "--extra-packages",
+                "htop",
+            ]
+            .iter()
+            .map(|x| x.to_string())
+            .collect(),
+        )?;
+
+        let Command::Create(args) = Args::try_parse_from(matrix_base_argv(&argv)?)?.command else {
+            panic!("expected create");
+        };
+
+        assert_eq!(args.image_name, "debian:12");
+        assert_eq!(args.output_file, PathBuf::from("debian.img"));
+        assert_eq!(args.disk_size, 16);
+        assert_eq!(args.extra_packages, vec!["htop".to_string()]);
+        assert!(args.chrony_phc);
+        assert!(!args.include_firmware);
+
+        Ok(())
+    }
+
+    #[test]
+    fn command_line_overrides_conflicting_config() -> Result<()> {
+        let dir = tempdir()?;
+        let config = dir.path().join("build.toml");
+
+        std::fs::write(
+            &config,
+            r##"
+image_name = "debian:12"
+output_file = "debian.img"
+flavor = "debian"
+disk_guid = "5f3c2a1e-8b4d-4e6f-9a0b-1c2d3e4f5a6b"
+"##,
+        )?;
+
+        let parse = |extra: &[&str]| -> Result<CreateArgs> {
+            let argv = [
+                "docker_to_uefi_bootable_image",
+                "create",
+                "--config",
+                config.to_str().unwrap(),
+            ]
+            .iter()
+            .chain(extra)
+            .map(|x| x.to_string())
+            .collect();
+
+            let Command::Create(args) = Args::try_parse_from(with_config_args(argv)?)?.command
+            else {
+                panic!("expected create");
+            };
+            Ok(*args)
+        };
+
+        let args = parse(&[])?;
+        assert_eq!(
+            args.disk_guid.unwrap().to_string(),
+            "5f3c2a1e-8b4d-4e6f-9a0b-1c2d3e4f5a6b"
+        );
+
+        let args = parse(&["--randomize-guids"])?;
+        assert!(args.randomize_guids);
+        assert!(args.disk_guid.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_config_key() -> Result<()> {
+        let dir = tempdir()?;
+        let config = dir.path().join("build.toml");
+
+        std::fs::write(&config, "flavour = \"debian\"\n")?;
+
+        let result = with_config_args(vec![
+            "docker_to_uefi_bootable_image".into(),
+            "create".into(),
+            format!("--config={}", config.to_str().unwrap()),
+        ]);
+
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_variables() -> Result<()> {
+        let dir = tempdir()?;
+        let config = dir.path().join("build.toml");
+
+        std::fs::write(
+            &config,
+            r##"
+image_name = "registry.example.com/app:{{ branch }}"
+output_file = "app.img"
+flavor = "debian"
+hostname = "app-{{ENVIRONMENT}}"
+extra_packages = ["{{packages}}"]
+"##,
+        )?;
+
+        let parse = |set: &[&str]| -> Result<CreateArgs> {
+            let argv = ["docker_to_uefi_bootable_image", "create"]
+                .iter()
+                .chain(set)
+                .map(|x| x.to_string())
+                .chain([format!("--config={}", config.to_str().unwrap())])
+                .collect();
+
+            let Command::Create(args) = Args::try_parse_from(with_config_args(argv)?)?.command
+            else {
+                panic!("expected create");
+            };
+            Ok(*args)
+        };
+
+        let args = parse(&[
+            "--set",
+            "branch=main",
+            "--set=ENVIRONMENT=staging",
+            "--set",
+            "packages=vim,curl",
+        ])?;
+        assert_eq!(args.image_name, "registry.example.com/app:main");
+        assert_eq!(args.hostname.as_deref(), Some("app-staging"));
+        assert_eq!(args.extra_packages, ["vim", "curl"]);
+
+        // PATH comes from the environment, but nothing sets branch
+        std::fs::write(
+            &config,
+            "image_name = \"{{PATH}}\"\noutput_file = \"app.img\"\nflavor = \"debian\"\n",
+        )?;
+        assert_eq!(parse(&[])?.image_name, std::env::var("PATH")?);
+
+        std::fs::write(&config, "image_name = \"app:{{branch}}\"\n")?;
+        assert!(parse(&[]).is_err());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod matrix_tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn config_matrix_section() -> Result<()> {
+        let dir = tempdir()?;
+        let config = dir.path().join("build.toml");
+
+        std::fs::write(
+            &config,
+            r##"
+image_name = "debian:12"
+output_file = "vm.img"
+
+[matrix]
+flavor = ["debian", "ubuntu"]
+disk-size = [4, 16]
+"##,
+        )?;
+
+        let argv = with_config_args(vec![
+            "docker_to_uefi_bootable_image".into(),
+            "create".into(),
+            format!("--config={}", config.to_str().unwrap()),
+        ])?;
+
+        let Command::Create(args) = Args::try_parse_from(matrix_base_argv(&argv)?)?.command else {
+            panic!("expected create");
+        };
+
+        assert_eq!(
+            args.matrix,
+            vec![
+                "disk-size=4,16".parse::<Axis>()?,
+                "flavor=debian,ubuntu".parse()?
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn variant_replaces_options() -> Result<()> {
+        let argv: Vec<String> = [
+            "docker_to_uefi_bootable_image",
+            "create",
+            "-i",
+            "debian:12",
+            "-o",
+            "vm.img",
+            "-d",
+            "8",
+            "--matrix",
+            "disk-size=4,16",
+            "--matrix=read-only-root=true,false",
+            "--read-only-root",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+
+        let variants = matrix::variants(&[
+            "disk-size=4,16".parse()?,
+            "read-only-root=true,false".parse()?,
+        ])?;
+
+        assert_eq!(
+            variant_argv(
+                &argv,
+                &variants[1],
+                Path::new("vm-4-false.img"),
+                Path::new("vm-4-false.json")
+            )?,
+            [
+                "docker_to_uefi_bootable_image",
+                "create",
+                "-i",
+                "debian:12",
+                "--disk-size",
+                "4",
+                "--output-file",
+                "vm-4-false.img",
+                "--manifest",
+                "vm-4-false.json",
+            ]
+        );
+
+        let variants = matrix::variants(&["flavour=debian".parse()?])?;
+        assert!(variant_argv(&argv, &variants[0], Path::new("x"), Path::new("y")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn variants_share_an_export() -> Result<()> {
+        let output_dir = tempdir()?;
+        let output_file = output_dir.path().join("vm.img");
+
+        let argv: Vec<String> = [
+            "docker_to_uefi_bootable_image",
+            "create",
+            "--image-name",
+            "tester",
+            "--output-file",
+            output_file.to_str().unwrap(),
+            "--disk-size",
+            "1",
+            "--matrix",
+            "flavor=debian,ubuntu",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+
+        let Command::Create(args) = Args::try_parse_from(matrix_base_argv(&argv)?)?.command else {
+            panic!("expected create");
+        };
+
+        let executor = Rc::new(RecordingExecutor::new(simulated_host(vec![])));
+        let previous = set_executor(executor.clone());
+        let result = matrix_build(*args, &argv);
+        set_executor(previous);
+        result?;
+
+        let exports = executor
+            .commands()
+            .iter()
+            .filter(|x| x.exe == "docker" && x.args[0] == "export")
+            .count();
+        assert_eq!(exports, 1);
+
+        for variant in ["debian", "ubuntu"] {
+            assert!(output_dir
+                .path()
+                .join(format!("vm-{}.img", variant))
+                .exists());
+        }
+
+        let manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(
+            output_dir.path().join("vm.matrix.json"),
+        )?)?;
+        assert_eq!(manifest["variants"][1]["options"]["flavor"], "ubuntu");
+        assert_eq!(manifest["variants"][1]["manifest"]["flavor"], "ubuntu");
+        assert!(manifest["variants"][0]["error"].is_null());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod resume_tests {
+    use super::*;
+
+    #[test]
+    fn resume_skips_completed_steps() -> Result<()> {
+        let output_dir = tempfile::tempdir()?;
+        let builder = ImageBuilder::new("tester")
+            .output_file(output_dir.path().join("output.img"))
+            .disk_size_gb(1)
+            .dry_run(true);
+
+        // Fail the first package install
+        let host = simulated_host(vec![]);
+        let failing = Rc::new(RecordingExecutor::new(move |exe, args| {
+            if exe == "chroot" && args.contains(&"install".to_string()) {
+                bail!("apt install failed");
+            }
+            host(exe, args)
+        }));
+        let previous = set_executor(failing.clone());
+        let result = builder.clone().build();
+        set_executor(previous);
+        assert!(result.is_err());
+
+        let workdir = failing
+            .commands()
+            .into_iter()
+            .find(|x| x.exe == "losetup" && x.args[0] == "--show")
+            .map(|x| {
+                Path::new(x.args.last().unwrap())
+                    .parent()
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .unwrap();
+
+        let recorder = Rc::new(RecordingExecutor::new(simulated_host(vec![])));
+        let previous = set_executor(recorder.clone());
+        let result = builder.resume(&workdir).build();
+        set_executor(previous);
+        result?;
+
+        let commands: Vec<String> = recorder.commands().iter().map(|x| x.to_string()).collect();
+        // The image from the first run is reattached, not created again
+        assert_eq!(
+            commands[0],
+            format!(
+                "losetup --show --find --partscan {}/output.img",
+                workdir.display()
+            )
+        );
+        for skipped in ["mkfs", "docker"] {
+            assert!(
+                !commands.iter().any(|x| x.starts_with(skipped)),
+                "{}",
+                skipped
+            );
+        }
+        assert!(commands.iter().any(|x| x.contains(" apt install ")));
+        assert!(commands.iter().any(|x| x.starts_with("grub-install")));
+
+        // The working directory goes once the build succeeds
+        assert!(!workdir.exists());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod events_tests {
+    use super::*;
+
+    use docker_to_uefi_bootable_image::events::BuildEvent;
+
+    fn build_events(host: impl Fn(&str, &[String]) -> Result<String> + 'static) -> Vec<BuildEvent> {
+        let output_dir = tempfile::tempdir().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        let builder = ImageBuilder::new("tester")
+            .output_file(output_dir.path().join("output.img"))
+            .disk_size_gb(1)
+            .dry_run(true)
+            .events(sender);
+
+        let previous = set_executor(Rc::new(RecordingExecutor::new(host)));
+        let _ = builder.build();
+        set_executor(previous);
+
+        receiver.try_iter().collect()
+    }
+
+    #[test]
+    fn events_follow_the_build() {
+        let events = build_events(simulated_host(vec![]));
+
+        assert_eq!(
+            events[0],
+            BuildEvent::PhaseStarted {
+                phase: "partition".into()
+            }
+        );
+        assert!(matches!(
+            events.last(),
+            Some(BuildEvent::PhaseFinished {
+                succeeded: true,
+                ..
+            })
+        ));
+
+        let started: Vec<&String> = events
+            .iter()
+            .filter_map(|x| match x {
+                BuildEvent::CommandStarted { command } => Some(command),
+                _ => None,
+            })
+            .collect();
+        let finished: Vec<&String> = events
+            .iter()
+            .filter_map(|x| match x {
+                BuildEvent::CommandFinished {
+                    command,
+                    succeeded: true,
+                    ..
+                } => Some(command),
+                _ => None,
+            })
+            .collect();
+
+        assert!(started.iter().any(|x| x.starts_with("grub-install")));
+        assert_eq!(started, finished);
+    }
+
+    #[test]
+    fn events_show_the_failed_phase() {
+        let host = simulated_host(vec![]);
+        let events = build_events(move |exe, args| {
+            if exe == "chroot" && args.contains(&"install".to_string()) {
+                bail!("apt install failed");
+            }
+            host(exe, args)
+        });
+
+        assert!(events.iter().any(|x| matches!(
+            x,
+            BuildEvent::CommandFinished {
+                command,
+                succeeded: false,
+                ..
+            } if command.contains(" install ")
+        )));
+        assert!(matches!(
+            events.last(),
+            Some(BuildEvent::PhaseFinished {
+                phase,
+                succeeded: false,
+                ..
+            }) if phase == "packages"
+        ));
+    }
+}
+
+/// Golden command sequence tests: run `create` against a fake host for each
+/// flavor and profile, and compare every external command against
+/// tests/snapshots. Run with UPDATE_SNAPSHOTS=1 to regenerate after an
+/// intentional change, and commit the diff along with the change.
+/// The dry run's host, with a made up image and compose project: an
+/// entrypoint and command, a user, a port and a health check for the
+/// container's service, environment and firewall to come from
+#[cfg(test)]
+fn simulated_host(initramfs_modules: Vec<String>) -> impl Fn(&str, &[String]) -> Result<String> {
+    let host = dry_run_host(initramfs_modules, Rc::new(RefCell::new(None)));
+
+    move |exe, args| {
+        match exe {
+        "docker" if args.contains(&"{{json .Config}}".to_string()) => Ok(r#"{
+            "Entrypoint": ["/docker-entrypoint.sh"],
+            "Cmd": ["serve", "--port", "8080"],
+            "Env": ["PATH=/usr/local/bin:/usr/bin:/bin"],
+            "WorkingDir": "/srv",
+            "User": "app",
+            "ExposedPorts": {"8080/tcp": {}},
+            "Healthcheck": {"Test": ["CMD", "/docker-entrypoint.sh", "check"]}
+        }"#
+        .into()),
+
+        "docker" if args.join(" ").ends_with("config --format json") => Ok(r#"{
+            "name": "shop",
+            "services": {
+                "db": {"image": "postgres:16"},
+                "web": {"image": "shop/web:1", "depends_on": {"db": {"condition": "service_started"}}}
+            }
+        }"#
+        .into()),
+
+        "docker" if args.join(" ").starts_with("image inspect") => {
+            Ok("linux amd64 134217728 sha256:0123456789abcdef".into())
+        }
+
+        _ => host(exe, args),
+    }
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    fn snapshot(name: &str, args: &[&str]) -> Result<()> {
+        let output_dir = tempdir()?;
+        let output_file = output_dir.path().join("output.img");
+
+        let ignition = output_dir.path().join("config.ign");
+        std::fs::write(&ignition, "{}")?;
+        std::fs::write(output_dir.path().join("compose.yml"), "services: {}\n")?;
+
+        let mut argv = vec![
+            "docker_to_uefi_bootable_image",
+            "create",
+            "--image-name",
+            "tester",
+            "--output-file",
+            output_file.to_str().unwrap(),
+        ];
+        if !args.contains(&"--disk-size") {
+            argv.extend(["--disk-size", "1"]);
+        }
+        argv.extend_from_slice(args);
+
+        let argv: Vec<String> = argv
+            .into_iter()
+            .map(|x| {
+                x.replace("{ignition}", ignition.to_str().unwrap())
+                    .replace("{output_dir}", output_dir.path().to_str().unwrap())
+            })
+            .collect();
+
+        let Command::Create(create_args) = Args::try_parse_from(argv)?.command else {
+            panic!("expected create");
+        };
+
+        let executor = Rc::new(RecordingExecutor::new(simulated_host(
+            create_args.initramfs_modules.clone(),
+        )));
+        let previous = set_executor(executor.clone());
+        let result = create(*create_args);
+        set_executor(previous);
+        result?;
+
+        let commands = executor.commands();
+
+        let manifest = output_dir.path().join("manifest.json");
+        if manifest.exists() {
+            let manifest: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(manifest)?)?;
+
+            assert_eq!(manifest["image_name"], "tester");
+            assert_eq!(manifest["sha256"].as_str().unwrap().len(), 64);
+
+            if let Some(checksum_file) = manifest["checksum_file"].as_str() {
+                assert_eq!(
+                    std::fs::read_to_string(checksum_file)?,
+                    format!("{}  output.img\n", manifest["sha256"].as_str().unwrap())
+                );
+            }
+            assert_eq!(
+                manifest["partitions"][1]["fs_uuid"],
+                "00000000-0000-0000-0000-000000000000"
+            );
+            assert_eq!(manifest["phases"][0]["phase"], "partition");
+            assert_eq!(
+                manifest["phases"].as_array().unwrap().last().unwrap()["phase"],
+                "output"
+            );
+        }
+
+        let spdx = SbomFormat::Spdx.path(&output_file);
+        if spdx.exists() {
+            let spdx: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(spdx)?)?;
+
+            assert_eq!(spdx["packages"][1]["name"], "tester");
+            assert_eq!(
+                spdx["packages"][1]["checksums"][0]["checksumValue"],
+                "0123456789abcdef"
+            );
+            assert_eq!(
+                spdx["packages"][2]["externalRefs"][0]["referenceLocator"],
+                "pkg:deb/debian/base-files@0.0.0?arch=amd64"
+            );
+        }
+
+        let cyclonedx = SbomFormat::Cyclonedx.path(&output_file);
+        if cyclonedx.exists() {
+            let cyclonedx: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(cyclonedx)?)?;
+
+            assert_eq!(
+                cyclonedx["components"][1]["purl"],
+                "pkg:apk/alpine/alpine-baselayout@0.0.0-r0?arch=x86_64"
+            );
+        }
+
+        // Scrub values that change from run to run
+        let workdir = commands
+            .iter()
+            .find(|x| {
+                x.exe == "mkdir" && x.args.last().unwrap().ends_with("/mnt/boot/efi/EFI/BOOT/")
+            })
+            .map(|x| {
+                x.args
+                    .last()
+                    .unwrap()
+                    .trim_end_matches("/mnt/boot/efi/EFI/BOOT/")
+                    .to_string()
+            })
+            .unwrap();
+
+        let container = commands
+            .iter()
+            .find(|x| x.exe == "docker" && x.args[0] == "run")
+            .map(|x| x.args[x.args.iter().position(|y| y == "--name").unwrap() + 1].clone())
+            .unwrap();
+
+        let actual: String = commands
+            .iter()
+            .map(|x| {
+                format!("{}\n", x)
+                    .replace(&workdir, "{workdir}")
+                    .replace(&container, "{container}")
+                    .replace(ignition.to_str().unwrap(), "{ignition}")
+                    .replace(output_dir.path().to_str().unwrap(), "{output_dir}")
+            })
+            .collect();
+
+        let path = format!(
+            "{}/tests/snapshots/{}.txt",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        );
+
+        if std::env::var("UPDATE_SNAPSHOTS").is_ok() {
+            std::fs::write(&path, &actual)?;
+            return Ok(());
+        }
+
+        let expected = std::fs::read_to_string(&path).unwrap_or_default();
+
+        assert!(
+            expected == actual,
+            "command sequence for {} changed, rerun with UPDATE_SNAPSHOTS=1 and \
+             review the diff of {}\n\n{}",
+            name,
+            path,
+            actual,
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn debian_default() -> Result<()> {
+        snapshot(
+            "create-debian-default",
+            &[
+                "--flavor",
+                "debian",
+                "--manifest",
+                "{output_dir}/manifest.json",
+            ],
+        )
+    }
+
+    #[test]
+    fn debian_full() -> Result<()> {
+        snapshot(
+            "create-debian-full",
+            &[
+                "--flavor",
+                "debian",
+                "--read-only-root",
+                "--esp-mirror",
+                "--kernel-version",
+                "6.1.0-18-amd64,6.1.0-17-amd64",
+                "--fstrim",
+                "--disk-size",
+                "2",
+                "--guest-tools",
+                "qemu",
+                "--container-service",
+                "--firewall",
+                "nftables",
+                "--allow-port",
+                "22",
+                "--root-passwd-hash",
+                "$6$salt$hash",
+                "--selinux",
+                "--network",
+                "static",
+                "--address",
+                "192.168.1.10/24",
+                "--gateway",
+                "192.168.1.1",
+                "--dns",
+                "1.1.1.1,8.8.8.8",
+                "--extra-packages",
+                "vim,curl",
+                "--run-in-chroot",
+                "post-extract:echo extracted",
+                "--run-in-chroot",
+                "dpkg -l",
+                "--run-in-chroot",
+                "pre-umount:rm -f /etc/motd",
+                "--chrony-phc",
+                "--include-firmware",
+                "--ignition",
+                "{ignition}",
+                "--cache-dir",
+                "{output_dir}/cache",
+                "--sbom",
+                "spdx",
+                "--checksum",
+                "sha256",
+                "--sign-key",
+                "builds@example.com",
+            ],
+        )
+    }
+
+    #[test]
+    fn ubuntu_default() -> Result<()> {
+        snapshot("create-ubuntu-default", &["--flavor", "ubuntu"])
+    }
+
+    #[test]
+    fn ubuntu_full() -> Result<()> {
+        snapshot(
+            "create-ubuntu-full",
+            &[
+                "--flavor",
+                "ubuntu",
+                "--container-env",
+                "--guest-tools",
+                "hyperv",
+                "--compose",
+                "{output_dir}/compose.yml",
+                "--partition-backend",
+                "repart",
+                "--mirror",
+                "http://mirror.example.com/ubuntu/",
+                "--pkg-proxy",
+                "http://proxy.example.com:3128",
+                "--lock-root",
+                "--initramfs-modules",
+                "virtio_blk,virtio_scsi",
+                "--initramfs-compression",
+                "lz4",
+                "--initramfs-module-set",
+                "dep",
+                "--no-clean",
+                "--minimal",
+                "--network",
+                "static",
+                "--address",
+                "192.168.1.10/24",
+                "--gateway",
+                "192.168.1.1",
+                "--dns",
+                "1.1.1.1,8.8.8.8",
+                "--extra-packages",
+                "vim,curl",
+                "--chrony-phc",
+                "--clocksource",
+                "tsc",
+                "--pause",
+                "after-packages",
+                "--enable-service",
+                "ssh",
+                "--disable-service",
+                "apt-daily.timer,apt-daily-upgrade.timer",
+                "--mask-service",
+                "systemd-resolved",
+                "--include-firmware",
+                "--ignition",
+                "{ignition}",
+                "--workdir-tmpfs",
+                "4G",
+                "--sign-key",
+                "{output_dir}/cosign.key",
+                "--signer",
+                "cosign",
+                "--upload",
+                "s3://images/ci/ubuntu.img",
+                "--s3-endpoint",
+                "http://minio.example.com:9000",
+                "--s3-part-size",
+                "512",
+            ],
+        )
+    }
+
+    #[test]
+    fn debian_persistence() -> Result<()> {
+        snapshot(
+            "create-debian-persistence",
+            &[
+                "--flavor",
+                "debian",
+                "--disk-size",
+                "2",
+                "--persistence",
+                "1",
+            ],
+        )
+    }
+
+    #[test]
+    fn debian_data_partitions() -> Result<()> {
+        snapshot(
+            "create-debian-data-partitions",
+            &[
+                "--flavor",
+                "debian",
+                "--disk-size",
+                "4",
+                "--data-partition",
+                "/data:size=1,fs=xfs,source={output_dir}",
+                "--data-partition",
+                "/srv/seed:size=1,source={ignition}",
+                "--disk-guid",
+                "5f3c2a1e-8b4d-4e6f-9a0b-1c2d3e4f5a6b",
+            ],
+        )
+    }
+
+    #[test]
+    fn alpine_default() -> Result<()> {
+        snapshot("create-alpine-default", &["--flavor", "alpine"])
+    }
+
+    #[test]
+    fn alpine_full() -> Result<()> {
+        snapshot(
+            "create-alpine-full",
+            &[
+                "--flavor",
+                "alpine",
+                "--rootless",
+                "--read-only-root",
+                "--fstab-opt",
+                "/:noatime",
+                "--initramfs-compression",
+                "zstd",
+                "--kernel-version",
+                "6.6.14-r0",
+                "--fstrim",
+                "--guest-tools",
+                "vmware",
+                "--container-service",
+                "--firewall",
+                "nftables",
+                "--allow-port",
+                "22/tcp",
+                "--mirror",
+                "https://mirror.example.com/alpine",
+                "--initramfs-modules",
+                "virtio_blk",
+                "--chrony-phc",
+                "--include-firmware",
+                "--enable-service",
+                "sshd",
+                "--disable-service",
+                "crond",
+                "--cache-dir",
+                "{output_dir}/cache",
+                "--sbom",
+                "cyclonedx",
+                "--disk-guid",
+                "5f3c2a1e-8b4d-4e6f-9a0b-1c2d3e4f5a6b",
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod boot_tests {
+    use super::*;
+
+    #[test]
+    fn qemu_args_forward_ports() -> Result<()> {
+        let Command::Boot(args) = Args::try_parse_from([
+            "docker_to_uefi_bootable_image",
+            "boot",
+            "debian.img",
+            "--forward",
+            "2222:22",
+            "--forward",
+            "8080:80",
+            "--ovmf",
+            "/usr/share/OVMF/OVMF_CODE.fd",
+        ])?
+        .command
+        else {
+            panic!("expected boot");
+        };
+
+        assert_eq!(
+            qemu_options(&args)
+                .args(&args.ovmf.resolve()?.qemu_args(Path::new("/tmp"))?)
+                .join(" "),
+            "-machine q35,accel=kvm -m 2G -smp 2 -bios /usr/share/OVMF/OVMF_CODE.fd \
+             -drive file=debian.img,if=virtio,format=raw -nographic -snapshot \
+             -netdev user,id=net0,hostfwd=tcp::2222-:22,hostfwd=tcp::8080-:80 \
+             -device virtio-net-pci,netdev=net0"
+        );
+
+        assert!(parse_port_forward("22").is_err());
+
+        Ok(())
+    }
 }
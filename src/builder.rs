@@ -0,0 +1,5419 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Building a bootable image out of a docker image:
+//!
+//! ```no_run
+//! use docker_to_uefi_bootable_image::builder::{Flavor, ImageBuilder};
+//!
+//! let image = ImageBuilder::new("debian:12")
+//!     .flavor(Flavor::Debian)
+//!     .disk_size_gb(8)
+//!     .extra_packages(["openssh-server", "vim"])
+//!     .output_file("debian.img")
+//!     .build()?;
+//!
+//! println!("built {:?} with kernels {:?}", image.path, image.kernel_versions);
+//! # anyhow::Ok(())
+//! ```
+//!
+//! Like the rest of this crate, building needs root, and shells out to
+//! docker, mkfs, grub-install and friends.
+
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context as _, Result};
+use clap::ValueEnum;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use tracing::span::EnteredSpan;
+use tracing::{debug, info, info_span, warn};
+
+use crate::app;
+use crate::compose::{self, Compose};
+use crate::console::{self, Console};
+use crate::datapart::{self, DataPartition};
+use crate::error::Error;
+use crate::events::{emit, set_events, BuildEvent, ImageBuilderEvents};
+use crate::fat::Fat32;
+use crate::firewall::{self, Firewall, Port};
+use crate::fstab::{self, FstabOptions};
+use crate::gpt::{self, derive_guid, Gpt, PartitionChange, SECTOR};
+use crate::minimal;
+use crate::probe::{Filesystem, FsType, FsUuid};
+use crate::readonly;
+use crate::repart;
+use crate::rootless;
+use crate::sbom::{self, Package};
+use crate::*;
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub enum HookPoint {
+    #[value(alias = "after-extract")]
+    PostExtract,
+    #[value(alias = "after-packages")]
+    PostPackages,
+    #[value(alias = "before-umount")]
+    PreUmount,
+}
+
+impl HookPoint {
+    pub fn name(&self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub enum NetworkMode {
+    Dhcp,
+    Static,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flavor {
+    Debian,
+    Ubuntu,
+    Alpine,
+}
+
+/// The hypervisor's guest agent, for reporting addresses, shutting down
+/// cleanly and freezing filesystems for snapshots
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum GuestTools {
+    Qemu,
+    Vmware,
+    Hyperv,
+    None,
+}
+
+impl GuestTools {
+    pub fn packages(&self, flavor: &Flavor) -> &'static [&'static str] {
+        match (self, flavor) {
+            (GuestTools::Qemu, _) => &["qemu-guest-agent"],
+            (GuestTools::Vmware, _) => &["open-vm-tools"],
+            (GuestTools::Hyperv, Flavor::Debian) => &["hyperv-daemons"],
+            (GuestTools::Hyperv, Flavor::Ubuntu) => &["linux-cloud-tools-virtual"],
+            (GuestTools::Hyperv, Flavor::Alpine) => &["hvtools"],
+            (GuestTools::None, _) => &[],
+        }
+    }
+
+    /// The services to enable. systemd's qemu-guest-agent is started by
+    /// udev when the virtio port shows up.
+    pub fn services(&self, flavor: &Flavor) -> &'static [&'static str] {
+        match (self, flavor) {
+            (GuestTools::Qemu, Flavor::Debian | Flavor::Ubuntu) => &[],
+            (GuestTools::Qemu, Flavor::Alpine) => &["qemu-guest-agent"],
+            (GuestTools::Vmware, _) => &["open-vm-tools"],
+            (GuestTools::Hyperv, Flavor::Debian | Flavor::Ubuntu) => {
+                &["hv-kvp-daemon", "hv-vss-daemon"]
+            }
+            (GuestTools::Hyperv, Flavor::Alpine) => &["hv_kvp_daemon", "hv_vss_daemon"],
+            (GuestTools::None, _) => &[],
+        }
+    }
+}
+
+/// What partitions the disk
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum PartitionBackend {
+    /// The GPT is written here, and root formatted with mkfs.ext4
+    Native,
+    /// systemd-repart, from repart.d definitions, which also grows root on
+    /// boot on systemd flavors. Needs systemd 249 or newer on the host.
+    Repart,
+}
+
+/// What the initramfs is compressed with. zstd and lz4 trade a bigger
+/// image for a faster boot.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum InitramfsCompression {
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+impl InitramfsCompression {
+    /// As initramfs-tools' COMPRESS and mkinitfs's -C spell it
+    pub fn name(&self) -> &'static str {
+        match self {
+            InitramfsCompression::Gzip => "gzip",
+            InitramfsCompression::Lz4 => "lz4",
+            InitramfsCompression::Zstd => "zstd",
+        }
+    }
+
+    /// The compressor, which runs in the image
+    pub fn packages(&self) -> &'static [&'static str] {
+        match self {
+            InitramfsCompression::Gzip => &[],
+            InitramfsCompression::Lz4 => &["lz4"],
+            InitramfsCompression::Zstd => &["zstd"],
+        }
+    }
+}
+
+/// Which modules initramfs-tools includes (its MODULES)
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum InitramfsModuleSet {
+    /// Most storage and filesystem drivers, so it boots anywhere
+    Most,
+    /// Only those the machine needs, found by looking at the hardware. That
+    /// is the build host's, so the VM's disk drivers have to be added with
+    /// `initramfs_modules`.
+    Dep,
+}
+
+impl InitramfsModuleSet {
+    fn name(&self) -> &'static str {
+        match self {
+            InitramfsModuleSet::Most => "most",
+            InitramfsModuleSet::Dep => "dep",
+        }
+    }
+}
+
+/// An initramfs-tools conf.d snippet setting what was asked for
+fn initramfs_tools_conf(
+    compression: Option<InitramfsCompression>,
+    module_set: Option<InitramfsModuleSet>,
+) -> String {
+    let mut conf = String::new();
+
+    if let Some(module_set) = module_set {
+        conf.push_str(&format!("MODULES={}\n", module_set.name()));
+    }
+
+    if let Some(compression) = compression {
+        conf.push_str(&format!("COMPRESS={}\n", compression.name()));
+    }
+
+    conf
+}
+
+/// Options for building an image. Everything but the image name has a
+/// default: an 8 GB Debian image with DHCP on eth0 and a generated root
+/// password, written to `<hostname>.img`.
+#[derive(Clone)]
+pub struct ImageBuilder {
+    image_name: String,
+    output_file: Option<PathBuf>,
+    disk_size: usize,
+    root_passwd: Option<String>,
+    root_passwd_hash: Option<String>,
+    lock_root: bool,
+    disable_ssh_password_auth: bool,
+    extra_packages: Vec<String>,
+    kernel_version: Vec<String>,
+    flavor: Flavor,
+    chrony_phc: bool,
+    clocksource: Option<String>,
+    consoles: Vec<Console>,
+    include_firmware: bool,
+    ignition: Option<PathBuf>,
+    hostname: Option<String>,
+    network: NetworkMode,
+    address: Option<String>,
+    gateway: Option<String>,
+    dns: Vec<String>,
+    enable_service: Vec<String>,
+    disable_service: Vec<String>,
+    mask_service: Vec<String>,
+    run_in_chroot: Vec<(HookPoint, String)>,
+    hook_dir: Option<PathBuf>,
+    pause: Vec<HookPoint>,
+    no_clean: bool,
+    minimal: bool,
+    selinux: bool,
+    debug_shell: bool,
+    panic: Option<u32>,
+    initramfs_modules: Vec<String>,
+    initramfs_compression: Option<InitramfsCompression>,
+    initramfs_module_set: Option<InitramfsModuleSet>,
+    sysctl: Vec<String>,
+    sysctl_file: Vec<PathBuf>,
+    blacklist_module: Vec<String>,
+    modprobe_file: Vec<PathBuf>,
+    mirror: Option<String>,
+    pkg_proxy: Option<String>,
+    cache_dir: Option<PathBuf>,
+    ca_cert: Vec<PathBuf>,
+    dry_run: bool,
+    keep_workdir: bool,
+    workdir_tmpfs: Option<String>,
+    list_packages: bool,
+    disk_guid: Option<uuid::Uuid>,
+    container_service: bool,
+    container_env: bool,
+    firewall: Option<Firewall>,
+    allow_port: Vec<Port>,
+    compose: Option<PathBuf>,
+    guest_tools: GuestTools,
+    read_only_root: bool,
+    persistence: Option<usize>,
+    data_partitions: Vec<DataPartition>,
+    esp_mirror: bool,
+    partition_backend: PartitionBackend,
+    rootless: bool,
+    rootfs_tar: Option<PathBuf>,
+    fstab_options: Vec<FstabOptions>,
+    gpt_partitions: Vec<PartitionChange>,
+    fstrim: bool,
+    diagnostics: Option<PathBuf>,
+    resume: Option<PathBuf>,
+    cancel: Option<CancelToken>,
+    command_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    events: Option<Arc<dyn ImageBuilderEvents>>,
+}
+
+// By hand, to keep the root password out of logs and panics
+impl std::fmt::Debug for ImageBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ImageBuilder {
+            image_name,
+            output_file,
+            disk_size,
+            root_passwd,
+            root_passwd_hash,
+            lock_root,
+            disable_ssh_password_auth,
+            extra_packages,
+            kernel_version,
+            flavor,
+            chrony_phc,
+            clocksource,
+            consoles,
+            include_firmware,
+            ignition,
+            hostname,
+            network,
+            address,
+            gateway,
+            dns,
+            enable_service,
+            disable_service,
+            mask_service,
+            run_in_chroot,
+            hook_dir,
+            pause,
+            no_clean,
+            minimal,
+            selinux,
+            debug_shell,
+            panic,
+            initramfs_modules,
+            initramfs_compression,
+            initramfs_module_set,
+            sysctl,
+            sysctl_file,
+            blacklist_module,
+            modprobe_file,
+            mirror,
+            pkg_proxy,
+            cache_dir,
+            ca_cert,
+            dry_run,
+            keep_workdir,
+            workdir_tmpfs,
+            list_packages,
+            disk_guid,
+            container_service,
+            container_env,
+            firewall,
+            allow_port,
+            compose,
+            guest_tools,
+            read_only_root,
+            persistence,
+            data_partitions,
+            esp_mirror,
+            partition_backend,
+            rootless,
+            rootfs_tar,
+            fstab_options,
+            gpt_partitions,
+            fstrim,
+            diagnostics,
+            resume,
+            cancel,
+            command_timeout,
+            retry_policy,
+            events,
+        } = self;
+
+        f.debug_struct("ImageBuilder")
+            .field("image_name", image_name)
+            .field("output_file", output_file)
+            .field("disk_size", disk_size)
+            .field("root_passwd", &root_passwd.as_ref().map(|_| "<redacted>"))
+            .field(
+                "root_passwd_hash",
+                &root_passwd_hash.as_ref().map(|_| "<redacted>"),
+            )
+            .field("lock_root", lock_root)
+            .field("disable_ssh_password_auth", disable_ssh_password_auth)
+            .field("extra_packages", extra_packages)
+            .field("kernel_version", kernel_version)
+            .field("flavor", flavor)
+            .field("chrony_phc", chrony_phc)
+            .field("clocksource", clocksource)
+            .field("consoles", consoles)
+            .field("include_firmware", include_firmware)
+            .field("ignition", ignition)
+            .field("hostname", hostname)
+            .field("network", network)
+            .field("address", address)
+            .field("gateway", gateway)
+            .field("dns", dns)
+            .field("enable_service", enable_service)
+            .field("disable_service", disable_service)
+            .field("mask_service", mask_service)
+            .field("run_in_chroot", run_in_chroot)
+            .field("hook_dir", hook_dir)
+            .field("pause", pause)
+            .field("no_clean", no_clean)
+            .field("minimal", minimal)
+            .field("selinux", selinux)
+            .field("debug_shell", debug_shell)
+            .field("panic", panic)
+            .field("initramfs_modules", initramfs_modules)
+            .field("initramfs_compression", initramfs_compression)
+            .field("initramfs_module_set", initramfs_module_set)
+            .field("sysctl", sysctl)
+            .field("sysctl_file", sysctl_file)
+            .field("blacklist_module", blacklist_module)
+            .field("modprobe_file", modprobe_file)
+            .field("mirror", mirror)
+            .field("pkg_proxy", pkg_proxy)
+            .field("cache_dir", cache_dir)
+            .field("ca_cert", ca_cert)
+            .field("dry_run", dry_run)
+            .field("keep_workdir", keep_workdir)
+            .field("workdir_tmpfs", workdir_tmpfs)
+            .field("list_packages", list_packages)
+            .field("disk_guid", disk_guid)
+            .field("container_service", container_service)
+            .field("container_env", container_env)
+            .field("firewall", firewall)
+            .field("allow_port", allow_port)
+            .field("compose", compose)
+            .field("guest_tools", guest_tools)
+            .field("read_only_root", read_only_root)
+            .field("persistence", persistence)
+            .field("data_partitions", data_partitions)
+            .field("esp_mirror", esp_mirror)
+            .field("partition_backend", partition_backend)
+            .field("rootless", rootless)
+            .field("rootfs_tar", rootfs_tar)
+            .field("fstab_options", fstab_options)
+            .field("gpt_partitions", gpt_partitions)
+            .field("fstrim", fstrim)
+            .field("diagnostics", diagnostics)
+            .field("resume", resume)
+            .field("cancel", cancel)
+            .field("command_timeout", command_timeout)
+            .field("retry_policy", retry_policy)
+            .field("events", events)
+            .finish()
+    }
+}
+
+/// A finished image, as returned by [`ImageBuilder::build`]
+#[derive(Debug, Clone)]
+pub struct BuiltImage {
+    /// Where the image was written (nothing is written for a dry run)
+    pub path: PathBuf,
+    pub image_name: String,
+    pub flavor: Flavor,
+    /// The root password, if one was generated
+    pub generated_root_passwd: Option<String>,
+    pub kernel_versions: Vec<String>,
+    /// The container image's ID, like "sha256:...", unless it was a dry run
+    pub image_id: Option<String>,
+    /// What's installed, if [`ImageBuilder::list_packages`] was set
+    pub packages: Vec<Package>,
+    /// The EFI system partition
+    pub esp: BuiltPartition,
+    /// The copy of the ESP, with `esp_mirror`
+    pub esp_mirror: Option<BuiltPartition>,
+    pub root: BuiltPartition,
+    /// How long each phase took, in order
+    pub phases: Vec<PhaseTiming>,
+}
+
+/// The wall-clock time of one phase of a build
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseTiming {
+    pub phase: &'static str,
+    pub elapsed: Duration,
+    /// Bytes unpacked or copied, for the phases that do either
+    pub bytes: Option<u64>,
+}
+
+/// A partition of a [`BuiltImage`]
+#[derive(Debug, Clone)]
+pub struct BuiltPartition {
+    pub number: u32,
+    pub partuuid: uuid::Uuid,
+    pub mountpoint: Option<&'static str>,
+    pub filesystem: Filesystem,
+}
+
+fn strings(values: impl IntoIterator<Item = impl Into<String>>) -> Vec<String> {
+    values.into_iter().map(Into::into).collect()
+}
+
+fn paths(values: impl IntoIterator<Item = impl Into<PathBuf>>) -> Vec<PathBuf> {
+    values.into_iter().map(Into::into).collect()
+}
+
+impl ImageBuilder {
+    pub fn new(image_name: impl Into<String>) -> Self {
+        Self {
+            image_name: image_name.into(),
+            output_file: None,
+            disk_size: 8,
+            root_passwd: None,
+            root_passwd_hash: None,
+            lock_root: false,
+            disable_ssh_password_auth: false,
+            extra_packages: vec![],
+            kernel_version: vec![],
+            flavor: Flavor::Debian,
+            chrony_phc: false,
+            clocksource: None,
+            consoles: vec![],
+            include_firmware: false,
+            ignition: None,
+            hostname: None,
+            network: NetworkMode::Dhcp,
+            address: None,
+            gateway: None,
+            dns: vec![],
+            enable_service: vec![],
+            disable_service: vec![],
+            mask_service: vec![],
+            run_in_chroot: vec![],
+            hook_dir: None,
+            pause: vec![],
+            no_clean: false,
+            minimal: false,
+            selinux: false,
+            debug_shell: false,
+            panic: None,
+            initramfs_modules: vec![],
+            initramfs_compression: None,
+            initramfs_module_set: None,
+            sysctl: vec![],
+            sysctl_file: vec![],
+            blacklist_module: vec![],
+            modprobe_file: vec![],
+            mirror: None,
+            pkg_proxy: None,
+            cache_dir: None,
+            ca_cert: vec![],
+            dry_run: false,
+            keep_workdir: false,
+            workdir_tmpfs: None,
+            list_packages: false,
+            disk_guid: None,
+            container_service: false,
+            container_env: false,
+            firewall: None,
+            allow_port: vec![],
+            compose: None,
+            guest_tools: GuestTools::None,
+            read_only_root: false,
+            persistence: None,
+            data_partitions: vec![],
+            esp_mirror: false,
+            partition_backend: PartitionBackend::Native,
+            rootless: false,
+            rootfs_tar: None,
+            fstab_options: vec![],
+            gpt_partitions: vec![],
+            fstrim: false,
+            diagnostics: None,
+            resume: None,
+            cancel: None,
+            command_timeout: None,
+            retry_policy: RetryPolicy::DEFAULT,
+            events: None,
+        }
+    }
+
+    /// Where to write the image, defaults to `<hostname>.img`
+    pub fn output_file(mut self, output_file: impl Into<PathBuf>) -> Self {
+        self.output_file = Some(output_file.into());
+        self
+    }
+
+    pub fn disk_size_gb(mut self, disk_size: usize) -> Self {
+        self.disk_size = disk_size;
+        self
+    }
+
+    /// Set this root password instead of generating one
+    pub fn root_passwd(mut self, root_passwd: impl Into<String>) -> Self {
+        self.root_passwd = Some(root_passwd.into());
+        self
+    }
+
+    /// Set this root password hash (as in /etc/shadow) instead of
+    /// generating a password
+    pub fn root_passwd_hash(mut self, root_passwd_hash: impl Into<String>) -> Self {
+        self.root_passwd_hash = Some(root_passwd_hash.into());
+        self
+    }
+
+    /// Lock the root account instead of setting a password
+    pub fn lock_root(mut self, lock_root: bool) -> Self {
+        self.lock_root = lock_root;
+        self
+    }
+
+    /// Turn off sshd password and keyboard-interactive authentication
+    pub fn disable_ssh_password_auth(mut self, disable: bool) -> Self {
+        self.disable_ssh_password_auth = disable;
+        self
+    }
+
+    pub fn extra_packages(mut self, packages: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.extra_packages.extend(strings(packages));
+        self
+    }
+
+    /// Install these kernels, held at their version, instead of whatever
+    /// the flavor's kernel metapackage pulls in. On Debian and Ubuntu
+    /// they're kernel releases, like 6.1.0-18-amd64, each getting a GRUB
+    /// entry with the newest the default. Alpine takes one linux-lts
+    /// package version, like 6.6.14-r0.
+    pub fn kernel_version(mut self, versions: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.kernel_version.extend(strings(versions));
+        self
+    }
+
+    pub fn flavor(mut self, flavor: Flavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Install chrony and use the PTP KVM clock (/dev/ptp0) as a refclock
+    pub fn chrony_phc(mut self, chrony_phc: bool) -> Self {
+        self.chrony_phc = chrony_phc;
+        self
+    }
+
+    /// Kernel clocksource (kvm-clock, tsc, ...)
+    pub fn clocksource(mut self, clocksource: impl Into<String>) -> Self {
+        self.clocksource = Some(clocksource.into());
+        self
+    }
+
+    /// Consoles to boot with, the primary first, like `ttyS0,115200` or
+    /// `tty0`. GRUB and a login prompt go on each. Defaults to ttyS0.
+    pub fn consoles(mut self, consoles: impl IntoIterator<Item = Console>) -> Self {
+        self.consoles.extend(consoles);
+        self
+    }
+
+    /// Install firmware packages if any installed kernel module needs
+    /// firmware that isn't already in /lib/firmware
+    pub fn include_firmware(mut self, include_firmware: bool) -> Self {
+        self.include_firmware = include_firmware;
+        self
+    }
+
+    /// Ignition config whose files stage (files, directories, links,
+    /// systemd units, users and groups) the image's own /usr/bin/ignition
+    /// applies on first boot, once the root filesystem is up. Configs that
+    /// partition or format disks are refused, since those stages would have
+    /// to run from the initramfs.
+    pub fn ignition(mut self, config: impl Into<PathBuf>) -> Self {
+        self.ignition = Some(config.into());
+        self
+    }
+
+    /// Defaults to one derived from the image name
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Network configuration for eth0. A static network also needs
+    /// `address`.
+    pub fn network(mut self, network: NetworkMode) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn address(mut self, address: impl Into<String>) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    pub fn gateway(mut self, gateway: impl Into<String>) -> Self {
+        self.gateway = Some(gateway.into());
+        self
+    }
+
+    pub fn dns(mut self, servers: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.dns.extend(strings(servers));
+        self
+    }
+
+    pub fn enable_service(mut self, services: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.enable_service.extend(strings(services));
+        self
+    }
+
+    pub fn disable_service(
+        mut self,
+        services: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.disable_service.extend(strings(services));
+        self
+    }
+
+    /// Install and enable the agent for the hypervisor the image will run on
+    pub fn guest_tools(mut self, guest_tools: GuestTools) -> Self {
+        self.guest_tools = guest_tools;
+        self
+    }
+
+    /// Mount the root read-only and keep every change in memory, so the
+    /// disk is never written and survives losing power. On Debian and
+    /// Ubuntu /etc and /var get a tmpfs overlay and /tmp is a tmpfs; on
+    /// Alpine the whole root is overlaid.
+    pub fn read_only_root(mut self, read_only_root: bool) -> Self {
+        self.read_only_root = read_only_root;
+        self
+    }
+
+    /// For live USB sticks: a read-only root, with changes to it kept on a
+    /// partition of this many GB at the end of the disk, labelled
+    /// persistence, rather than in memory. Debian and Ubuntu only.
+    pub fn persistence(mut self, persistence: Option<usize>) -> Self {
+        self.persistence = persistence;
+        self
+    }
+
+    /// Add partitions after root, each mounted on its mountpoint and filled
+    /// from its source, if it has one. Native partitioning only.
+    pub fn data_partitions(mut self, partitions: impl IntoIterator<Item = DataPartition>) -> Self {
+        self.data_partitions.extend(partitions);
+        self
+    }
+
+    /// Add a second ESP, partition 4, written with the same files as the
+    /// first, for firmware to fall back to if the first is corrupted. It is
+    /// in fstab as noauto on /boot/efi2.
+    pub fn esp_mirror(mut self, esp_mirror: bool) -> Self {
+        self.esp_mirror = esp_mirror;
+        self
+    }
+
+    /// Partition with systemd-repart rather than writing the GPT here
+    pub fn partition_backend(mut self, partition_backend: PartitionBackend) -> Self {
+        self.partition_backend = partition_backend;
+        self
+    }
+
+    /// Build without loop devices or mounting the disk: root's filesystem
+    /// is made from its directory at the end, and GRUB is made rather than
+    /// installed. Run as root in a user namespace, see [`crate::rootless`].
+    pub fn rootless(mut self, rootless: bool) -> Self {
+        self.rootless = rootless;
+        self
+    }
+
+    /// Unpack this `docker export` of the image rather than exporting it
+    /// again, as the variants of a matrix build share one
+    pub fn rootfs_tar(mut self, rootfs_tar: impl Into<PathBuf>) -> Self {
+        self.rootfs_tar = Some(rootfs_tar.into());
+        self
+    }
+
+    /// Add or, with a leading -, remove fstab options for a mountpoint. By
+    /// default root has `errors=remount-ro,discard`, and on systemd
+    /// flavors `x-systemd.growfs` so it fills a grown disk.
+    pub fn fstab_options(mut self, options: impl IntoIterator<Item = FstabOptions>) -> Self {
+        self.fstab_options.extend(options);
+        self
+    }
+
+    /// Change partitions' types, names and attributes from the layout's,
+    /// e.g. to make root a discoverable partition. Native partitioning only.
+    pub fn gpt_partitions(mut self, changes: impl IntoIterator<Item = PartitionChange>) -> Self {
+        self.gpt_partitions.extend(changes);
+        self
+    }
+
+    /// Keep a thin-provisioned disk thin: trim root weekly, with
+    /// fstrim.timer or on Alpine crond, and mount it `noatime,discard`.
+    pub fn fstrim(mut self, fstrim: bool) -> Self {
+        self.fstrim = fstrim;
+        self
+    }
+
+    /// Not supported for Alpine
+    pub fn mask_service(mut self, services: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.mask_service.extend(strings(services));
+        self
+    }
+
+    /// Run a shell command in the chroot at `point`
+    pub fn run_in_chroot(mut self, point: HookPoint, command: impl Into<String>) -> Self {
+        self.run_in_chroot.push((point, command.into()));
+        self
+    }
+
+    /// Directory with post-extract/, post-packages/ and pre-umount/
+    /// subdirectories of executables to run in the chroot
+    pub fn hook_dir(mut self, hook_dir: impl Into<PathBuf>) -> Self {
+        self.hook_dir = Some(hook_dir.into());
+        self
+    }
+
+    /// Drop into a shell chrooted into the image at `point`
+    pub fn pause(mut self, point: HookPoint) -> Self {
+        self.pause.push(point);
+        self
+    }
+
+    /// Keep machine-id, SSH host keys, package caches and logs
+    pub fn no_clean(mut self, no_clean: bool) -> Self {
+        self.no_clean = no_clean;
+        self
+    }
+
+    /// A smaller root: no recommended packages on Debian and Ubuntu, no
+    /// docs, man pages or translations, and no package caches or lists
+    /// even with `no_clean`
+    pub fn minimal(mut self, minimal: bool) -> Self {
+        self.minimal = minimal;
+        self
+    }
+
+    /// Install the SELinux policy and enable it (Debian and Ubuntu only)
+    pub fn selinux(mut self, selinux: bool) -> Self {
+        self.selinux = selinux;
+        self
+    }
+
+    /// Boot verbosely, and leave a shell on the console when boot fails:
+    /// the initramfs's, and systemd's emergency shell even with root locked.
+    /// systemd's debug shell runs on tty9 too.
+    pub fn debug_shell(mut self, debug_shell: bool) -> Self {
+        self.debug_shell = debug_shell;
+        self
+    }
+
+    /// Reboot this many seconds after a kernel panic, or a failure in the
+    /// initramfs, rather than hang
+    pub fn panic(mut self, seconds: u32) -> Self {
+        self.panic = Some(seconds);
+        self
+    }
+
+    pub fn initramfs_modules(
+        mut self,
+        modules: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.initramfs_modules.extend(strings(modules));
+        self
+    }
+
+    /// Compress the initramfs with this rather than the flavor's default
+    pub fn initramfs_compression(mut self, compression: InitramfsCompression) -> Self {
+        self.initramfs_compression = Some(compression);
+        self
+    }
+
+    /// Not supported for Alpine, whose mkinitfs includes modules by feature
+    pub fn initramfs_module_set(mut self, module_set: InitramfsModuleSet) -> Self {
+        self.initramfs_module_set = Some(module_set);
+        self
+    }
+
+    /// sysctl settings (key=value) for /etc/sysctl.d
+    pub fn sysctl(mut self, settings: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.sysctl.extend(strings(settings));
+        self
+    }
+
+    /// Files to copy into /etc/sysctl.d
+    pub fn sysctl_file(mut self, files: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        self.sysctl_file.extend(paths(files));
+        self
+    }
+
+    /// Kernel modules to blacklist in /etc/modprobe.d
+    pub fn blacklist_module(
+        mut self,
+        modules: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.blacklist_module.extend(strings(modules));
+        self
+    }
+
+    /// Files to copy into /etc/modprobe.d
+    pub fn modprobe_file(mut self, files: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        self.modprobe_file.extend(paths(files));
+        self
+    }
+
+    /// Package mirror base URL
+    pub fn mirror(mut self, mirror: impl Into<String>) -> Self {
+        self.mirror = Some(mirror.into());
+        self
+    }
+
+    /// HTTP(S) proxy for package downloads during the build
+    pub fn pkg_proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.pkg_proxy = Some(proxy.into());
+        self
+    }
+
+    /// Keep downloaded packages in `dir` (one subdirectory per flavor)
+    /// rather than in the image, so the next build can reuse them
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// PEM CA certificates to add to the image's trust store
+    pub fn ca_cert(mut self, certs: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        self.ca_cert.extend(paths(certs));
+        self
+    }
+
+    /// Skip the preflight checks and don't write the output file. Meant to
+    /// be used with a `RecordingExecutor`.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Keep the working directory after a successful build. It is always
+    /// kept if the build fails.
+    pub fn keep_workdir(mut self, keep_workdir: bool) -> Self {
+        self.keep_workdir = keep_workdir;
+        self
+    }
+
+    /// Build in a tmpfs of `size`, like "16G" or "50%", instead of under the
+    /// temporary directory. It has to hold the container's export as well as
+    /// the disk's used blocks.
+    pub fn workdir_tmpfs(mut self, size: impl Into<String>) -> Self {
+        self.workdir_tmpfs = Some(size.into());
+        self
+    }
+
+    /// Read the packages installed in the image into
+    /// [`BuiltImage::packages`], for an [SBOM](crate::sbom)
+    pub fn list_packages(mut self, list_packages: bool) -> Self {
+        self.list_packages = list_packages;
+        self
+    }
+
+    /// Give the disk this GPT GUID, and derive the partitions' GUIDs, the
+    /// ESP's volume ID and the root filesystem's UUID from it, instead of
+    /// making up new ones. Two builds with the same GUID get the same IDs,
+    /// so images attached to one host at once need different GUIDs.
+    pub fn disk_guid(mut self, disk_guid: uuid::Uuid) -> Self {
+        self.disk_guid = Some(disk_guid);
+        self
+    }
+
+    /// Run the container's entrypoint and command, with its environment,
+    /// working directory and user, as a service started on boot:
+    /// container.service, or /etc/init.d/container on Alpine
+    ///
+    /// The image's HEALTHCHECK runs from container-health.timer, or
+    /// supervise-daemon on Alpine, and restarts the service when it keeps
+    /// failing.
+    pub fn container_service(mut self, container_service: bool) -> Self {
+        self.container_service = container_service;
+        self
+    }
+
+    /// Give logins the container's environment, in /etc/environment, or
+    /// /etc/profile.d/container-env.sh on Alpine
+    pub fn container_env(mut self, container_env: bool) -> Self {
+        self.container_env = container_env;
+        self
+    }
+
+    /// Install `firewall` and drop everything coming in except the ports
+    /// the container image EXPOSEs and those from `allow_port`. Not with
+    /// `compose`.
+    pub fn firewall(mut self, firewall: Firewall) -> Self {
+        self.firewall = Some(firewall);
+        self
+    }
+
+    /// Also let these ports through the firewall, e.g. 22 for ssh
+    pub fn allow_port(mut self, allow_port: Vec<Port>) -> Self {
+        self.allow_port = allow_port;
+        self
+    }
+
+    /// Install docker and compose, save the images of this compose file's
+    /// services onto the disk, and start each service on boot after those
+    /// it depends on. Not with `firewall`.
+    pub fn compose(mut self, compose: impl Into<PathBuf>) -> Self {
+        self.compose = Some(compose.into());
+        self
+    }
+
+    /// Write a tarball with the build log, every command run and its output,
+    /// and the configs generated in the image
+    pub fn diagnostics(mut self, bundle: impl Into<PathBuf>) -> Self {
+        self.diagnostics = Some(bundle.into());
+        self
+    }
+
+    /// Continue a failed build from the working directory it left behind,
+    /// skipping the steps it completed. The image name, flavor and disk size
+    /// have to match the failed build's.
+    pub fn resume(mut self, working_dir: impl Into<PathBuf>) -> Self {
+        self.resume = Some(working_dir.into());
+        self
+    }
+
+    /// Stop the build once `token` is cancelled: the running command is
+    /// killed, and everything is unmounted and detached as for any failure
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Kill any command that runs longer than `timeout`, failing the build
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    /// How many times to retry steps that go over the network (package
+    /// index updates, pulling the image) and unmounts or detaches that find
+    /// the device busy. Defaults to 3.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retry_policy.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry, doubling after each one.
+    /// Defaults to 2 seconds.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_policy.delay = delay;
+        self
+    }
+
+    /// Tell `events` as phases start and finish, commands run, and long
+    /// copies make progress
+    pub fn events(mut self, events: impl ImageBuilderEvents + 'static) -> Self {
+        self.events = Some(Arc::new(events));
+        self
+    }
+
+    /// Build the image for async callers. The build runs on a thread of its
+    /// own, which its commands block for the whole build, so the runtime's
+    /// threads stay free; the future works with any runtime. Dropping it
+    /// before it's ready cancels the build (through the cancel token, if one
+    /// was given), so `tokio::time::timeout` and `select!` stop a build like
+    /// Ctrl-C does. A panic in the build comes back as an error.
+    ///
+    /// Commands go through the host executor, as executors are per thread.
+    /// If this thread has one that records or replays commands, the future
+    /// is an error instead; use [`build`](Self::build) there.
+    pub fn build_async(mut self) -> BuildFuture {
+        let cancel = self.cancel.get_or_insert_with(CancelToken::new).clone();
+
+        if !executor().concurrent() {
+            return BuildFuture::ready(
+                cancel,
+                Err(Error::InvalidOptions(
+                    "build_async runs commands with the host executor, not this thread's; use build"
+                        .into(),
+                )
+                .into()),
+            );
+        }
+
+        BuildFuture::spawn(cancel, move || self.build())
+    }
+
+    /// Build the image, writing it to the output file
+    pub fn build(mut self) -> Result<BuiltImage> {
+        let _stop = RestoreStopWhen(Some(set_stop_when(StopWhen {
+            cancel: self.cancel.take(),
+            timeout: self.command_timeout,
+        })));
+        let _events = RestoreEvents(Some(set_events(self.events.take())));
+        let _retry = RestoreRetryPolicy(set_retry_policy(self.retry_policy));
+
+        let mut plan = self.plan()?;
+
+        info!(
+            "Creating a bootable image {:?} out of {:?}",
+            plan.output_file, plan.options.image_name,
+        );
+
+        let diagnostics = plan
+            .options
+            .diagnostics
+            .take()
+            .map(Diagnostics::start)
+            .transpose()?;
+
+        let options = &plan.options;
+        let mut steps = Steps::new(match &options.resume {
+            Some(dir) => {
+                let state = BuildState::load(dir)?;
+                state.check(&options.image_name, &options.flavor, options.disk_size)?;
+                state
+            }
+            None => BuildState {
+                image_name: options.image_name.clone(),
+                flavor: options.flavor.clone(),
+                disk_size: options.disk_size,
+                completed: vec![],
+            },
+        });
+
+        let partitioned_disk = plan.partition(&mut steps)?;
+        let mut build = Build::new(plan, steps, partitioned_disk)?;
+
+        build.format()?;
+        build.mount(diagnostics.as_ref())?;
+        build.extract()?;
+        build.packages()?;
+        build.configure()?;
+        let filesystems = build.bootloader()?;
+        build.initramfs()?;
+        build.finalize(filesystems)
+    }
+
+    /// Check the options, filling in the defaults that depend on others, and
+    /// look at the image and the host before anything is touched
+    fn plan(mut self) -> Result<Plan> {
+        let output_file = self.output_file.take().unwrap_or_else(|| {
+            format!("{}.img", hostname_from_image_name(&self.image_name)).into()
+        });
+
+        if matches!(self.network, NetworkMode::Static) && self.address.is_none() {
+            return Err(Error::InvalidOptions("a static network needs an address".into()).into());
+        }
+
+        // The proxy is passed in the environment of the package manager rather
+        // than written to the image, as the VM likely won't sit behind it
+        let mut pkg_env: Vec<(String, String)> = match &self.pkg_proxy {
+            Some(proxy) => ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"]
+                .iter()
+                .map(|x| (x.to_string(), proxy.clone()))
+                .collect(),
+            None => vec![],
+        };
+        pkg_env.extend(noninteractive_env(&self.flavor));
+
+        for file in self
+            .sysctl_file
+            .iter()
+            .chain(self.modprobe_file.iter())
+            .chain(self.ca_cert.iter())
+        {
+            if !file.is_file() {
+                return Err(Error::MissingFile(file.clone()).into());
+            }
+        }
+
+        // A directory, or a tar archive
+        for source in self
+            .data_partitions
+            .iter()
+            .filter_map(|x| x.source.as_ref())
+        {
+            if !source.exists() {
+                return Err(Error::MissingFile(source.clone()).into());
+            }
+        }
+
+        let flavor = self.flavor.clone();
+        let unsupported = |option| Error::UnsupportedFlavor {
+            flavor: flavor.clone(),
+            option,
+        };
+
+        let fstrim_services = match self.fstrim {
+            true => fstab::fstrim_services(&flavor),
+            false => &[],
+        };
+
+        // Before the user's, so they can still disable them
+        self.enable_service = self
+            .guest_tools
+            .services(&flavor)
+            .iter()
+            .map(|x| x.to_string())
+            .chain(fstrim_services.iter().map(|x| x.to_string()))
+            .chain(std::mem::take(&mut self.enable_service))
+            .collect();
+
+        // Before the user's, so they can still take them away
+        self.fstab_options = match self.fstrim {
+            true => fstab::fstrim_options(),
+            false => vec![],
+        }
+        .into_iter()
+        .chain(std::mem::take(&mut self.fstab_options))
+        .collect();
+
+        if self.selinux && matches!(flavor, Flavor::Alpine) {
+            return Err(unsupported("--selinux").into());
+        }
+
+        if self.debug_shell && self.panic.is_some() {
+            return Err(Error::InvalidOptions(
+                "--panic reboots where --debug-shell would leave a shell, pick one".into(),
+            )
+            .into());
+        }
+
+        if !self.mask_service.is_empty() && matches!(flavor, Flavor::Alpine) {
+            return Err(unsupported("--mask-service").into());
+        }
+
+        if matches!(self.firewall, Some(Firewall::Ufw | Firewall::Firewalld))
+            && matches!(flavor, Flavor::Alpine)
+        {
+            return Err(unsupported("--firewall ufw or firewalld").into());
+        }
+
+        if self.initramfs_module_set.is_some() && matches!(flavor, Flavor::Alpine) {
+            return Err(unsupported("--initramfs-module-set").into());
+        }
+
+        if matches!(self.initramfs_module_set, Some(InitramfsModuleSet::Dep))
+            && self.initramfs_modules.is_empty()
+        {
+            warn!("MODULES=dep picks drivers for the build host, add the VM's with --initramfs-modules");
+        }
+
+        if self.kernel_version.len() > 1 && matches!(flavor, Flavor::Alpine) {
+            return Err(unsupported("--kernel-version more than once").into());
+        }
+
+        if self.persistence.is_some() && matches!(flavor, Flavor::Alpine) {
+            return Err(unsupported("--persistence").into());
+        }
+
+        if self.persistence.is_some()
+            && (self.rootless || self.partition_backend == PartitionBackend::Repart)
+        {
+            return Err(Error::InvalidOptions(
+                "--persistence needs the native partitioner and a loop device".into(),
+            )
+            .into());
+        }
+
+        if !self.data_partitions.is_empty()
+            && (self.rootless || self.partition_backend == PartitionBackend::Repart)
+        {
+            return Err(Error::InvalidOptions(
+                "--data-partition needs the native partitioner and a loop device".into(),
+            )
+            .into());
+        }
+
+        if !self.gpt_partitions.is_empty() && self.partition_backend == PartitionBackend::Repart {
+            return Err(Error::InvalidOptions(
+                "--gpt-partition changes the native partitioner's layout".into(),
+            )
+            .into());
+        }
+
+        // Checked now rather than once the build gets to partitioning. Data
+        // partitions are found by the numbers they get.
+        let data_numbers = match self.partition_backend {
+            PartitionBackend::Native => {
+                native_layout(
+                    self.disk_size,
+                    self.esp_mirror,
+                    self.persistence,
+                    &self.data_partitions,
+                    &self.gpt_partitions,
+                )
+                .map_err(|e| Error::InvalidOptions(e.to_string()))?
+                .1
+            }
+            PartitionBackend::Repart => vec![],
+        };
+
+        // Persistence is the read-only root's overlays kept on disk
+        self.read_only_root = self.read_only_root || self.persistence.is_some();
+
+        if !self.allow_port.is_empty() && self.firewall.is_none() {
+            return Err(Error::InvalidOptions("--allow-port needs --firewall".into()).into());
+        }
+
+        // Docker publishes compose ports through its own forward rules,
+        // which a firewall dropping what it doesn't know would block
+        if self.compose.is_some() && self.firewall.is_some() {
+            return Err(Error::InvalidOptions(
+                "--firewall can't be used with --compose, as it would drop the traffic docker forwards to the services' published ports".into(),
+            )
+            .into());
+        }
+
+        // Checked against placeholder entries now rather than once the disk
+        // is built
+        let mut entries = fstab::default_entries(
+            &flavor,
+            "",
+            "",
+            self.esp_mirror.then_some(""),
+            self.read_only_root,
+        );
+        for data in &self.data_partitions {
+            if entries.iter().any(|x| x.mountpoint == data.mountpoint) {
+                return Err(Error::InvalidOptions(format!(
+                    "{} is already mounted, it can't hold a data partition",
+                    data.mountpoint
+                ))
+                .into());
+            }
+            entries.push(data.fstab_entry(uuid::Uuid::nil()));
+        }
+        fstab::apply(&mut entries, &self.fstab_options)?;
+
+        if let Some(size) = &self.workdir_tmpfs {
+            if !valid_tmpfs_size(size) {
+                return Err(Error::InvalidOptions(format!("bad tmpfs size {:?}", size)).into());
+            }
+
+            if self.resume.is_some() {
+                return Err(Error::InvalidOptions(
+                    "a resumed build stays in its working directory, drop --workdir-tmpfs".into(),
+                )
+                .into());
+            }
+        }
+
+        if self.rootless && self.resume.is_some() {
+            return Err(Error::InvalidOptions(
+                "a rootless build's root only goes into the disk at the end, it can't be resumed"
+                    .into(),
+            )
+            .into());
+        }
+
+        if self.rootless && self.partition_backend == PartitionBackend::Repart {
+            return Err(Error::InvalidOptions(
+                "--rootless makes root's filesystem itself, drop --partition-backend repart".into(),
+            )
+            .into());
+        }
+
+        let hostname = self
+            .hostname
+            .take()
+            .unwrap_or_else(|| hostname_from_image_name(&self.image_name));
+
+        if self.consoles.is_empty() {
+            self.consoles = console::defaults();
+        }
+
+        if let Some(ignition) = &self.ignition {
+            if matches!(flavor, Flavor::Alpine) {
+                return Err(unsupported("--ignition").into());
+            }
+
+            if !ignition.exists() {
+                return Err(Error::MissingFile(ignition.clone()).into());
+            }
+
+            check_ignition_config(&std::fs::read_to_string(ignition)?)?;
+        }
+
+        // Only for the preflight space check and the export's progress bar
+        let mut image_size = None;
+        let mut image_id = None;
+
+        // A dry run doesn't need any of this
+        if !self.dry_run {
+            let image = inspect_image(&self.image_name);
+            image_size = image.as_ref().ok().and_then(|x| x.size);
+            image_id = image.as_ref().ok().map(|x| x.id.clone());
+
+            let failed: Vec<Check> = preflight_checks(
+                self.disk_size,
+                image_size,
+                Some(&output_file),
+                self.workdir_tmpfs.is_some(),
+                self.rootless,
+            )
+            .into_iter()
+            .filter(|x| !x.ok)
+            .collect();
+
+            // Without root nothing else matters
+            if failed.iter().any(|x| x.name == "root") {
+                return Err(Error::NotRoot.into());
+            }
+
+            if !failed.is_empty() {
+                return Err(Error::PreflightFailed(
+                    failed
+                        .iter()
+                        .map(|x| format!("{}: {}", x.name, x.detail))
+                        .collect(),
+                )
+                .into());
+            }
+
+            // After the host checks, as docker itself could be what's missing
+            image?.check(&self.image_name)?;
+        }
+
+        let container_config = if self.container_service
+            || self.container_env
+            || self.firewall.is_some()
+        {
+            match app::inspect_config(&self.image_name) {
+                Ok(config) => Some(config),
+
+                // The image may not be pulled yet. Nothing made up stands in
+                // for its config; the plan just goes without what needs it.
+                Err(e) if self.dry_run => {
+                    warn!(
+                            "can't read {}'s config, so the container's service, environment and firewall depend on it: {:#}",
+                            self.image_name, e
+                        );
+                    None
+                }
+
+                Err(e) => return Err(e),
+            }
+        } else {
+            None
+        };
+
+        let compose = self.compose.as_deref().map(Compose::load).transpose()?;
+
+        Ok(Plan {
+            options: self,
+            output_file,
+            hostname,
+            pkg_env,
+            data_numbers,
+            image_size,
+            image_id,
+            container_config,
+            compose,
+        })
+    }
+}
+
+/// A build's options once [`ImageBuilder::plan`] has checked them, and what
+/// it worked out before the disk is made
+struct Plan {
+    options: ImageBuilder,
+    output_file: PathBuf,
+    hostname: String,
+    /// For the package manager: the proxy, and no prompts
+    pkg_env: Vec<(String, String)>,
+    /// The partition numbers of `options.data_partitions`
+    data_numbers: Vec<u32>,
+    image_size: Option<u64>,
+    image_id: Option<String>,
+    container_config: Option<app::ContainerConfig>,
+    compose: Option<Compose>,
+}
+
+impl Plan {
+    /// The partitioned disk: a new one, or the one a resumed build left
+    fn partition(&self, steps: &mut Steps) -> Result<PartitionedLoopbackDisk> {
+        let options = &self.options;
+        let disk_size = options.disk_size;
+
+        if !steps.begin(Step::Partition)? {
+            let dir = options.resume.as_ref().unwrap();
+            info!("Resuming the build in {:?}", dir);
+            return PartitionedLoopbackDisk::open(LoopbackDisk::open(dir)?);
+        }
+
+        info!("Creating {} GB partitioned disk", disk_size);
+        let working_dir = match &options.workdir_tmpfs {
+            Some(size) => {
+                info!("Building in a {} tmpfs", size);
+                WorkingDir::tmpfs(size)?
+            }
+            None => WorkingDir::new()?,
+        };
+
+        match options.partition_backend {
+            PartitionBackend::Native => {
+                let (mut layout, _) = native_layout(
+                    disk_size,
+                    options.esp_mirror,
+                    options.persistence,
+                    &options.data_partitions,
+                    &options.gpt_partitions,
+                )?;
+                if let Some(disk_guid) = options.disk_guid {
+                    layout.set_disk_guid(disk_guid);
+                }
+                let esps = esp_volumes(&layout)?;
+                let partition = |image: &Path| {
+                    layout.write(image)?;
+                    esps.iter().try_for_each(|esp| esp.write(image, None))
+                };
+
+                if options.rootless {
+                    PartitionedLoopbackDisk::unattached_in(working_dir, disk_size, partition)
+                } else {
+                    PartitionedLoopbackDisk::partitioned_by(working_dir, disk_size, partition)
+                }
+            }
+
+            PartitionBackend::Repart => {
+                let definitions = working_dir.path().join("repart.d");
+                repart::write_definitions(&definitions, options.esp_mirror)?;
+                PartitionedLoopbackDisk::partitioned_by(working_dir, disk_size, |image| {
+                    repart::partition(image, &definitions, options.disk_guid)?;
+                    esp_volumes(&Gpt::read(image)?)?
+                        .iter()
+                        .try_for_each(|esp| esp.write(image, None))
+                })
+            }
+        }
+    }
+}
+
+/// What the bootloader step probed on the disk, for the built image
+struct Filesystems {
+    root: Filesystem,
+    esp: Filesystem,
+    esp_mirror: Option<Filesystem>,
+}
+
+/// What the steps of a build share once the disk is partitioned. Fields are
+/// dropped in order, so on failure the package cache is put back, the
+/// configs are captured for the diagnostics, the binds and root are
+/// unmounted, and only then does the loop device go.
+struct Build<'a> {
+    package_cache: Option<PackageCache>,
+    capture_configs: Option<CaptureConfigs<'a>>,
+    /// Undone newest first: the binds, then root
+    mounts: CleanupStack,
+    /// The export only needs the working directory, so it runs while the
+    /// disk is formatted and mounted
+    export: Option<Task<()>>,
+    kept_on_failure: KeptOnFailure,
+    partitioned_disk: PartitionedLoopbackDisk,
+    steps: Steps,
+
+    plan: Plan,
+    esp_partition: Partition,
+    esp_mirror_partition: Option<Partition>,
+    root_partition: Partition,
+    persistence_partition: Option<Partition>,
+    data_partitions: Vec<(DataPartition, Partition)>,
+    export_path: String,
+    /// Chosen up front for a rootless build, whose root is only made at the
+    /// end
+    root_fs_uuid: uuid::Uuid,
+    root_fs_hash_seed: Option<uuid::Uuid>,
+    mount_root_path: String,
+}
+
+impl<'a> Build<'a> {
+    /// Find the partitions on `partitioned_disk`, and start exporting the
+    /// image unless an earlier run already extracted it
+    fn new(
+        mut plan: Plan,
+        mut steps: Steps,
+        mut partitioned_disk: PartitionedLoopbackDisk,
+    ) -> Result<Self> {
+        steps.save_in(partitioned_disk.working_dir().path());
+
+        // Keep the evidence unless the build gets to the end
+        partitioned_disk.keep_working_dir(true);
+        let kept_on_failure = KeptOnFailure {
+            path: partitioned_disk.working_dir().path().to_path_buf(),
+            succeeded: false,
+        };
+
+        info!("Main disk at {}", partitioned_disk.path());
+
+        let esp_partition = partitioned_disk.partition_at("/boot/efi")?;
+        let esp_mirror_partition = partitioned_disk.partition_at(ESP_MIRROR_MOUNTPOINT).ok();
+        let root_partition = partitioned_disk.partition_at("/")?;
+        let persistence_partition = partitioned_disk
+            .gpt()
+            .partitions
+            .iter()
+            .find(|(_, x)| x.name == readonly::PERSISTENCE_LABEL)
+            .map(|(number, _)| partitioned_disk.partition(*number))
+            .transpose()?;
+        let data_partitions: Vec<(DataPartition, Partition)> =
+            std::mem::take(&mut plan.options.data_partitions)
+                .into_iter()
+                .zip(&plan.data_numbers)
+                .map(|(data, number)| Ok((data, partitioned_disk.partition(*number)?)))
+                .collect::<Result<_>>()?;
+
+        let export_path = match &plan.options.rootfs_tar {
+            Some(path) => path.display().to_string(),
+            None => {
+                let mut path = partitioned_disk.working_dir().path().to_path_buf();
+                path.push("export.tar");
+                path.into_os_string().into_string().unwrap()
+            }
+        };
+
+        let export = if steps.done(Step::Extract) || plan.options.rootfs_tar.is_some() {
+            None
+        } else {
+            let image_name = plan.options.image_name.clone();
+            let export_path = export_path.clone();
+            let image_size = plan.image_size;
+
+            Some(spawn_task("docker export", move || {
+                export_image(&image_name, Path::new(&export_path), image_size)
+            })?)
+        };
+
+        let disk_guid = plan.options.disk_guid;
+        let root_fs_uuid = match disk_guid {
+            Some(disk_guid) => derive_guid(disk_guid, "root filesystem"),
+            None => uuid::Uuid::new_v4(),
+        };
+        let root_fs_hash_seed =
+            disk_guid.map(|disk_guid| derive_guid(disk_guid, "root filesystem hash seed"));
+
+        let mount_root_path = {
+            let mut path = partitioned_disk.working_dir().path().to_path_buf();
+            path.push("mnt");
+            path.into_os_string().into_string().unwrap()
+        };
+
+        Ok(Self {
+            package_cache: None,
+            capture_configs: None,
+            mounts: CleanupStack::new(),
+            export,
+            kept_on_failure,
+            partitioned_disk,
+            steps,
+            plan,
+            esp_partition,
+            esp_mirror_partition,
+            root_partition,
+            persistence_partition,
+            data_partitions,
+            export_path,
+            root_fs_uuid,
+            root_fs_hash_seed,
+            mount_root_path,
+        })
+    }
+
+    /// Make root's filesystem and the persistence and data partitions'. The
+    /// ESP was formatted along with the partition table, and systemd-repart
+    /// formats root itself.
+    fn format(&mut self) -> Result<()> {
+        let format = self.steps.begin(Step::Format)?;
+        let options = &self.plan.options;
+        if !format || options.partition_backend != PartitionBackend::Native || options.rootless {
+            return Ok(());
+        }
+
+        info!("Format partitions");
+
+        // The hash seed too, or directories come out different
+        let mut args = vec![];
+        if let Some(hash_seed) = self.root_fs_hash_seed {
+            args.extend([
+                "-U".into(),
+                self.root_fs_uuid.to_string(),
+                "-E".into(),
+                format!("hash_seed={}", hash_seed),
+            ]);
+        }
+        args.push(self.root_partition.device.clone());
+
+        run("mkfs.ext4".into(), &args)?;
+
+        if let Some(partition) = &self.persistence_partition {
+            run(
+                "mkfs.ext4".into(),
+                &[
+                    "-L".into(),
+                    readonly::PERSISTENCE_LABEL.into(),
+                    partition.device.clone(),
+                ],
+            )?;
+        }
+
+        for (data, partition) in &self.data_partitions {
+            let seed = options.disk_guid.map(|disk_guid| {
+                let what = format!("data filesystem {}", data.mountpoint);
+                (
+                    derive_guid(disk_guid, &what),
+                    derive_guid(disk_guid, &format!("{} hash seed", what)),
+                )
+            });
+            datapart::make_filesystem(data.fs, &partition.device, seed)?;
+
+            if let Some(source) = &data.source {
+                info!("Fill {} from {:?}", data.mountpoint, source);
+                let dir = self
+                    .partitioned_disk
+                    .working_dir()
+                    .path()
+                    .join(format!("data{}", partition.number))
+                    .display()
+                    .to_string();
+                std::fs::create_dir_all(&dir)?;
+
+                let mount = Mount::new(
+                    partition.device.clone(),
+                    dir.clone(),
+                    &MountOptions::fstype(data.fs.as_str()),
+                )?;
+                datapart::populate(source, &dir)?;
+                drop(mount);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mount root, where a rootless build has a plain directory instead
+    fn mount(&mut self, diagnostics: Option<&'a Diagnostics>) -> Result<()> {
+        self.steps.begin(Step::Mount)?;
+        info!("Mount partitions");
+
+        let rootless = self.plan.options.rootless;
+        let mount_root_path = &self.mount_root_path;
+
+        if !rootless {
+            unmount_automounts(&self.partitioned_disk.path())?;
+        }
+
+        if rootless {
+            std::fs::create_dir_all(mount_root_path)?;
+        } else {
+            self.mounts.push(Mount::new(
+                self.root_partition.device.clone(),
+                mount_root_path.clone(),
+                &MountOptions::fstype("ext4"),
+            )?);
+        }
+
+        self.capture_configs = Some(CaptureConfigs {
+            diagnostics,
+            root: mount_root_path.clone(),
+        });
+
+        // The ESP's contents are gathered here, and written to the ESP at the
+        // end of the build
+        run(
+            "mkdir".into(),
+            &[
+                "-p".into(),
+                format!("{}/boot/efi/EFI/BOOT/", mount_root_path),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Unpack the exported image into root
+    fn extract(&mut self) -> Result<()> {
+        if !self.steps.begin(Step::Extract)? {
+            return Ok(());
+        }
+
+        info!("Copy docker image contents to directory");
+
+        self.export.take().map(Task::join).transpose()?;
+
+        unpack_tar(&self.export_path, &self.mount_root_path)?;
+        if let Ok(metadata) = std::fs::metadata(&self.export_path) {
+            self.steps.add_bytes(metadata.len());
+        }
+
+        info!("remove container artifacts");
+        run(
+            "rm".into(),
+            &["-f".into(), format!("{}/.dockerenv", self.mount_root_path)],
+        )?;
+
+        Ok(())
+    }
+
+    /// Set up the chroot, whether or not packages are installed, as the steps
+    /// after need it too, then install what the image needs to boot
+    fn packages(&mut self) -> Result<()> {
+        let packages = self.steps.begin(Step::Packages)?;
+        let options = &self.plan.options;
+        let mount_root_path = &self.mount_root_path;
+
+        std::fs::copy(
+            "/etc/resolv.conf",
+            format!("{}/etc/resolv.conf", mount_root_path),
+        )?;
+
+        for dir in ["/dev", "/proc", "/sys"] {
+            let dest = format!("{}{}", mount_root_path, dir);
+            self.mounts.push(if options.rootless {
+                Mount::rbind(dir.into(), dest)?
+            } else {
+                Mount::bind(dir.into(), dest)?
+            });
+        }
+
+        if let Some(dir) = &options.cache_dir {
+            self.package_cache = Some(PackageCache::mount(dir, mount_root_path, &options.flavor)?);
+        }
+
+        if packages {
+            self.install_packages()?;
+        }
+
+        Ok(())
+    }
+
+    fn install_packages(&self) -> Result<()> {
+        let Plan {
+            options:
+                ImageBuilder {
+                    flavor,
+                    mirror,
+                    minimal,
+                    run_in_chroot,
+                    hook_dir,
+                    pause,
+                    ..
+                },
+            pkg_env,
+            ..
+        } = &self.plan;
+        let mount_root_path = &self.mount_root_path;
+
+        info!("install extra packages in container to support UEFI boot");
+
+        run_hooks(
+            mount_root_path,
+            HookPoint::PostExtract,
+            run_in_chroot,
+            hook_dir,
+        )?;
+        pause_at(mount_root_path, HookPoint::PostExtract, pause)?;
+
+        if let Some(mirror) = mirror {
+            info!("use package mirror {}", mirror);
+            use_mirror(mount_root_path, flavor, mirror)?;
+        }
+
+        if *minimal && !matches!(flavor, Flavor::Alpine) {
+            info!("keep docs, man pages and translations out");
+            let path = format!("{}{}", mount_root_path, minimal::DPKG_CFG);
+            std::fs::create_dir_all(Path::new(&path).parent().unwrap())?;
+            std::fs::write(&path, minimal::dpkg_cfg())?;
+        }
+
+        // Update package repos
+        match flavor {
+            Flavor::Debian | Flavor::Ubuntu => {
+                retry("apt update", is_transient, || {
+                    run_with_env(
+                        "chroot".into(),
+                        &[
+                            mount_root_path.clone(),
+                            "apt".into(),
+                            "update".into(),
+                            "-y".into(),
+                        ],
+                        pkg_env,
+                    )
+                })?;
+            }
+
+            Flavor::Alpine => {
+                retry("apk update", is_transient, || {
+                    run_with_env(
+                        "chroot".into(),
+                        &[mount_root_path.clone(), "apk".into(), "update".into()],
+                        pkg_env,
+                    )
+                })?;
+            }
+        }
+
+        // Install necessary installer packages for EFI
+        match flavor {
+            Flavor::Debian | Flavor::Ubuntu => self.install_apt_packages()?,
+            Flavor::Alpine => self.install_alpine_packages()?,
+        }
+
+        run_hooks(
+            mount_root_path,
+            HookPoint::PostPackages,
+            run_in_chroot,
+            hook_dir,
+        )?;
+        pause_at(mount_root_path, HookPoint::PostPackages, pause)?;
+
+        Ok(())
+    }
+
+    /// The kernel, grub and the rest on Debian or Ubuntu, then the extra
+    /// packages
+    fn install_apt_packages(&self) -> Result<()> {
+        let Plan {
+            options:
+                ImageBuilder {
+                    flavor,
+                    kernel_version,
+                    minimal,
+                    chrony_phc,
+                    selinux,
+                    ca_cert,
+                    firewall,
+                    guest_tools,
+                    initramfs_compression,
+                    partition_backend,
+                    extra_packages,
+                    ..
+                },
+            pkg_env,
+            compose,
+            ..
+        } = &self.plan;
+        let mount_root_path = &self.mount_root_path;
+
+        let kernel_pkgs: Vec<String> = match flavor {
+            _ if !kernel_version.is_empty() => kernel_version
+                .iter()
+                .map(|x| format!("linux-image-{}", x))
+                .collect(),
+            Flavor::Debian => vec!["linux-image-amd64".into()],
+            Flavor::Ubuntu => vec!["linux-image-generic".into()],
+            // Alpine's kernel is linux-lts, installed with apk
+            Flavor::Alpine => {
+                return Err(Error::UnsupportedFlavor {
+                    flavor: flavor.clone(),
+                    option: "linux-image packages",
+                }
+                .into())
+            }
+        };
+
+        let mut args = vec![
+            mount_root_path.clone(),
+            "apt".into(),
+            "install".into(),
+            "-y".into(),
+            "-o".into(),
+            DPKG_FORCE_CONFNEW.into(),
+        ];
+        if *minimal {
+            args.push("--no-install-recommends".into());
+        }
+        args.extend(kernel_pkgs.iter().cloned());
+        args.extend([
+            "systemd-sysv".into(),
+            "grub2-common".into(),
+            "grub-efi-amd64-bin".into(),
+            "initramfs-tools".into(),
+        ]);
+
+        // Packages that read the network config written below
+        match flavor {
+            Flavor::Debian => {
+                args.push("ifupdown".into());
+                args.push("isc-dhcp-client".into());
+            }
+            Flavor::Ubuntu => {
+                args.push("netplan.io".into());
+            }
+            _ => {}
+        }
+
+        if *chrony_phc {
+            args.push("chrony".into());
+        }
+
+        if *selinux {
+            args.push("selinux-basics".into());
+            args.push("selinux-policy-default".into());
+            args.push("auditd".into());
+        }
+
+        if !ca_cert.is_empty() {
+            args.push("ca-certificates".into());
+        }
+
+        if let Some(firewall) = firewall {
+            args.push(firewall.package().into());
+        }
+
+        if compose.is_some() {
+            args.extend(compose::packages(flavor).iter().map(|x| x.to_string()));
+        }
+
+        args.extend(guest_tools.packages(flavor).iter().map(|x| x.to_string()));
+
+        if let Some(compression) = initramfs_compression {
+            args.extend(compression.packages().iter().map(|x| x.to_string()));
+        }
+
+        // Part of the systemd package before 253
+        if *partition_backend == PartitionBackend::Repart
+            && run(
+                "chroot".into(),
+                &[
+                    mount_root_path.clone(),
+                    "apt-cache".into(),
+                    "show".into(),
+                    "systemd-repart".into(),
+                ],
+            )
+            .is_ok()
+        {
+            args.push("systemd-repart".into());
+        }
+
+        with_spinner("apt install", || {
+            run_with_env("chroot".into(), &args, pkg_env)
+        })?;
+
+        if !kernel_version.is_empty() {
+            info!("hold the kernels at their versions");
+
+            let mut args = vec![mount_root_path.clone(), "apt-mark".into(), "hold".into()];
+            args.extend(kernel_pkgs);
+            run("chroot".into(), &args)?;
+        }
+
+        // If Debian or Ubuntu, install extra packages - there isn't
+        // separate disk like Alpine.
+        if !extra_packages.is_empty() {
+            info!("install extra packages");
+
+            let mut args = vec![
+                mount_root_path.clone(),
+                "apt".into(),
+                "install".into(),
+                "-y".into(),
+                "-o".into(),
+                DPKG_FORCE_CONFNEW.into(),
+            ];
+            if *minimal {
+                args.push("--no-install-recommends".into());
+            }
+            args.extend_from_slice(&extra_packages[..]);
+
+            with_spinner("apt install", || {
+                run_with_env("chroot".into(), &args, pkg_env)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// The kernel, grub and the rest on Alpine, then setup-alpine
+    fn install_alpine_packages(&self) -> Result<()> {
+        let Plan {
+            options:
+                ImageBuilder {
+                    flavor,
+                    kernel_version,
+                    chrony_phc,
+                    ca_cert,
+                    firewall,
+                    guest_tools,
+                    initramfs_compression,
+                    network,
+                    address,
+                    gateway,
+                    dns,
+                    mirror,
+                    ..
+                },
+            hostname,
+            pkg_env,
+            compose,
+            ..
+        } = &self.plan;
+        let mount_root_path = &self.mount_root_path;
+
+        let mut args = vec![
+            mount_root_path.clone(),
+            "apk".into(),
+            "add".into(),
+            "grub-efi".into(),
+            "mkinitfs".into(),
+            "alpine-conf".into(),
+            // Pinned in /etc/apk/world
+            match kernel_version.first() {
+                Some(version) => format!("linux-lts={}", version),
+                None => "linux-lts".into(),
+            },
+        ];
+
+        if *chrony_phc {
+            args.push("chrony".into());
+        }
+
+        if !ca_cert.is_empty() {
+            args.push("ca-certificates".into());
+        }
+
+        if let Some(firewall) = firewall {
+            args.push(firewall.package().into());
+        }
+
+        if compose.is_some() {
+            args.extend(compose::packages(flavor).iter().map(|x| x.to_string()));
+        }
+
+        args.extend(guest_tools.packages(flavor).iter().map(|x| x.to_string()));
+
+        if let Some(compression) = initramfs_compression {
+            args.extend(compression.packages().iter().map(|x| x.to_string()));
+        }
+
+        with_spinner("apk add", || run_with_env("chroot".into(), &args, pkg_env))?;
+
+        // Populate /answers for setup-alpine
+        let mut answers = File::create(format!("{}/answers", mount_root_path))?;
+
+        writeln!(
+            answers,
+            r##"
+KEYMAPOPTS="us us"
+HOSTNAMEOPTS="-n {hostname}"
+DEVDOPTS="mdev"
+INTERFACESOPTS="{interfaces}"
+DNSOPTS="-d example.com {dns}"
+TIMEZONEOPTS="-z UTC"
+APKREPOSOPTS="-1"
+SSHDOPTS="-c openssh"
+NTPOPTS="-c openntpd"
+DISKOPTS="-m sys /"
+"##,
+            hostname = hostname,
+            interfaces = interfaces_file(network, hostname, address, gateway),
+            dns = if dns.is_empty() {
+                "8.8.8.8".to_string()
+            } else {
+                dns.join(" ")
+            },
+        )?;
+
+        drop(answers);
+
+        // Run setup-alpine
+        run_with_env(
+            "chroot".into(),
+            &[
+                mount_root_path.clone(),
+                "setup-alpine".into(),
+                "-q".into(),
+                "-f".into(),
+                "/answers".into(),
+            ],
+            &[&[("USE_EFI".into(), "1".into())], &pkg_env[..]].concat(),
+        )?;
+
+        // setup-alpine picked its own repositories
+        if let Some(mirror) = mirror {
+            use_mirror(mount_root_path, flavor, mirror)?;
+        }
+
+        run(
+            "chroot".into(),
+            &[mount_root_path.clone(), "rm".into(), "/answers".into()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Write the image's own configuration: hostname, network, services and
+    /// the rest of the options
+    fn configure(&mut self) -> Result<()> {
+        if !self.steps.begin(Step::Configure)? {
+            return Ok(());
+        }
+
+        let Plan {
+            options:
+                ImageBuilder {
+                    image_name,
+                    flavor,
+                    sysctl,
+                    sysctl_file,
+                    blacklist_module,
+                    modprobe_file,
+                    ca_cert,
+                    include_firmware,
+                    ignition,
+                    chrony_phc,
+                    container_env,
+                    container_service,
+                    firewall,
+                    allow_port,
+                    partition_backend,
+                    fstrim,
+                    debug_shell,
+                    ..
+                },
+            hostname,
+            pkg_env,
+            container_config,
+            compose,
+            ..
+        } = &self.plan;
+        let mount_root_path = &self.mount_root_path;
+
+        info!("set hostname to {}", hostname);
+
+        let mut hostname_file = File::create(format!("{}/etc/hostname", mount_root_path))?;
+        writeln!(hostname_file, "{}", hostname)?;
+        drop(hostname_file);
+
+        let mut hosts = File::create(format!("{}/etc/hosts", mount_root_path))?;
+        writeln!(
+            hosts,
+            r##"127.0.0.1	localhost
+127.0.1.1	{hostname}
+
+::1	localhost ip6-localhost ip6-loopback
+ff02::1	ip6-allnodes
+ff02::2	ip6-allrouters"##,
+            hostname = hostname,
+        )?;
+        drop(hosts);
+
+        if !sysctl.is_empty() || !sysctl_file.is_empty() {
+            info!("write sysctl.d");
+            install_snippets(
+                &format!("{}/etc/sysctl.d", mount_root_path),
+                "90-docker-to-uefi.conf",
+                sysctl,
+                sysctl_file,
+            )?;
+        }
+
+        if !blacklist_module.is_empty() || !modprobe_file.is_empty() {
+            info!("write modprobe.d");
+            let blacklist: Vec<String> = blacklist_module
+                .iter()
+                .map(|x| format!("blacklist {}", x))
+                .collect();
+            install_snippets(
+                &format!("{}/etc/modprobe.d", mount_root_path),
+                "90-docker-to-uefi-blacklist.conf",
+                &blacklist,
+                modprobe_file,
+            )?;
+        }
+
+        if !ca_cert.is_empty() {
+            info!("install CA certificates");
+
+            let cert_dir = format!("{}/usr/local/share/ca-certificates", mount_root_path);
+            std::fs::create_dir_all(&cert_dir)?;
+
+            // update-ca-certificates only picks up *.crt
+            for cert in ca_cert {
+                let stem = cert.file_stem().unwrap().to_string_lossy();
+                std::fs::copy(cert, format!("{}/{}.crt", cert_dir, stem))?;
+            }
+
+            run(
+                "chroot".into(),
+                &[mount_root_path.clone(), "update-ca-certificates".into()],
+            )?;
+        }
+
+        self.write_network_config()?;
+
+        if *include_firmware {
+            info!("detect required firmware");
+
+            let missing = missing_firmware(mount_root_path)?;
+
+            debug!("missing firmware files: {:?}", missing);
+
+            if !missing.is_empty() {
+                install_firmware(mount_root_path, flavor, &missing, pkg_env)?;
+            }
+        }
+
+        if let Some(ignition) = ignition {
+            info!("install ignition config");
+            install_ignition(mount_root_path, ignition)?;
+        }
+
+        if *chrony_phc {
+            info!("configure chrony PHC refclock");
+
+            // ptp_kvm exposes the host's clock as /dev/ptp0
+            append_missing_lines(
+                &format!("{}/etc/modules", mount_root_path),
+                &strings(["ptp_kvm"]),
+                true,
+            )?;
+
+            append_missing_lines(
+                &format!("{}/etc/chrony/chrony.conf", mount_root_path),
+                &strings(["refclock PHC /dev/ptp0 poll 2 dpoll -2 offset 0 stratum 2"]),
+                false,
+            )?;
+
+            if matches!(flavor, Flavor::Alpine) {
+                run(
+                    "chroot".into(),
+                    &[
+                        mount_root_path.clone(),
+                        "rc-update".into(),
+                        "add".into(),
+                        "chronyd".into(),
+                        "default".into(),
+                    ],
+                )?;
+            }
+        }
+
+        if let Some(config) = container_config {
+            if *container_env {
+                info!("write the container's environment");
+                app::install_environment(mount_root_path, flavor, config)?;
+            }
+
+            if *container_service {
+                info!("install the container's service");
+                app::install_service(mount_root_path, flavor, image_name, config)?;
+            }
+
+            if let Some(firewall) = firewall {
+                info!("configure the firewall");
+                let mut ports = config.exposed_ports()?;
+                ports.extend_from_slice(allow_port);
+                ports.sort();
+                ports.dedup();
+                firewall::install(mount_root_path, flavor, *firewall, &ports)?;
+            }
+        }
+
+        if *partition_backend == PartitionBackend::Repart && !matches!(flavor, Flavor::Alpine) {
+            info!("grow root on boot with systemd-repart");
+            repart::install_grow_on_boot(mount_root_path)?;
+        }
+
+        if *fstrim && matches!(flavor, Flavor::Alpine) {
+            info!("install the weekly fstrim");
+            fstab::install_fstrim_script(mount_root_path)?;
+        }
+
+        if let Some(compose) = compose {
+            info!("install compose project {}", compose.name);
+            compose::install(mount_root_path, flavor, compose)?;
+        }
+
+        if *debug_shell && !matches!(flavor, Flavor::Alpine) {
+            info!("let the emergency shell in with root locked");
+            install_debug_shell(mount_root_path)?;
+        }
+
+        self.configure_services()
+    }
+
+    fn write_network_config(&self) -> Result<()> {
+        let ImageBuilder {
+            flavor,
+            network,
+            address,
+            gateway,
+            dns,
+            ..
+        } = &self.plan.options;
+        let mount_root_path = &self.mount_root_path;
+
+        info!("write network config");
+
+        match flavor {
+            Flavor::Debian => {
+                let mut interfaces =
+                    File::create(format!("{}/etc/network/interfaces", mount_root_path))?;
+                write!(
+                    interfaces,
+                    "{}",
+                    interfaces_file(network, &self.plan.hostname, address, gateway)
+                )?;
+                drop(interfaces);
+            }
+
+            Flavor::Ubuntu => {
+                run(
+                    "mkdir".into(),
+                    &["-p".into(), format!("{}/etc/netplan/", mount_root_path)],
+                )?;
+
+                let netplan_path = format!("{}/etc/netplan/01-eth0.yaml", mount_root_path);
+                let mut netplan = File::create(&netplan_path)?;
+                write!(netplan, "{}", netplan_file(network, address, gateway, dns))?;
+                drop(netplan);
+
+                // netplan complains about world readable configs
+                std::fs::set_permissions(&netplan_path, std::fs::Permissions::from_mode(0o600))?;
+            }
+
+            // setup-alpine took care of it
+            Flavor::Alpine => {}
+        }
+
+        Ok(())
+    }
+
+    fn configure_services(&self) -> Result<()> {
+        let ImageBuilder {
+            flavor,
+            enable_service,
+            disable_service,
+            mask_service,
+            ..
+        } = &self.plan.options;
+
+        if enable_service.is_empty() && disable_service.is_empty() && mask_service.is_empty() {
+            return Ok(());
+        }
+
+        info!("configure services");
+
+        for (action, services) in [
+            ("enable", enable_service),
+            ("disable", disable_service),
+            ("mask", mask_service),
+        ] {
+            for service in services {
+                let args: Vec<String> = match flavor {
+                    Flavor::Debian | Flavor::Ubuntu => vec![
+                        self.mount_root_path.clone(),
+                        "systemctl".into(),
+                        action.into(),
+                        service.clone(),
+                    ],
+
+                    Flavor::Alpine => vec![
+                        self.mount_root_path.clone(),
+                        "rc-update".into(),
+                        if action == "enable" { "add" } else { "del" }.into(),
+                        service.clone(),
+                        "default".into(),
+                    ],
+                };
+
+                run("chroot".into(), &args)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Probe the filesystems, which the built image reports either way, and
+    /// write fstab and install grub unless an earlier run did
+    fn bootloader(&mut self) -> Result<Filesystems> {
+        let bootloader = self.steps.begin(Step::Bootloader)?;
+        let rootless = self.plan.options.rootless;
+
+        let root = if rootless {
+            Filesystem {
+                fs_type: FsType::Ext4,
+                uuid: FsUuid::Uuid(self.root_fs_uuid),
+            }
+        } else {
+            probe_filesystem(&self.root_partition.device)?
+        };
+        if root.fs_type != FsType::Ext4 {
+            bail!(
+                "{} holds {}, not ext4",
+                self.root_partition.device,
+                root.fs_type
+            );
+        }
+
+        let probe_esp = |partition: &Partition| {
+            if rootless {
+                return esp_filesystem(self.partitioned_disk.gpt(), partition);
+            }
+
+            let fs = probe_filesystem(&partition.device)?;
+            if fs.fs_type != FsType::Vfat {
+                bail!("{} holds {}, not vfat", partition.device, fs.fs_type);
+            }
+            Ok(fs)
+        };
+        let esp = probe_esp(&self.esp_partition)?;
+        let esp_mirror = self
+            .esp_mirror_partition
+            .as_ref()
+            .map(probe_esp)
+            .transpose()?;
+
+        let filesystems = Filesystems {
+            root,
+            esp,
+            esp_mirror,
+        };
+
+        if bootloader {
+            self.write_fstab(&filesystems)?;
+            self.install_grub(&filesystems)?;
+        }
+
+        Ok(filesystems)
+    }
+
+    fn write_fstab(&self, filesystems: &Filesystems) -> Result<()> {
+        let ImageBuilder {
+            flavor,
+            read_only_root,
+            fstab_options,
+            ..
+        } = &self.plan.options;
+        let mount_root_path = &self.mount_root_path;
+
+        info!("write fstab");
+
+        let mut entries = fstab::default_entries(
+            flavor,
+            &filesystems.root.uuid.to_string(),
+            &filesystems.esp.uuid.to_string(),
+            filesystems
+                .esp_mirror
+                .map(|x| x.uuid.to_string())
+                .as_deref(),
+            *read_only_root,
+        );
+        for (data, partition) in &self.data_partitions {
+            entries.push(data.fstab_entry(partition.unique_guid));
+            std::fs::create_dir_all(format!("{}{}", mount_root_path, data.mountpoint))?;
+        }
+        fstab::apply(&mut entries, fstab_options)?;
+
+        if filesystems.esp_mirror.is_some() {
+            std::fs::create_dir_all(format!("{}{}", mount_root_path, ESP_MIRROR_MOUNTPOINT))?;
+        }
+
+        std::fs::write(
+            format!("{}/etc/fstab", mount_root_path),
+            fstab::fstab(&entries),
+        )?;
+
+        run("cat".into(), &[format!("{}/etc/fstab", mount_root_path)])?;
+
+        Ok(())
+    }
+
+    fn install_grub(&self, filesystems: &Filesystems) -> Result<()> {
+        let ImageBuilder {
+            flavor,
+            consoles,
+            rootless,
+            ..
+        } = &self.plan.options;
+        let mount_root_path = &self.mount_root_path;
+        let p3_fs_uuid = format!("UUID={}", filesystems.root.uuid);
+
+        info!("install grub");
+
+        run(
+            "mkdir".into(),
+            &["-p".into(), format!("{}/boot/grub/", mount_root_path)],
+        )?;
+
+        if !rootless {
+            let mut device_map = File::create(format!("{}/boot/grub/device.map", mount_root_path))?;
+            writeln!(device_map, "(hd0) {}", self.partitioned_disk.path())?;
+            drop(device_map);
+        }
+
+        run(
+            "mkdir".into(),
+            &["-p".into(), format!("{}/etc/default/", mount_root_path)],
+        )?;
+
+        let mut grub_file = File::create(format!("{}/etc/default/grub", mount_root_path))?;
+        writeln!(grub_file, "GRUB_DEVICE={}", p3_fs_uuid)?;
+        writeln!(
+            grub_file,
+            "GRUB_TERMINAL=\"{}\"",
+            console::grub_terminals(consoles).join(" ")
+        )?;
+        if let Some(serial) = console::grub_serial_command(consoles) {
+            writeln!(grub_file, "GRUB_SERIAL_COMMAND=\"{}\"", serial)?;
+        }
+
+        let cmdline = self.kernel_cmdline();
+
+        writeln!(
+            grub_file,
+            "GRUB_CMDLINE_LINUX_DEFAULT=\"{}\"",
+            cmdline.join(" ")
+        )?;
+        drop(grub_file);
+
+        if *rootless {
+            rootless::install_grub(
+                mount_root_path,
+                flavor,
+                self.root_fs_uuid,
+                &kernel_versions(mount_root_path)?,
+                &cmdline.join(" "),
+                consoles,
+            )?;
+        } else {
+            run(
+                "grub-install".into(),
+                &[
+                    "--target=x86_64-efi".into(),
+                    format!("--efi-directory={}/boot/efi/", mount_root_path),
+                    format!("--root-directory={}", mount_root_path),
+                    "--no-floppy".into(),
+                    self.partitioned_disk.path(),
+                ],
+            )?;
+            run(
+                "chroot".into(),
+                &[
+                    mount_root_path.clone(),
+                    "grub-mkconfig".into(),
+                    "-o".into(),
+                    "/boot/grub/grub.cfg".into(),
+                ],
+            )?;
+
+            info!("no loop necessary in final image");
+            run(
+                "chroot".into(),
+                &[
+                    mount_root_path.clone(),
+                    "rm".into(),
+                    "/boot/grub/device.map".into(),
+                ],
+            )?;
+        }
+
+        // An empty or wrong grub.cfg otherwise only shows up as a VM that
+        // doesn't boot
+        info!("check grub.cfg");
+        check_grub_cfg(mount_root_path, &filesystems.root.uuid.to_string())
+    }
+
+    /// The kernel command line grub boots with
+    fn kernel_cmdline(&self) -> Vec<String> {
+        let ImageBuilder {
+            flavor,
+            consoles,
+            initramfs_modules,
+            chrony_phc,
+            clocksource,
+            selinux,
+            read_only_root,
+            debug_shell,
+            panic,
+            ..
+        } = &self.plan.options;
+
+        let mut cmdline: Vec<String> = match flavor {
+            Flavor::Debian | Flavor::Ubuntu => vec![
+                "quiet",
+                "splash",
+                "init=/lib/systemd/systemd-bootchart",
+                // the network config is written for eth0
+                "net.ifnames=0",
+            ],
+
+            Flavor::Alpine => vec![
+                "quiet",
+                "splash",
+                "rootfstype=ext4",
+                "modules=sd-mod,usb-storage,nvme,ext4",
+            ],
+        }
+        .into_iter()
+        .map(String::from)
+        .collect();
+        cmdline.extend(console::kernel_args(consoles));
+
+        // Alpine's initramfs only loads the modules named on the command line
+        if matches!(flavor, Flavor::Alpine) && !initramfs_modules.is_empty() {
+            for arg in cmdline.iter_mut() {
+                if arg.starts_with("modules=") {
+                    arg.push(',');
+                    arg.push_str(&initramfs_modules.join(","));
+                }
+            }
+        }
+
+        let clocksource = if *chrony_phc && clocksource.is_none() {
+            Some("kvm-clock".to_string())
+        } else {
+            clocksource.clone()
+        };
+
+        if let Some(clocksource) = clocksource {
+            cmdline.push(format!("clocksource={}", clocksource));
+        }
+
+        if *selinux {
+            cmdline.push("security=selinux".into());
+        }
+
+        if *read_only_root && matches!(flavor, Flavor::Alpine) {
+            cmdline.push("overlaytmpfs".into());
+        }
+
+        if *debug_shell {
+            // Everything the kernel and init say goes to the console
+            cmdline.retain(|x| x != "quiet" && x != "splash");
+
+            if !matches!(flavor, Flavor::Alpine) {
+                cmdline.push("systemd.debug-shell=1".into());
+            }
+        }
+
+        if let Some(panic) = panic {
+            cmdline.push(format!("panic={}", panic));
+        }
+
+        cmdline
+    }
+
+    /// Make the initramfs with the modules and overlays the image boots with
+    fn initramfs(&mut self) -> Result<()> {
+        if !self.steps.begin(Step::Initramfs)? {
+            return Ok(());
+        }
+
+        let ImageBuilder {
+            flavor,
+            initramfs_modules,
+            initramfs_compression,
+            initramfs_module_set,
+            read_only_root,
+            persistence,
+            kernel_version,
+            consoles,
+            ..
+        } = &self.plan.options;
+        let mount_root_path = &self.mount_root_path;
+
+        match flavor {
+            Flavor::Debian | Flavor::Ubuntu => {
+                if !initramfs_modules.is_empty() {
+                    info!("add initramfs modules");
+
+                    append_missing_lines(
+                        &format!("{}/etc/initramfs-tools/modules", mount_root_path),
+                        initramfs_modules,
+                        true,
+                    )?;
+                }
+
+                if initramfs_compression.is_some() || initramfs_module_set.is_some() {
+                    info!("configure initramfs-tools");
+                    let conf_d = format!("{}/etc/initramfs-tools/conf.d", mount_root_path);
+                    std::fs::create_dir_all(&conf_d)?;
+                    std::fs::write(
+                        format!("{}/docker-to-uefi.conf", conf_d),
+                        initramfs_tools_conf(*initramfs_compression, *initramfs_module_set),
+                    )?;
+                }
+
+                if *read_only_root {
+                    info!("add the read-only root's overlays to the initramfs");
+                    readonly::install_initramfs_script(mount_root_path, persistence.is_some())?;
+                }
+
+                info!("update-initramfs");
+                let mut args = vec![
+                    mount_root_path.clone(),
+                    "update-initramfs".into(),
+                    "-u".into(),
+                ];
+                // Otherwise only the newest is updated
+                if kernel_version.len() > 1 {
+                    args.extend(["-k".into(), "all".into()]);
+                }
+                run("chroot".into(), &args)?;
+            }
+
+            Flavor::Alpine => {
+                // by default, mkinitfs will use the docker host's kernel version
+                info!("get kernel version");
+
+                let mut kernelversion: Vec<String> = kernel_versions(mount_root_path)?;
+
+                debug!("detected kernel versions {:?}", kernelversion);
+                if kernelversion.len() != 1 {
+                    bail!("incorrect number of kernel vers");
+                }
+
+                let kernelversion: String = kernelversion.pop().unwrap();
+
+                if !initramfs_modules.is_empty() {
+                    info!("add initramfs modules");
+                    add_mkinitfs_modules(mount_root_path, &kernelversion, initramfs_modules)?;
+                }
+
+                info!("mkinitfs");
+                let mut args: Vec<String> = vec![
+                    mount_root_path.clone(),
+                    "mkinitfs".into(),
+                    "-c".into(),
+                    "/etc/mkinitfs/mkinitfs.conf".into(),
+                    "-b".into(),
+                    "/".into(),
+                ];
+                if let Some(compression) = initramfs_compression {
+                    args.extend(["-C".into(), compression.name().into()]);
+                }
+                args.push(kernelversion);
+                run("chroot".into(), &args)?;
+            }
+        }
+
+        // Alpine's inittab only has gettys on virtual terminals; systemd
+        // starts them on the kernel's consoles by itself
+        if matches!(flavor, Flavor::Alpine) {
+            let path = format!("{}/etc/inittab", mount_root_path);
+            let inittab = std::fs::read_to_string(&path)?;
+            std::fs::write(&path, console::inittab(&inittab, consoles))?;
+        }
+
+        Ok(())
+    }
+
+    /// Set root's password, clean up, write the ESP, unmount and check
+    /// everything, and copy the image to the output file
+    fn finalize(mut self, filesystems: Filesystems) -> Result<BuiltImage> {
+        self.steps.begin(Step::Finalize)?;
+
+        // Everything is installed, and the image's own cache is about to be
+        // cleaned, so the host's has to be out of the way
+        if let Some(mut package_cache) = self.package_cache.take() {
+            package_cache.cleanup()?;
+        }
+
+        let generated_root_passwd = self.set_root_password()?;
+
+        let ImageBuilder {
+            flavor,
+            disable_ssh_password_auth,
+            minimal,
+            no_clean,
+            run_in_chroot,
+            hook_dir,
+            pause,
+            dns,
+            list_packages,
+            rootless,
+            ..
+        } = &self.plan.options;
+        let mount_root_path = &self.mount_root_path;
+
+        if *disable_ssh_password_auth {
+            info!("disable SSH password authentication");
+            disable_sshd_password_auth(mount_root_path)?;
+        }
+
+        if *minimal {
+            info!("delete docs, man pages and translations");
+            let deleted = minimal::purge(Path::new(mount_root_path))?;
+            debug!("deleted {} files", deleted);
+        }
+
+        if !no_clean {
+            info!("reset per-instance state");
+            clean_instance_state(mount_root_path, flavor)?;
+        } else if *minimal {
+            info!("clear package caches");
+            clean_package_caches(mount_root_path, flavor)?;
+        }
+
+        run_hooks(
+            mount_root_path,
+            HookPoint::PreUmount,
+            run_in_chroot,
+            hook_dir,
+        )?;
+        pause_at(mount_root_path, HookPoint::PreUmount, pause)?;
+
+        // policy-rc.d stops services from starting in the chroot during the
+        // build, but would do the same in the booted image
+        info!("remove container policy files");
+        run(
+            "rm".into(),
+            &[
+                "-f".into(),
+                format!("{}/usr/sbin/policy-rc.d", mount_root_path),
+            ],
+        )?;
+
+        if !matches!(flavor, Flavor::Alpine) {
+            info!("replace build host resolv.conf");
+            restore_resolv_conf(mount_root_path, dns)?;
+        }
+
+        // Nothing written during the build has a security context, so have the
+        // first boot relabel everything. setfiles in the chroot would need an
+        // SELinux enabled build host.
+        if selinux_enabled(mount_root_path)? {
+            info!("schedule SELinux relabel");
+            File::create(format!("{}/.autorelabel", mount_root_path))?;
+        }
+
+        let installed_kernels = kernel_versions(mount_root_path)?;
+
+        let packages = if *list_packages {
+            info!("list installed packages");
+            sbom::installed_packages(mount_root_path, flavor)?
+        } else {
+            vec![]
+        };
+
+        info!("write the ESP");
+        if !rootless {
+            unmount_automounts(&self.esp_partition.device)?;
+            if let Some(partition) = &self.esp_mirror_partition {
+                unmount_automounts(&partition.device)?;
+            }
+        }
+        write_esp(
+            Path::new(&self.partitioned_disk.img_path()),
+            &format!("{}/boot/efi", mount_root_path),
+        )?;
+
+        self.steps.enter_phase("cleanup");
+        info!("Clean up");
+        drop(self.capture_configs.take());
+        self.mounts.unwind()?;
+
+        let rootless = self.plan.options.rootless;
+        if rootless {
+            info!("make the root filesystem");
+            let Some(partition) = self
+                .partitioned_disk
+                .gpt()
+                .partition(self.root_partition.number)
+            else {
+                bail!("no partition {} for root", self.root_partition.number);
+            };
+            with_spinner("mkfs.ext4", || {
+                rootless::make_root_filesystem(
+                    Path::new(&self.partitioned_disk.img_path()),
+                    partition,
+                    &self.mount_root_path,
+                    self.root_fs_uuid,
+                    self.root_fs_hash_seed,
+                )
+            })?;
+        }
+
+        // A bad unmount shouldn't ship. The ESP was written straight into
+        // the image, so it's checked there rather than through its device.
+        // A rootless build's root was never mounted.
+        info!("check filesystems");
+        if !rootless {
+            run(
+                "fsck.ext4".into(),
+                &["-f".into(), "-n".into(), self.root_partition.device.clone()],
+            )
+            .context("the root filesystem has errors")?;
+        }
+        check_esp(Path::new(&self.partitioned_disk.img_path())).context("the ESP has errors")?;
+
+        let built_partition = |partition: &Partition, filesystem: Filesystem| BuiltPartition {
+            number: partition.number,
+            partuuid: partition.unique_guid,
+            mountpoint: partition.mountpoint,
+            filesystem,
+        };
+        let esp = built_partition(&self.esp_partition, filesystems.esp);
+        let esp_mirror = self
+            .esp_mirror_partition
+            .as_ref()
+            .zip(filesystems.esp_mirror)
+            .map(|(partition, fs)| built_partition(partition, fs));
+        let root = built_partition(&self.root_partition, filesystems.root);
+
+        self.steps.enter_phase("output");
+
+        let Plan {
+            options,
+            output_file,
+            image_id,
+            ..
+        } = self.plan;
+        let keep_workdir = options.keep_workdir;
+
+        if options.dry_run {
+            info!("dry run, not writing {:?}", output_file);
+            self.partitioned_disk.keep_working_dir(keep_workdir);
+            self.kept_on_failure.succeeded = true;
+            self.steps.finish_phase(true);
+            return Ok(BuiltImage {
+                path: output_file,
+                image_name: options.image_name,
+                flavor: options.flavor,
+                generated_root_passwd,
+                kernel_versions: installed_kernels,
+                image_id,
+                packages,
+                esp,
+                esp_mirror,
+                root,
+                phases: self.steps.timings.clone(),
+            });
+        }
+
+        info!(
+            "Copy {:?} to {:?}",
+            self.partitioned_disk.img_path(),
+            output_file
+        );
+        let copied = copy_with_progress(
+            "copy image",
+            Path::new(&self.partitioned_disk.img_path()),
+            &output_file,
+        )?;
+        self.steps.add_bytes(copied);
+
+        self.partitioned_disk.keep_working_dir(keep_workdir);
+        self.kept_on_failure.succeeded = true;
+        self.steps.finish_phase(true);
+
+        if keep_workdir {
+            info!(
+                "working directory kept at {:?}",
+                self.partitioned_disk.working_dir().path()
+            );
+        }
+
+        Ok(BuiltImage {
+            path: output_file,
+            image_name: options.image_name,
+            flavor: options.flavor,
+            generated_root_passwd,
+            kernel_versions: installed_kernels,
+            image_id,
+            packages,
+            esp,
+            esp_mirror,
+            root,
+            phases: self.steps.timings.clone(),
+        })
+    }
+
+    /// Lock root, or set its password, returning the password if it was
+    /// generated, as only then does it need to be told to the user
+    fn set_root_password(&self) -> Result<Option<String>> {
+        let ImageBuilder {
+            lock_root,
+            root_passwd,
+            root_passwd_hash,
+            ..
+        } = &self.plan.options;
+        let mount_root_path = &self.mount_root_path;
+
+        if *lock_root {
+            info!("lock root account");
+
+            run(
+                "chroot".into(),
+                &[
+                    mount_root_path.clone(),
+                    "passwd".into(),
+                    "-l".into(),
+                    "root".into(),
+                ],
+            )?;
+
+            return Ok(None);
+        }
+
+        if let Some(root_passwd_hash) = root_passwd_hash {
+            info!("set root password hash");
+
+            run_with_stdin(
+                "chroot".into(),
+                &[mount_root_path.clone(), "chpasswd".into(), "-e".into()],
+                format!("root:{}\n", root_passwd_hash),
+            )?;
+
+            return Ok(None);
+        }
+
+        let mut generated_root_passwd = None;
+        let root_passwd: String = if let Some(v) = root_passwd {
+            v.clone()
+        } else {
+            let v: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(16)
+                .map(char::from)
+                .collect();
+            generated_root_passwd = Some(v.clone());
+            v
+        };
+
+        info!("set root password");
+
+        run_with_stdin(
+            "chroot".into(),
+            &[mount_root_path.clone(), "passwd".into()],
+            format!("{}\n{}\n", root_passwd, root_passwd),
+        )?;
+
+        Ok(generated_root_passwd)
+    }
+}
+
+/// A build running on its own thread, as returned by
+/// [`ImageBuilder::build_async`]
+pub struct BuildFuture {
+    shared: Arc<Mutex<BuildFutureState>>,
+    cancel: CancelToken,
+    done: bool,
+}
+
+#[derive(Default)]
+struct BuildFutureState {
+    result: Option<Result<BuiltImage>>,
+    waker: Option<Waker>,
+}
+
+impl BuildFuture {
+    fn ready(cancel: CancelToken, result: Result<BuiltImage>) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(BuildFutureState {
+                result: Some(result),
+                waker: None,
+            })),
+            cancel,
+            done: false,
+        }
+    }
+
+    /// Run `build` on a new thread, the future ready with what it returns
+    fn spawn(
+        cancel: CancelToken,
+        build: impl FnOnce() -> Result<BuiltImage> + Send + 'static,
+    ) -> Self {
+        let shared = Arc::new(Mutex::new(BuildFutureState::default()));
+
+        let state = shared.clone();
+        let spawned = std::thread::Builder::new()
+            .name("build".into())
+            .spawn(move || {
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(build))
+                    .unwrap_or_else(|panic| {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(|x| x.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        Err(anyhow::anyhow!("the build panicked: {}", message))
+                    });
+
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+
+        match spawned {
+            Ok(_) => Self {
+                shared,
+                cancel,
+                done: false,
+            },
+            Err(e) => Self::ready(cancel, Err(e.into())),
+        }
+    }
+}
+
+impl Future for BuildFuture {
+    type Output = Result<BuiltImage>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.lock().unwrap();
+
+        match state.result.take() {
+            Some(result) => {
+                drop(state);
+                self.done = true;
+                Poll::Ready(result)
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for BuildFuture {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cancel.cancel();
+        }
+    }
+}
+
+/// Says where the working directory was left if the build fails
+struct KeptOnFailure {
+    path: PathBuf,
+    succeeded: bool,
+}
+
+impl Drop for KeptOnFailure {
+    fn drop(&mut self) {
+        if !self.succeeded {
+            warn!(
+                "build failed, working directory kept at {:?}; continue the build with --resume {}",
+                self.path,
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Puts back the previous StopWhen when dropped
+struct RestoreStopWhen(Option<StopWhen>);
+
+impl Drop for RestoreStopWhen {
+    fn drop(&mut self) {
+        set_stop_when(self.0.take().unwrap());
+    }
+}
+
+/// A host directory bind-mounted over the image's package cache, so
+/// packages downloaded by one build are there for the next. Debian and
+/// Ubuntu images delete packages once installed (docker-clean), and apt(8)
+/// doesn't keep them anyway, so both are overridden until this is cleaned
+/// up; Alpine only caches with /etc/apk/cache.
+struct PackageCache {
+    mount: Mount,
+
+    /// Files in the image to remove when done, and ones to move back
+    remove: Vec<String>,
+    restore: Vec<(String, String)>,
+}
+
+/// Keeps what apt(8) downloads, overriding its own default
+const APT_KEEP_PACKAGES: &str = "Binary::apt::APT::Keep-Downloaded-Packages \"true\";\n";
+
+impl PackageCache {
+    fn mount(cache_dir: &Path, root: &str, flavor: &Flavor) -> Result<Self> {
+        let (name, cache) = match flavor {
+            Flavor::Debian => ("debian", "/var/cache/apt/archives"),
+            Flavor::Ubuntu => ("ubuntu", "/var/cache/apt/archives"),
+            Flavor::Alpine => ("alpine", "/var/cache/apk"),
+        };
+
+        let source = cache_dir.join(name).to_string_lossy().to_string();
+        let dest = format!("{}{}", root, cache);
+        run("mkdir".into(), &["-p".into(), source.clone(), dest.clone()])?;
+
+        info!("use package cache {}", source);
+        let mut package_cache = Self {
+            mount: Mount::bind(source, dest)?,
+            remove: vec![],
+            restore: vec![],
+        };
+
+        match flavor {
+            Flavor::Debian | Flavor::Ubuntu => {
+                // apt only reads files without an extension or ending .conf
+                let docker_clean = format!("{}/etc/apt/apt.conf.d/docker-clean", root);
+                if Path::new(&docker_clean).exists() {
+                    let disabled = format!("{}.disabled", docker_clean);
+                    run("mv".into(), &[docker_clean.clone(), disabled.clone()])?;
+                    package_cache.restore.push((disabled, docker_clean));
+                }
+
+                let config_dir = format!("{}/etc/apt/apt.conf.d", root);
+                std::fs::create_dir_all(&config_dir)?;
+                let config = format!("{}/zz-package-cache", config_dir);
+                std::fs::write(&config, APT_KEEP_PACKAGES)?;
+                package_cache.remove.push(config);
+            }
+
+            Flavor::Alpine => {
+                let link = format!("{}/etc/apk/cache", root);
+                if std::fs::symlink_metadata(&link).is_err() {
+                    run("ln".into(), &["-s".into(), cache.into(), link.clone()])?;
+                    package_cache.remove.push(link);
+                }
+            }
+        }
+
+        Ok(package_cache)
+    }
+}
+
+impl Cleanup for PackageCache {
+    fn cleanup(&mut self) -> Result<()> {
+        self.mount.cleanup()?;
+
+        for path in self.remove.drain(..) {
+            run("rm".into(), &["-f".into(), path])?;
+        }
+
+        for (from, to) in self.restore.drain(..) {
+            run("mv".into(), &[from, to])?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PackageCache {
+    fn drop(&mut self) {
+        cleanup_or_warn(self);
+    }
+}
+
+struct RestoreRetryPolicy(RetryPolicy);
+
+impl Drop for RestoreRetryPolicy {
+    fn drop(&mut self) {
+        set_retry_policy(self.0);
+    }
+}
+
+struct RestoreEvents(Option<Option<Arc<dyn ImageBuilderEvents>>>);
+
+impl Drop for RestoreEvents {
+    fn drop(&mut self) {
+        set_events(self.0.take().unwrap());
+    }
+}
+
+/// The steps of a build, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Partition,
+    Format,
+    Mount,
+    Extract,
+    Packages,
+    Configure,
+    Bootloader,
+    Initramfs,
+    Finalize,
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Partition => "partition",
+            Step::Format => "format",
+            Step::Mount => "mount",
+            Step::Extract => "extract",
+            Step::Packages => "packages",
+            Step::Configure => "configure",
+            Step::Bootloader => "bootloader",
+            Step::Initramfs => "initramfs",
+            Step::Finalize => "finalize",
+        }
+    }
+
+    /// Mounts don't outlive the run that made them, and finalize has to run
+    /// again to know what root password it set, so neither is ever skipped
+    fn skippable(&self) -> bool {
+        !matches!(self, Step::Mount | Step::Finalize)
+    }
+}
+
+/// What a build has done so far, saved in its working directory after each
+/// step so a failed build can be resumed
+#[derive(Debug, Serialize, Deserialize)]
+struct BuildState {
+    image_name: String,
+    flavor: Flavor,
+    disk_size: usize,
+    completed: Vec<Step>,
+}
+
+impl BuildState {
+    const FILE: &'static str = "build-state.json";
+
+    fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(Self::FILE);
+        if !path.exists() {
+            bail!("{:?} has no build to resume", dir);
+        }
+
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::write(dir.join(Self::FILE), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Make sure a resumed build is the same build
+    fn check(&self, image_name: &str, flavor: &Flavor, disk_size: usize) -> Result<()> {
+        if self.image_name != image_name || &self.flavor != flavor || self.disk_size != disk_size {
+            bail!(
+                "the build to resume was of {} ({:?}, {} GB), not {} ({:?}, {} GB)",
+                self.image_name,
+                self.flavor,
+                self.disk_size,
+                image_name,
+                flavor,
+                disk_size
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Tracks the running step: enters its span, and checkpoints the build
+/// state as each step completes
+struct Steps {
+    state: BuildState,
+    dir: Option<PathBuf>,
+    running: Option<Step>,
+    phase: Option<(&'static str, Instant, EnteredSpan)>,
+    phase_bytes: Option<u64>,
+    timings: Vec<PhaseTiming>,
+}
+
+impl Steps {
+    fn new(state: BuildState) -> Self {
+        Self {
+            state,
+            dir: None,
+            running: None,
+            phase: None,
+            phase_bytes: None,
+            timings: vec![],
+        }
+    }
+
+    /// Leave the current phase, telling subscribers how it went
+    fn finish_phase(&mut self, succeeded: bool) {
+        if let Some((name, start, _span)) = self.phase.take() {
+            let elapsed = start.elapsed();
+
+            emit(BuildEvent::PhaseFinished {
+                phase: name.into(),
+                elapsed,
+                succeeded,
+            });
+
+            self.timings.push(PhaseTiming {
+                phase: name,
+                elapsed,
+                bytes: self.phase_bytes.take(),
+            });
+        }
+    }
+
+    /// Count `bytes` unpacked or copied towards the current phase
+    fn add_bytes(&mut self, bytes: u64) {
+        *self.phase_bytes.get_or_insert(0) += bytes;
+    }
+
+    /// Checkpoint into `dir` from now on
+    fn save_in(&mut self, dir: &Path) {
+        self.dir = Some(dir.to_path_buf());
+    }
+
+    /// Leave the current phase's span, if any, and enter a new one. The step
+    /// running until now is complete.
+    fn enter_phase(&mut self, name: &'static str) {
+        self.finish_phase(true);
+
+        if let Some(step) = self.running.take() {
+            if !self.state.completed.contains(&step) {
+                self.state.completed.push(step);
+            }
+
+            if let Some(dir) = &self.dir {
+                if let Err(e) = self.state.save(dir) {
+                    warn!("could not save build state: {}", e);
+                }
+            }
+        }
+
+        emit(BuildEvent::PhaseStarted { phase: name.into() });
+        self.phase = Some((
+            name,
+            Instant::now(),
+            info_span!("phase", phase = name).entered(),
+        ));
+    }
+
+    /// Whether an earlier run completed `step`, so it will be skipped
+    fn done(&self, step: Step) -> bool {
+        step.skippable() && self.state.completed.contains(&step)
+    }
+
+    /// Start `step`, returning false if an earlier run already completed it
+    fn begin(&mut self, step: Step) -> Result<bool> {
+        check_cancelled()?;
+        self.enter_phase(step.name());
+
+        if self.done(step) {
+            info!("skip {}, completed by an earlier run", step.name());
+            return Ok(false);
+        }
+
+        self.running = Some(step);
+        Ok(true)
+    }
+}
+
+/// A phase still running when the build returns is one it failed in, unless
+/// the build got to the end and finished it
+impl Drop for Steps {
+    fn drop(&mut self) {
+        self.finish_phase(false);
+    }
+}
+
+/// Everything logged through LogWriter so far, for diagnostics bundles
+static LOG: Mutex<Vec<u8>> = Mutex::new(vec![]);
+
+/// Writes log output to stderr, keeping a copy for diagnostics bundles. Use
+/// it as a tracing subscriber's writer.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        LOG.lock().unwrap().extend_from_slice(buf);
+        std::io::stderr().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Collects a --diagnostics bundle: the log, a transcript of every command
+/// run, and configs generated in the image. The bundle is written when this
+/// is dropped, whether the build succeeded or not.
+struct Diagnostics {
+    bundle: PathBuf,
+    staging: tempfile::TempDir,
+    transcript: Rc<TranscriptExecutor>,
+    previous: Rc<dyn Executor>,
+}
+
+impl Diagnostics {
+    /// Files worth looking at when an image doesn't boot
+    const CONFIGS: &'static [&'static str] = &[
+        "etc/fstab",
+        "etc/default/grub",
+        "boot/grub/grub.cfg",
+        "etc/network/interfaces",
+        "etc/netplan/01-eth0.yaml",
+    ];
+
+    fn start(bundle: PathBuf) -> Result<Self> {
+        let transcript = Rc::new(TranscriptExecutor::new(executor()));
+        let previous = set_executor(transcript.clone());
+
+        Ok(Self {
+            bundle,
+            staging: tempfile::tempdir()?,
+            transcript,
+            previous,
+        })
+    }
+
+    /// Copy whichever of CONFIGS exist under `root`
+    fn capture_configs(&self, root: &str) {
+        for config in Self::CONFIGS {
+            let source = Path::new(root).join(config);
+            if !source.exists() {
+                continue;
+            }
+
+            let dest = self.staging.path().join("image").join(config);
+            let copied = std::fs::create_dir_all(dest.parent().unwrap())
+                .and_then(|_| std::fs::copy(&source, &dest));
+
+            if let Err(e) = copied {
+                warn!("could not copy {:?} for diagnostics: {}", source, e);
+            }
+        }
+    }
+
+    fn write_bundle(&self) -> Result<()> {
+        std::fs::write(self.staging.path().join("build.log"), &*LOG.lock().unwrap())?;
+        std::fs::write(
+            self.staging.path().join("commands.txt"),
+            self.transcript.transcript(),
+        )?;
+
+        run(
+            "tar".into(),
+            &[
+                "-czf".into(),
+                self.bundle.to_string_lossy().to_string(),
+                "-C".into(),
+                self.staging.path().to_string_lossy().to_string(),
+                ".".into(),
+            ],
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Diagnostics {
+    fn drop(&mut self) {
+        set_executor(self.previous.clone());
+
+        match cleanup(|| self.write_bundle()) {
+            Ok(()) => info!("diagnostics written to {:?}", self.bundle),
+            Err(e) => warn!("could not write diagnostics to {:?}: {}", self.bundle, e),
+        }
+    }
+}
+
+/// Copies the generated configs into the diagnostics when dropped, which
+/// happens before the image is unmounted
+struct CaptureConfigs<'a> {
+    diagnostics: Option<&'a Diagnostics>,
+    root: String,
+}
+
+impl Drop for CaptureConfigs<'_> {
+    fn drop(&mut self) {
+        if let Some(diagnostics) = self.diagnostics {
+            diagnostics.capture_configs(&self.root);
+        }
+    }
+}
+
+/// Host tools `create` runs, with the flag that makes each print its version
+pub const REQUIRED_TOOLS: &[(&str, &str)] = &[
+    ("docker", "--version"),
+    ("mkfs.ext4", "-V"),
+    ("tar", "--version"),
+    ("chroot", "--version"),
+    ("grub-install", "--version"),
+];
+
+/// Capabilities a build needs, by number from <linux/capability.h>, and
+/// what for. Root usually has them all, but root in a container may not.
+pub const REQUIRED_CAPABILITIES: &[(u32, &str, &str)] = &[
+    (0, "CAP_CHOWN", "extracted files' owners"),
+    (1, "CAP_DAC_OVERRIDE", "opening loop devices"),
+    (3, "CAP_FOWNER", "extracted files' modes and times"),
+    (18, "CAP_SYS_CHROOT", "chroot"),
+    (21, "CAP_SYS_ADMIN", "loop devices and mounts"),
+    (27, "CAP_MKNOD", "extracted device nodes"),
+    (31, "CAP_SETFCAP", "extracted file capabilities"),
+];
+
+/// The outcome of one preflight check
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// Everything that would make `create` fail partway through, checked up
+/// front. The free space needed includes `image_size`, for the container's
+/// export, and a copy of the disk at `output_file`, when they're known.
+/// With `workdir_tmpfs` nothing but that copy lands on disk. A `rootless`
+/// build needs neither a loop device nor grub-install.
+pub fn preflight_checks(
+    disk_size: usize,
+    image_size: Option<u64>,
+    output_file: Option<&Path>,
+    workdir_tmpfs: bool,
+    rootless: bool,
+) -> Vec<Check> {
+    let mut checks = vec![];
+
+    let uid = run("id".into(), &["-u".into()]).map(|x| output_stdout_string(&x));
+    let missing: Result<Vec<String>> = capabilities().map(|caps| {
+        REQUIRED_CAPABILITIES
+            .iter()
+            .filter(|(number, _, _)| caps & 1 << number == 0)
+            .map(|(_, name, what)| format!("{} ({})", name, what))
+            .collect()
+    });
+    let capable = matches!(&missing, Ok(x) if x.is_empty());
+
+    // Not being root is fine with the capabilities root would have had
+    checks.push(Check {
+        name: "root".into(),
+        ok: matches!(&uid, Ok(uid) if uid == "0") || capable,
+        detail: match uid {
+            Ok(uid) if uid == "0" => "running as root".into(),
+            Ok(uid) if capable => format!("running as uid {} with the needed capabilities", uid),
+            Ok(uid) => format!("running as uid {}, loop devices and mounts need root", uid),
+            Err(e) => format!("could not run id: {}", e),
+        },
+    });
+
+    checks.push(Check {
+        name: "capabilities".into(),
+        ok: capable,
+        detail: match missing {
+            Ok(missing) if missing.is_empty() => "all needed capabilities present".into(),
+            Ok(missing) => format!(
+                "missing {}; in a container, run it with --privileged",
+                missing.join(", ")
+            ),
+            Err(e) => format!("could not read capabilities: {}", e),
+        },
+    });
+
+    for (tool, version_flag) in REQUIRED_TOOLS {
+        if rootless && *tool == "grub-install" {
+            continue;
+        }
+
+        let version = run(tool.to_string(), &[version_flag.to_string()]);
+
+        checks.push(Check {
+            name: tool.to_string(),
+            ok: version.is_ok(),
+            detail: match version {
+                // Some print their version to stderr
+                Ok(output) => [output_stdout_string(&output), output_stderr_string(&output)]
+                    .iter()
+                    .flat_map(|x| x.lines().map(String::from).collect::<Vec<_>>())
+                    .find(|x| !x.trim().is_empty())
+                    .unwrap_or_default(),
+                Err(_) => "not found".into(),
+            },
+        });
+    }
+
+    if !rootless {
+        let loop_device = find_free_loop();
+        checks.push(Check {
+            name: "loop device".into(),
+            ok: matches!(&loop_device, Ok(x) if !x.is_empty()),
+            detail: match loop_device {
+                Ok(x) if !x.is_empty() => format!("{} is free", x),
+                _ => "no free loop device, is the loop module loaded?".into(),
+            },
+        });
+    }
+
+    // The image is built under the temporary directory, next to the
+    // container's export, then copied to the output file
+    let tmp = std::env::temp_dir();
+    let disk_bytes = disk_size as u64 * GIB;
+    let export_bytes = image_size.unwrap_or(0);
+    let output_dir = output_file.and_then(|x| x.parent()).map(|x| {
+        if x.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            x
+        }
+    });
+    let output_in_tmp = !workdir_tmpfs && output_dir.is_some_and(|x| same_filesystem(x, &tmp));
+
+    if !workdir_tmpfs {
+        let mut needed = disk_bytes + export_bytes;
+        if output_in_tmp {
+            needed += disk_bytes;
+        }
+        checks.push(free_space_check("free space", &tmp, needed));
+    }
+
+    if let Some(output_dir) = output_dir.filter(|_| !output_in_tmp) {
+        checks.push(free_space_check("output space", output_dir, disk_bytes));
+    }
+
+    if !workdir_tmpfs {
+        checks.push(sparse_file_check(&tmp, disk_bytes));
+    }
+
+    checks
+}
+
+/// Whether tmpfs(5) takes `size`: a number of bytes with an optional k, m, g
+/// suffix, or a percentage of RAM
+fn valid_tmpfs_size(size: &str) -> bool {
+    let digits = size.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', '%']);
+    size.len() - digits.len() <= 1
+        && !digits.is_empty()
+        && digits.bytes().all(|x| x.is_ascii_digit())
+        && digits.bytes().any(|x| x != b'0')
+}
+
+/// The Docker architecture of the images built, for the x86_64 EFI GRUB
+/// and kernels installed
+const IMAGE_ARCHITECTURE: &str = "amd64";
+
+/// What `docker image inspect` says about the container image
+#[derive(Debug, PartialEq)]
+struct ImageInfo {
+    os: String,
+    architecture: String,
+    id: String,
+
+    /// Only an estimate of the export's size, but close enough to check for
+    /// space and drive a progress bar
+    size: Option<u64>,
+}
+
+impl ImageInfo {
+    fn parse(output: &str) -> Result<Self> {
+        let fields: Vec<&str> = output.split_whitespace().collect();
+        let [os, architecture, size, id] = fields[..] else {
+            bail!("unexpected docker image inspect output {:?}", output);
+        };
+
+        Ok(Self {
+            os: os.into(),
+            architecture: architecture.into(),
+            id: id.into(),
+            size: size.parse().ok(),
+        })
+    }
+
+    /// Whether the image can boot as built: Linux, for x86_64
+    fn check(&self, image_name: &str) -> Result<()> {
+        let reason = if self.os != "linux" {
+            format!("it is a {} image, not linux", self.os)
+        } else if self.architecture != IMAGE_ARCHITECTURE {
+            format!(
+                "it is for {}, not {}",
+                self.architecture, IMAGE_ARCHITECTURE
+            )
+        } else {
+            return Ok(());
+        };
+
+        Err(Error::InvalidImage {
+            image: image_name.into(),
+            reason,
+        }
+        .into())
+    }
+}
+
+/// Inspect the container image, pulling it first if it isn't local, so a
+/// misspelled name fails before any disk is made
+fn inspect_image(image_name: &str) -> Result<ImageInfo> {
+    let inspect = || {
+        run(
+            "docker".into(),
+            &[
+                "image".into(),
+                "inspect".into(),
+                "--format".into(),
+                "{{.Os}} {{.Architecture}} {{.Size}} {{.Id}}".into(),
+                image_name.into(),
+            ],
+        )
+    };
+
+    let output = match inspect() {
+        Ok(output) => output,
+
+        Err(_) => {
+            info!("{} isn't local, pulling it", image_name);
+            if let Err(e) = retry("docker pull", is_transient, || {
+                run("docker".into(), &["pull".into(), image_name.into()])
+            }) {
+                return Err(Error::InvalidImage {
+                    image: image_name.into(),
+                    reason: format!("it isn't local and couldn't be pulled: {:#}", e),
+                }
+                .into());
+            }
+            inspect()?
+        }
+    };
+
+    ImageInfo::parse(&output_stdout_string(&output))
+}
+
+/// `docker export` a container of `image_name` to `path`, what a build
+/// unpacks into root. `image_size` is for the progress bar.
+pub fn export_image(image_name: &str, path: &Path, image_size: Option<u64>) -> Result<()> {
+    // Removed when dropped if anything below fails
+    let container = Container::run(image_name)?;
+
+    with_file_progress("docker export", path, image_size, || {
+        run(
+            "docker".into(),
+            &[
+                "export".into(),
+                "-o".into(),
+                path.display().to_string(),
+                container.name().into(),
+            ],
+        )
+    })?;
+
+    container.remove()
+}
+
+#[test]
+fn test_image_info() -> Result<()> {
+    let info = ImageInfo::parse("linux amd64 123456789 sha256:0123abcd\n")?;
+    assert_eq!(
+        info,
+        ImageInfo {
+            os: "linux".into(),
+            architecture: "amd64".into(),
+            id: "sha256:0123abcd".into(),
+            size: Some(123456789),
+        }
+    );
+    info.check("debian:12")?;
+
+    let error = ImageInfo::parse("linux arm64 1 sha256:0123abcd")?
+        .check("debian:12")
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "can't build from debian:12: it is for arm64, not amd64"
+    );
+
+    assert!(ImageInfo::parse("windows amd64 1 sha256:0123abcd")?
+        .check("x")
+        .is_err());
+    assert!(ImageInfo::parse("").is_err());
+
+    Ok(())
+}
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Whether `dir` has at least `needed` bytes free, from df(1)
+fn free_space_check(name: &str, dir: &Path, needed: u64) -> Check {
+    let dir = dir.to_string_lossy().to_string();
+    let avail = run(
+        "df".into(),
+        &["--output=avail".into(), "-B1".into(), dir.clone()],
+    )
+    .and_then(|x| {
+        Ok(output_stdout_string(&x)
+            .lines()
+            .last()
+            .unwrap_or_default()
+            .trim()
+            .parse::<u64>()?)
+    });
+
+    Check {
+        name: name.into(),
+        ok: matches!(avail, Ok(avail) if avail >= needed),
+        detail: match avail {
+            Ok(avail) => format!(
+                "{} GiB free in {}, {} GiB needed",
+                avail / GIB,
+                dir,
+                needed.div_ceil(GIB)
+            ),
+            Err(e) => format!("could not check free space in {}: {}", dir, e),
+        },
+    }
+}
+
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+/// Whether a file of `len` bytes can be made in `dir` without taking up
+/// the space. The disk image is mostly empty, so the free space checks
+/// above assume it's sparse; a filesystem that can't (or can't hold a file
+/// that big at all, like FAT) would run out partway through.
+fn sparse_file_check(dir: &Path, len: u64) -> Check {
+    use std::os::unix::fs::MetadataExt;
+
+    let sparse = tempfile::NamedTempFile::new_in(dir).and_then(|file| {
+        file.as_file().set_len(len)?;
+        Ok(file.as_file().metadata()?.blocks() * 512 < len)
+    });
+
+    Check {
+        name: "sparse files".into(),
+        ok: matches!(sparse, Ok(true)),
+        detail: match sparse {
+            Ok(true) => format!("{} supports sparse files", dir.display()),
+            Ok(false) => format!(
+                "{} doesn't support sparse files, the disk would take all {} GiB up front",
+                dir.display(),
+                len.div_ceil(GIB)
+            ),
+            Err(e) => format!(
+                "could not make a {} GiB file in {}: {}",
+                len.div_ceil(GIB),
+                dir.display(),
+                e
+            ),
+        },
+    }
+}
+
+/// Return the firmware files referenced by the installed kernel modules that
+/// are not present under /lib/firmware.
+fn missing_firmware(root: &str) -> Result<Vec<String>> {
+    let output = run(
+        "chroot".into(),
+        &[
+            root.into(),
+            "sh".into(),
+            "-c".into(),
+            "find /lib/modules -name '*.ko*' -exec modinfo -F firmware {} +".into(),
+        ],
+    )?;
+
+    let mut missing: Vec<String> = output_stdout_string(&output)
+        .split('\n')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .filter(|x| {
+            // firmware may be installed compressed
+            ["", ".xz", ".zst"]
+                .iter()
+                .all(|ext| !Path::new(&format!("{}/lib/firmware/{}{}", root, x, ext)).exists())
+        })
+        .map(String::from)
+        .collect();
+
+    missing.sort();
+    missing.dedup();
+
+    Ok(missing)
+}
+
+/// Have dpkg take the package's version of a config file the image already
+/// has, rather than ask which to keep
+const DPKG_FORCE_CONFNEW: &str = "Dpkg::Options::=--force-confnew";
+
+/// The environment that stops package installs from waiting on a question
+/// or a pager nobody can see: debconf takes its defaults, and
+/// apt-listchanges shows nothing. apk only asks with --interactive, so
+/// Alpine needs nothing.
+fn noninteractive_env(flavor: &Flavor) -> Vec<(String, String)> {
+    match flavor {
+        Flavor::Debian | Flavor::Ubuntu => vec![
+            ("DEBIAN_FRONTEND".into(), "noninteractive".into()),
+            ("APT_LISTCHANGES_FRONTEND".into(), "none".into()),
+        ],
+
+        Flavor::Alpine => vec![],
+    }
+}
+
+/// Debian's firmware packages by the start of the paths they install under
+/// /lib/firmware. Anything else is in firmware-misc-nonfree.
+const DEBIAN_FIRMWARE: &[(&str, &str)] = &[
+    ("amdgpu/", "firmware-amd-graphics"),
+    ("radeon/", "firmware-amd-graphics"),
+    ("ar3k/", "firmware-atheros"),
+    ("ath3k", "firmware-atheros"),
+    ("ath9k_htc/", "firmware-atheros"),
+    ("ath10k/", "firmware-atheros"),
+    ("ath11k/", "firmware-atheros"),
+    ("bnx2/", "firmware-bnx2"),
+    ("bnx2x/", "firmware-bnx2x"),
+    ("brcm/", "firmware-brcm80211"),
+    ("cypress/", "firmware-brcm80211"),
+    ("intel/ibt-", "firmware-iwlwifi"),
+    ("iwlwifi-", "firmware-iwlwifi"),
+    ("libertas/", "firmware-libertas"),
+    ("mrvl/", "firmware-libertas"),
+    ("qed/", "firmware-qlogic"),
+    ("ql2", "firmware-qlogic"),
+    ("rtl_bt/", "firmware-realtek"),
+    ("rtl_nic/", "firmware-realtek"),
+    ("rtlwifi/", "firmware-realtek"),
+    ("rtw88/", "firmware-realtek"),
+    ("rtw89/", "firmware-realtek"),
+    ("ti-connectivity/", "firmware-ti-connectivity"),
+];
+
+/// The packages with the firmware files `missing`, relative to /lib/firmware
+fn firmware_packages(flavor: &Flavor, missing: &[String]) -> Vec<String> {
+    let mut packages: Vec<String> = missing
+        .iter()
+        .map(|file| match flavor {
+            Flavor::Debian => DEBIAN_FIRMWARE
+                .iter()
+                .find(|(prefix, _)| file.starts_with(prefix))
+                .map_or("firmware-misc-nonfree", |(_, package)| package)
+                .to_string(),
+
+            // All of it, in main
+            Flavor::Ubuntu => "linux-firmware".into(),
+
+            // Split by top level directory, with loose files in -other
+            Flavor::Alpine => match file.split_once('/') {
+                Some((dir, _)) => format!("linux-firmware-{}", dir),
+                None => "linux-firmware-other".into(),
+            },
+        })
+        .collect();
+
+    packages.sort();
+    packages.dedup();
+
+    packages
+}
+
+/// Install the packages with the firmware files `missing`
+fn install_firmware(
+    root: &str,
+    flavor: &Flavor,
+    missing: &[String],
+    pkg_env: &[(String, String)],
+) -> Result<()> {
+    let packages = firmware_packages(flavor, missing);
+    info!("install firmware packages {}", packages.join(" "));
+
+    match flavor {
+        Flavor::Debian => {
+            // The firmware packages live in non-free-firmware from bookworm
+            // on, whose images have deb822 sources, and non-free before
+            let deb822_sources = format!("{}/etc/apt/sources.list.d/debian.sources", root);
+
+            if Path::new(&deb822_sources).exists() {
+                run(
+                    "chroot".into(),
+                    &[
+                        root.into(),
+                        "sed".into(),
+                        "-i".into(),
+                        "-e".into(),
+                        "s/^Components: main$/Components: main non-free-firmware/".into(),
+                        "/etc/apt/sources.list.d/debian.sources".into(),
+                    ],
+                )?;
+            } else {
+                run(
+                    "chroot".into(),
+                    &[
+                        root.into(),
+                        "sed".into(),
+                        "-i".into(),
+                        "-e".into(),
+                        "s/ main$/ main non-free non-free-firmware/".into(),
+                        "/etc/apt/sources.list".into(),
+                    ],
+                )?;
+            }
+
+            retry("apt update", is_transient, || {
+                run_with_env(
+                    "chroot".into(),
+                    &[root.into(), "apt".into(), "update".into(), "-y".into()],
+                    pkg_env,
+                )
+            })?;
+        }
+
+        Flavor::Ubuntu | Flavor::Alpine => {}
+    }
+
+    let mut args: Vec<String> = match flavor {
+        Flavor::Debian | Flavor::Ubuntu => vec![
+            root.into(),
+            "apt".into(),
+            "install".into(),
+            "-y".into(),
+            "-o".into(),
+            DPKG_FORCE_CONFNEW.into(),
+        ],
+
+        Flavor::Alpine => vec![root.into(), "apk".into(), "add".into()],
+    };
+    args.extend(packages);
+
+    run_with_env("chroot".into(), &args, pkg_env)?;
+
+    Ok(())
+}
+
+/// The parts of an Ignition config's `storage` that need its disks and mount
+/// stages, which run from the initramfs before the root filesystem is used
+const IGNITION_DISK_SECTIONS: &[&str] = &["disks", "raid", "filesystems", "luks"];
+
+/// Refuse an Ignition config that's not JSON, or that asks for more than
+/// the files stage run on first boot can do
+fn check_ignition_config(config: &str) -> Result<()> {
+    let config: serde_json::Value = serde_json::from_str(config)
+        .map_err(|e| Error::InvalidOptions(format!("the Ignition config isn't JSON: {}", e)))?;
+
+    let unsupported: Vec<&str> = IGNITION_DISK_SECTIONS
+        .iter()
+        .filter(|x| {
+            config["storage"][**x]
+                .as_array()
+                .is_some_and(|x| !x.is_empty())
+        })
+        .copied()
+        .collect();
+
+    if !unsupported.is_empty() {
+        return Err(Error::InvalidOptions(format!(
+            "the Ignition config has storage.{}, but only its files stage is run, on first boot",
+            unsupported.join(", storage.")
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Embed an Ignition config and a first boot unit that has the image's
+/// ignition fetch it and run the files stage against the running root
+/// filesystem. Debian and Ubuntu don't package Ignition, so the container
+/// image has to bring it.
+fn install_ignition(root: &str, config: &Path) -> Result<()> {
+    if !Path::new(&format!("{}/usr/bin/ignition", root)).exists() {
+        bail!("--ignition needs /usr/bin/ignition in the container image, which has none");
+    }
+
+    run(
+        "mkdir".into(),
+        &["-p".into(), format!("{}/etc/ignition/", root)],
+    )?;
+
+    let config_path = format!("{}/etc/ignition/config.ign", root);
+    std::fs::copy(config, &config_path)?;
+    std::fs::set_permissions(&config_path, std::fs::Permissions::from_mode(0o600))?;
+
+    // The fetch stage caches the config in /run for the files stage
+    let mut unit = File::create(format!(
+        "{}/etc/systemd/system/ignition-firstboot.service",
+        root
+    ))?;
+
+    writeln!(
+        unit,
+        r##"[Unit]
+Description=Apply Ignition config on first boot
+ConditionPathExists=!/var/lib/ignition/done
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+WorkingDirectory=/etc/ignition
+Environment=IGNITION_CONFIG_FILE=/etc/ignition/config.ign
+ExecStart=/usr/bin/ignition -platform file -stage fetch -root /
+ExecStart=/usr/bin/ignition -platform file -stage files -root /
+ExecStartPost=/bin/mkdir -p /var/lib/ignition
+ExecStartPost=/bin/touch /var/lib/ignition/done
+
+[Install]
+WantedBy=multi-user.target"##
+    )?;
+
+    drop(unit);
+
+    run(
+        "chroot".into(),
+        &[
+            root.into(),
+            "systemctl".into(),
+            "enable".into(),
+            "ignition-firstboot.service".into(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Run the --run-in-chroot commands and --hook-dir executables for a hook point
+fn run_hooks(
+    root: &str,
+    point: HookPoint,
+    commands: &[(HookPoint, String)],
+    hook_dir: &Option<PathBuf>,
+) -> Result<()> {
+    for (_, command) in commands.iter().filter(|(x, _)| *x == point) {
+        info!("{} hook: {}", point.name(), command);
+        run(
+            "chroot".into(),
+            &[root.into(), "/bin/sh".into(), "-c".into(), command.clone()],
+        )?;
+    }
+
+    let Some(hook_dir) = hook_dir else {
+        return Ok(());
+    };
+
+    let point_dir = hook_dir.join(point.name());
+    if !point_dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut hooks: Vec<PathBuf> = std::fs::read_dir(&point_dir)?
+        .collect::<Result<Vec<std::fs::DirEntry>, std::io::Error>>()?
+        .into_iter()
+        .map(|x| x.path())
+        .filter(|x| x.is_file())
+        .collect();
+    hooks.sort();
+
+    for hook in hooks {
+        let name = hook.file_name().unwrap().to_string_lossy().to_string();
+        info!("{} hook: {}", point.name(), name);
+
+        let chroot_path = format!("/tmp/hook-{}", name);
+        let host_path = format!("{}{}", root, chroot_path);
+
+        std::fs::copy(&hook, &host_path)?;
+        std::fs::set_permissions(&host_path, std::fs::Permissions::from_mode(0o755))?;
+
+        let result = run("chroot".into(), &[root.into(), chroot_path]);
+        std::fs::remove_file(&host_path)?;
+        result?;
+    }
+
+    Ok(())
+}
+
+fn disable_sshd_password_auth(root: &str) -> Result<()> {
+    let sshd_config = format!("{}/etc/ssh/sshd_config", root);
+
+    if !Path::new(&sshd_config).exists() {
+        bail!("--disable-ssh-password-auth but the image has no /etc/ssh/sshd_config");
+    }
+
+    let settings = "PasswordAuthentication no\nKbdInteractiveAuthentication no\n";
+
+    // The first value sshd sees wins, and the drop-in directory is included
+    // at the top of the config
+    if std::fs::read_to_string(&sshd_config)?
+        .lines()
+        .any(|x| x.trim() == "Include /etc/ssh/sshd_config.d/*.conf")
+    {
+        std::fs::write(
+            format!("{}/etc/ssh/sshd_config.d/50-no-password-auth.conf", root),
+            settings,
+        )?;
+    } else {
+        let contents = std::fs::read_to_string(&sshd_config)?;
+        std::fs::write(&sshd_config, format!("{}{}", settings, contents))?;
+    }
+
+    Ok(())
+}
+
+/// The table the native partitioner writes for a disk of `disk_size` GB,
+/// and the numbers of the data partitions in it
+fn native_layout(
+    disk_size: usize,
+    esp_mirror: bool,
+    persistence: Option<usize>,
+    data_partitions: &[DataPartition],
+    changes: &[PartitionChange],
+) -> Result<(Gpt, Vec<u32>)> {
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    let mut layout = if esp_mirror {
+        Gpt::mirrored_esp_layout(disk_size as u64 * GIB)?
+    } else {
+        Gpt::default_layout(disk_size as u64 * GIB)?
+    };
+
+    if let Some(size) = persistence {
+        // Root is partition 3 in either layout
+        layout.split_end(
+            3,
+            readonly::PERSISTENCE_LABEL,
+            gpt::LINUX_FILESYSTEM,
+            size as u64 * GIB,
+        )?;
+    }
+
+    // Each taken off the end of root in turn, so the first is last on disk
+    let data_numbers = data_partitions
+        .iter()
+        .map(|x| layout.split_end(3, x.name(), gpt::LINUX_FILESYSTEM, x.size as u64 * GIB))
+        .collect::<Result<_>>()?;
+
+    layout.change(changes)?;
+
+    // It's found by its name
+    if persistence.is_some()
+        && !layout
+            .partitions
+            .values()
+            .any(|x| x.name == readonly::PERSISTENCE_LABEL)
+    {
+        bail!("the persistence partition has to keep its name");
+    }
+
+    Ok((layout, data_numbers))
+}
+
+/// The ESP in `gpt`, and its mirror if there is one, each with a volume ID
+/// from its partition's GUID so that it gets the same UUID each time it's
+/// written
+fn esp_volumes(gpt: &Gpt) -> Result<Vec<Fat32>> {
+    let esps: Vec<Fat32> = gpt
+        .partitions
+        .values()
+        .filter(|x| x.type_guid == gpt::EFI_SYSTEM)
+        .map(|esp| {
+            let guid = esp.unique_guid.as_bytes();
+            let volume_id = u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]]);
+
+            Fat32::new(esp.first_lba * SECTOR, esp.size(), volume_id)
+        })
+        .collect();
+
+    if esps.is_empty() {
+        bail!("no ESP in the partition table");
+    }
+
+    Ok(esps)
+}
+
+/// The filesystem `esp_volumes` writes to ESP `partition`, for a build
+/// without device nodes to probe
+fn esp_filesystem(gpt: &Gpt, partition: &Partition) -> Result<Filesystem> {
+    let offset = gpt
+        .partition(partition.number)
+        .map(|x| x.first_lba * SECTOR);
+
+    let Some(esp) = esp_volumes(gpt)?
+        .into_iter()
+        .find(|x| Some(x.offset) == offset)
+    else {
+        bail!("partition {} isn't an ESP", partition.number);
+    };
+
+    Ok(Filesystem {
+        fs_type: FsType::Vfat,
+        uuid: FsUuid::VolumeId(esp.volume_id),
+    })
+}
+
+/// Write what was gathered in `staging` to the ESPs of `image`, then empty
+/// `staging`, as it's where the ESP is mounted in the booted image
+fn write_esp(image: &Path, staging: &str) -> Result<()> {
+    for esp in esp_volumes(&Gpt::read(image)?)? {
+        esp.write(image, Some(Path::new(staging)))?;
+    }
+
+    for entry in std::fs::read_dir(staging)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            std::fs::remove_dir_all(path)?;
+        } else {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_esp(image: &Path) -> Result<()> {
+    esp_volumes(&Gpt::read(image)?)?
+        .iter()
+        .try_for_each(|esp| esp.check(image))
+}
+
+/// Remove the state that would otherwise be shared by every VM booted from
+/// this image: machine-id, SSH host keys, package caches, and logs.
+fn clean_instance_state(root: &str, flavor: &Flavor) -> Result<()> {
+    // an empty machine-id means "first boot" to systemd, which will generate
+    // a new one
+    if Path::new(&format!("{}/etc/machine-id", root)).exists() {
+        run(
+            "chroot".into(),
+            &[
+                root.into(),
+                "truncate".into(),
+                "-s".into(),
+                "0".into(),
+                "/etc/machine-id".into(),
+            ],
+        )?;
+    }
+
+    run(
+        "chroot".into(),
+        &[
+            root.into(),
+            "rm".into(),
+            "-f".into(),
+            "/var/lib/dbus/machine-id".into(),
+        ],
+    )?;
+
+    if Path::new(&format!("{}/etc/ssh", root)).exists() {
+        run(
+            "chroot".into(),
+            &[
+                root.into(),
+                "sh".into(),
+                "-c".into(),
+                "rm -f /etc/ssh/ssh_host_*".into(),
+            ],
+        )?;
+
+        // Alpine's sshd init script generates missing host keys, Debian's
+        // ssh.service refuses to start without them
+        if matches!(flavor, Flavor::Debian | Flavor::Ubuntu) {
+            let mut unit =
+                File::create(format!("{}/etc/systemd/system/ssh-host-keys.service", root))?;
+
+            writeln!(
+                unit,
+                r##"[Unit]
+Description=Generate SSH host keys
+ConditionPathExistsGlob=!/etc/ssh/ssh_host_*_key
+Before=ssh.service
+
+[Service]
+Type=oneshot
+ExecStart=/usr/bin/ssh-keygen -A
+
+[Install]
+WantedBy=multi-user.target"##
+            )?;
+
+            drop(unit);
+
+            run(
+                "chroot".into(),
+                &[
+                    root.into(),
+                    "systemctl".into(),
+                    "enable".into(),
+                    "ssh-host-keys.service".into(),
+                ],
+            )?;
+        }
+    }
+
+    clean_package_caches(root, flavor)?;
+
+    run(
+        "chroot".into(),
+        &[
+            root.into(),
+            "find".into(),
+            "/var/log".into(),
+            "-type".into(),
+            "f".into(),
+            "-exec".into(),
+            "truncate".into(),
+            "-s".into(),
+            "0".into(),
+            "{}".into(),
+            "+".into(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Remove downloaded packages and package lists, the container image's too
+fn clean_package_caches(root: &str, flavor: &Flavor) -> Result<()> {
+    match flavor {
+        Flavor::Debian | Flavor::Ubuntu => {
+            run(
+                "chroot".into(),
+                &[root.into(), "apt".into(), "clean".into()],
+            )?;
+            run(
+                "chroot".into(),
+                &[
+                    root.into(),
+                    "sh".into(),
+                    "-c".into(),
+                    "rm -rf /var/lib/apt/lists/*".into(),
+                ],
+            )?;
+        }
+
+        Flavor::Alpine => {
+            run(
+                "chroot".into(),
+                &[
+                    root.into(),
+                    "sh".into(),
+                    "-c".into(),
+                    "rm -rf /var/cache/apk/*".into(),
+                ],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace the build host's resolv.conf (copied in so the chroot has DNS)
+/// with the static DNS servers, the systemd-resolved stub, or an empty file
+/// for the DHCP client to fill in.
+fn restore_resolv_conf(root: &str, dns: &[String]) -> Result<()> {
+    let resolv_conf = format!("{}/etc/resolv.conf", root);
+    std::fs::remove_file(&resolv_conf)?;
+
+    if !dns.is_empty() {
+        let mut file = File::create(&resolv_conf)?;
+        for server in dns {
+            writeln!(file, "nameserver {}", server)?;
+        }
+        return Ok(());
+    }
+
+    let has_resolved = ["lib", "usr/lib"]
+        .iter()
+        .any(|x| Path::new(&format!("{}/{}/systemd/systemd-resolved", root, x)).exists());
+
+    if has_resolved {
+        std::os::unix::fs::symlink("../run/systemd/resolve/stub-resolv.conf", &resolv_conf)?;
+    } else {
+        File::create(&resolv_conf)?;
+    }
+
+    Ok(())
+}
+
+/// mkinitfs includes modules through "features", each listing module paths
+/// relative to /lib/modules/<version>. Add a custom feature with the paths of
+/// the requested modules.
+fn add_mkinitfs_modules(root: &str, kernelversion: &str, modules: &[String]) -> Result<()> {
+    let module_dir = PathBuf::from(format!("{}/lib/modules/{}", root, kernelversion));
+
+    let mut paths: Vec<String> = vec![];
+    let mut to_visit = vec![module_dir.clone()];
+
+    while let Some(dir) = to_visit.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                to_visit.push(path);
+                continue;
+            }
+
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            let Some((name, _)) = file_name.split_once(".ko") else {
+                continue;
+            };
+
+            if modules
+                .iter()
+                .any(|x| x.replace('-', "_") == name.replace('-', "_"))
+            {
+                paths.push(
+                    path.strip_prefix(&module_dir)?
+                        .to_string_lossy()
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    debug!("found initramfs modules {:?}", paths);
+    if paths.is_empty() {
+        bail!("none of {:?} found for kernel {}", modules, kernelversion);
+    }
+
+    paths.sort();
+
+    let mut feature = File::create(format!("{}/etc/mkinitfs/features.d/custom.modules", root))?;
+    for path in paths {
+        writeln!(feature, "{}", path)?;
+    }
+    drop(feature);
+
+    run(
+        "chroot".into(),
+        &[
+            root.into(),
+            "sed".into(),
+            "-i".into(),
+            "-e".into(),
+            r#"s/^features="\(.*\)"/features="\1 custom"/"#.into(),
+            "/etc/mkinitfs/mkinitfs.conf".into(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Write `lines` to `dir/name` and copy `files` into `dir`, for the *.d style
+/// config directories.
+fn install_snippets(dir: &str, name: &str, lines: &[String], files: &[PathBuf]) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    if !lines.is_empty() {
+        let mut file = File::create(format!("{}/{}", dir, name))?;
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+    }
+
+    for file in files {
+        let file_name = file.file_name().unwrap().to_string_lossy();
+        std::fs::copy(file, format!("{}/{}", dir, file_name))?;
+    }
+
+    Ok(())
+}
+
+/// Append the `lines` that `path` doesn't have yet, so that a resumed build
+/// doesn't add them twice. Creates `path` if `create`.
+fn append_missing_lines(path: &str, lines: &[String], create: bool) -> Result<()> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(e) if create && e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut file = OpenOptions::new().create(create).append(true).open(path)?;
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        writeln!(file)?;
+    }
+    for line in lines {
+        if !existing.lines().any(|x| x.trim() == line) {
+            writeln!(file, "{}", line)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Point the package manager's sources at a mirror
+fn use_mirror(root: &str, flavor: &Flavor, mirror: &str) -> Result<()> {
+    let (default, files) = match flavor {
+        Flavor::Debian => (
+            "http://deb.debian.org/debian",
+            vec![
+                "/etc/apt/sources.list",
+                "/etc/apt/sources.list.d/debian.sources",
+            ],
+        ),
+
+        Flavor::Ubuntu => (
+            "http://archive.ubuntu.com/ubuntu",
+            vec![
+                "/etc/apt/sources.list",
+                "/etc/apt/sources.list.d/ubuntu.sources",
+            ],
+        ),
+
+        Flavor::Alpine => (
+            "https://dl-cdn.alpinelinux.org/alpine",
+            vec!["/etc/apk/repositories"],
+        ),
+    };
+
+    let mirror = mirror.trim_end_matches('/');
+
+    for file in files {
+        if !Path::new(&format!("{}{}", root, file)).exists() {
+            continue;
+        }
+
+        run(
+            "chroot".into(),
+            &[
+                root.into(),
+                "sed".into(),
+                "-i".into(),
+                "-e".into(),
+                format!("s|{}|{}|g", default, mirror),
+                file.into(),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The `linux` lines of grub.cfg's menu entries, submenus' included
+fn grub_linux_lines(cfg: &str) -> Vec<&str> {
+    let mut lines = vec![];
+    // Brace depth of each menuentry being read, innermost last
+    let mut entries: Vec<usize> = vec![];
+    let mut depth = 0;
+
+    for line in cfg.lines().map(str::trim) {
+        if line.starts_with("menuentry ") && line.ends_with('{') {
+            entries.push(depth);
+        }
+
+        let command = line.split_whitespace().next();
+        if !entries.is_empty() && matches!(command, Some("linux" | "linuxefi")) {
+            lines.push(line);
+        }
+
+        depth += line.matches('{').count();
+        depth = depth.saturating_sub(line.matches('}').count());
+
+        while entries.last().is_some_and(|x| *x >= depth) {
+            entries.pop();
+        }
+    }
+
+    lines
+}
+
+/// Fail unless root's grub.cfg has a menu entry that boots a kernel which is
+/// in the image, with root by this filesystem UUID
+fn check_grub_cfg(root: &str, root_uuid: &str) -> Result<()> {
+    let path = format!("{}/boot/grub/grub.cfg", root);
+    let cfg = std::fs::read_to_string(&path)
+        .context("grub.cfg wasn't written, see grub-mkconfig's output")?;
+
+    let lines = grub_linux_lines(&cfg);
+    if lines.is_empty() {
+        bail!(
+            "grub.cfg has no menu entry that boots a kernel, did grub-mkconfig find none in /boot?"
+        );
+    }
+
+    let kernels: Vec<&str> = lines
+        .iter()
+        .filter_map(|x| x.split_whitespace().nth(1))
+        .collect();
+    let installed: Vec<&&str> = lines
+        .iter()
+        .filter(|x| {
+            x.split_whitespace()
+                .nth(1)
+                .is_some_and(|kernel| Path::new(&format!("{}{}", root, kernel)).is_file())
+        })
+        .collect();
+    if installed.is_empty() {
+        bail!(
+            "grub.cfg's menu entries boot {}, none of which is in the image",
+            kernels.join(", ")
+        );
+    }
+
+    let root_arg = format!("root=UUID={}", root_uuid);
+    if !installed
+        .iter()
+        .any(|x| x.split_whitespace().any(|arg| arg == root_arg))
+    {
+        bail!(
+            "no menu entry in grub.cfg mounts root with {}, the root filesystem's UUID",
+            root_arg
+        );
+    }
+
+    Ok(())
+}
+
+/// systemd's emergency and rescue shells ask for root's password, and with
+/// root locked there is none to give. Let them in regardless.
+fn install_debug_shell(root: &str) -> Result<()> {
+    for unit in ["emergency.service", "rescue.service"] {
+        let dir = format!("{}/etc/systemd/system/{}.d", root, unit);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            format!("{}/debug-shell.conf", dir),
+            "[Service]\nEnvironment=SYSTEMD_SULOGIN_FORCE=1\n",
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Whether /etc/selinux/config enables SELinux, either from the container
+/// image or from --selinux
+fn selinux_enabled(root: &str) -> Result<bool> {
+    let config = format!("{}/etc/selinux/config", root);
+
+    if !Path::new(&config).exists() {
+        return Ok(false);
+    }
+
+    Ok(std::fs::read_to_string(config)?
+        .lines()
+        .map(|x| x.trim())
+        .any(|x| x.starts_with("SELINUX=") && x != "SELINUX=disabled"))
+}
+
+/// Drop into an interactive shell in the chroot if --pause asked for it
+fn pause_at(root: &str, point: HookPoint, pause: &[HookPoint]) -> Result<()> {
+    if !pause.contains(&point) {
+        return Ok(());
+    }
+
+    info!(
+        "paused {}, exit the shell to resume the build",
+        point.name()
+    );
+
+    let status = run_interactive("chroot".into(), &[root.into(), "/bin/sh".into()])?;
+
+    info!("shell exited with {}, resuming", status);
+
+    Ok(())
+}
+
+/// ifupdown style config, used by Debian and (through setup-alpine) Alpine
+fn interfaces_file(
+    network: &NetworkMode,
+    hostname: &str,
+    address: &Option<String>,
+    gateway: &Option<String>,
+) -> String {
+    let mut text = String::from("auto lo\niface lo inet loopback\n\nauto eth0\n");
+
+    match network {
+        NetworkMode::Dhcp => {
+            text.push_str("iface eth0 inet dhcp\n");
+            text.push_str(&format!("    hostname {}\n", hostname));
+        }
+
+        NetworkMode::Static => {
+            text.push_str("iface eth0 inet static\n");
+            if let Some(address) = address {
+                text.push_str(&format!("    address {}\n", address));
+            }
+            if let Some(gateway) = gateway {
+                text.push_str(&format!("    gateway {}\n", gateway));
+            }
+        }
+    }
+
+    text
+}
+
+fn netplan_file(
+    network: &NetworkMode,
+    address: &Option<String>,
+    gateway: &Option<String>,
+    dns: &[String],
+) -> String {
+    let mut text =
+        String::from("network:\n  version: 2\n  renderer: networkd\n  ethernets:\n    eth0:\n");
+
+    match network {
+        NetworkMode::Dhcp => {
+            text.push_str("      dhcp4: true\n");
+        }
+
+        NetworkMode::Static => {
+            if let Some(address) = address {
+                text.push_str(&format!("      addresses: [{}]\n", address));
+            }
+            if let Some(gateway) = gateway {
+                text.push_str("      routes:\n");
+                text.push_str(&format!(
+                    "        - to: default\n          via: {}\n",
+                    gateway
+                ));
+            }
+            if !dns.is_empty() {
+                text.push_str(&format!(
+                    "      nameservers:\n        addresses: [{}]\n",
+                    dns.join(", ")
+                ));
+            }
+        }
+    }
+
+    text
+}
+
+#[test]
+fn test_diagnostics_bundle() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path().join("root");
+    std::fs::create_dir_all(root.join("etc"))?;
+    std::fs::write(root.join("etc/fstab"), "UUID=0 / ext4 defaults 0 1\n")?;
+
+    let recorder = Rc::new(RecordingExecutor::new(|_, _| Ok("done".into())));
+    let previous = set_executor(recorder.clone());
+
+    let diagnostics = Diagnostics::start(dir.path().join("diagnostics.tar.gz"))?;
+    run("sgdisk".into(), &["--version".into()])?;
+    diagnostics.capture_configs(root.to_str().unwrap());
+
+    let staging = diagnostics.staging.path().to_path_buf();
+    let transcript = diagnostics.transcript.transcript();
+    assert!(transcript.starts_with("$ sgdisk --version\nexit status: 0 after "));
+    assert!(transcript.contains("\n--- stdout\ndone\n"));
+    assert!(staging.join("image/etc/fstab").exists());
+    assert!(!staging.join("image/etc/default/grub").exists());
+
+    drop(diagnostics);
+    let restored = Rc::ptr_eq(&executor(), &(recorder.clone() as Rc<dyn Executor>));
+    set_executor(previous);
+    assert!(restored);
+
+    let tar = recorder.commands().last().unwrap().to_string();
+    assert_eq!(
+        tar,
+        format!(
+            "tar -czf {}/diagnostics.tar.gz -C {} .",
+            dir.path().display(),
+            staging.display()
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_preflight_reports_every_problem() {
+    let host = |exe: &str, _args: &[String]| -> Result<String> {
+        match exe {
+            "id" => Ok("1000".into()),
+            "grep" => Ok("CapEff:\t0000000000000000".into()),
+            "df" => Ok("   Avail\n1073741824".into()),
+            "tar" | "grub-install" => bail!("not found"),
+            _ => Ok(format!("{} 1.0", exe)),
+        }
+    };
+
+    let previous = set_executor(Rc::new(RecordingExecutor::new(host)));
+    let checks = preflight_checks(8, None, None, false, false);
+    set_executor(previous);
+
+    let failed: Vec<&str> = checks
+        .iter()
+        .filter(|x| !x.ok)
+        .map(|x| x.name.as_str())
+        .collect();
+
+    assert_eq!(
+        failed,
+        ["root", "capabilities", "tar", "grub-install", "free space"]
+    );
+
+    let docker = checks.iter().find(|x| x.name == "docker").unwrap();
+    assert_eq!(docker.detail, "docker 1.0");
+
+    // A rootless build doesn't look for either
+    let previous = set_executor(Rc::new(RecordingExecutor::new(host)));
+    let checks = preflight_checks(8, None, None, false, true);
+    set_executor(previous);
+    assert!(!checks
+        .iter()
+        .any(|x| x.name == "grub-install" || x.name == "loop device"));
+}
+
+#[test]
+fn test_preflight_counts_export_and_copy() {
+    // 1 GiB disk, 1 GiB export, and a copy of the disk on the same filesystem
+    let output = std::env::temp_dir().join("output.img");
+
+    for (avail, ok) in [(3 * GIB, true), (3 * GIB - 1, false)] {
+        let host = move |exe: &str, _args: &[String]| -> Result<String> {
+            match exe {
+                "df" => Ok(format!("   Avail\n{}", avail)),
+                _ => Ok(String::new()),
+            }
+        };
+
+        let previous = set_executor(Rc::new(RecordingExecutor::new(host)));
+        let checks = preflight_checks(1, Some(GIB), Some(&output), false, false);
+        set_executor(previous);
+
+        let space = checks.iter().find(|x| x.name == "free space").unwrap();
+        assert_eq!(space.ok, ok);
+        assert!(space.detail.ends_with("3 GiB needed"));
+        assert!(!checks.iter().any(|x| x.name == "output space"));
+    }
+}
+
+#[test]
+fn test_preflight_with_workdir_tmpfs() {
+    // Only the copy of the disk needs room, wherever the output goes
+    let output = std::env::temp_dir().join("output.img");
+
+    for (avail, ok) in [(GIB, true), (GIB - 1, false)] {
+        let host = move |exe: &str, _args: &[String]| -> Result<String> {
+            match exe {
+                "df" => Ok(format!("   Avail\n{}", avail)),
+                _ => Ok(String::new()),
+            }
+        };
+
+        let previous = set_executor(Rc::new(RecordingExecutor::new(host)));
+        let checks = preflight_checks(1, Some(GIB), Some(&output), true, false);
+        set_executor(previous);
+
+        let space = checks.iter().find(|x| x.name == "output space").unwrap();
+        assert_eq!(space.ok, ok);
+        assert!(!checks.iter().any(|x| x.name == "free space"));
+        assert!(!checks.iter().any(|x| x.name == "sparse files"));
+    }
+}
+
+#[test]
+fn test_valid_tmpfs_size() {
+    for size in ["16G", "512m", "1048576", "50%"] {
+        assert!(valid_tmpfs_size(size), "{}", size);
+    }
+
+    for size in ["", "G", "0", "16GB", "1.5G", "-1G", "16 G"] {
+        assert!(!valid_tmpfs_size(size), "{}", size);
+    }
+}
+
+#[test]
+fn test_preflight_root_without_capabilities() {
+    // Root in an unprivileged container: everything but CAP_SYS_ADMIN
+    let host = |exe: &str, _args: &[String]| -> Result<String> {
+        match exe {
+            "id" => Ok("0".into()),
+            "grep" => Ok(format!("CapEff:\t{:016x}", 0x1ff_ffff_ffffu64 & !(1 << 21))),
+            "df" => Ok("   Avail\n1099511627776".into()),
+            _ => Ok(format!("{} 1.0", exe)),
+        }
+    };
+
+    let previous = set_executor(Rc::new(RecordingExecutor::new(host)));
+    let checks = preflight_checks(8, None, None, false, false);
+    set_executor(previous);
+
+    let failed: Vec<&Check> = checks.iter().filter(|x| !x.ok).collect();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].name, "capabilities");
+    assert_eq!(
+        failed[0].detail,
+        "missing CAP_SYS_ADMIN (loop devices and mounts); in a container, run it with --privileged"
+    );
+}
+
+#[test]
+fn test_build_async() {
+    let mut future = std::pin::pin!(ImageBuilder::new("tester")
+        .network(NetworkMode::Static)
+        .build_async());
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let result = loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(result) => break result,
+            Poll::Pending => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    let error = result.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<Error>(),
+        Some(Error::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn test_build_async_panic() {
+    let mut future = std::pin::pin!(BuildFuture::spawn(CancelToken::new(), || {
+        panic!("out of loop devices")
+    }));
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let result = loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(result) => break result,
+            Poll::Pending => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "the build panicked: out of loop devices"
+    );
+}
+
+#[test]
+fn test_build_async_other_executor() {
+    let executor = Rc::new(RecordingExecutor::new(|_, _| Ok(String::new())));
+    let previous = set_executor(executor.clone());
+
+    let mut future = std::pin::pin!(ImageBuilder::new("tester").build_async());
+    let result = future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()));
+
+    set_executor(previous);
+
+    let Poll::Ready(Err(error)) = result else {
+        panic!("expected the build to be refused");
+    };
+    assert!(matches!(
+        error.downcast_ref::<Error>(),
+        Some(Error::InvalidOptions(_))
+    ));
+    assert!(executor.commands().is_empty());
+}
+
+#[test]
+fn test_debug_redacts_root_password() {
+    let debug = format!(
+        "{:?}",
+        ImageBuilder::new("tester")
+            .root_passwd("hunter2")
+            .root_passwd_hash("$6$salt$hash")
+    );
+
+    assert!(debug.contains("root_passwd: Some(\"<redacted>\")"));
+    assert!(!debug.contains("hunter2"));
+    assert!(!debug.contains("$6$salt$hash"));
+}
+
+#[test]
+fn test_firewall_with_compose() {
+    let error = ImageBuilder::new("tester")
+        .firewall(Firewall::Nftables)
+        .compose("compose.yml")
+        .build()
+        .unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<Error>(),
+        Some(Error::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn test_initramfs_tools_conf() {
+    assert_eq!(initramfs_tools_conf(None, None), "");
+    assert_eq!(
+        initramfs_tools_conf(
+            Some(InitramfsCompression::Zstd),
+            Some(InitramfsModuleSet::Dep)
+        ),
+        "MODULES=dep\nCOMPRESS=zstd\n"
+    );
+    assert_eq!(
+        initramfs_tools_conf(Some(InitramfsCompression::Lz4), None),
+        "COMPRESS=lz4\n"
+    );
+}
+
+#[test]
+fn test_append_missing_lines() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let modules = root.path().join("modules").display().to_string();
+    let chrony_conf = root.path().join("chrony.conf").display().to_string();
+
+    assert!(
+        append_missing_lines(&chrony_conf, &strings(["refclock PHC /dev/ptp0"]), false).is_err()
+    );
+
+    std::fs::write(&modules, "# comment\nloop")?;
+    for _ in 0..2 {
+        append_missing_lines(&modules, &strings(["ptp_kvm", "loop"]), true)?;
+    }
+    assert_eq!(
+        std::fs::read_to_string(&modules)?,
+        "# comment\nloop\nptp_kvm\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_install_debug_shell() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let root_path = root.path().display().to_string();
+
+    install_debug_shell(&root_path)?;
+
+    for unit in ["emergency.service", "rescue.service"] {
+        assert_eq!(
+            std::fs::read_to_string(format!(
+                "{}/etc/systemd/system/{}.d/debug-shell.conf",
+                root_path, unit
+            ))?,
+            "[Service]\nEnvironment=SYSTEMD_SULOGIN_FORCE=1\n"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_grub_cfg() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let root_path = root.path().display().to_string();
+    let uuid = "9f2e0c4a-0000-4000-8000-000000000001";
+
+    std::fs::create_dir_all(format!("{}/boot/grub", root_path))?;
+    File::create(format!("{}/boot/vmlinuz-6.1.0-18-amd64", root_path))?;
+
+    let write = |cfg: &str| std::fs::write(format!("{}/boot/grub/grub.cfg", root_path), cfg);
+
+    write(&format!(
+        "set default=0\n\
+         menuentry 'Debian GNU/Linux' --class debian {{\n\
+         \tinsmod ext2\n\
+         \tif [ x$feature_platform_search_hint = xy ]; then\n\
+         \t  search --no-floppy --fs-uuid --set=root {uuid}\n\
+         \tfi\n\
+         \tlinux\t/boot/vmlinuz-6.1.0-18-amd64 root=UUID={uuid} ro quiet\n\
+         \tinitrd\t/boot/initrd.img-6.1.0-18-amd64\n\
+         }}\n\
+         submenu 'Advanced options' {{\n\
+         \tmenuentry 'recovery mode' {{\n\
+         \t\tlinux /boot/vmlinuz-6.1.0-18-amd64 root=UUID={uuid} ro single\n\
+         \t}}\n\
+         }}\n\
+         linux /boot/not-an-entry\n"
+    ))?;
+    assert_eq!(
+        grub_linux_lines(&std::fs::read_to_string(format!(
+            "{}/boot/grub/grub.cfg",
+            root_path
+        ))?)
+        .len(),
+        2
+    );
+    check_grub_cfg(&root_path, uuid)?;
+
+    // Wrong root
+    assert!(check_grub_cfg(&root_path, "00000000-0000-0000-0000-000000000000").is_err());
+
+    // A kernel that isn't there
+    write(&format!(
+        "menuentry 'Linux' {{\n\tlinux /boot/vmlinuz-5.10.0-28-amd64 root=UUID={uuid} ro\n}}\n"
+    ))?;
+    assert!(check_grub_cfg(&root_path, uuid).is_err());
+
+    // No menu entries at all
+    write("set default=0\n")?;
+    assert!(check_grub_cfg(&root_path, uuid).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_install_firmware() -> Result<()> {
+    use std::rc::Rc;
+
+    let root = tempfile::tempdir()?;
+    let root_path = root.path().display().to_string();
+    std::fs::create_dir_all(format!("{}/lib/firmware/rtl_nic", root_path))?;
+    File::create(format!(
+        "{}/lib/firmware/rtl_nic/rtl8168h-2.fw.zst",
+        root_path
+    ))?;
+
+    let executor = Rc::new(RecordingExecutor::new(|_, args| {
+        Ok(if args[3].contains("modinfo") {
+            "i915/tgl_dmc.bin\nrtl_nic/rtl8168h-2.fw\nrtl_nic/rtl8125a-3.fw\n\
+             iwlwifi-9000-pu-b0-jf-b0-46.ucode\ni915/tgl_dmc.bin\n"
+                .into()
+        } else {
+            String::new()
+        })
+    }));
+    let previous = set_executor(executor.clone());
+
+    let result = missing_firmware(&root_path).and_then(|missing| {
+        install_firmware(&root_path, &Flavor::Debian, &missing, &[])?;
+        Ok(missing)
+    });
+
+    set_executor(previous);
+    let missing = result?;
+
+    assert_eq!(
+        missing,
+        [
+            "i915/tgl_dmc.bin",
+            "iwlwifi-9000-pu-b0-jf-b0-46.ucode",
+            "rtl_nic/rtl8125a-3.fw",
+        ]
+    );
+
+    let commands: Vec<String> = executor.commands().iter().map(|x| x.to_string()).collect();
+    assert_eq!(
+        commands[1..],
+        [
+            format!(
+                "chroot {} sed -i -e 's/ main$/ main non-free non-free-firmware/' /etc/apt/sources.list",
+                root_path
+            ),
+            format!("chroot {} apt update -y", root_path),
+            format!(
+                "chroot {} apt install -y -o Dpkg::Options::=--force-confnew firmware-iwlwifi firmware-misc-nonfree firmware-realtek",
+                root_path
+            ),
+        ]
+    );
+
+    assert_eq!(
+        firmware_packages(&Flavor::Alpine, &missing),
+        [
+            "linux-firmware-i915",
+            "linux-firmware-other",
+            "linux-firmware-rtl_nic"
+        ]
+    );
+    assert_eq!(
+        firmware_packages(&Flavor::Ubuntu, &missing),
+        ["linux-firmware"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_check_ignition_config() {
+    assert!(check_ignition_config(
+        r#"{"ignition": {"version": "3.4.0"},
+            "storage": {"files": [{"path": "/etc/motd", "contents": {"source": "data:,hi"}}]},
+            "passwd": {"users": [{"name": "core"}]}}"#
+    )
+    .is_ok());
+    assert!(check_ignition_config(r#"{"storage": {"disks": []}}"#).is_ok());
+
+    let e = check_ignition_config(
+        r#"{"storage": {"disks": [{"device": "/dev/vdb"}],
+                        "filesystems": [{"device": "/dev/vdb1", "format": "ext4"}]}}"#,
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("storage.disks, storage.filesystems"));
+
+    assert!(check_ignition_config("variant: fcos").is_err());
+}
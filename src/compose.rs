@@ -0,0 +1,463 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! A docker compose project as part of the image: a container runtime and
+//! compose installed, the project's images saved onto the disk to be loaded
+//! on first boot, and a service per compose service, started in the order
+//! their depends_on says. The compose file is read by `docker compose
+//! config` on the host, which resolves variables, extends and the rest of
+//! the format into JSON that compose reads back in the image.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::builder::Flavor;
+use crate::*;
+
+/// Where a project's compose file and saved images go in the image
+pub const COMPOSE_DIR: &str = "/var/lib/compose";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Compose {
+    pub name: String,
+    pub services: BTreeMap<String, ComposeService>,
+
+    /// The whole normalized config, written to the image
+    #[serde(skip)]
+    pub json: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ComposeService {
+    pub image: Option<String>,
+    /// Normalized to the long form, service name to its condition
+    pub depends_on: BTreeMap<String, serde_json::Value>,
+}
+
+impl Compose {
+    pub fn parse(json: &str) -> Result<Self> {
+        let mut compose: Compose = serde_json::from_str(json)?;
+        compose.json = json.to_string();
+        Ok(compose)
+    }
+
+    /// Read `file` with `docker compose config`
+    pub fn load(file: &Path) -> Result<Self> {
+        if !file.is_file() {
+            return Err(Error::MissingFile(file.into()).into());
+        }
+
+        let output = run(
+            "docker".into(),
+            &[
+                "compose".into(),
+                "--file".into(),
+                file.display().to_string(),
+                "config".into(),
+                "--format".into(),
+                "json".into(),
+            ],
+        )?;
+
+        let compose = Compose::parse(&output_stdout_string(&output))?;
+        compose.check()?;
+        Ok(compose)
+    }
+
+    /// Every service needs an image, as nothing is built in the VM, and
+    /// what they depend on has to be there and not go around in a circle
+    pub fn check(&self) -> Result<()> {
+        for (name, service) in &self.services {
+            if service.image.is_none() {
+                return Err(Error::InvalidOptions(format!(
+                    "compose service {} has no image; build it and set image:",
+                    name
+                ))
+                .into());
+            }
+        }
+
+        self.start_order()?;
+        Ok(())
+    }
+
+    /// The services, each after those it depends on
+    pub fn start_order(&self) -> Result<Vec<&str>> {
+        let mut order: Vec<&str> = vec![];
+
+        // Depth first, `visiting` being the path there to spot cycles
+        fn visit<'a>(
+            compose: &'a Compose,
+            name: &'a str,
+            visiting: &mut Vec<&'a str>,
+            order: &mut Vec<&'a str>,
+        ) -> Result<()> {
+            if order.contains(&name) {
+                return Ok(());
+            }
+
+            if visiting.contains(&name) {
+                visiting.push(name);
+                return Err(Error::InvalidOptions(format!(
+                    "compose services depend on each other in a cycle: {}",
+                    visiting.join(" -> ")
+                ))
+                .into());
+            }
+
+            let Some(service) = compose.services.get(name) else {
+                return Err(Error::InvalidOptions(format!(
+                    "compose service {} depends on {}, which doesn't exist",
+                    visiting.last().unwrap_or(&"?"),
+                    name
+                ))
+                .into());
+            };
+
+            visiting.push(name);
+            for dependency in service.depends_on.keys() {
+                visit(compose, dependency, visiting, order)?;
+            }
+            visiting.pop();
+
+            order.push(name);
+            Ok(())
+        }
+
+        for name in self.services.keys() {
+            visit(self, name, &mut vec![], &mut order)?;
+        }
+
+        Ok(order)
+    }
+
+    /// The distinct images, to save
+    pub fn images(&self) -> Vec<&str> {
+        let mut images: Vec<&str> = self
+            .services
+            .values()
+            .filter_map(|x| x.image.as_deref())
+            .collect();
+        images.sort();
+        images.dedup();
+        images
+    }
+
+    fn dir(&self) -> String {
+        format!("{}/{}", COMPOSE_DIR, self.name)
+    }
+
+    fn compose_file(&self) -> String {
+        format!("{}/compose.json", self.dir())
+    }
+
+    fn images_tar(&self) -> String {
+        format!("{}/images.tar", self.dir())
+    }
+
+    /// What the service loading the images and creating the containers is
+    /// called
+    pub fn setup_service(&self) -> String {
+        format!("compose-{}", self.name)
+    }
+
+    /// What the service running compose service `name` is called
+    pub fn service(&self, name: &str) -> String {
+        format!("compose-{}-{}", self.name, name)
+    }
+
+    /// `docker compose` with this project's file and name, then `args`
+    fn compose_command(&self, args: &str) -> String {
+        format!(
+            "/usr/bin/docker compose --file {} --project-name {} {}",
+            self.compose_file(),
+            self.name,
+            args
+        )
+    }
+
+    /// Loads the saved images the first time, and creates the containers
+    /// and networks up front, so services starting in parallel don't each
+    /// try to create the network
+    pub fn systemd_setup_unit(&self) -> String {
+        format!(
+            "[Unit]\n\
+             Description=Images and containers of compose project {name}\n\
+             Requires=docker.service\n\
+             After=docker.service\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             RemainAfterExit=yes\n\
+             ExecStart=/bin/sh -c 'if [ -e {tar} ]; then docker load --input {tar} && rm {tar}; fi'\n\
+             ExecStart={create}\n",
+            name = self.name,
+            tar = self.images_tar(),
+            create = self.compose_command("create"),
+        )
+    }
+
+    /// A unit running compose service `name` in the foreground
+    pub fn systemd_unit(&self, name: &str) -> String {
+        let mut after = vec![format!("{}.service", self.setup_service())];
+        after.extend(
+            self.services[name]
+                .depends_on
+                .keys()
+                .map(|x| format!("{}.service", self.service(x))),
+        );
+
+        format!(
+            "[Unit]\n\
+             Description={name} of compose project {project}\n\
+             Requires={after}\n\
+             After={after}\n\
+             \n\
+             [Service]\n\
+             ExecStart={up}\n\
+             ExecStop={stop}\n\
+             Restart=on-failure\n\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+            project = self.name,
+            after = after.join(" "),
+            up = self.compose_command(&format!("up --no-deps --no-log-prefix {}", name)),
+            stop = self.compose_command(&format!("stop {}", name)),
+        )
+    }
+
+    pub fn openrc_setup_service(&self) -> String {
+        format!(
+            "#!/sbin/openrc-run\n\
+             \n\
+             description=\"Images and containers of compose project {name}\"\n\
+             \n\
+             depend() {{\n\
+             \tneed docker\n\
+             }}\n\
+             \n\
+             start() {{\n\
+             \tebegin \"Creating the containers of {name}\"\n\
+             \tif [ -e {tar} ]; then\n\
+             \t\tdocker load --input {tar} && rm {tar} || return 1\n\
+             \tfi\n\
+             \t{create}\n\
+             \teend $?\n\
+             }}\n",
+            name = self.name,
+            tar = self.images_tar(),
+            create = self.compose_command("create"),
+        )
+    }
+
+    pub fn openrc_service(&self, name: &str) -> String {
+        let mut need = vec![self.setup_service()];
+        need.extend(
+            self.services[name]
+                .depends_on
+                .keys()
+                .map(|x| self.service(x)),
+        );
+
+        format!(
+            "#!/sbin/openrc-run\n\
+             \n\
+             description=\"{name} of compose project {project}\"\n\
+             command=/usr/bin/docker\n\
+             command_args=\"{args}\"\n\
+             command_background=true\n\
+             pidfile=\"/run/${{RC_SVCNAME}}.pid\"\n\
+             \n\
+             depend() {{\n\
+             \tneed {need}\n\
+             }}\n\
+             \n\
+             stop_pre() {{\n\
+             \t{stop}\n\
+             }}\n",
+            project = self.name,
+            args = self
+                .compose_command(&format!("up --no-deps --no-log-prefix {}", name))
+                .trim_start_matches("/usr/bin/docker "),
+            need = need.join(" "),
+            stop = self.compose_command(&format!("stop {}", name)),
+        )
+    }
+}
+
+/// The container runtime and compose, for `flavor`. Debian's compose is
+/// the `docker compose` plugin from trixie on.
+pub fn packages(flavor: &Flavor) -> &'static [&'static str] {
+    match flavor {
+        Flavor::Debian => &["docker.io", "docker-compose"],
+        Flavor::Ubuntu => &["docker.io", "docker-compose-v2"],
+        Flavor::Alpine => &["docker", "docker-cli-compose"],
+    }
+}
+
+/// Save `compose`'s images and write its file and services into the image
+/// at `root`, and enable them. The packages must already be installed.
+pub fn install(root: &str, flavor: &Flavor, compose: &Compose) -> Result<()> {
+    let dir = format!("{}{}", root, compose.dir());
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(format!("{}{}", root, compose.compose_file()), &compose.json)?;
+
+    let mut args = vec![
+        "save".to_string(),
+        "--output".to_string(),
+        format!("{}{}", root, compose.images_tar()),
+    ];
+    args.extend(compose.images().iter().map(|x| x.to_string()));
+    with_spinner("docker save", || run("docker".into(), &args))?;
+
+    let order = compose.start_order()?;
+
+    let enable = |service: &str| match flavor {
+        Flavor::Debian | Flavor::Ubuntu => run(
+            "chroot".into(),
+            &[
+                root.into(),
+                "systemctl".into(),
+                "enable".into(),
+                service.into(),
+            ],
+        ),
+        Flavor::Alpine => run(
+            "chroot".into(),
+            &[
+                root.into(),
+                "rc-update".into(),
+                "add".into(),
+                service.into(),
+                "default".into(),
+            ],
+        ),
+    };
+
+    match flavor {
+        Flavor::Debian | Flavor::Ubuntu => {
+            let unit = |name: String| format!("{}/etc/systemd/system/{}.service", root, name);
+
+            std::fs::write(unit(compose.setup_service()), compose.systemd_setup_unit())?;
+            for name in &order {
+                std::fs::write(unit(compose.service(name)), compose.systemd_unit(name))?;
+            }
+
+            enable("docker.service")?;
+            for name in &order {
+                enable(&format!("{}.service", compose.service(name)))?;
+            }
+        }
+
+        Flavor::Alpine => {
+            let write = |name: String, script: String| -> Result<()> {
+                let path = format!("{}/etc/init.d/{}", root, name);
+                std::fs::write(&path, script)?;
+                std::fs::set_permissions(
+                    &path,
+                    std::os::unix::fs::PermissionsExt::from_mode(0o755),
+                )?;
+                Ok(())
+            };
+
+            write(compose.setup_service(), compose.openrc_setup_service())?;
+            for name in &order {
+                write(compose.service(name), compose.openrc_service(name))?;
+            }
+
+            enable("docker")?;
+            for name in &order {
+                enable(&compose.service(name))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_compose() -> Compose {
+    Compose::parse(
+        r#"{
+            "name": "shop",
+            "services": {
+                "db": {"image": "postgres:16", "environment": {"POSTGRES_PASSWORD": "x"}},
+                "web": {
+                    "image": "shop/web:1",
+                    "depends_on": {
+                        "cache": {"condition": "service_started", "required": true},
+                        "db": {"condition": "service_healthy", "required": true}
+                    }
+                },
+                "cache": {"image": "redis:7", "depends_on": {"db": {"condition": "service_started"}}},
+                "worker": {"image": "shop/web:1", "depends_on": {"db": {"condition": "service_started"}}}
+            },
+            "networks": {"default": {"name": "shop_default"}}
+        }"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_start_order() -> Result<()> {
+    let compose = test_compose();
+    assert_eq!(compose.start_order()?, ["db", "cache", "web", "worker"]);
+    assert_eq!(compose.images(), ["postgres:16", "redis:7", "shop/web:1"]);
+    compose.check()?;
+
+    let cycle = Compose::parse(
+        r#"{"name": "x", "services": {
+            "a": {"image": "a", "depends_on": {"b": {}}},
+            "b": {"image": "b", "depends_on": {"a": {}}}
+        }}"#,
+    )?;
+    assert!(cycle.start_order().is_err());
+
+    let missing = Compose::parse(
+        r#"{"name": "x", "services": {"a": {"image": "a", "depends_on": {"b": {}}}}}"#,
+    )?;
+    assert!(missing.start_order().is_err());
+
+    let unbuilt =
+        Compose::parse(r#"{"name": "x", "services": {"a": {"build": {"context": "."}}}}"#)?;
+    assert!(unbuilt.check().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_systemd_unit() {
+    assert_eq!(
+        test_compose().systemd_unit("web"),
+        "[Unit]
+Description=web of compose project shop
+Requires=compose-shop.service compose-shop-cache.service compose-shop-db.service
+After=compose-shop.service compose-shop-cache.service compose-shop-db.service
+
+[Service]
+ExecStart=/usr/bin/docker compose --file /var/lib/compose/shop/compose.json --project-name shop up --no-deps --no-log-prefix web
+ExecStop=/usr/bin/docker compose --file /var/lib/compose/shop/compose.json --project-name shop stop web
+Restart=on-failure
+
+[Install]
+WantedBy=multi-user.target
+"
+    );
+}
+
+#[test]
+fn test_openrc_service() {
+    let service = test_compose().openrc_service("cache");
+    assert!(service.contains(
+        "command_args=\"compose --file /var/lib/compose/shop/compose.json --project-name shop up --no-deps --no-log-prefix cache\"\n"
+    ));
+    assert!(service.contains("\tneed compose-shop compose-shop-db\n"));
+}
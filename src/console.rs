@@ -0,0 +1,219 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! The consoles an image boots with: the kernel's `console=` arguments,
+//! GRUB's terminals, and a login prompt on each. systemd's getty generator
+//! starts those from the kernel command line by itself; Alpine's inittab is
+//! written for them.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+/// A console as the kernel takes it, like `ttyS0,115200n8`, `tty0` or `hvc0`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Console {
+    pub device: String,
+    /// Speed, parity and bits for a serial port
+    pub options: Option<String>,
+}
+
+impl FromStr for Console {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (device, options) = match s.split_once(',') {
+            Some((device, options)) => (device, Some(options.to_string())),
+            None => (s, None),
+        };
+
+        let valid_device = ["tty", "hvc"]
+            .iter()
+            .any(|x| device.strip_prefix(x).is_some_and(|rest| !rest.is_empty()))
+            && device.chars().all(|x| x.is_ascii_alphanumeric());
+        if !valid_device {
+            bail!(
+                "expected a console like ttyS0,115200, tty0 or hvc0, not {:?}",
+                s
+            );
+        }
+
+        if let Some(options) = &options {
+            if !options.starts_with(|x: char| x.is_ascii_digit()) {
+                bail!(
+                    "expected a speed like 115200 or 115200n8, not {:?}",
+                    options
+                );
+            }
+        }
+
+        Ok(Self {
+            device: device.into(),
+            options,
+        })
+    }
+}
+
+impl std::fmt::Display for Console {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.options {
+            Some(options) => write!(f, "{},{}", self.device, options),
+            None => write!(f, "{}", self.device),
+        }
+    }
+}
+
+impl Console {
+    /// The serial port number of ttyS0, ttyS1, ...
+    pub fn serial_unit(&self) -> Option<u32> {
+        self.device.strip_prefix("ttyS")?.parse().ok()
+    }
+
+    /// A virtual terminal, like tty0 or tty1, rather than a serial port
+    pub fn is_virtual_terminal(&self) -> bool {
+        self.device
+            .strip_prefix("tty")
+            .is_some_and(|x| x.parse::<u32>().is_ok())
+    }
+
+    /// The serial port's speed, 115200 unless given
+    pub fn speed(&self) -> u32 {
+        self.options
+            .as_deref()
+            .map(|x| {
+                x.chars()
+                    .take_while(char::is_ascii_digit)
+                    .collect::<String>()
+            })
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(115200)
+    }
+}
+
+/// What images have always booted with: the first serial port
+pub fn defaults() -> Vec<Console> {
+    vec![Console {
+        device: "ttyS0".into(),
+        options: Some("115200".into()),
+    }]
+}
+
+/// `console=` for each. The kernel makes the last one /dev/console, where
+/// the initramfs and init write, so the first given goes last.
+pub fn kernel_args(consoles: &[Console]) -> Vec<String> {
+    consoles
+        .iter()
+        .rev()
+        .map(|x| format!("console={}", x))
+        .collect()
+}
+
+/// GRUB's terminals for /etc/default/grub's GRUB_TERMINAL, in order. GRUB
+/// has only the one serial port, and no hypervisor consoles.
+pub fn grub_terminals(consoles: &[Console]) -> Vec<&'static str> {
+    let mut terminals = vec![];
+
+    for console in consoles {
+        let terminal = if console.serial_unit().is_some() {
+            "serial"
+        } else if console.is_virtual_terminal() {
+            "console"
+        } else {
+            continue;
+        };
+
+        if !terminals.contains(&terminal) {
+            terminals.push(terminal);
+        }
+    }
+
+    if terminals.is_empty() {
+        terminals.push("console");
+    }
+
+    terminals
+}
+
+/// GRUB's `serial` command for the first serial console, if there is one
+pub fn grub_serial_command(consoles: &[Console]) -> Option<String> {
+    consoles.iter().find_map(|x| {
+        Some(format!(
+            "serial --unit={} --speed={}",
+            x.serial_unit()?,
+            x.speed()
+        ))
+    })
+}
+
+/// Alpine's /etc/inittab, `inittab`, with a getty on each console that
+/// isn't a virtual terminal (those already have theirs). It replaces any
+/// line for the console, like the stock, commented out `#ttyS0::respawn...`.
+pub fn inittab(inittab: &str, consoles: &[Console]) -> String {
+    let mut lines: Vec<String> = inittab.lines().map(String::from).collect();
+
+    for console in consoles.iter().filter(|x| !x.is_virtual_terminal()) {
+        let prefix = format!("{}::", console.device);
+        let getty = format!(
+            "{0}::respawn:/sbin/getty -L {1} {0} vt100",
+            console.device,
+            console.speed()
+        );
+
+        match lines
+            .iter_mut()
+            .find(|x| x.trim_start_matches('#').starts_with(&prefix))
+        {
+            Some(line) => *line = getty,
+            None => lines.push(getty),
+        }
+    }
+
+    lines.iter().map(|x| format!("{}\n", x)).collect()
+}
+
+#[test]
+fn test_console() -> Result<()> {
+    let consoles: Vec<Console> = ["tty0", "ttyS1,9600n8", "hvc0"]
+        .iter()
+        .map(|x| x.parse())
+        .collect::<Result<_>>()?;
+
+    assert_eq!(consoles[1].to_string(), "ttyS1,9600n8");
+    assert_eq!(consoles[1].serial_unit(), Some(1));
+    assert_eq!(consoles[1].speed(), 9600);
+    assert!(consoles[0].is_virtual_terminal());
+    assert!(!consoles[2].is_virtual_terminal());
+
+    assert_eq!(
+        kernel_args(&consoles),
+        ["console=hvc0", "console=ttyS1,9600n8", "console=tty0"]
+    );
+    assert_eq!(grub_terminals(&consoles), ["console", "serial"]);
+    assert_eq!(
+        grub_serial_command(&consoles).as_deref(),
+        Some("serial --unit=1 --speed=9600")
+    );
+    assert_eq!(
+        inittab(
+            "tty1::respawn:/sbin/getty 38400 tty1\n\
+             #ttyS1::respawn:/sbin/getty -L 115200 ttyS1 vt100\n",
+            &consoles
+        ),
+        "tty1::respawn:/sbin/getty 38400 tty1\n\
+         ttyS1::respawn:/sbin/getty -L 9600 ttyS1 vt100\n\
+         hvc0::respawn:/sbin/getty -L 115200 hvc0 vt100\n"
+    );
+
+    assert_eq!(grub_terminals(&["hvc0".parse()?]), ["console"]);
+    assert_eq!(grub_serial_command(&["tty0".parse()?]), None);
+
+    assert!("ttyS0,fast".parse::<Console>().is_err());
+    assert!("tty".parse::<Console>().is_err());
+    assert!("/dev/ttyS0".parse::<Console>().is_err());
+    assert!("lp0".parse::<Console>().is_err());
+
+    Ok(())
+}
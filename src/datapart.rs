@@ -0,0 +1,219 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Data partitions: more partitions after root, each with its own
+//! filesystem, mounted through fstab and filled from a directory or tar
+//! archive on the host, so an appliance's seed data ships apart from its OS.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use uuid::Uuid;
+
+use crate::fstab::FstabEntry;
+use crate::*;
+
+/// The filesystems a data partition can have
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataFs {
+    Ext4,
+    Xfs,
+}
+
+impl DataFs {
+    /// The name mount(8) and fstab use
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DataFs::Ext4 => "ext4",
+            DataFs::Xfs => "xfs",
+        }
+    }
+}
+
+/// One data partition, like `/data:size=20,fs=xfs,source=seed.tar`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataPartition {
+    pub mountpoint: String,
+    /// In GB
+    pub size: usize,
+    pub fs: DataFs,
+    /// A directory whose contents are copied in, or a tar archive unpacked
+    pub source: Option<PathBuf>,
+}
+
+impl FromStr for DataPartition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let usage = || {
+            anyhow::anyhow!(
+                "expected MOUNTPOINT:size=GB[,fs=ext4|xfs][,source=PATH], not {:?}",
+                s
+            )
+        };
+
+        let (mountpoint, options) = s.split_once(':').ok_or_else(usage)?;
+        if !mountpoint.starts_with('/') || mountpoint.trim_end_matches('/').is_empty() {
+            return Err(usage());
+        }
+
+        let mut size = None;
+        let mut fs = DataFs::Ext4;
+        let mut source = None;
+
+        for x in options.split(',') {
+            match x.split_once('=').ok_or_else(usage)? {
+                ("size", value) => match value.parse() {
+                    Ok(value) if value > 0 => size = Some(value),
+                    _ => bail!("bad data partition size {:?}", value),
+                },
+                ("fs", "ext4") => fs = DataFs::Ext4,
+                ("fs", "xfs") => fs = DataFs::Xfs,
+                ("fs", value) => bail!("data partitions can't be {:?}, only ext4 or xfs", value),
+                ("source", value) => source = Some(PathBuf::from(value)),
+                _ => return Err(usage()),
+            }
+        }
+
+        Ok(Self {
+            mountpoint: mountpoint.trim_end_matches('/').into(),
+            size: size.ok_or_else(usage)?,
+            fs,
+            source,
+        })
+    }
+}
+
+impl DataPartition {
+    /// The GPT partition name: the mountpoint's last component
+    pub fn name(&self) -> &str {
+        self.mountpoint.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// Its fstab entry, by the partition's GUID, which is known before its
+    /// filesystem is made
+    pub fn fstab_entry(&self, partuuid: Uuid) -> FstabEntry {
+        // xfs has no fsck to run at boot
+        let pass = match self.fs {
+            DataFs::Ext4 => 2,
+            DataFs::Xfs => 0,
+        };
+
+        FstabEntry::new(
+            &format!("PARTUUID={}", partuuid),
+            &self.mountpoint,
+            self.fs.as_str(),
+            &[],
+            pass,
+        )
+    }
+}
+
+/// Make the filesystem on `device`. For reproducible builds, `seed` gives
+/// it a UUID and, on ext4, directory hashes that are the same each time.
+pub fn make_filesystem(fs: DataFs, device: &str, seed: Option<(Uuid, Uuid)>) -> Result<()> {
+    let mut args: Vec<String> = match (fs, seed) {
+        (DataFs::Ext4, Some((uuid, hash_seed))) => vec![
+            "-U".into(),
+            uuid.to_string(),
+            "-E".into(),
+            format!("hash_seed={}", hash_seed),
+        ],
+        (DataFs::Xfs, Some((uuid, _))) => vec!["-f".into(), "-m".into(), format!("uuid={}", uuid)],
+        (DataFs::Ext4, None) => vec![],
+        (DataFs::Xfs, None) => vec!["-f".into()],
+    };
+    args.push(device.into());
+
+    run(format!("mkfs.{}", fs.as_str()), &args)?;
+
+    Ok(())
+}
+
+/// Copy `source`'s contents into `dir`: a directory's with cp -a, keeping
+/// owners and modes, or a tar archive's by unpacking it
+pub fn populate(source: &Path, dir: &str) -> Result<()> {
+    if source.is_dir() {
+        run(
+            "cp".into(),
+            &[
+                "-a".into(),
+                format!("{}/.", source.display()),
+                format!("{}/", dir),
+            ],
+        )?;
+    } else {
+        unpack_tar(&source.display().to_string(), dir)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_data_partition() -> Result<()> {
+    let data: DataPartition = "/srv/data/:size=20,fs=xfs,source=seed.tar".parse()?;
+    assert_eq!(
+        data,
+        DataPartition {
+            mountpoint: "/srv/data".into(),
+            size: 20,
+            fs: DataFs::Xfs,
+            source: Some("seed.tar".into()),
+        }
+    );
+    assert_eq!(data.name(), "data");
+    assert_eq!(
+        data.fstab_entry(Uuid::from_u128(1)).to_string(),
+        "PARTUUID=00000000-0000-0000-0000-000000000001 /srv/data xfs defaults 0 0"
+    );
+
+    let data: DataPartition = "/data:size=1".parse()?;
+    assert_eq!(data.fs, DataFs::Ext4);
+    assert_eq!(data.source, None);
+    assert_eq!(
+        data.fstab_entry(Uuid::from_u128(1)).to_string(),
+        "PARTUUID=00000000-0000-0000-0000-000000000001 /data ext4 defaults 0 2"
+    );
+
+    assert!("/data".parse::<DataPartition>().is_err());
+    assert!("data:size=1".parse::<DataPartition>().is_err());
+    assert!("/:size=1".parse::<DataPartition>().is_err());
+    assert!("/data:fs=ext4".parse::<DataPartition>().is_err());
+    assert!("/data:size=0".parse::<DataPartition>().is_err());
+    assert!("/data:size=1,fs=btrfs".parse::<DataPartition>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_make_filesystem() -> Result<()> {
+    let recorder = std::rc::Rc::new(RecordingExecutor::new(|_, _| Ok(String::new())));
+    let previous = set_executor(recorder.clone());
+
+    let seed = Some((Uuid::from_u128(1), Uuid::from_u128(2)));
+    let result = make_filesystem(DataFs::Xfs, "/dev/loop0p4", seed)
+        .and_then(|_| make_filesystem(DataFs::Ext4, "/dev/loop0p5", seed))
+        .and_then(|_| make_filesystem(DataFs::Xfs, "/dev/loop0p6", None));
+    set_executor(previous);
+    result?;
+
+    let commands: Vec<String> = recorder
+        .commands()
+        .iter()
+        .map(|x| format!("{} {}", x.exe, x.args.join(" ")))
+        .collect();
+    assert_eq!(
+        commands,
+        [
+            "mkfs.xfs -f -m uuid=00000000-0000-0000-0000-000000000001 /dev/loop0p4",
+            "mkfs.ext4 -U 00000000-0000-0000-0000-000000000001 -E hash_seed=00000000-0000-0000-0000-000000000002 /dev/loop0p5",
+            "mkfs.xfs -f /dev/loop0p6",
+        ]
+    );
+
+    Ok(())
+}
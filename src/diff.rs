@@ -0,0 +1,324 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Comparing the container image a disk was built from with the disk's root
+//! filesystem, to audit what the conversion changed.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use tracing::info;
+
+use crate::builder::export_image;
+use crate::gpt::{self, Gpt};
+use crate::*;
+
+/// Paths every build adds or rewrites: the kernel and its modules and
+/// firmware, the bootloader and initramfs config, fstab and the other files
+/// made for the machine, and the package databases, caches and logs
+pub const EXPECTED: &[&str] = &[
+    "boot",
+    "etc/default/grub",
+    "etc/fstab",
+    "etc/grub.d",
+    "etc/hostname",
+    "etc/hosts",
+    "etc/initramfs-tools",
+    "etc/machine-id",
+    "etc/mkinitfs",
+    "etc/resolv.conf",
+    "lib/apk/db",
+    "lib/firmware",
+    "lib/modules",
+    "usr/lib/firmware",
+    "usr/lib/grub",
+    "usr/lib/modules",
+    "usr/share/grub",
+    "var/cache",
+    "var/lib/apt",
+    "var/lib/dpkg",
+    "var/log",
+];
+
+/// Whether `path`, relative to root, is one of `EXPECTED` or under one
+pub fn is_expected(path: &str) -> bool {
+    EXPECTED.iter().any(|x| {
+        path.strip_prefix(x)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+/// What's compared of each path
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    kind: Kind,
+    /// Permission bits, with setuid, setgid and sticky
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    target: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    /// What differs: type, mode, owner, target or contents
+    Modified(Vec<&'static str>),
+}
+
+/// One path that differs, relative to root
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub path: String,
+    pub change: Change,
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.change {
+            Change::Added => write!(f, "A  /{}", self.path),
+            Change::Removed => write!(f, "D  /{}", self.path),
+            Change::Modified(what) => write!(f, "M  /{} ({})", self.path, what.join(", ")),
+        }
+    }
+}
+
+/// Every path under `root`, without following symlinks
+fn walk(root: &Path) -> Result<BTreeMap<String, Node>> {
+    let mut nodes = BTreeMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            let file_type = metadata.file_type();
+
+            let kind = if file_type.is_symlink() {
+                Kind::Symlink
+            } else if file_type.is_dir() {
+                dirs.push(path.clone());
+                Kind::Directory
+            } else if file_type.is_file() {
+                Kind::File
+            } else if file_type.is_block_device()
+                || file_type.is_char_device()
+                || file_type.is_fifo()
+                || file_type.is_socket()
+            {
+                Kind::Other
+            } else {
+                bail!("can't tell what {:?} is", path);
+            };
+
+            nodes.insert(
+                path.strip_prefix(root)?.to_string_lossy().into_owned(),
+                Node {
+                    kind,
+                    mode: metadata.mode() & 0o7777,
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                    size: if kind == Kind::File {
+                        metadata.len()
+                    } else {
+                        0
+                    },
+                    target: match kind {
+                        Kind::Symlink => Some(std::fs::read_link(&path)?),
+                        _ => None,
+                    },
+                },
+            );
+        }
+    }
+
+    Ok(nodes)
+}
+
+/// Whether two files of the same size have the same bytes
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let mut a = File::open(a)?;
+    let mut b = File::open(b)?;
+    let mut a_buf = vec![0; 64 * 1024];
+    let mut b_buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = a.read(&mut a_buf)?;
+        if n == 0 {
+            return Ok(true);
+        }
+
+        b.read_exact(&mut b_buf[..n])?;
+        if a_buf[..n] != b_buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// What differs between the trees at `before` and `after`, by path
+pub fn diff_trees(before: &Path, after: &Path) -> Result<Vec<Difference>> {
+    let old = walk(before)?;
+    let mut new = walk(after)?;
+    let mut differences = vec![];
+
+    for (path, a) in old {
+        let Some(b) = new.remove(&path) else {
+            differences.push(Difference {
+                path,
+                change: Change::Removed,
+            });
+            continue;
+        };
+
+        let mut what = vec![];
+        if a.kind != b.kind {
+            what.push("type");
+        } else {
+            if a.target != b.target {
+                what.push("target");
+            }
+
+            if a.kind == Kind::File
+                && (a.size != b.size || !same_contents(&before.join(&path), &after.join(&path))?)
+            {
+                what.push("contents");
+            }
+        }
+
+        // Symlinks' own modes mean nothing
+        if a.mode != b.mode && a.kind != Kind::Symlink {
+            what.push("mode");
+        }
+
+        if (a.uid, a.gid) != (b.uid, b.gid) {
+            what.push("owner");
+        }
+
+        if !what.is_empty() {
+            differences.push(Difference {
+                path,
+                change: Change::Modified(what),
+            });
+        }
+    }
+
+    differences.extend(new.into_keys().map(|path| Difference {
+        path,
+        change: Change::Added,
+    }));
+    differences.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(differences)
+}
+
+/// Compare `image_name`'s file tree with the root filesystem of the disk
+/// image `disk`, which is attached and mounted read-only for it
+pub fn diff(image_name: &str, disk: &Path) -> Result<Vec<Difference>> {
+    let gpt = Gpt::read(disk)?;
+    let Some(root) = gpt.first_of(gpt::ROOT_TYPES) else {
+        bail!("{:?} has no root partition", disk);
+    };
+
+    let dir = tempfile::tempdir()?;
+    let export = dir.path().join("export.tar");
+    let container_root = dir.path().join("container").display().to_string();
+    let disk_root = dir.path().join("disk").display().to_string();
+
+    info!("export {}", image_name);
+    export_image(image_name, &export, None)?;
+    std::fs::create_dir_all(&container_root)?;
+    unpack_tar(&export.display().to_string(), &container_root)?;
+    std::fs::remove_file(&export)?;
+
+    let loop_device = LoopbackDevice::attach(
+        &disk.to_string_lossy(),
+        &LoopOptions {
+            read_only: true,
+            partscan: true,
+        },
+    )?;
+    let device = loop_device.partition(root)?;
+    let fs = probe_filesystem(&device)?;
+    let _mount = Mount::new(
+        device,
+        disk_root.clone(),
+        &MountOptions::fstype(fs.fs_type.as_str()).read_only(true),
+    )?;
+
+    info!(
+        "compare {} with partition {} of {:?}",
+        image_name, root, disk
+    );
+    diff_trees(Path::new(&container_root), Path::new(&disk_root))
+}
+
+#[test]
+fn test_is_expected() {
+    assert!(is_expected("boot"));
+    assert!(is_expected("boot/vmlinuz-6.1.0-18-amd64"));
+    assert!(is_expected("lib/modules/6.1.0-18-amd64/modules.dep"));
+    assert!(!is_expected("bootstrap.sh"));
+    assert!(!is_expected("etc/passwd"));
+    assert!(!is_expected("lib"));
+}
+
+#[test]
+fn test_diff_trees() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let before = tempfile::tempdir()?;
+    let after = tempfile::tempdir()?;
+
+    for root in [before.path(), after.path()] {
+        std::fs::create_dir_all(root.join("etc/app"))?;
+        std::fs::write(root.join("etc/hostname"), "same")?;
+        std::fs::write(root.join("etc/app/config"), "port=80\n")?;
+        std::fs::write(root.join("etc/app/script"), "#!/bin/sh\n")?;
+        std::os::unix::fs::symlink("config", root.join("etc/app/link"))?;
+    }
+
+    std::fs::write(before.path().join(".dockerenv"), "")?;
+    std::fs::write(after.path().join("etc/app/config"), "port=81\n")?;
+    std::fs::set_permissions(
+        after.path().join("etc/app/script"),
+        std::fs::Permissions::from_mode(0o755),
+    )?;
+    std::fs::remove_file(after.path().join("etc/app/link"))?;
+    std::os::unix::fs::symlink("script", after.path().join("etc/app/link"))?;
+    std::fs::create_dir_all(after.path().join("boot"))?;
+
+    let differences: Vec<String> = diff_trees(before.path(), after.path())?
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    assert_eq!(
+        differences,
+        [
+            "D  /.dockerenv",
+            "A  /boot",
+            "M  /etc/app/config (contents)",
+            "M  /etc/app/link (target)",
+            "M  /etc/app/script (mode)",
+        ]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Failures worth telling apart. Functions in this crate still return
+//! `anyhow::Result`, with these inside for the cases below, so match on them
+//! with `downcast_ref`:
+//!
+//! ```no_run
+//! use docker_to_uefi_bootable_image::builder::ImageBuilder;
+//! use docker_to_uefi_bootable_image::error::Error;
+//!
+//! if let Err(e) = ImageBuilder::new("debian:12").build() {
+//!     match e.downcast_ref::<Error>() {
+//!         Some(Error::NotRoot) => eprintln!("try again with sudo"),
+//!         Some(Error::CommandFailed { cmd, .. }) => eprintln!("{} failed", cmd),
+//!         _ => eprintln!("{:#}", e),
+//!     }
+//! }
+//! ```
+
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use crate::builder::Flavor;
+
+#[derive(Debug)]
+pub enum Error {
+    /// An external command exited unsuccessfully
+    CommandFailed {
+        cmd: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+
+    /// A command ran longer than the command timeout and was killed
+    TimedOut { cmd: String, timeout: Duration },
+
+    /// The build was cancelled, e.g. with Ctrl-C
+    Cancelled,
+
+    /// An option the flavor doesn't support
+    UnsupportedFlavor {
+        flavor: Flavor,
+        option: &'static str,
+    },
+
+    /// Building needs root
+    NotRoot,
+
+    /// The host can't build images: missing tools, loop devices or space.
+    /// One line per failed check.
+    PreflightFailed(Vec<String>),
+
+    /// Mounts or loop devices that couldn't be undone, one line each. The
+    /// rest were undone anyway.
+    CleanupFailed(Vec<String>),
+
+    /// The container image doesn't exist, or isn't for the platform the
+    /// image boots on
+    InvalidImage { image: String, reason: String },
+
+    /// A file named in the options doesn't exist
+    MissingFile(PathBuf),
+
+    /// Options that are incomplete or contradict each other
+    InvalidOptions(String),
+
+    /// The image didn't show `marker` on its serial console in time
+    BootFailed {
+        image: PathBuf,
+        marker: String,
+        timeout: Duration,
+        console_tail: String,
+    },
+
+    /// A finished image failed `verify`'s checks. One line per failed check.
+    VerifyFailed(Vec<String>),
+}
+
+// By hand rather than with thiserror: it isn't in Cargo.lock, and the build
+// has to work offline from the crates that are already there
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::CommandFailed {
+                cmd,
+                status,
+                stderr,
+            } => {
+                write!(f, "{} failed: {}", cmd, status)?;
+                if !stderr.is_empty() {
+                    write!(f, "\n{}", stderr)?;
+                }
+                Ok(())
+            }
+
+            Error::TimedOut { cmd, timeout } => {
+                write!(f, "{} was killed after running for {:.0?}", cmd, timeout)
+            }
+
+            Error::Cancelled => write!(f, "cancelled"),
+
+            Error::UnsupportedFlavor { flavor, option } => {
+                write!(f, "{} is not supported for {:?}", option, flavor)
+            }
+
+            Error::NotRoot => write!(f, "building an image needs root"),
+
+            Error::PreflightFailed(failed) => {
+                write!(f, "preflight checks failed:\n  {}", failed.join("\n  "))
+            }
+
+            Error::CleanupFailed(failed) => {
+                write!(f, "cleanup failed:\n  {}", failed.join("\n  "))
+            }
+
+            Error::InvalidImage { image, reason } => {
+                write!(f, "can't build from {}: {}", image, reason)
+            }
+
+            Error::MissingFile(path) => write!(f, "{:?} does not exist", path),
+
+            Error::InvalidOptions(message) => write!(f, "{}", message),
+
+            Error::BootFailed {
+                image,
+                marker,
+                timeout,
+                console_tail,
+            } => write!(
+                f,
+                "{:?} did not show {:?} on the serial console within {:?}. Last output:\n{}",
+                image, marker, timeout, console_tail
+            ),
+
+            Error::VerifyFailed(failed) => {
+                write!(f, "verify failed:\n  {}", failed.join("\n  "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
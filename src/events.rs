@@ -0,0 +1,168 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Build events for programs embedding the library, so they can show their
+//! own progress rather than scrape the log. Subscribe with
+//! [`ImageBuilder::events`](crate::builder::ImageBuilder::events).
+
+use std::cell::RefCell;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildEvent {
+    /// A phase of the build started, e.g. "partition" or "install"
+    PhaseStarted { phase: String },
+
+    /// A phase ended, either because the next one started or because the
+    /// build finished or failed during it
+    PhaseFinished {
+        phase: String,
+        elapsed: Duration,
+        succeeded: bool,
+    },
+
+    /// A command is about to run, as a shell would quote it
+    CommandStarted { command: String },
+
+    CommandFinished {
+        command: String,
+        elapsed: Duration,
+        succeeded: bool,
+    },
+
+    /// Bytes done so far of something long, like "copy image". `total` is
+    /// None when it isn't known up front.
+    Progress {
+        task: String,
+        done: u64,
+        total: Option<u64>,
+    },
+}
+
+/// Receives [`BuildEvent`]s. Implemented for closures and for channel
+/// senders. Events for file progress come from a helper thread, hence
+/// `Send + Sync`.
+pub trait ImageBuilderEvents: Send + Sync {
+    fn event(&self, event: &BuildEvent);
+}
+
+impl<F: Fn(&BuildEvent) + Send + Sync> ImageBuilderEvents for F {
+    fn event(&self, event: &BuildEvent) {
+        self(event)
+    }
+}
+
+impl ImageBuilderEvents for Sender<BuildEvent> {
+    fn event(&self, event: &BuildEvent) {
+        // Nobody listening any more isn't the build's problem
+        let _ = self.send(event.clone());
+    }
+}
+
+impl std::fmt::Debug for dyn ImageBuilderEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ImageBuilderEvents")
+    }
+}
+
+thread_local! {
+    static EVENTS: RefCell<Option<Arc<dyn ImageBuilderEvents>>> = const { RefCell::new(None) };
+}
+
+/// Send this thread's events to `events` from now on, returning where they
+/// were going before
+pub fn set_events(
+    events: Option<Arc<dyn ImageBuilderEvents>>,
+) -> Option<Arc<dyn ImageBuilderEvents>> {
+    EVENTS.with(|x| x.replace(events))
+}
+
+/// Where this thread's events go, for handing to another thread
+pub fn events() -> Option<Arc<dyn ImageBuilderEvents>> {
+    EVENTS.with(|x| x.borrow().clone())
+}
+
+pub fn emit(event: BuildEvent) {
+    if let Some(events) = events() {
+        events.event(&event);
+    }
+}
+
+/// Progress for one task, sent at most every 100ms (and always at the end)
+/// so a subscriber isn't flooded by a tar with a hundred thousand entries
+pub struct ProgressEvents {
+    events: Option<Arc<dyn ImageBuilderEvents>>,
+    task: String,
+    total: Option<u64>,
+    last: Option<Instant>,
+}
+
+impl ProgressEvents {
+    pub fn new(task: &str, total: Option<u64>) -> Self {
+        Self {
+            events: events(),
+            task: task.to_string(),
+            total,
+            last: None,
+        }
+    }
+
+    pub fn set_position(&mut self, done: u64) {
+        if self
+            .last
+            .is_some_and(|x| x.elapsed() < Duration::from_millis(100))
+        {
+            return;
+        }
+
+        self.last = Some(Instant::now());
+        self.send(done);
+    }
+
+    pub fn finish(&mut self, done: u64) {
+        self.send(done);
+    }
+
+    fn send(&self, done: u64) {
+        if let Some(events) = &self.events {
+            events.event(&BuildEvent::Progress {
+                task: self.task.clone(),
+                done,
+                total: self.total,
+            });
+        }
+    }
+}
+
+#[test]
+fn test_progress_events() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let previous = set_events(Some(Arc::new(sender)));
+
+    let mut progress = ProgressEvents::new("copy image", Some(300));
+    progress.set_position(100);
+    progress.set_position(200);
+    progress.finish(300);
+
+    set_events(previous);
+
+    let done: Vec<u64> = receiver
+        .try_iter()
+        .map(|x| match x {
+            BuildEvent::Progress { task, done, total } => {
+                assert_eq!(task, "copy image");
+                assert_eq!(total, Some(300));
+                done
+            }
+            x => panic!("unexpected {:?}", x),
+        })
+        .collect();
+
+    // The second update came too soon after the first
+    assert_eq!(done, [100, 300]);
+}
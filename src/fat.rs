@@ -0,0 +1,914 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! FAT32 filesystems, written whole into a region of an image file from a
+//! directory on the host, so the ESP needs neither mkfs.vfat nor a mount.
+//! See Microsoft's "FAT: General Overview of On-Disk Format".
+
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+const SECTOR: u64 = 512;
+const RESERVED_SECTORS: u64 = 32;
+const FATS: u64 = 2;
+const FSINFO_SECTOR: u64 = 1;
+const BACKUP_BOOT_SECTOR: u64 = 6;
+const ROOT_CLUSTER: u32 = 2;
+
+/// FAT32 needs at least this many clusters, or it's FAT16 by definition
+const MIN_CLUSTERS: u64 = 65525;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+const DIR_ENTRY: usize = 32;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// Set in a short entry when its base name or extension is lower case
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+/// 1980-01-01, the DOS epoch. Every timestamp is this, so the same files
+/// make the same filesystem.
+const DOS_EPOCH_DATE: u16 = 0x0021;
+
+/// A FAT32 filesystem `bytes` long at `offset` in an image file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fat32 {
+    pub offset: u64,
+    pub bytes: u64,
+
+    /// Shown by blkid as the filesystem's UUID, e.g. "1A2B-3C4D"
+    pub volume_id: u32,
+}
+
+struct Geometry {
+    sectors_per_cluster: u64,
+    fat_sectors: u64,
+    clusters: u64,
+}
+
+impl Geometry {
+    fn data_sector(&self) -> u64 {
+        RESERVED_SECTORS + FATS * self.fat_sectors
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster * SECTOR
+    }
+}
+
+enum Node {
+    File { source: PathBuf, len: u64 },
+    Directory(Vec<Entry>),
+}
+
+struct Entry {
+    name: String,
+    node: Node,
+    cluster: u32,
+}
+
+impl Fat32 {
+    pub fn new(offset: u64, bytes: u64, volume_id: u32) -> Self {
+        Self {
+            offset,
+            bytes,
+            volume_id,
+        }
+    }
+
+    /// The filesystem's UUID as blkid shows it
+    pub fn uuid(&self) -> String {
+        format!(
+            "{:04X}-{:04X}",
+            self.volume_id >> 16,
+            self.volume_id & 0xFFFF
+        )
+    }
+
+    /// Cluster sizes as Microsoft's format picks them
+    fn geometry(&self) -> Result<Geometry> {
+        const MIB: u64 = 1024 * 1024;
+
+        let sectors = self.bytes / SECTOR;
+        let sectors_per_cluster = match self.bytes {
+            x if x <= 260 * MIB => 1,
+            x if x <= 8 * 1024 * MIB => 8,
+            x if x <= 16 * 1024 * MIB => 16,
+            x if x <= 32 * 1024 * MIB => 32,
+            _ => 64,
+        };
+
+        // A bigger FAT leaves fewer clusters to describe, so this settles
+        let mut fat_sectors = 1;
+        let clusters = loop {
+            let clusters =
+                sectors.saturating_sub(RESERVED_SECTORS + FATS * fat_sectors) / sectors_per_cluster;
+            let needed = ((clusters + 2) * 4).div_ceil(SECTOR);
+            if needed <= fat_sectors {
+                break clusters;
+            }
+            fat_sectors = needed;
+        };
+
+        if clusters < MIN_CLUSTERS {
+            bail!("{} bytes is too small for FAT32", self.bytes);
+        }
+
+        Ok(Geometry {
+            sectors_per_cluster,
+            fat_sectors,
+            clusters,
+        })
+    }
+
+    /// Write a new filesystem holding a copy of `source`, or nothing. All of
+    /// the filesystem's metadata is written, but free clusters are left as
+    /// they were.
+    pub fn write(&self, image: &Path, source: Option<&Path>) -> Result<()> {
+        let geometry = self.geometry()?;
+
+        let mut root = match source {
+            Some(source) => read_tree(source)?,
+            None => vec![],
+        };
+
+        let mut fat = vec![0u32; geometry.clusters as usize + 2];
+        fat[0] = 0x0FFF_FFF8;
+        fat[1] = END_OF_CHAIN;
+
+        let mut next = ROOT_CLUSTER;
+        let root_entries = directory_entries(&root, false);
+        allocate(
+            &mut fat,
+            &mut next,
+            &geometry,
+            root_entries * DIR_ENTRY as u64,
+        )?;
+        allocate_tree(&mut root, &mut fat, &mut next, &geometry)?;
+
+        let mut file = OpenOptions::new().write(true).open(image)?;
+        if file.metadata()?.len() < self.offset + self.bytes {
+            bail!("{:?} is too short for the filesystem", image);
+        }
+
+        // Everything before the data clusters starts out zero
+        file.seek(SeekFrom::Start(self.offset))?;
+        let zeros = vec![0; SECTOR as usize];
+        for _ in 0..geometry.data_sector() {
+            file.write_all(&zeros)?;
+        }
+
+        let boot = self.boot_sector(&geometry);
+        let free = fat.iter().skip(2).filter(|x| **x == 0).count() as u32;
+        let fsinfo = fsinfo_sector(free, next);
+        for sector in [0, BACKUP_BOOT_SECTOR] {
+            self.write_at(&mut file, sector * SECTOR, &boot)?;
+            self.write_at(&mut file, (sector + FSINFO_SECTOR) * SECTOR, &fsinfo)?;
+        }
+
+        let fat_bytes: Vec<u8> = fat.iter().flat_map(|x| x.to_le_bytes()).collect();
+        for i in 0..FATS {
+            self.write_at(
+                &mut file,
+                (RESERVED_SECTORS + i * geometry.fat_sectors) * SECTOR,
+                &fat_bytes,
+            )?;
+        }
+
+        self.write_directory(&mut file, &geometry, &root, ROOT_CLUSTER, None)?;
+
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Check the filesystem as `fsck.vfat -n` would, without changing it:
+    /// the boot sectors and FSInfo, that both FATs agree, and that every
+    /// cluster chain ends properly and is used by exactly one file or
+    /// directory of the right size. Fails listing every problem found.
+    pub fn check(&self, image: &Path) -> Result<()> {
+        let geometry = self.geometry()?;
+        let mut file = File::open(image)?;
+        let mut problems = vec![];
+
+        let boot = self.read_at(&mut file, 0, SECTOR)?;
+        if boot[..] != self.boot_sector(&geometry) {
+            problems.push("the boot sector is not the one written".to_string());
+        }
+        if self.read_at(&mut file, BACKUP_BOOT_SECTOR * SECTOR, SECTOR)? != boot {
+            problems.push("the backup boot sector differs".to_string());
+        }
+
+        let fat_bytes = geometry.fat_sectors * SECTOR;
+        let first = self.read_at(&mut file, RESERVED_SECTORS * SECTOR, fat_bytes)?;
+        let second = self.read_at(
+            &mut file,
+            (RESERVED_SECTORS + geometry.fat_sectors) * SECTOR,
+            fat_bytes,
+        )?;
+        if first != second {
+            problems.push("the two FATs differ".to_string());
+        }
+
+        let fat: Vec<u32> = first
+            .chunks(4)
+            .take(geometry.clusters as usize + 2)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()) & 0x0FFF_FFFF)
+            .collect();
+        let mut used = vec![false; fat.len()];
+
+        // Every directory, from the root down
+        let mut directories = vec![(String::new(), ROOT_CLUSTER)];
+        while let Some((path, cluster)) = directories.pop() {
+            let chain = match follow(&fat, &mut used, cluster) {
+                Ok(chain) => chain,
+                Err(e) => {
+                    problems.push(format!("/{}: {}", path, e));
+                    continue;
+                }
+            };
+
+            let mut bytes = vec![];
+            for cluster in chain {
+                bytes.extend(self.read_at(
+                    &mut file,
+                    self.cluster_offset(&geometry, cluster),
+                    geometry.cluster_bytes(),
+                )?);
+            }
+
+            for entry in bytes.chunks(DIR_ENTRY) {
+                match entry[0] {
+                    0 => break,
+                    // Deleted, or "." and ".."
+                    0xE5 | b'.' => continue,
+                    _ if entry[11] == ATTR_LONG_NAME => continue,
+                    _ => {}
+                }
+
+                let name = format!(
+                    "{}/{}",
+                    path,
+                    String::from_utf8_lossy(&entry[..11]).trim_end()
+                );
+                let cluster = u32::from(u16::from_le_bytes([entry[20], entry[21]])) << 16
+                    | u32::from(u16::from_le_bytes([entry[26], entry[27]]));
+                let len = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as u64;
+
+                if entry[11] & ATTR_DIRECTORY != 0 {
+                    directories.push((name.trim_start_matches('/').to_string(), cluster));
+                    continue;
+                }
+
+                if len == 0 && cluster == 0 {
+                    continue;
+                }
+
+                match follow(&fat, &mut used, cluster) {
+                    Ok(chain) if chain.len() as u64 != len.div_ceil(geometry.cluster_bytes()) => {
+                        problems.push(format!(
+                            "{}: {} bytes in {} clusters",
+                            name,
+                            len,
+                            chain.len()
+                        ));
+                    }
+                    Ok(_) => {}
+                    Err(e) => problems.push(format!("{}: {}", name, e)),
+                }
+            }
+        }
+
+        let lost = (2..fat.len()).filter(|x| fat[*x] != 0 && !used[*x]).count();
+        if lost > 0 {
+            problems.push(format!("{} clusters are allocated but not used", lost));
+        }
+
+        let fsinfo = self.read_at(&mut file, FSINFO_SECTOR * SECTOR, SECTOR)?;
+        let free = fat.iter().skip(2).filter(|x| **x == 0).count() as u32;
+        let next = u32::from_le_bytes(fsinfo[492..496].try_into().unwrap());
+        if fsinfo[..] != fsinfo_sector(free, next) {
+            problems.push(format!("FSInfo doesn't say {} clusters are free", free));
+        }
+
+        if !problems.is_empty() {
+            bail!("FAT filesystem errors: {}", problems.join("; "));
+        }
+
+        Ok(())
+    }
+
+    fn read_at(&self, file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; len as usize];
+        file.seek(SeekFrom::Start(self.offset + offset))?;
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_at(&self, file: &mut File, offset: u64, bytes: &[u8]) -> Result<()> {
+        file.seek(SeekFrom::Start(self.offset + offset))?;
+        file.write_all(bytes)?;
+        Ok(())
+    }
+
+    fn cluster_offset(&self, geometry: &Geometry, cluster: u32) -> u64 {
+        (geometry.data_sector() + (cluster as u64 - 2) * geometry.sectors_per_cluster) * SECTOR
+    }
+
+    /// Write the directory at `cluster`, whose parent is at `parent` (None
+    /// for the root), then everything in it
+    fn write_directory(
+        &self,
+        file: &mut File,
+        geometry: &Geometry,
+        entries: &[Entry],
+        cluster: u32,
+        parent: Option<u32>,
+    ) -> Result<()> {
+        let mut bytes = vec![];
+
+        if let Some(parent) = parent {
+            bytes.extend(short_entry(b".          ", ATTR_DIRECTORY, 0, cluster, 0));
+            // The root is cluster 0 to ".."
+            let parent = if parent == ROOT_CLUSTER { 0 } else { parent };
+            bytes.extend(short_entry(b"..         ", ATTR_DIRECTORY, 0, parent, 0));
+        }
+
+        let mut used = BTreeSet::new();
+        for entry in entries {
+            let (short, case) = short_name(&entry.name, &mut used);
+
+            if case.is_none() {
+                bytes.extend(long_name_entries(&entry.name, &short));
+            }
+
+            let (attr, len) = match &entry.node {
+                Node::File { len, .. } => (ATTR_ARCHIVE, *len as u32),
+                Node::Directory(_) => (ATTR_DIRECTORY, 0),
+            };
+            bytes.extend(short_entry(
+                &short,
+                attr,
+                case.unwrap_or(0),
+                entry.cluster,
+                len,
+            ));
+        }
+
+        // Pad to whole clusters, which also ends the directory
+        let padded = (bytes.len() as u64)
+            .max(1)
+            .div_ceil(geometry.cluster_bytes())
+            * geometry.cluster_bytes();
+        bytes.resize(padded as usize, 0);
+        self.write_at(file, self.cluster_offset(geometry, cluster), &bytes)?;
+
+        for entry in entries {
+            match &entry.node {
+                Node::File { source, len } => {
+                    if *len > 0 {
+                        let offset = self.cluster_offset(geometry, entry.cluster);
+                        file.seek(SeekFrom::Start(self.offset + offset))?;
+                        let copied = std::io::copy(&mut File::open(source)?, file)?;
+                        if copied != *len {
+                            bail!("{:?} changed while it was copied", source);
+                        }
+                    }
+                }
+
+                Node::Directory(children) => {
+                    self.write_directory(file, geometry, children, entry.cluster, Some(cluster))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn boot_sector(&self, geometry: &Geometry) -> [u8; SECTOR as usize] {
+        let mut boot = [0; SECTOR as usize];
+
+        boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        boot[3..11].copy_from_slice(b"MSWIN4.1");
+        boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        boot[13] = geometry.sectors_per_cluster as u8;
+        boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        boot[16] = FATS as u8;
+        // Media descriptor: fixed disk
+        boot[21] = 0xF8;
+        boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+        boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+        boot[28..32].copy_from_slice(&((self.offset / SECTOR) as u32).to_le_bytes());
+        boot[32..36].copy_from_slice(&((self.bytes / SECTOR) as u32).to_le_bytes());
+        boot[36..40].copy_from_slice(&(geometry.fat_sectors as u32).to_le_bytes());
+        boot[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+        boot[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+        boot[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+        boot[64] = 0x80;
+        boot[66] = 0x29;
+        boot[67..71].copy_from_slice(&self.volume_id.to_le_bytes());
+        boot[71..82].copy_from_slice(b"NO NAME    ");
+        boot[82..90].copy_from_slice(b"FAT32   ");
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+
+        boot
+    }
+}
+
+fn fsinfo_sector(free: u32, next: u32) -> [u8; SECTOR as usize] {
+    let mut fsinfo = [0; SECTOR as usize];
+
+    fsinfo[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    fsinfo[488..492].copy_from_slice(&free.to_le_bytes());
+    fsinfo[492..496].copy_from_slice(&next.to_le_bytes());
+    fsinfo[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+    fsinfo
+}
+
+/// The clusters of the chain starting at `cluster`, marking them used. A
+/// chain must stay in range, end with an end of chain mark, and not use a
+/// cluster already used.
+fn follow(fat: &[u32], used: &mut [bool], mut cluster: u32) -> Result<Vec<u32>, String> {
+    let mut chain = vec![];
+
+    loop {
+        if !(2..fat.len() as u32).contains(&cluster) {
+            return Err(format!("cluster {} is out of range", cluster));
+        }
+
+        if used[cluster as usize] {
+            return Err(format!("cluster {} is used twice", cluster));
+        }
+
+        used[cluster as usize] = true;
+        chain.push(cluster);
+
+        match fat[cluster as usize] {
+            0x0FFF_FFF8.. => return Ok(chain),
+            0 => return Err(format!("cluster {} is marked free", cluster)),
+            next => cluster = next,
+        }
+    }
+}
+
+/// Everything under `dir`, sorted by name so the layout is the same each time
+fn read_tree(dir: &Path) -> Result<Vec<Entry>> {
+    let mut entries = vec![];
+
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        let file_type = dir_entry.file_type()?;
+
+        let Some(name) = dir_entry.file_name().to_str().map(String::from) else {
+            bail!("{:?} is not valid UTF-8", path);
+        };
+
+        let node = if file_type.is_dir() {
+            Node::Directory(read_tree(&path)?)
+        } else if file_type.is_file() {
+            Node::File {
+                len: dir_entry.metadata()?.len(),
+                source: path,
+            }
+        } else {
+            bail!("{:?} can't be put on a FAT filesystem", path);
+        };
+
+        entries.push(Entry {
+            name,
+            node,
+            cluster: 0,
+        });
+    }
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(entries)
+}
+
+/// How many directory entries `entries` take, with "." and ".." unless this
+/// is the root
+fn directory_entries(entries: &[Entry], dots: bool) -> u64 {
+    let mut used = BTreeSet::new();
+    let names: u64 = entries
+        .iter()
+        .map(|x| match short_name(&x.name, &mut used).1 {
+            Some(_) => 1,
+            None => 1 + x.name.encode_utf16().count().div_ceil(13) as u64,
+        })
+        .sum();
+
+    names + if dots { 2 } else { 0 }
+}
+
+/// Give each file and directory under `entries` a contiguous run of clusters
+fn allocate_tree(
+    entries: &mut [Entry],
+    fat: &mut [u32],
+    next: &mut u32,
+    geometry: &Geometry,
+) -> Result<()> {
+    for entry in entries.iter_mut() {
+        match &mut entry.node {
+            Node::File { len, .. } => {
+                entry.cluster = allocate(fat, next, geometry, *len)?;
+            }
+
+            Node::Directory(children) => {
+                let bytes = directory_entries(children, true) * DIR_ENTRY as u64;
+                entry.cluster = allocate(fat, next, geometry, bytes)?;
+                allocate_tree(children, fat, next, geometry)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Chain enough clusters for `bytes` from `next`, returning the first, or 0
+/// for nothing
+fn allocate(fat: &mut [u32], next: &mut u32, geometry: &Geometry, bytes: u64) -> Result<u32> {
+    let count = bytes.div_ceil(geometry.cluster_bytes()) as u32;
+    if count == 0 {
+        return Ok(0);
+    }
+
+    let first = *next;
+    let last = first + count - 1;
+    if last as usize >= fat.len() {
+        bail!("not enough space on the FAT filesystem");
+    }
+
+    for cluster in first..last {
+        fat[cluster as usize] = cluster + 1;
+    }
+    fat[last as usize] = END_OF_CHAIN;
+
+    *next = last + 1;
+
+    Ok(first)
+}
+
+fn short_entry(name: &[u8; 11], attr: u8, case: u8, cluster: u32, len: u32) -> [u8; DIR_ENTRY] {
+    let mut entry = [0; DIR_ENTRY];
+
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attr;
+    entry[12] = case;
+    for date in [16, 18, 24] {
+        entry[date..date + 2].copy_from_slice(&DOS_EPOCH_DATE.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&len.to_le_bytes());
+
+    entry
+}
+
+fn short_name_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// The 8.3 name for `name`, not already in `used`, and the case flags if
+/// that says all there is to say about `name`. Otherwise it needs a long
+/// name too.
+fn short_name(name: &str, used: &mut BTreeSet<[u8; 11]>) -> ([u8; 11], Option<u8>) {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+
+    // One case per part, to be recorded in the flags
+    let case = |part: &str, flag: u8| {
+        if part
+            .chars()
+            .all(|c| short_name_char(c.to_ascii_uppercase()))
+        {
+            if part == part.to_ascii_uppercase() {
+                Some(0)
+            } else if part == part.to_ascii_lowercase() {
+                Some(flag)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    };
+
+    let fits = !base.is_empty() && base.len() <= 8 && ext.len() <= 3;
+    if fits {
+        if let (Some(base_case), Some(ext_case)) =
+            (case(base, NT_LOWER_BASE), case(ext, NT_LOWER_EXT))
+        {
+            let short = pad_short(&base.to_ascii_uppercase(), &ext.to_ascii_uppercase());
+            if used.insert(short) {
+                return (short, Some(base_case | ext_case));
+            }
+        }
+    }
+
+    // Windows' basis name: upper case, without what 8.3 can't hold
+    let clean = |part: &str| -> String {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if short_name_char(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    };
+    let base = clean(base);
+    let ext: String = clean(ext).chars().take(3).collect();
+
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let stem: String = base.chars().take(8 - tail.len()).collect();
+        let short = pad_short(&format!("{}{}", stem, tail), &ext);
+        if used.insert(short) {
+            return (short, None);
+        }
+    }
+
+    unreachable!()
+}
+
+fn pad_short(base: &str, ext: &str) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    short
+}
+
+/// The VFAT long name entries for `name`, in the order they're stored: last
+/// part first
+fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<u8> {
+    let checksum = short
+        .iter()
+        .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b));
+
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(13) {
+        units.push(0);
+    }
+    while !units.len().is_multiple_of(13) {
+        units.push(0xFFFF);
+    }
+
+    let count = units.len() / 13;
+    let mut bytes = vec![];
+
+    for (i, part) in units.chunks(13).enumerate().rev() {
+        let mut entry = [0; DIR_ENTRY];
+
+        entry[0] = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (offset, unit) in offsets.zip(part) {
+            entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        bytes.extend(entry);
+    }
+
+    bytes
+}
+
+/// Just enough of a FAT32 reader to check what `write` wrote
+#[cfg(test)]
+fn read_file(image: &Path, offset: u64, path: &str) -> Result<Vec<u8>> {
+    let disk = std::fs::read(image)?;
+    let disk = &disk[offset as usize..];
+
+    let u16_at = |x: &[u8], i: usize| u16::from_le_bytes([x[i], x[i + 1]]) as u64;
+    let u32_at = |x: &[u8], i: usize| u32::from_le_bytes(x[i..i + 4].try_into().unwrap()) as u64;
+
+    let spc = disk[13] as u64;
+    let reserved = u16_at(disk, 14);
+    let fat_sectors = u32_at(disk, 36);
+    let fat = &disk[(reserved * SECTOR) as usize..];
+    let data = ((reserved + disk[16] as u64 * fat_sectors) * SECTOR) as usize;
+    let cluster_bytes = (spc * SECTOR) as usize;
+
+    let chain = |mut cluster: u64| -> Vec<u8> {
+        let mut bytes = vec![];
+        while (2..0x0FFF_FFF8).contains(&cluster) {
+            let start = data + (cluster as usize - 2) * cluster_bytes;
+            bytes.extend(&disk[start..start + cluster_bytes]);
+            cluster = u32_at(fat, cluster as usize * 4) & 0x0FFF_FFFF;
+        }
+        bytes
+    };
+
+    let mut dir = chain(u32_at(disk, 44));
+    let parts: Vec<&str> = path.split('/').collect();
+
+    for (i, part) in parts.iter().enumerate() {
+        let mut long = vec![];
+        let mut found = None;
+
+        for entry in dir.chunks(DIR_ENTRY) {
+            if entry[0] == 0 {
+                break;
+            }
+
+            if entry[11] == ATTR_LONG_NAME {
+                let units: Vec<u16> = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]
+                    .iter()
+                    .map(|x| u16_at(entry, *x) as u16)
+                    .take_while(|x| *x != 0)
+                    .collect();
+                long.insert(0, String::from_utf16_lossy(&units));
+                continue;
+            }
+
+            let name = if long.is_empty() {
+                let case = |x: &[u8], lower: bool| {
+                    let x = String::from_utf8_lossy(x).trim_end().to_string();
+                    if lower {
+                        x.to_lowercase()
+                    } else {
+                        x
+                    }
+                };
+                let base = case(&entry[0..8], entry[12] & NT_LOWER_BASE != 0);
+                let ext = case(&entry[8..11], entry[12] & NT_LOWER_EXT != 0);
+                if ext.is_empty() {
+                    base
+                } else {
+                    format!("{}.{}", base, ext)
+                }
+            } else {
+                long.concat()
+            };
+            long.clear();
+
+            if name == *part {
+                let cluster = u16_at(entry, 20) << 16 | u16_at(entry, 26);
+                found = Some((cluster, u32_at(entry, 28) as usize));
+                break;
+            }
+        }
+
+        let Some((cluster, len)) = found else {
+            bail!("no {} in {}", part, path);
+        };
+
+        dir = chain(cluster);
+        if i == parts.len() - 1 {
+            dir.truncate(len);
+        }
+    }
+
+    Ok(dir)
+}
+
+#[test]
+fn test_short_name() {
+    let mut used = BTreeSet::new();
+
+    assert_eq!(
+        short_name("BOOTX64.EFI", &mut used),
+        (*b"BOOTX64 EFI", Some(0))
+    );
+    assert_eq!(
+        short_name("grubx64.efi", &mut used),
+        (*b"GRUBX64 EFI", Some(NT_LOWER_BASE | NT_LOWER_EXT))
+    );
+    assert_eq!(short_name("EFI", &mut used), (*b"EFI        ", Some(0)));
+
+    assert_eq!(short_name("Debian", &mut used), (*b"DEBIAN~1   ", None));
+    assert_eq!(
+        short_name("a long name.conf", &mut used),
+        (*b"ALONGN~1CON", None)
+    );
+    assert_eq!(
+        short_name("a long name.conf2", &mut used),
+        (*b"ALONGN~2CON", None)
+    );
+}
+
+#[test]
+fn test_write_and_read_back() -> Result<()> {
+    const MIB: u64 = 1024 * 1024;
+
+    let dir = tempfile::tempdir()?;
+    let source = dir.path().join("esp");
+
+    let big: Vec<u8> = (0..5000u32).flat_map(|x| x.to_le_bytes()).collect();
+    for (path, contents) in [
+        ("EFI/BOOT/BOOTX64.EFI", &big[..]),
+        ("EFI/debian/grubx64.efi", b"grub"),
+        ("EFI/debian/grub.cfg", b"search.fs_uuid\n"),
+        ("EFI/debian/A Long File Name For A Config.txt", b"long"),
+        ("EFI/debian/empty", b""),
+    ] {
+        let path = source.join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)?;
+    }
+
+    let image = dir.path().join("disk.img");
+    File::create(&image)?.set_len(66 * MIB)?;
+
+    let fat = Fat32::new(MIB, 64 * MIB, 0x1A2B_3C4D);
+    fat.write(&image, Some(&source))?;
+    assert_eq!(fat.uuid(), "1A2B-3C4D");
+
+    assert_eq!(read_file(&image, MIB, "EFI/BOOT/BOOTX64.EFI")?, big);
+    assert_eq!(read_file(&image, MIB, "EFI/debian/grubx64.efi")?, b"grub");
+    assert_eq!(
+        read_file(&image, MIB, "EFI/debian/grub.cfg")?,
+        b"search.fs_uuid\n"
+    );
+    assert_eq!(
+        read_file(&image, MIB, "EFI/debian/A Long File Name For A Config.txt")?,
+        b"long"
+    );
+    assert_eq!(read_file(&image, MIB, "EFI/debian/empty")?, b"");
+    assert!(read_file(&image, MIB, "EFI/debian/missing").is_err());
+
+    let disk = std::fs::read(&image)?;
+    let boot = &disk[MIB as usize..][..SECTOR as usize];
+    assert_eq!(&boot[82..90], b"FAT32   ");
+    assert_eq!(&boot[67..71], &0x1A2B_3C4Du32.to_le_bytes());
+    assert_eq!(&boot[510..512], &[0x55, 0xAA]);
+
+    // The backup boot sector matches
+    assert_eq!(
+        boot,
+        &disk[(MIB + BACKUP_BOOT_SECTOR * SECTOR) as usize..][..SECTOR as usize]
+    );
+
+    // Nothing outside the filesystem was touched
+    assert!(disk[..MIB as usize].iter().all(|x| *x == 0));
+    assert!(disk[(65 * MIB) as usize..].iter().all(|x| *x == 0));
+
+    Ok(())
+}
+
+#[test]
+fn test_check() -> Result<()> {
+    const MIB: u64 = 1024 * 1024;
+
+    let dir = tempfile::tempdir()?;
+    let source = dir.path().join("esp");
+    std::fs::create_dir_all(source.join("EFI/BOOT"))?;
+    std::fs::write(source.join("EFI/BOOT/BOOTX64.EFI"), vec![7u8; 3000])?;
+    std::fs::write(source.join("EFI/BOOT/empty"), b"")?;
+
+    let image = dir.path().join("disk.img");
+    File::create(&image)?.set_len(66 * MIB)?;
+    let fat = Fat32::new(MIB, 64 * MIB, 0x1A2B_3C4D);
+    fat.write(&image, Some(&source))?;
+    fat.check(&image)?;
+
+    // Point the first FAT's entry for the root directory at a free cluster
+    let mut file = OpenOptions::new().write(true).open(&image)?;
+    file.seek(SeekFrom::Start(
+        MIB + RESERVED_SECTORS * SECTOR + ROOT_CLUSTER as u64 * 4,
+    ))?;
+    file.write_all(&1000u32.to_le_bytes())?;
+    drop(file);
+
+    let error = fat.check(&image).unwrap_err().to_string();
+    assert!(error.contains("the two FATs differ"), "{}", error);
+    assert!(error.contains("cluster 1000 is marked free"), "{}", error);
+
+    Ok(())
+}
+
+#[test]
+fn test_too_small() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("disk.img");
+    File::create(&image)?.set_len(16 * 1024 * 1024)?;
+
+    assert!(Fat32::new(0, 16 * 1024 * 1024, 0)
+        .write(&image, None)
+        .is_err());
+
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! A default-deny firewall for the image that lets in only the ports the
+//! container image EXPOSEs, and any others asked for: nftables rules, or
+//! ufw or firewalld configured offline in the chroot.
+
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+use crate::builder::Flavor;
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Firewall {
+    Nftables,
+    Ufw,
+    Firewalld,
+}
+
+impl Firewall {
+    /// The package that provides it
+    pub fn package(&self) -> &'static str {
+        match self {
+            Firewall::Nftables => "nftables",
+            Firewall::Ufw => "ufw",
+            Firewall::Firewalld => "firewalld",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+/// A port to let in, written like EXPOSE does: 80, 80/tcp or 53/udp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Port {
+    pub protocol: Protocol,
+    pub number: u16,
+}
+
+impl FromStr for Port {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (number, protocol) = s.split_once('/').unwrap_or((s, "tcp"));
+
+        let protocol = match protocol {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => bail!("unsupported protocol {:?} in port {:?}", protocol, s),
+        };
+
+        match number.parse() {
+            Ok(number) if number != 0 => Ok(Port { protocol, number }),
+            _ => bail!("bad port number {:?}", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Port {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.number, self.protocol.as_str())
+    }
+}
+
+/// An nftables ruleset dropping everything coming in but replies, loopback,
+/// ICMP and `ports`
+pub fn nftables_conf(ports: &[Port]) -> String {
+    let mut accept = String::new();
+
+    for protocol in [Protocol::Tcp, Protocol::Udp] {
+        let numbers: Vec<String> = ports
+            .iter()
+            .filter(|x| x.protocol == protocol)
+            .map(|x| x.number.to_string())
+            .collect();
+
+        match numbers.len() {
+            0 => {}
+            1 => writeln!(
+                accept,
+                "\t\t{} dport {} accept",
+                protocol.as_str(),
+                numbers[0]
+            )
+            .unwrap(),
+            _ => writeln!(
+                accept,
+                "\t\t{} dport {{ {} }} accept",
+                protocol.as_str(),
+                numbers.join(", ")
+            )
+            .unwrap(),
+        }
+    }
+
+    format!(
+        "#!/usr/sbin/nft -f\n\
+         \n\
+         flush ruleset\n\
+         \n\
+         table inet filter {{\n\
+         \tchain input {{\n\
+         \t\ttype filter hook input priority filter; policy drop;\n\
+         \t\tct state established,related accept\n\
+         \t\tct state invalid drop\n\
+         \t\tiif lo accept\n\
+         \t\tmeta l4proto {{ icmp, ipv6-icmp }} accept\n\
+         {}\
+         \t}}\n\
+         \n\
+         \tchain forward {{\n\
+         \t\ttype filter hook forward priority filter; policy drop;\n\
+         \t}}\n\
+         \n\
+         \tchain output {{\n\
+         \t\ttype filter hook output priority filter; policy accept;\n\
+         \t}}\n\
+         }}\n",
+        accept
+    )
+}
+
+/// Configure `firewall` in the image at `root` to let in `ports` and
+/// nothing else, and enable it. Its package must already be installed.
+pub fn install(root: &str, flavor: &Flavor, firewall: Firewall, ports: &[Port]) -> Result<()> {
+    let chroot = |args: &[&str]| {
+        let args: Vec<String> = std::iter::once(root)
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        run("chroot".into(), &args)
+    };
+
+    match (firewall, flavor) {
+        (Firewall::Nftables, Flavor::Debian | Flavor::Ubuntu) => {
+            std::fs::write(format!("{}/etc/nftables.conf", root), nftables_conf(ports))?;
+            chroot(&["systemctl", "enable", "nftables.service"])?;
+        }
+
+        (Firewall::Nftables, Flavor::Alpine) => {
+            std::fs::write(format!("{}/etc/nftables.nft", root), nftables_conf(ports))?;
+            chroot(&["rc-update", "add", "nftables", "default"])?;
+        }
+
+        // Both deny incoming by default. With the firewall not running,
+        // their commands only change the config.
+        (Firewall::Ufw, Flavor::Debian | Flavor::Ubuntu) => {
+            for port in ports {
+                chroot(&["ufw", "allow", &port.to_string()])?;
+            }
+
+            let path = format!("{}/etc/ufw/ufw.conf", root);
+            let existing = match std::fs::read_to_string(&path) {
+                Ok(existing) => existing,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e.into()),
+            };
+            let mut conf: String = existing
+                .lines()
+                .filter(|x| !x.starts_with("ENABLED="))
+                .map(|x| format!("{}\n", x))
+                .collect();
+            conf.push_str("ENABLED=yes\n");
+            std::fs::write(&path, conf)?;
+
+            chroot(&["systemctl", "enable", "ufw.service"])?;
+        }
+
+        (Firewall::Firewalld, Flavor::Debian | Flavor::Ubuntu) => {
+            // The default zone lets in ssh, which wasn't asked for
+            chroot(&["firewall-offline-cmd", "--remove-service=ssh"])?;
+
+            for port in ports {
+                chroot(&["firewall-offline-cmd", &format!("--add-port={}", port)])?;
+            }
+
+            chroot(&["systemctl", "enable", "firewalld.service"])?;
+        }
+
+        (_, Flavor::Alpine) => {
+            return Err(Error::UnsupportedFlavor {
+                flavor: flavor.clone(),
+                option: "--firewall ufw or firewalld",
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_port() -> Result<()> {
+    assert_eq!("80".parse::<Port>()?.to_string(), "80/tcp");
+    assert_eq!("53/udp".parse::<Port>()?.to_string(), "53/udp");
+
+    assert!("0".parse::<Port>().is_err());
+    assert!("65536/tcp".parse::<Port>().is_err());
+    assert!("80/sctp".parse::<Port>().is_err());
+    assert!("http".parse::<Port>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_nftables_conf() -> Result<()> {
+    let ports: Vec<Port> = ["22", "8080/tcp", "53/udp"]
+        .iter()
+        .map(|x| x.parse())
+        .collect::<Result<_>>()?;
+
+    let conf = nftables_conf(&ports);
+    assert!(conf.contains("\t\ttcp dport { 22, 8080 } accept\n"));
+    assert!(conf.contains("\t\tudp dport 53 accept\n"));
+    assert!(conf.contains("policy drop;"));
+
+    // Nothing exposed still leaves replies and loopback working
+    let conf = nftables_conf(&[]);
+    assert!(!conf.contains("dport"));
+    assert!(conf.contains("ct state established,related accept"));
+
+    Ok(())
+}
+
+#[test]
+fn test_install() -> Result<()> {
+    use std::rc::Rc;
+
+    let root = tempfile::tempdir()?;
+    std::fs::create_dir_all(root.path().join("etc/ufw"))?;
+    std::fs::write(
+        root.path().join("etc/ufw/ufw.conf"),
+        "# /etc/ufw/ufw.conf\nENABLED=no\nLOGLEVEL=low\n",
+    )?;
+    let root_path = root.path().to_str().unwrap();
+
+    let ports = ["8080/tcp".parse()?, "53/udp".parse()?];
+
+    let executor = Rc::new(RecordingExecutor::new(|_, _| Ok(String::new())));
+    let previous = set_executor(executor.clone());
+    let result = install(root_path, &Flavor::Ubuntu, Firewall::Ufw, &ports)
+        .and_then(|_| install(root_path, &Flavor::Debian, Firewall::Firewalld, &ports));
+    set_executor(previous);
+    result?;
+
+    let commands: Vec<String> = executor
+        .commands()
+        .iter()
+        .map(|x| x.args[1..].join(" "))
+        .collect();
+    assert_eq!(
+        commands,
+        [
+            "ufw allow 8080/tcp",
+            "ufw allow 53/udp",
+            "systemctl enable ufw.service",
+            "firewall-offline-cmd --remove-service=ssh",
+            "firewall-offline-cmd --add-port=8080/tcp",
+            "firewall-offline-cmd --add-port=53/udp",
+            "systemctl enable firewalld.service",
+        ]
+    );
+
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("etc/ufw/ufw.conf"))?,
+        "# /etc/ufw/ufw.conf\nLOGLEVEL=low\nENABLED=yes\n"
+    );
+
+    assert!(install(root_path, &Flavor::Alpine, Firewall::Ufw, &ports).is_err());
+
+    Ok(())
+}
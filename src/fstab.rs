@@ -0,0 +1,277 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! The image's /etc/fstab: an entry for each partition with options to
+//! suit a VM disk, which can then be changed per mountpoint.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+use crate::builder::Flavor;
+use crate::error::Error;
+use crate::ESP_MIRROR_MOUNTPOINT;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FstabEntry {
+    /// UUID=..., or what to mount for a pseudo filesystem
+    pub spec: String,
+    pub mountpoint: String,
+    pub fstype: String,
+    pub options: Vec<String>,
+    /// fsck's order, 0 for never
+    pub pass: u8,
+}
+
+impl FstabEntry {
+    pub fn new(spec: &str, mountpoint: &str, fstype: &str, options: &[&str], pass: u8) -> Self {
+        Self {
+            spec: spec.into(),
+            mountpoint: mountpoint.into(),
+            fstype: fstype.into(),
+            options: options.iter().map(|x| x.to_string()).collect(),
+            pass,
+        }
+    }
+}
+
+impl std::fmt::Display for FstabEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let options = if self.options.is_empty() {
+            "defaults".to_string()
+        } else {
+            self.options.join(",")
+        };
+
+        write!(
+            f,
+            "{} {} {} {} 0 {}",
+            self.spec, self.mountpoint, self.fstype, options, self.pass
+        )
+    }
+}
+
+/// Options to change for one mountpoint, like `/:noatime,-discard`: each
+/// is added, or with a leading - removed
+#[derive(Debug, Clone, PartialEq)]
+pub struct FstabOptions {
+    pub mountpoint: String,
+    pub options: Vec<String>,
+}
+
+impl FromStr for FstabOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((mountpoint, options)) = s.split_once(':') else {
+            bail!("expected MOUNTPOINT:OPTION[,OPTION...], not {:?}", s);
+        };
+
+        if !mountpoint.starts_with('/') || options.is_empty() {
+            bail!("expected MOUNTPOINT:OPTION[,OPTION...], not {:?}", s);
+        }
+
+        Ok(Self {
+            mountpoint: mountpoint.into(),
+            options: options.split(',').map(String::from).collect(),
+        })
+    }
+}
+
+/// The entries for an image's partitions, by filesystem UUID. Root is
+/// trimmed as files are deleted, which keeps thin-provisioned disks thin,
+/// and with systemd grows to fill its partition when that is made bigger.
+pub fn default_entries(
+    flavor: &Flavor,
+    root_uuid: &str,
+    esp_uuid: &str,
+    esp_mirror_uuid: Option<&str>,
+    read_only_root: bool,
+) -> Vec<FstabEntry> {
+    let systemd = !matches!(flavor, Flavor::Alpine);
+
+    let mut root_options = vec![];
+    let mut esp_options = vec![];
+
+    // Alpine's overlaytmpfs root has to stay mounted read-write
+    if read_only_root && systemd {
+        root_options.push("ro");
+        esp_options.push("ro");
+    }
+    root_options.extend(["errors=remount-ro", "discard"]);
+
+    // A read-only filesystem can't be grown online
+    if systemd && !read_only_root {
+        root_options.push("x-systemd.growfs");
+    }
+
+    let mut entries = vec![
+        FstabEntry::new(
+            &format!("UUID={}", root_uuid),
+            "/",
+            "ext4",
+            &root_options,
+            1,
+        ),
+        FstabEntry::new(
+            &format!("UUID={}", esp_uuid),
+            "/boot/efi",
+            "vfat",
+            &esp_options,
+            2,
+        ),
+    ];
+
+    if let Some(uuid) = esp_mirror_uuid {
+        entries.push(FstabEntry::new(
+            &format!("UUID={}", uuid),
+            ESP_MIRROR_MOUNTPOINT,
+            "vfat",
+            &["noauto", "nofail"],
+            0,
+        ));
+    }
+
+    // /tmp isn't overlaid, so it is a tmpfs of its own
+    if read_only_root && systemd {
+        entries.push(FstabEntry::new(
+            "tmpfs",
+            "/tmp",
+            "tmpfs",
+            &["nosuid", "nodev"],
+            0,
+        ));
+    }
+
+    entries
+}
+
+/// Change `entries`' options as `changes` say
+pub fn apply(entries: &mut [FstabEntry], changes: &[FstabOptions]) -> Result<()> {
+    for change in changes {
+        let Some(entry) = entries
+            .iter_mut()
+            .find(|x| x.mountpoint == change.mountpoint)
+        else {
+            return Err(Error::InvalidOptions(format!(
+                "no fstab entry for {}, only {}",
+                change.mountpoint,
+                entries
+                    .iter()
+                    .map(|x| x.mountpoint.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+            .into());
+        };
+
+        for option in &change.options {
+            match option.strip_prefix('-') {
+                Some(option) => entry.options.retain(|x| x != option),
+                None if option == "defaults" => {}
+                None => {
+                    if !entry.options.contains(option) {
+                        entry.options.push(option.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Alpine's weekly trim, run by crond
+pub const ALPINE_FSTRIM_SCRIPT: &str = "/etc/periodic/weekly/fstrim";
+
+/// For `fstrim`: not writing access times saves a write for every read
+pub fn fstrim_options() -> Vec<FstabOptions> {
+    vec![FstabOptions {
+        mountpoint: "/".into(),
+        options: vec!["noatime".into(), "discard".into()],
+    }]
+}
+
+/// What runs the periodic trim: util-linux's timer, or crond on Alpine
+pub fn fstrim_services(flavor: &Flavor) -> &'static [&'static str] {
+    match flavor {
+        Flavor::Debian | Flavor::Ubuntu => &["fstrim.timer"],
+        Flavor::Alpine => &["crond"],
+    }
+}
+
+/// Write Alpine's weekly trim script into the image at `root`
+pub fn install_fstrim_script(root: &str) -> Result<()> {
+    let path = format!("{}{}", root, ALPINE_FSTRIM_SCRIPT);
+    std::fs::create_dir_all(std::path::Path::new(&path).parent().unwrap())?;
+    std::fs::write(&path, "#!/bin/sh\nexec fstrim /\n")?;
+    std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+    Ok(())
+}
+
+/// The whole file
+pub fn fstab(entries: &[FstabEntry]) -> String {
+    entries.iter().map(|x| format!("{}\n", x)).collect()
+}
+
+#[test]
+fn test_default_entries() {
+    assert_eq!(
+        fstab(&default_entries(&Flavor::Debian, "r", "e", None, false)),
+        "UUID=r / ext4 errors=remount-ro,discard,x-systemd.growfs 0 1\n\
+         UUID=e /boot/efi vfat defaults 0 2\n"
+    );
+
+    // busybox's mount would hand x-systemd options to the kernel
+    assert_eq!(
+        fstab(&default_entries(&Flavor::Alpine, "r", "e", Some("m"), true)),
+        "UUID=r / ext4 errors=remount-ro,discard 0 1\n\
+         UUID=e /boot/efi vfat defaults 0 2\n\
+         UUID=m /boot/efi2 vfat noauto,nofail 0 0\n"
+    );
+
+    assert_eq!(
+        fstab(&default_entries(&Flavor::Ubuntu, "r", "e", None, true)),
+        "UUID=r / ext4 ro,errors=remount-ro,discard 0 1\n\
+         UUID=e /boot/efi vfat ro 0 2\n\
+         tmpfs /tmp tmpfs nosuid,nodev 0 0\n"
+    );
+}
+
+#[test]
+fn test_apply() -> Result<()> {
+    let mut entries = default_entries(&Flavor::Debian, "r", "e", None, false);
+
+    apply(
+        &mut entries,
+        &[
+            "/:noatime,-discard,errors=remount-ro".parse()?,
+            "/boot/efi:umask=0077".parse()?,
+        ],
+    )?;
+    assert_eq!(
+        fstab(&entries),
+        "UUID=r / ext4 errors=remount-ro,x-systemd.growfs,noatime 0 1\n\
+         UUID=e /boot/efi vfat umask=0077 0 2\n"
+    );
+
+    // Taking every option away leaves defaults
+    apply(&mut entries, &["/boot/efi:-umask=0077".parse()?])?;
+    assert_eq!(entries[1].to_string(), "UUID=e /boot/efi vfat defaults 0 2");
+
+    assert!(apply(&mut entries, &["/srv:noatime".parse()?]).is_err());
+
+    // What --fstrim adds is already there but for noatime
+    apply(&mut entries, &fstrim_options())?;
+    assert_eq!(
+        entries[0].to_string(),
+        "UUID=r / ext4 errors=remount-ro,x-systemd.growfs,noatime,discard 0 1"
+    );
+    assert!("noatime".parse::<FstabOptions>().is_err());
+    assert!("/:".parse::<FstabOptions>().is_err());
+
+    Ok(())
+}
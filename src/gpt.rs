@@ -0,0 +1,940 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! GUID partition tables, read from and written straight into an image file,
+//! so laying out a disk needs neither root nor sgdisk. See chapter 5 of the
+//! UEFI specification.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub const SECTOR: u64 = 512;
+
+/// Partitions start on 1 MiB boundaries, as sgdisk and parted do
+const ALIGN: u64 = 1024 * 1024 / SECTOR;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: u32 = 92;
+const ENTRY_COUNT: u32 = 128;
+const ENTRY_SIZE: u32 = 128;
+const ENTRY_SECTORS: u64 = (ENTRY_COUNT * ENTRY_SIZE) as u64 / SECTOR;
+/// The most entries read from a disk's header claims, in bytes
+const MAX_ENTRIES_SIZE: u32 = 1024 * 1024;
+
+/// Partition names are UTF-16, in 36 code units
+const NAME_UNITS: usize = 36;
+
+pub const BIOS_BOOT: Uuid = Uuid::from_u128(0x21686148_6449_6E6F_744E_656564454649);
+pub const EFI_SYSTEM: Uuid = Uuid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B);
+pub const LINUX_FILESYSTEM: Uuid = Uuid::from_u128(0x0FC63DAF_8483_4772_8E79_3D69D8477DE4);
+pub const LINUX_SWAP: Uuid = Uuid::from_u128(0x0657FD6D_A4AB_43C4_84E5_0933C84B4F4F);
+/// The Discoverable Partitions Specification's x86-64 root
+pub const LINUX_ROOT_X86_64: Uuid = Uuid::from_u128(0x4F68BCE3_E8CD_4DB1_96E7_FBCAF984B709);
+/// The Boot Loader Specification's extended boot partition
+pub const XBOOTLDR: Uuid = Uuid::from_u128(0xBC13C2FF_59E6_4262_A352_B275FD6F7172);
+
+/// Types a root partition can have
+pub const ROOT_TYPES: &[Uuid] = &[LINUX_FILESYSTEM, LINUX_ROOT_X86_64];
+
+/// Names for partition types, besides their GUIDs
+const TYPE_NAMES: &[(&str, Uuid)] = &[
+    ("bios-boot", BIOS_BOOT),
+    ("esp", EFI_SYSTEM),
+    ("linux", LINUX_FILESYSTEM),
+    ("swap", LINUX_SWAP),
+    ("root-x86-64", LINUX_ROOT_X86_64),
+    ("xbootldr", XBOOTLDR),
+];
+
+/// Names for attribute bits, besides their numbers: the three every
+/// partition can have, then those of basic data and discoverable partitions
+const ATTRIBUTE_NAMES: &[(&str, u32)] = &[
+    ("required", 0),
+    ("no-block-io", 1),
+    ("legacy-bios-bootable", 2),
+    ("grow-file-system", 59),
+    ("read-only", 60),
+    ("hidden", 62),
+    ("no-automount", 63),
+];
+
+/// A random-looking GUID for `what`, always the same for the same `seed`
+pub fn derive_guid(seed: Uuid, what: &str) -> Uuid {
+    let hash = Sha256::new()
+        .chain_update(seed.as_bytes())
+        .chain_update(what.as_bytes())
+        .finalize();
+
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash[..16]);
+
+    uuid::Builder::from_bytes(bytes)
+        .set_variant(uuid::Variant::RFC4122)
+        .set_version(uuid::Version::Random)
+        .build()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub type_guid: Uuid,
+    pub unique_guid: Uuid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl Partition {
+    pub fn size(&self) -> u64 {
+        (self.last_lba - self.first_lba + 1) * SECTOR
+    }
+
+    /// Whether it starts on a 1 MiB boundary
+    pub fn is_aligned(&self) -> bool {
+        self.first_lba.is_multiple_of(ALIGN)
+    }
+}
+
+/// How big a new partition is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    /// Exactly this many bytes
+    Bytes(u64),
+
+    /// Up to this many bytes before the end of the disk
+    AllBut(u64),
+}
+
+/// Changes to one partition of a layout, like
+/// `3:type=root-x86-64,name=root,attribute=no-automount`. Given
+/// attributes replace the partition's.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionChange {
+    pub number: u32,
+    pub type_guid: Option<Uuid>,
+    pub name: Option<String>,
+    pub attributes: Option<u64>,
+}
+
+impl FromStr for PartitionChange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let usage = || {
+            anyhow::anyhow!(
+                "expected NUMBER:type=TYPE,name=NAME,attribute=ATTRIBUTE..., not {:?}",
+                s
+            )
+        };
+
+        let (number, changes) = s.split_once(':').ok_or_else(usage)?;
+        let mut change = Self {
+            number: number.parse().map_err(|_| usage())?,
+            type_guid: None,
+            name: None,
+            attributes: None,
+        };
+
+        for x in changes.split(',') {
+            match x.split_once('=').ok_or_else(usage)? {
+                ("type", value) => {
+                    let type_guid = match TYPE_NAMES.iter().find(|(name, _)| *name == value) {
+                        Some((_, type_guid)) => *type_guid,
+                        None => value
+                            .parse()
+                            .map_err(|_| anyhow::anyhow!("unknown partition type {:?}", value))?,
+                    };
+                    change.type_guid = Some(type_guid);
+                }
+                ("name", value) => change.name = Some(value.into()),
+                ("attribute", value) => {
+                    let bit = match ATTRIBUTE_NAMES.iter().find(|(name, _)| *name == value) {
+                        Some((_, bit)) => *bit,
+                        None => match value.parse() {
+                            Ok(bit) if bit < 64 => bit,
+                            _ => bail!("unknown partition attribute {:?}", value),
+                        },
+                    };
+                    *change.attributes.get_or_insert(0) |= 1 << bit;
+                }
+                _ => return Err(usage()),
+            }
+        }
+
+        Ok(change)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gpt {
+    pub disk_guid: Uuid,
+
+    /// The size of the disk in sectors
+    pub sectors: u64,
+
+    /// By partition number, starting at 1
+    pub partitions: BTreeMap<u32, Partition>,
+}
+
+impl Gpt {
+    /// An empty table for a disk of `bytes`
+    pub fn new(bytes: u64) -> Self {
+        Self {
+            disk_guid: Uuid::new_v4(),
+            sectors: bytes / SECTOR,
+            partitions: BTreeMap::new(),
+        }
+    }
+
+    /// The layout images are built with: a BIOS boot partition (unused, but
+    /// kept for hybrid boot), the ESP, and root up to 100 MiB before the end
+    pub fn default_layout(bytes: u64) -> Result<Self> {
+        const MIB: u64 = 1024 * 1024;
+
+        let mut gpt = Self::new(bytes);
+        gpt.add("BIOS Boot Partition", BIOS_BOOT, Size::Bytes(2 * MIB))?;
+        gpt.add("EFI System Partition", EFI_SYSTEM, Size::Bytes(512 * MIB))?;
+        // XXX cloud-init cannot grow root partition if swap is right after!
+        gpt.add("Root Partition", LINUX_FILESYSTEM, Size::AllBut(100 * MIB))?;
+
+        Ok(gpt)
+    }
+
+    /// The default layout with a second ESP, to hold a copy of the first,
+    /// between it and root. Root is still partition 3 and last on the disk,
+    /// so it can grow and shrink as before; the mirror is partition 4.
+    pub fn mirrored_esp_layout(bytes: u64) -> Result<Self> {
+        const MIB: u64 = 1024 * 1024;
+
+        let mut gpt = Self::new(bytes);
+        gpt.add("BIOS Boot Partition", BIOS_BOOT, Size::Bytes(2 * MIB))?;
+        gpt.add("EFI System Partition", EFI_SYSTEM, Size::Bytes(512 * MIB))?;
+        let mirror = gpt.add(
+            "EFI System Partition Mirror",
+            EFI_SYSTEM,
+            Size::Bytes(512 * MIB),
+        )?;
+        let root = gpt.add("Root Partition", LINUX_FILESYSTEM, Size::AllBut(100 * MIB))?;
+
+        // Entries needn't be in disk order
+        let mirror = gpt.partitions.remove(&mirror).unwrap();
+        let root = gpt.partitions.remove(&root).unwrap();
+        gpt.partitions.insert(3, root);
+        gpt.partitions.insert(4, mirror);
+
+        Ok(gpt)
+    }
+
+    /// Give the disk `disk_guid`, and each partition a GUID derived from it
+    /// and the partition's number, so the same GUID makes the same table
+    pub fn set_disk_guid(&mut self, disk_guid: Uuid) {
+        self.disk_guid = disk_guid;
+
+        for (number, partition) in &mut self.partitions {
+            partition.unique_guid = derive_guid(disk_guid, &format!("partition {}", number));
+        }
+    }
+
+    pub fn first_usable_lba(&self) -> u64 {
+        2 + ENTRY_SECTORS
+    }
+
+    pub fn last_usable_lba(&self) -> u64 {
+        self.sectors.saturating_sub(2 + ENTRY_SECTORS)
+    }
+
+    pub fn partition(&self, number: u32) -> Option<&Partition> {
+        self.partitions.get(&number)
+    }
+
+    pub fn partition_mut(&mut self, number: u32) -> Option<&mut Partition> {
+        self.partitions.get_mut(&number)
+    }
+
+    /// The number of the first partition of any of `type_guids`
+    pub fn first_of(&self, type_guids: &[Uuid]) -> Option<u32> {
+        self.partitions
+            .iter()
+            .find(|(_, x)| type_guids.contains(&x.type_guid))
+            .map(|(number, _)| *number)
+    }
+
+    /// Add a partition after the last one, aligned to 1 MiB, with the lowest
+    /// free number, and return that number
+    pub fn add(&mut self, name: &str, type_guid: Uuid, size: Size) -> Result<u32> {
+        let after = self
+            .partitions
+            .values()
+            .map(|x| x.last_lba + 1)
+            .max()
+            .unwrap_or(0)
+            .max(self.first_usable_lba());
+        let first_lba = after.div_ceil(ALIGN) * ALIGN;
+
+        let last_lba = match size {
+            Size::Bytes(bytes) => first_lba + bytes.div_ceil(SECTOR) - 1,
+            Size::AllBut(bytes) => match (self.last_usable_lba() + 1)
+                .checked_sub(bytes.div_ceil(SECTOR))
+                .map(|x| x / ALIGN * ALIGN)
+            {
+                Some(end) if end > 0 => end - 1,
+                _ => bail!("disk too small for partition {:?}", name),
+            },
+        };
+
+        if last_lba < first_lba || last_lba > self.last_usable_lba() {
+            bail!("no room on the disk for partition {:?}", name);
+        }
+
+        let number = (1..=ENTRY_COUNT)
+            .find(|x| !self.partitions.contains_key(x))
+            .unwrap();
+
+        self.partitions.insert(
+            number,
+            Partition {
+                type_guid,
+                unique_guid: Uuid::new_v4(),
+                first_lba,
+                last_lba,
+                attributes: 0,
+                name: name.to_string(),
+            },
+        );
+
+        Ok(number)
+    }
+
+    /// Give the last `bytes` of partition `number`, rounded up to 1 MiB, to
+    /// a new partition with the lowest free number, and return that number
+    pub fn split_end(
+        &mut self,
+        number: u32,
+        name: &str,
+        type_guid: Uuid,
+        bytes: u64,
+    ) -> Result<u32> {
+        let Some(partition) = self.partitions.get_mut(&number) else {
+            bail!("no partition {} to split", number);
+        };
+
+        let sectors = bytes.div_ceil(SECTOR).div_ceil(ALIGN) * ALIGN;
+        let last_lba = partition.last_lba;
+        let first_lba = (last_lba + 1).saturating_sub(sectors);
+
+        if first_lba <= partition.first_lba {
+            bail!("no room in partition {} for partition {:?}", number, name);
+        }
+        partition.last_lba = first_lba - 1;
+
+        let new_number = (1..=ENTRY_COUNT)
+            .find(|x| !self.partitions.contains_key(x))
+            .unwrap();
+
+        self.partitions.insert(
+            new_number,
+            Partition {
+                type_guid,
+                unique_guid: Uuid::new_v4(),
+                first_lba,
+                last_lba,
+                attributes: 0,
+                name: name.to_string(),
+            },
+        );
+
+        Ok(new_number)
+    }
+
+    /// Make `changes` to the partitions. An ESP has to stay one, and root,
+    /// the first partition of a root type, keep a root type, or the image
+    /// wouldn't boot.
+    pub fn change(&mut self, changes: &[PartitionChange]) -> Result<()> {
+        let root = self
+            .partitions
+            .iter()
+            .find(|(_, x)| ROOT_TYPES.contains(&x.type_guid))
+            .map(|(number, _)| *number);
+
+        for change in changes {
+            let Some(partition) = self.partitions.get_mut(&change.number) else {
+                bail!("the layout has no partition {}", change.number);
+            };
+
+            if let Some(type_guid) = change.type_guid {
+                if partition.type_guid == EFI_SYSTEM && type_guid != EFI_SYSTEM {
+                    bail!(
+                        "partition {} is an ESP, its type can't change",
+                        change.number
+                    );
+                }
+
+                if root == Some(change.number) && !ROOT_TYPES.contains(&type_guid) {
+                    bail!("partition {} is root, it needs a root type", change.number);
+                }
+
+                partition.type_guid = type_guid;
+            }
+
+            if let Some(name) = &change.name {
+                partition.name = name.clone();
+            }
+
+            if let Some(attributes) = change.attributes {
+                partition.attributes = attributes;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the partitions are on the disk and don't overlap
+    pub fn check(&self) -> Result<()> {
+        let mut previous: Option<(u32, &Partition)> = None;
+
+        let mut by_start: Vec<_> = self.partitions.iter().collect();
+        by_start.sort_by_key(|(_, x)| x.first_lba);
+
+        for (number, partition) in by_start {
+            if partition.first_lba < self.first_usable_lba()
+                || partition.last_lba > self.last_usable_lba()
+                || partition.last_lba < partition.first_lba
+            {
+                bail!("partition {} is not within the usable sectors", number);
+            }
+
+            if let Some((previous_number, previous)) = previous {
+                if previous.last_lba >= partition.first_lba {
+                    bail!("partitions {} and {} overlap", previous_number, number);
+                }
+            }
+
+            previous = Some((*number, partition));
+        }
+
+        Ok(())
+    }
+
+    /// Read the primary table from `path`
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::read_at(path, 1, "GPT")
+    }
+
+    /// Read the backup table from the end of `path`. It should be the same
+    /// as the primary.
+    pub fn read_backup(path: &Path) -> Result<Self> {
+        let sectors = std::fs::metadata(path)?.len() / SECTOR;
+        if sectors == 0 {
+            bail!("{:?} is empty", path);
+        }
+
+        Self::read_at(path, sectors - 1, "backup GPT")
+    }
+
+    /// Read the table whose header is at `lba`, `what` naming it in errors
+    fn read_at(path: &Path, lba: u64, what: &str) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let sectors = file.metadata()?.len() / SECTOR;
+
+        let mut header = [0; SECTOR as usize];
+        file.seek(SeekFrom::Start(lba * SECTOR))?;
+        file.read_exact(&mut header)?;
+
+        if &header[0..8] != SIGNATURE || u64_at(&header, 24) != lba {
+            bail!("{:?} has no {}", path, what);
+        }
+
+        let header_size = u32_at(&header, 12);
+        if !(HEADER_SIZE..=SECTOR as u32).contains(&header_size) {
+            bail!("{:?} has a {} header of {} bytes", path, what, header_size);
+        }
+
+        let mut checked = header[..header_size as usize].to_vec();
+        checked[16..20].fill(0);
+        if crc32(&checked) != u32_at(&header, 16) {
+            bail!("{:?} has a corrupt {} header", path, what);
+        }
+
+        let entry_lba = u64_at(&header, 72);
+        let entry_count = u32_at(&header, 80);
+        let entry_size = u32_at(&header, 84);
+        if entry_size < ENTRY_SIZE || !entry_size.is_multiple_of(8) {
+            bail!("{:?} has unexpected {} entries", path, what);
+        }
+
+        // Bounded, as the header is whatever was on the disk
+        let (Some(entries_size), Some(entries_offset)) = (
+            entry_count
+                .checked_mul(entry_size)
+                .filter(|x| *x <= MAX_ENTRIES_SIZE),
+            entry_lba.checked_mul(SECTOR),
+        ) else {
+            bail!("{:?} has unexpected {} entries", path, what);
+        };
+
+        let mut entries = vec![0; entries_size as usize];
+        file.seek(SeekFrom::Start(entries_offset))?;
+        file.read_exact(&mut entries)?;
+
+        if crc32(&entries) != u32_at(&header, 88) {
+            bail!("{:?} has corrupt {} entries", path, what);
+        }
+
+        let mut partitions = BTreeMap::new();
+        for (i, entry) in entries.chunks(entry_size as usize).enumerate() {
+            let type_guid = guid_from_bytes(&entry[0..16]);
+            if type_guid.is_nil() {
+                continue;
+            }
+
+            let units: Vec<u16> = entry[56..56 + NAME_UNITS * 2]
+                .chunks(2)
+                .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                .take_while(|x| *x != 0)
+                .collect();
+
+            partitions.insert(
+                i as u32 + 1,
+                Partition {
+                    type_guid,
+                    unique_guid: guid_from_bytes(&entry[16..32]),
+                    first_lba: u64_at(entry, 32),
+                    last_lba: u64_at(entry, 40),
+                    attributes: u64_at(entry, 48),
+                    name: String::from_utf16_lossy(&units),
+                },
+            );
+        }
+
+        Ok(Self {
+            disk_guid: guid_from_bytes(&header[56..72]),
+            sectors,
+            partitions,
+        })
+    }
+
+    /// Write a protective MBR and the primary and backup tables into the
+    /// image at `path`, which must already be `sectors` long. Nothing
+    /// between the tables is touched.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        self.check()?;
+
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        if file.metadata()?.len() / SECTOR != self.sectors {
+            bail!("{:?} is not {} sectors long", path, self.sectors);
+        }
+
+        let mut entries = vec![0; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+        for (number, partition) in &self.partitions {
+            if *number == 0 || *number > ENTRY_COUNT {
+                bail!("no partition entry for partition {}", number);
+            }
+
+            let entry = &mut entries[((number - 1) * ENTRY_SIZE) as usize..][..ENTRY_SIZE as usize];
+            entry[0..16].copy_from_slice(&guid_to_bytes(&partition.type_guid));
+            entry[16..32].copy_from_slice(&guid_to_bytes(&partition.unique_guid));
+            entry[32..40].copy_from_slice(&partition.first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&partition.last_lba.to_le_bytes());
+            entry[48..56].copy_from_slice(&partition.attributes.to_le_bytes());
+
+            let units: Vec<u16> = partition.name.encode_utf16().collect();
+            if units.len() > NAME_UNITS {
+                bail!("partition name {:?} is too long", partition.name);
+            }
+            for (i, unit) in units.iter().enumerate() {
+                entry[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+
+        let last_lba = self.sectors - 1;
+        let backup_entry_lba = last_lba - ENTRY_SECTORS;
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.protective_mbr())?;
+        file.write_all(&self.header(1, last_lba, 2, &entries))?;
+        file.write_all(&entries)?;
+
+        file.seek(SeekFrom::Start(backup_entry_lba * SECTOR))?;
+        file.write_all(&entries)?;
+        file.write_all(&self.header(last_lba, 1, backup_entry_lba, &entries))?;
+
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// One partition of type 0xEE covering the disk, so that tools which
+    /// only know MBR leave it alone
+    fn protective_mbr(&self) -> [u8; SECTOR as usize] {
+        let mut mbr = [0; SECTOR as usize];
+
+        let entry = &mut mbr[446..462];
+        entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+        entry[4] = 0xEE;
+        entry[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+        entry[12..16]
+            .copy_from_slice(&((self.sectors - 1).min(u32::MAX as u64) as u32).to_le_bytes());
+
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+
+        mbr
+    }
+
+    fn header(
+        &self,
+        my_lba: u64,
+        alternate_lba: u64,
+        entry_lba: u64,
+        entries: &[u8],
+    ) -> [u8; SECTOR as usize] {
+        let mut header = [0; SECTOR as usize];
+
+        header[0..8].copy_from_slice(SIGNATURE);
+        header[8..12].copy_from_slice(&REVISION.to_le_bytes());
+        header[12..16].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable_lba().to_le_bytes());
+        header[48..56].copy_from_slice(&self.last_usable_lba().to_le_bytes());
+        header[56..72].copy_from_slice(&guid_to_bytes(&self.disk_guid));
+        header[72..80].copy_from_slice(&entry_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&ENTRY_COUNT.to_le_bytes());
+        header[84..88].copy_from_slice(&ENTRY_SIZE.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+
+        let crc = crc32(&header[..HEADER_SIZE as usize]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        header
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// GUIDs are stored with their first three fields little endian
+fn guid_to_bytes(guid: &Uuid) -> [u8; 16] {
+    let mut bytes = *guid.as_bytes();
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
+
+fn guid_from_bytes(bytes: &[u8]) -> Uuid {
+    let mut bytes: [u8; 16] = bytes.try_into().unwrap();
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Uuid::from_bytes(bytes)
+}
+
+/// CRC-32 as used by zlib and Ethernet
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test]
+fn test_default_layout() -> Result<()> {
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    let gpt = Gpt::default_layout(8 * GIB)?;
+
+    // Where sgdisk put them, with root ending on a MiB boundary
+    let lbas: Vec<(u32, u64, u64)> = gpt
+        .partitions
+        .iter()
+        .map(|(n, x)| (*n, x.first_lba, x.last_lba))
+        .collect();
+    assert_eq!(
+        lbas,
+        [(1, 2048, 6143), (2, 6144, 1054719), (3, 1054720, 16570367)]
+    );
+
+    assert_eq!(gpt.partition(2).unwrap().type_guid, EFI_SYSTEM);
+
+    Ok(())
+}
+
+#[test]
+fn test_split_end() -> Result<()> {
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    let mut gpt = Gpt::default_layout(8 * GIB)?;
+    let root_end = gpt.partition(3).unwrap().last_lba;
+
+    assert_eq!(
+        gpt.split_end(3, "Persistence", LINUX_FILESYSTEM, 2 * GIB)?,
+        4
+    );
+    gpt.check()?;
+
+    let persistence = gpt.partition(4).unwrap();
+    assert_eq!(persistence.last_lba, root_end);
+    assert_eq!(persistence.size(), 2 * GIB);
+    assert_eq!(
+        gpt.partition(3).unwrap().last_lba + 1,
+        persistence.first_lba
+    );
+
+    assert!(gpt
+        .split_end(3, "Too big", LINUX_FILESYSTEM, 8 * GIB)
+        .is_err());
+    assert!(gpt.split_end(9, "Nothing", LINUX_FILESYSTEM, GIB).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_partition_change() -> Result<()> {
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    let change: PartitionChange =
+        "3:type=root-x86-64,name=root,attribute=no-automount,attribute=2".parse()?;
+    assert_eq!(
+        change,
+        PartitionChange {
+            number: 3,
+            type_guid: Some(LINUX_ROOT_X86_64),
+            name: Some("root".into()),
+            attributes: Some(1 << 63 | 1 << 2),
+        }
+    );
+
+    let change: PartitionChange = "1:type=bc13c2ff-59e6-4262-a352-b275fd6f7172".parse()?;
+    assert_eq!(change.type_guid, Some(XBOOTLDR));
+
+    assert!("3".parse::<PartitionChange>().is_err());
+    assert!("3:type=nope".parse::<PartitionChange>().is_err());
+    assert!("3:attribute=64".parse::<PartitionChange>().is_err());
+    assert!("3:size=1".parse::<PartitionChange>().is_err());
+
+    let mut gpt = Gpt::default_layout(8 * GIB)?;
+    gpt.change(&["3:type=root-x86-64,name=root,attribute=read-only".parse()?])?;
+    let root = gpt.partition(3).unwrap();
+    assert_eq!(root.type_guid, LINUX_ROOT_X86_64);
+    assert_eq!(root.name, "root");
+    assert_eq!(root.attributes, 1 << 60);
+
+    assert!(gpt.change(&["2:type=linux".parse()?]).is_err());
+    assert!(gpt.change(&["3:type=swap".parse()?]).is_err());
+    assert!(gpt.change(&["4:name=data".parse()?]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_mirrored_esp_layout() -> Result<()> {
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    let gpt = Gpt::mirrored_esp_layout(8 * GIB)?;
+
+    let lbas: Vec<(u32, u64, u64)> = gpt
+        .partitions
+        .iter()
+        .map(|(n, x)| (*n, x.first_lba, x.last_lba))
+        .collect();
+    assert_eq!(
+        lbas,
+        [
+            (1, 2048, 6143),
+            (2, 6144, 1054719),
+            (3, 2103296, 16570367),
+            (4, 1054720, 2103295)
+        ]
+    );
+
+    assert_eq!(gpt.partition(3).unwrap().type_guid, LINUX_FILESYSTEM);
+    assert_eq!(gpt.partition(4).unwrap().type_guid, EFI_SYSTEM);
+    assert_eq!(
+        gpt.partition(4).unwrap().size(),
+        gpt.partition(2).unwrap().size()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_set_disk_guid() -> Result<()> {
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    let disk_guid = Uuid::parse_str("5f3c2a1e-8b4d-4e6f-9a0b-1c2d3e4f5a6b")?;
+    let mut first = Gpt::default_layout(8 * GIB)?;
+    first.set_disk_guid(disk_guid);
+    let mut second = Gpt::default_layout(8 * GIB)?;
+    second.set_disk_guid(disk_guid);
+
+    assert_eq!(first, second);
+    assert_eq!(first.disk_guid, disk_guid);
+
+    let guids: std::collections::BTreeSet<Uuid> =
+        first.partitions.values().map(|x| x.unique_guid).collect();
+    assert_eq!(guids.len(), 3);
+    assert!(guids
+        .iter()
+        .all(|x| x.get_version() == Some(uuid::Version::Random)));
+
+    Ok(())
+}
+
+#[test]
+fn test_write_then_read() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("disk.img");
+    File::create(&image)?.set_len(64 * 1024 * 1024)?;
+
+    let mut gpt = Gpt::new(64 * 1024 * 1024);
+    gpt.add(
+        "EFI System Partition",
+        EFI_SYSTEM,
+        Size::Bytes(8 * 1024 * 1024),
+    )?;
+    gpt.add("Root Partition", LINUX_FILESYSTEM, Size::AllBut(0))?;
+    gpt.write(&image)?;
+
+    assert_eq!(Gpt::read(&image)?, gpt);
+    assert_eq!(Gpt::read_backup(&image)?, gpt);
+    assert!(gpt.partitions.values().all(Partition::is_aligned));
+
+    let bytes = std::fs::read(&image)?;
+    assert_eq!(&bytes[510..512], &[0x55, 0xAA]);
+    assert_eq!(bytes[446 + 4], 0xEE);
+
+    // The backup header is in the last sector, pointing back at the primary
+    let backup = &bytes[bytes.len() - SECTOR as usize..];
+    assert_eq!(&backup[0..8], SIGNATURE);
+    assert_eq!(u64_at(backup, 32), 1);
+
+    // Type GUIDs are mixed endian on disk
+    assert_eq!(
+        &bytes[2 * SECTOR as usize..][..16],
+        &[
+            0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+            0xC9, 0x3B
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_overlap_is_refused() -> Result<()> {
+    let mut gpt = Gpt::new(64 * 1024 * 1024);
+    gpt.add("a", LINUX_FILESYSTEM, Size::Bytes(8 * 1024 * 1024))?;
+    gpt.add("b", LINUX_FILESYSTEM, Size::Bytes(8 * 1024 * 1024))?;
+
+    gpt.partition_mut(1).unwrap().last_lba = gpt.partition(2).unwrap().first_lba;
+    assert!(gpt.check().is_err());
+
+    assert!(gpt
+        .add("c", LINUX_FILESYSTEM, Size::Bytes(64 * 1024 * 1024))
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_backup_is_checked() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("disk.img");
+    File::create(&image)?.set_len(64 * 1024 * 1024)?;
+
+    let mut gpt = Gpt::new(64 * 1024 * 1024);
+    gpt.add("Root Partition", LINUX_FILESYSTEM, Size::AllBut(0))?;
+    gpt.write(&image)?;
+
+    // Growing the image leaves the backup behind, short of the new end
+    OpenOptions::new()
+        .write(true)
+        .open(&image)?
+        .set_len(65 * 1024 * 1024)?;
+    assert_eq!(Gpt::read(&image)?.partitions, gpt.partitions);
+    assert!(Gpt::read_backup(&image).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_disk_too_small() {
+    let mut gpt = Gpt::new(4 * 1024 * 1024);
+    assert!(gpt
+        .add(
+            "Root Partition",
+            LINUX_FILESYSTEM,
+            Size::AllBut(8 * 1024 * 1024)
+        )
+        .is_err());
+    assert!(gpt
+        .add("Root Partition", LINUX_FILESYSTEM, Size::AllBut(u64::MAX))
+        .is_err());
+}
+
+#[test]
+fn test_malformed_entries_are_refused() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("disk.img");
+    File::create(&image)?.set_len(64 * 1024 * 1024)?;
+
+    let mut gpt = Gpt::new(64 * 1024 * 1024);
+    gpt.add("Root Partition", LINUX_FILESYSTEM, Size::AllBut(0))?;
+    gpt.write(&image)?;
+
+    // Rewrite the primary header's entry count and size, with a good CRC
+    for (count, size) in [(u32::MAX, 128u32), (128, 64), (128, 132), (65536, 128)] {
+        let mut file = OpenOptions::new().read(true).write(true).open(&image)?;
+        let mut header = [0; SECTOR as usize];
+        file.seek(SeekFrom::Start(SECTOR))?;
+        file.read_exact(&mut header)?;
+
+        header[80..84].copy_from_slice(&count.to_le_bytes());
+        header[84..88].copy_from_slice(&size.to_le_bytes());
+        header[16..20].fill(0);
+        let crc = crc32(&header[..HEADER_SIZE as usize]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        file.seek(SeekFrom::Start(SECTOR))?;
+        file.write_all(&header)?;
+        drop(file);
+
+        let e = Gpt::read(&image).unwrap_err();
+        assert!(e.to_string().contains("unexpected"), "{}", e);
+    }
+
+    Ok(())
+}
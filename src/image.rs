@@ -0,0 +1,696 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Working on finished images: mounting them for inspection, checking them
+//! without a boot, and shrinking, checksumming and signing them for
+//! distribution.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::builder::Check;
+use crate::gpt::{self, Gpt, SECTOR};
+use crate::*;
+
+/// Attach `image` to a free loop device, with a device node per partition
+fn attach_partitioned(image: &Path, read_only: bool) -> Result<String> {
+    attach_loop(
+        &image.to_string_lossy(),
+        &LoopOptions {
+            read_only,
+            partscan: true,
+        },
+    )
+}
+
+/// Parse `Key: value` lines, as printed by dumpe2fs -h
+fn colon_fields(text: &str) -> BTreeMap<String, String> {
+    text.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+/// The first space separated word of field `key`
+fn field_word<'a>(fields: &'a BTreeMap<String, String>, key: &str) -> Result<&'a str> {
+    match fields.get(key).and_then(|x| x.split_whitespace().next()) {
+        Some(word) => Ok(word),
+        None => bail!("no {:?} in output", key),
+    }
+}
+
+/// Shrink an image's root filesystem to its minimum and its root partition
+/// to match, then truncate the image and move the backup GPT to the new end.
+/// Root has to be the last partition, so images with a persistence or data
+/// partition after it are refused before anything is changed.
+pub fn shrink(image_path: &Path) -> Result<()> {
+    const MIB: u64 = 1024 * 1024;
+
+    // The backup GPT: 32 sectors of partition entries, then the header
+    const BACKUP_GPT_SECTORS: u64 = 33;
+
+    let image = image_path.to_string_lossy().to_string();
+    let before = std::fs::metadata(image_path)?.len();
+
+    let mut gpt = Gpt::read(image_path)?;
+    let Some(root_start) = gpt.partition(3).map(|x| x.first_lba) else {
+        bail!("{:?} has no partition 3", image_path);
+    };
+
+    let after_root: Vec<String> = gpt
+        .partitions
+        .iter()
+        .filter(|(_, x)| x.first_lba > root_start)
+        .map(|(number, _)| number.to_string())
+        .collect();
+    if !after_root.is_empty() {
+        bail!(
+            "can't shrink {:?}: partition {} comes after root and would be cut off",
+            image_path,
+            after_root.join(", ")
+        );
+    }
+
+    let loop_device = LoopbackDevice::attach(
+        &image,
+        &LoopOptions {
+            read_only: false,
+            partscan: true,
+        },
+    )?;
+
+    let root_partition = loop_device.partition(3)?;
+
+    info!("shrink the filesystem on {}", root_partition);
+    run(
+        "e2fsck".into(),
+        &["-f".into(), "-y".into(), root_partition.clone()],
+    )?;
+    run("resize2fs".into(), &["-M".into(), root_partition.clone()])?;
+
+    let fs = colon_fields(&output_stdout_string(&run(
+        "dumpe2fs".into(),
+        &["-h".into(), root_partition],
+    )?));
+    let fs_bytes = field_word(&fs, "Block count")?.parse::<u64>()?
+        * field_word(&fs, "Block size")?.parse::<u64>()?;
+
+    drop(loop_device);
+
+    // Move the end of partition 3, just big enough for the filesystem,
+    // keeping its start, type, GUID and name so nothing referring to it by
+    // PARTUUID breaks
+    let partition = gpt.partition_mut(3).unwrap();
+
+    // End the partition on a MiB boundary
+    let start = partition.first_lba * SECTOR;
+    let end = (start + fs_bytes).div_ceil(MIB) * MIB;
+    partition.last_lba = end / SECTOR - 1;
+
+    info!("resize partition 3 to {} MiB", (end - start) / MIB);
+
+    // Truncate, leaving room for the backup GPT, then write both tables
+    // again. The new layout is checked first, so a bad one leaves the image
+    // whole.
+    let after = end + MIB.max(BACKUP_GPT_SECTORS * SECTOR);
+    gpt.sectors = after / SECTOR;
+    gpt.check()?;
+
+    OpenOptions::new()
+        .write(true)
+        .open(image_path)?
+        .set_len(after)?;
+
+    gpt.write(image_path)?;
+
+    info!(
+        "shrunk {:?} from {} MiB to {} MiB",
+        image_path,
+        before / MIB,
+        after / MIB
+    );
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+}
+
+/// `path` with `suffix` added, e.g. debian.img.sha256
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Write `digest` of `image` next to it, e.g. debian.img.sha256, in the
+/// format `sha256sum -c` checks. Returns where it was written.
+pub fn write_checksum(image: &Path, algorithm: ChecksumAlgorithm, digest: &str) -> Result<PathBuf> {
+    let suffix = match algorithm {
+        ChecksumAlgorithm::Sha256 => ".sha256",
+    };
+    let path = with_suffix(image, suffix);
+
+    let Some(name) = image.file_name() else {
+        bail!("{:?} is not a file", image);
+    };
+
+    // Just the file name, so it checks wherever the two are copied to
+    std::fs::write(&path, format!("{}  {}\n", digest, name.to_string_lossy()))?;
+
+    Ok(path)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Signer {
+    Gpg,
+    Cosign,
+}
+
+/// Write a detached signature of `image` next to it and return where: an
+/// armored debian.img.asc from gpg, with `key` a key ID or email, or a
+/// debian.img.sig from `cosign sign-blob`, with `key` a key file or KMS URI
+pub fn sign(image: &Path, signer: Signer, key: &str) -> Result<PathBuf> {
+    let image_path = image.to_string_lossy().to_string();
+
+    let signature = match signer {
+        Signer::Gpg => {
+            let signature = with_suffix(image, ".asc");
+            run(
+                "gpg".into(),
+                &[
+                    "--batch".into(),
+                    "--yes".into(),
+                    "--local-user".into(),
+                    key.into(),
+                    "--armor".into(),
+                    "--output".into(),
+                    signature.to_string_lossy().to_string(),
+                    "--detach-sign".into(),
+                    image_path,
+                ],
+            )?;
+            signature
+        }
+
+        Signer::Cosign => {
+            let signature = with_suffix(image, ".sig");
+            run(
+                "cosign".into(),
+                &[
+                    "sign-blob".into(),
+                    "--yes".into(),
+                    "--key".into(),
+                    key.into(),
+                    "--output-signature".into(),
+                    signature.to_string_lossy().to_string(),
+                    image_path,
+                ],
+            )?;
+            signature
+        }
+    };
+
+    info!("signed {:?} with {:?}", image, signature);
+
+    Ok(signature)
+}
+
+/// Where `mount` records what it set up so `umount` can tear it down
+pub const MOUNT_STATE_PATH: &str = "/run/docker_to_uefi_bootable_image/mounts.json";
+
+/// What `mount` set up for one directory, in the order it was set up
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MountState {
+    image: PathBuf,
+    loop_device: Option<String>,
+    mounts: Vec<String>,
+}
+
+fn read_mount_states(state_path: &Path) -> Result<BTreeMap<String, MountState>> {
+    match std::fs::read_to_string(state_path) {
+        Ok(text) => Ok(serde_json::from_str(&text)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_mount_states(state_path: &Path, states: &BTreeMap<String, MountState>) -> Result<()> {
+    if let Some(parent) = state_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(state_path, serde_json::to_string_pretty(states)?)?;
+    Ok(())
+}
+
+/// Unmount in reverse order, then detach the loop device
+fn teardown_mount(state: &MountState) -> Result<()> {
+    run("sync".into(), &[])?;
+
+    for mount in state.mounts.iter().rev() {
+        info!("umount {}", mount);
+        umount_fs(mount)?;
+    }
+
+    if let Some(loop_device) = &state.loop_device {
+        info!("detach {}", loop_device);
+        detach_loop(loop_device)?;
+    }
+
+    Ok(())
+}
+
+fn setup_mount(state: &mut MountState, dir: &str, read_only: bool) -> Result<()> {
+    let loop_device = attach_partitioned(&state.image, read_only)?;
+    info!("attached {:?} to {}", state.image, loop_device);
+    state.loop_device = Some(loop_device.clone());
+
+    let esp_dir = format!("{}/boot/efi", dir);
+
+    for (device, dest, fstype) in [
+        (partition_device(&loop_device, 3)?, dir.to_string(), "ext4"),
+        (partition_device(&loop_device, 2)?, esp_dir.clone(), "vfat"),
+    ] {
+        // Images built by `create` already have boot/efi, and a read-only
+        // root couldn't get one anyway
+        if dest == esp_dir && !read_only {
+            std::fs::create_dir_all(&dest)?;
+        }
+
+        info!("mount {} {}", device, dest);
+        mount_fs(
+            &device,
+            &dest,
+            &MountOptions::fstype(fstype).read_only(read_only),
+        )?;
+        state.mounts.push(dest);
+    }
+
+    Ok(())
+}
+
+/// Mount `image`'s root partition at `dir` and its EFI partition at
+/// `dir`/boot/efi, recording what was set up in `state_path` for `umount`.
+/// Returns the canonical `dir`.
+pub fn mount(image: &Path, dir: &Path, read_only: bool, state_path: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let dir = dir.canonicalize()?.to_string_lossy().to_string();
+
+    let mut states = read_mount_states(state_path)?;
+    if states.contains_key(&dir) {
+        bail!("{} is already mounted, umount it first", dir);
+    }
+
+    let mut state = MountState {
+        image: image.canonicalize()?,
+        ..Default::default()
+    };
+
+    if let Err(e) = setup_mount(&mut state, &dir, read_only) {
+        if let Err(teardown_error) = teardown_mount(&state) {
+            bail!("{}, and cleaning up also failed: {}", e, teardown_error);
+        }
+        return Err(e);
+    }
+
+    states.insert(dir.clone(), state);
+    write_mount_states(state_path, &states)?;
+
+    Ok(dir.into())
+}
+
+/// Undo `mount` of `dir`: unmount everything in reverse order and detach the
+/// loop device
+pub fn umount(dir: &Path, state_path: &Path) -> Result<()> {
+    let dir = dir.canonicalize()?.to_string_lossy().to_string();
+
+    let mut states = read_mount_states(state_path)?;
+    let Some(state) = states.remove(&dir) else {
+        bail!("{} was not mounted by this tool", dir);
+    };
+
+    teardown_mount(&state)?;
+    write_mount_states(state_path, &states)?;
+
+    Ok(())
+}
+
+/// A check passes with its detail, or fails with its error's
+fn check(name: &str, result: Result<String>) -> Check {
+    let ok = result.is_ok();
+
+    Check {
+        name: name.into(),
+        ok,
+        detail: result.unwrap_or_else(|e| format!("{:#}", e)),
+    }
+}
+
+/// Check `image` without booting it: that its primary and backup GPTs
+/// agree, that its partitions are aligned, that its ESP is a sound FAT
+/// filesystem with the removable media bootloader firmware looks for, and
+/// that root mounts read-only. Returns every check, passed or not.
+pub fn verify(image: &Path) -> Result<Vec<Check>> {
+    let mut checks = vec![];
+
+    // Nothing else can be found without it
+    let gpt = match Gpt::read(image) {
+        Ok(gpt) => gpt,
+        Err(e) => {
+            checks.push(check("gpt", Err(e)));
+            return Ok(checks);
+        }
+    };
+
+    checks.push(check(
+        "gpt",
+        (|| {
+            gpt.check()?;
+            if Gpt::read_backup(image)? != gpt {
+                bail!("the backup GPT differs from the primary");
+            }
+            Ok(format!(
+                "primary and backup agree on {} partitions",
+                gpt.partitions.len()
+            ))
+        })(),
+    ));
+
+    let misaligned: Vec<String> = gpt
+        .partitions
+        .iter()
+        .filter(|(_, x)| !x.is_aligned())
+        .map(|(number, _)| number.to_string())
+        .collect();
+    checks.push(check(
+        "alignment",
+        match misaligned.is_empty() {
+            true => Ok("every partition starts on a 1 MiB boundary".into()),
+            false => Err(anyhow::anyhow!(
+                "partition {} doesn't start on a 1 MiB boundary",
+                misaligned.join(", ")
+            )),
+        },
+    ));
+
+    let loop_device = LoopbackDevice::attach(
+        &image.to_string_lossy(),
+        &LoopOptions {
+            read_only: true,
+            partscan: true,
+        },
+    )?;
+    let dir = tempfile::tempdir()?;
+
+    match gpt.first_of(&[gpt::EFI_SYSTEM]) {
+        Some(number) => {
+            let device = loop_device.partition(number)?;
+
+            checks.push(check(
+                "esp",
+                run("fsck.vfat".into(), &["-n".into(), device.clone()])
+                    .map(|_| format!("partition {} is a sound FAT filesystem", number)),
+            ));
+
+            let esp_dir = dir.path().join("esp").display().to_string();
+            checks.push(check(
+                "bootloader",
+                Mount::new(
+                    device,
+                    esp_dir.clone(),
+                    &MountOptions::fstype("vfat").read_only(true),
+                )
+                .and_then(|_esp| {
+                    if !Path::new(&format!("{}/EFI/BOOT/BOOTX64.EFI", esp_dir)).is_file() {
+                        bail!("no EFI/BOOT/BOOTX64.EFI on the ESP, firmware won't find GRUB");
+                    }
+                    Ok("EFI/BOOT/BOOTX64.EFI is on the ESP".into())
+                }),
+            ));
+        }
+        None => checks.push(check(
+            "esp",
+            Err(anyhow::anyhow!("no EFI system partition")),
+        )),
+    }
+
+    match gpt.first_of(gpt::ROOT_TYPES) {
+        Some(number) => {
+            let device = loop_device.partition(number)?;
+            let root_dir = dir.path().join("root").display().to_string();
+
+            checks.push(check(
+                "root",
+                probe_filesystem(&device).and_then(|fs| {
+                    let _root = Mount::new(
+                        device,
+                        root_dir,
+                        &MountOptions::fstype(fs.fs_type.as_str()).read_only(true),
+                    )?;
+                    Ok(format!(
+                        "partition {} ({}) mounts read-only",
+                        number, fs.fs_type
+                    ))
+                }),
+            ));
+        }
+        None => checks.push(check("root", Err(anyhow::anyhow!("no root partition")))),
+    }
+
+    Ok(checks)
+}
+
+#[test]
+fn test_umount_reverses_mount() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("debian.img");
+    std::fs::write(&image, "")?;
+    let mnt = dir.path().join("mnt");
+    let state_path = dir.path().join("mounts.json");
+
+    let executor = Rc::new(RecordingExecutor::new(|exe, args| match exe {
+        "losetup" if args.contains(&"--show".to_string()) => Ok("/dev/loop0".into()),
+        _ => Ok(String::new()),
+    }));
+    let previous = set_executor(executor.clone());
+
+    let result = mount(&image, &mnt, false, &state_path).and_then(|_| {
+        // Mounting the same directory twice is refused
+        assert!(mount(&image, &mnt, false, &state_path).is_err());
+
+        umount(&mnt, &state_path)
+    });
+
+    set_executor(previous);
+    result?;
+
+    let commands: Vec<String> = executor
+        .commands()
+        .iter()
+        .map(|x| {
+            x.to_string().replace(
+                dir.path().canonicalize().unwrap().to_str().unwrap(),
+                "{dir}",
+            )
+        })
+        .collect();
+
+    assert_eq!(
+        commands,
+        [
+            "losetup --show --find --partscan {dir}/debian.img",
+            "mount -t ext4 /dev/loop0p3 {dir}/mnt",
+            "mount -t vfat /dev/loop0p2 {dir}/mnt/boot/efi",
+            "sync",
+            "umount {dir}/mnt/boot/efi",
+            "umount {dir}/mnt",
+            "losetup -d /dev/loop0",
+        ]
+    );
+
+    assert!(read_mount_states(&state_path)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_shrink_to_filesystem() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("debian.img");
+    File::create(&image)?.set_len(8 * 1024 * 1024 * 1024)?;
+    let gpt = Gpt::default_layout(8 * 1024 * 1024 * 1024)?;
+    gpt.write(&image)?;
+
+    let executor = Rc::new(RecordingExecutor::new(|exe, args| match exe {
+        "losetup" if args.contains(&"--show".to_string()) => Ok("/dev/loop0".into()),
+
+        "dumpe2fs" => Ok("Block count:              307200\n\
+                          Block size:               4096\n"
+            .into()),
+
+        _ => Ok(String::new()),
+    }));
+
+    let previous = set_executor(executor.clone());
+    let result = shrink(&image);
+    set_executor(previous);
+    result?;
+
+    let image_path = image.to_str().unwrap();
+    let commands: Vec<String> = executor
+        .commands()
+        .iter()
+        .map(|x| x.to_string().replace(image_path, "{image}"))
+        .collect();
+
+    // 515 MiB + 1200 MiB of filesystem, then 1 MiB for the backup GPT
+    assert_eq!(
+        commands,
+        [
+            "losetup --show --find --partscan {image}",
+            "e2fsck -f -y /dev/loop0p3",
+            "resize2fs -M /dev/loop0p3",
+            "dumpe2fs -h /dev/loop0p3",
+            "lsblk --raw --noheadings --output MOUNTPOINT /dev/loop0",
+            "losetup -d /dev/loop0",
+        ]
+    );
+
+    assert_eq!(std::fs::metadata(&image)?.len(), 1716 * 1024 * 1024);
+
+    // Only the end of the root partition moved
+    let shrunk = Gpt::read(&image)?;
+    let root = shrunk.partition(3).unwrap();
+    assert_eq!(root.last_lba, 3512319);
+    assert_eq!(
+        (root.first_lba, root.unique_guid, &root.name),
+        (
+            gpt.partition(3).unwrap().first_lba,
+            gpt.partition(3).unwrap().unique_guid,
+            &gpt.partition(3).unwrap().name
+        )
+    );
+    assert_eq!(shrunk.partition(2), gpt.partition(2));
+
+    // A data partition split off the end of root is after it, and would be
+    // cut off, so nothing is touched
+    let mut gpt = Gpt::default_layout(8 * 1024 * 1024 * 1024)?;
+    gpt.split_end(3, "Data", crate::gpt::LINUX_FILESYSTEM, 1024 * 1024 * 1024)?;
+    File::create(&image)?.set_len(8 * 1024 * 1024 * 1024)?;
+    gpt.write(&image)?;
+
+    let executor = Rc::new(RecordingExecutor::new(|_, _| Ok(String::new())));
+    let previous = set_executor(executor.clone());
+    let result = shrink(&image);
+    set_executor(previous);
+
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        format!(
+            "can't shrink {:?}: partition 4 comes after root and would be cut off",
+            image
+        )
+    );
+    assert!(executor.commands().is_empty());
+    assert_eq!(std::fs::metadata(&image)?.len(), 8 * 1024 * 1024 * 1024);
+    assert_eq!(Gpt::read(&image)?, gpt);
+
+    Ok(())
+}
+
+#[test]
+fn test_write_checksum() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("debian.img");
+
+    let path = write_checksum(&image, ChecksumAlgorithm::Sha256, "abc123")?;
+    assert_eq!(path, dir.path().join("debian.img.sha256"));
+    assert_eq!(std::fs::read_to_string(path)?, "abc123  debian.img\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_sign() -> Result<()> {
+    let executor = Rc::new(RecordingExecutor::new(|_, _| Ok(String::new())));
+    let previous = set_executor(executor.clone());
+
+    let image = Path::new("/images/debian.img");
+    let result = sign(image, Signer::Gpg, "builds@example.com")
+        .and_then(|gpg| Ok((gpg, sign(image, Signer::Cosign, "cosign.key")?)));
+
+    set_executor(previous);
+    let (gpg, cosign) = result?;
+
+    assert_eq!(gpg, Path::new("/images/debian.img.asc"));
+    assert_eq!(cosign, Path::new("/images/debian.img.sig"));
+
+    let commands: Vec<String> = executor.commands().iter().map(|x| x.to_string()).collect();
+    assert_eq!(
+        commands,
+        [
+            "gpg --batch --yes --local-user builds@example.com --armor --output /images/debian.img.asc --detach-sign /images/debian.img",
+            "cosign sign-blob --yes --key cosign.key --output-signature /images/debian.img.sig /images/debian.img",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_verify() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("debian.img");
+    std::fs::File::create(&image)?.set_len(1024 * 1024 * 1024)?;
+    Gpt::default_layout(1024 * 1024 * 1024)?.write(&image)?;
+
+    let executor = Rc::new(RecordingExecutor::new(|exe, args| match exe {
+        "losetup" if args.contains(&"--show".to_string()) => Ok("/dev/loop0".into()),
+        "blkid" => Ok("TYPE=ext4\nUUID=9f2e0c4a-0000-4000-8000-000000000001".into()),
+        _ => Ok(String::new()),
+    }));
+    let previous = set_executor(executor.clone());
+    let result = verify(&image);
+    set_executor(previous);
+
+    let checks: Vec<(String, bool)> = result?.into_iter().map(|x| (x.name, x.ok)).collect();
+    assert_eq!(
+        checks,
+        [
+            ("gpt".into(), true),
+            ("alignment".into(), true),
+            ("esp".into(), true),
+            // Nothing was really mounted, so there's no bootloader
+            ("bootloader".into(), false),
+            ("root".into(), true),
+        ]
+    );
+
+    assert!(executor
+        .commands()
+        .iter()
+        .any(|x| x.to_string() == "fsck.vfat -n /dev/loop0p2"));
+
+    // Without the backup table
+    OpenOptions::new()
+        .write(true)
+        .open(&image)?
+        .set_len(1025 * 1024 * 1024)?;
+    let previous = set_executor(executor.clone());
+    let result = verify(&image);
+    set_executor(previous);
+    assert!(!result?[0].ok);
+
+    Ok(())
+}
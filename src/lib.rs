@@ -4,126 +4,2070 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
 
+use std::cell::{Cell, RefCell};
+use std::ffi::CString;
 use std::fs::File;
-use std::process::{Command, Output, Stdio};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+use tracing::{debug, trace, warn};
+
+use crate::error::Error;
+use crate::events::{emit, BuildEvent, ProgressEvents};
+use crate::probe::Filesystem;
+
+pub mod app;
+pub mod audit;
+pub mod builder;
+pub mod compose;
+pub mod console;
+pub mod datapart;
+pub mod diff;
+pub mod error;
+pub mod events;
+pub mod fat;
+pub mod firewall;
+pub mod fstab;
+pub mod gpt;
+pub mod image;
+mod loopdev;
+pub mod matrix;
+pub mod minimal;
+pub mod ovmf;
+pub mod probe;
+pub mod qemu;
+pub mod readonly;
+pub mod relaunch;
+pub mod repart;
+pub mod rootless;
+pub mod sbom;
+mod untar;
+pub mod upload;
+
+/// What a command did, as returned by `run` and friends
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    /// The command line, quoted for a shell
+    pub command: String,
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration: Duration,
+}
+
+/// Decode command output, replacing invalid UTF-8, and drop the trailing
+/// newline
+fn output_string(bytes: &[u8]) -> String {
+    let mut text = String::from_utf8_lossy(bytes).into_owned();
+
+    if text.ends_with('\n') {
+        text.pop();
+    }
+
+    text
+}
+
+pub fn output_stdout_string(output: &CommandOutput) -> String {
+    output
+        .stdout
+        .strip_suffix('\n')
+        .unwrap_or(&output.stdout)
+        .to_string()
+}
+
+pub fn output_stderr_string(output: &CommandOutput) -> String {
+    output
+        .stderr
+        .strip_suffix('\n')
+        .unwrap_or(&output.stderr)
+        .to_string()
+}
+
+/// Runs external commands on behalf of `run` and friends. The host executor
+/// is used unless a different one is installed with `set_executor`, which lets
+/// tests (or a dry run) see every command without touching the host.
+pub trait Executor {
+    /// Run a command to completion, unless `stop` says to kill it first
+    fn execute(
+        &self,
+        exe: &str,
+        args: &[String],
+        env_vars: &[(String, String)],
+        stdin: Option<&str>,
+        stop: &StopWhen,
+    ) -> Result<Output>;
+
+    /// Run a command attached to the terminal, like a debug shell
+    fn execute_interactive(&self, exe: &str, args: &[String]) -> Result<ExitStatus>;
+
+    /// Run a command until `marker` shows up in its stdout or `timeout`
+    /// passes, then kill it. Returns whether the marker was seen, and the
+    /// output up to that point.
+    fn execute_until(
+        &self,
+        exe: &str,
+        args: &[String],
+        marker: &str,
+        timeout: Duration,
+    ) -> Result<(bool, String)>;
+
+    /// Mount `source` on `target`. By default this runs mount(8) through
+    /// `execute`, which is what recording executors want.
+    fn mount(&self, source: &str, target: &str, options: &MountOptions) -> Result<()> {
+        let args = options.mount_args(source, target);
+        check_status(
+            "mount",
+            &args,
+            self.execute("mount", &args, &[], None, &StopWhen::default())?,
+        )
+    }
+
+    /// Unmount `target`, by default by running umount(8)
+    fn umount(&self, target: &str) -> Result<()> {
+        let args = vec![target.to_string()];
+        check_status(
+            "umount",
+            &args,
+            self.execute("umount", &args, &[], None, &StopWhen::default())?,
+        )
+    }
+
+    /// Detach the mount on `target` now and clean it up once it's no longer
+    /// busy. By default this runs `umount -l`.
+    fn umount_lazy(&self, target: &str) -> Result<()> {
+        let args = vec!["-l".to_string(), target.to_string()];
+        check_status(
+            "umount",
+            &args,
+            self.execute("umount", &args, &[], None, &StopWhen::default())?,
+        )
+    }
+
+    /// Attach `image` to a free loop device, returning the device's path. By
+    /// default this runs losetup(8).
+    fn attach_loop(&self, image: &str, options: &LoopOptions) -> Result<String> {
+        let args = options.losetup_args(image);
+        let output = self.execute("losetup", &args, &[], None, &StopWhen::default())?;
+        let stdout = output_string(&output.stdout);
+        check_status("losetup", &args, output)?;
+        Ok(stdout)
+    }
+
+    fn detach_loop(&self, device: &str) -> Result<()> {
+        let args = vec!["-d".to_string(), device.to_string()];
+        check_status(
+            "losetup",
+            &args,
+            self.execute("losetup", &args, &[], None, &StopWhen::default())?,
+        )
+    }
+
+    /// The first free loop device, by default from `losetup --find`
+    fn find_free_loop(&self) -> Result<String> {
+        let args = vec!["--find".to_string()];
+        let output = self.execute("losetup", &args, &[], None, &StopWhen::default())?;
+        let stdout = output_string(&output.stdout);
+        check_status("losetup", &args, output)?;
+        Ok(stdout)
+    }
+
+    /// The device node for partition `number` of `device`. By default this
+    /// is guessed from the kernel's naming: /dev/loop0p2 or /dev/nvme0n1p2
+    /// when the device's name ends in a digit, /dev/sda2 when it doesn't.
+    fn partition_device(&self, device: &str, number: u32) -> Result<String> {
+        Ok(partition_name(device, number))
+    }
+
+    /// Unpack the tar archive `archive` into `dest`, keeping ownership,
+    /// xattrs, hard links and holes. By default this runs tar(1).
+    fn unpack_tar(&self, archive: &str, dest: &str) -> Result<()> {
+        let args = tar_args(archive, dest);
+        check_status(
+            "tar",
+            &args,
+            self.execute("tar", &args, &[], None, &StopWhen::default())?,
+        )
+    }
+
+    /// The type and UUID of the filesystem on `device`, by default from
+    /// `blkid -o export`
+    fn probe_filesystem(&self, device: &str) -> Result<Filesystem> {
+        let args = blkid_args(device);
+        let output = self.execute("blkid", &args, &[], None, &StopWhen::default())?;
+        let stdout = output_string(&output.stdout);
+        check_status("blkid", &args, output)?;
+        Filesystem::from_blkid(&stdout).with_context(|| format!("could not probe {}", device))
+    }
+
+    /// This process's effective capabilities, as a bitmask of CAP_*
+    /// numbers. By default grep(1) reads its own from /proc, which for root
+    /// are the same.
+    fn capabilities(&self) -> Result<u64> {
+        let args = cap_eff_args();
+        let output = self.execute("grep", &args, &[], None, &StopWhen::default())?;
+        let stdout = output_string(&output.stdout);
+        check_status("grep", &args, output)?;
+        parse_cap_eff(&stdout)
+    }
+
+    /// Where `device` and any of its partitions are mounted, newest first.
+    /// By default from lsblk(8), which only knows one mount point each.
+    fn mounts_of(&self, device: &str) -> Result<Vec<String>> {
+        let args = lsblk_args(device);
+        let output = self.execute("lsblk", &args, &[], None, &StopWhen::default())?;
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        check_status("lsblk", &args, output)?;
+
+        // The device comes before its partitions
+        Ok(stdout
+            .lines()
+            .rev()
+            .filter(|x| !x.is_empty())
+            .map(|x| x.replace("\\x20", " "))
+            .collect())
+    }
+
+    /// Whether commands can run on other threads, each with the host
+    /// executor. Executors that record or replay commands say no, as the
+    /// order they see commands in matters.
+    fn concurrent(&self) -> bool {
+        false
+    }
+}
+
+fn cap_eff_args() -> Vec<String> {
+    vec!["^CapEff:".into(), "/proc/self/status".into()]
+}
+
+/// The effective capability set from a /proc/<pid>/status, see proc(5)
+pub fn parse_cap_eff(status: &str) -> Result<u64> {
+    let Some(mask) = status
+        .lines()
+        .find_map(|x| x.strip_prefix("CapEff:"))
+        .map(|x| x.trim())
+    else {
+        bail!("no CapEff in process status");
+    };
+
+    u64::from_str_radix(mask, 16).with_context(|| format!("bad CapEff {:?}", mask))
+}
+
+#[test]
+fn test_parse_cap_eff() -> Result<()> {
+    let status = "Name:\tcat\nCapPrm:\t0000000000000000\nCapEff:\t000001ffffffffff\n";
+    assert_eq!(parse_cap_eff(status)?, 0x1ff_ffff_ffff);
+    assert_eq!(parse_cap_eff("CapEff:\t0000000000200000\n")?, 1 << 21);
+    assert!(parse_cap_eff("Name:\tcat\n").is_err());
+    assert!(parse_cap_eff("CapEff:\tnope\n").is_err());
+
+    Ok(())
+}
+
+fn lsblk_args(device: &str) -> Vec<String> {
+    vec![
+        "--raw".into(),
+        "--noheadings".into(),
+        "--output".into(),
+        "MOUNTPOINT".into(),
+        device.into(),
+    ]
+}
+
+fn blkid_args(device: &str) -> Vec<String> {
+    vec!["-o".into(), "export".into(), device.into()]
+}
+
+fn partition_name(device: &str, number: u32) -> String {
+    if device.ends_with(|x: char| x.is_ascii_digit()) {
+        format!("{}p{}", device, number)
+    } else {
+        format!("{}{}", device, number)
+    }
+}
+
+#[test]
+fn test_partition_name() {
+    assert_eq!(partition_name("/dev/loop0", 2), "/dev/loop0p2");
+    assert_eq!(partition_name("/dev/nvme0n1", 3), "/dev/nvme0n1p3");
+    assert_eq!(partition_name("/dev/sda", 2), "/dev/sda2");
+}
+
+/// The tar(1) arguments that unpack like `Executor::unpack_tar`
+fn tar_args(archive: &str, dest: &str) -> Vec<String> {
+    vec![
+        "--sparse".into(),
+        "--xattrs".into(),
+        "--xattrs-include=*".into(),
+        "--numeric-owner".into(),
+        "-p".into(),
+        "-C".into(),
+        dest.into(),
+        "-xf".into(),
+        archive.into(),
+    ]
+}
+
+fn check_status(exe: &str, args: &[String], output: Output) -> Result<()> {
+    if !output.status.success() {
+        return Err(Error::CommandFailed {
+            cmd: command_line(exe, args),
+            status: output.status,
+            stderr: output_string(&output.stderr),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// How to attach a loop device
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoopOptions {
+    pub read_only: bool,
+    /// Have the kernel create a device for each partition
+    pub partscan: bool,
+}
+
+impl LoopOptions {
+    /// The losetup(8) arguments that would do the same
+    pub fn losetup_args(&self, image: &str) -> Vec<String> {
+        let mut args = vec!["--show".into(), "--find".into()];
+
+        if self.partscan {
+            args.push("--partscan".into());
+        }
+
+        if self.read_only {
+            args.push("--read-only".into());
+        }
+
+        args.push(image.into());
+        args
+    }
+}
+
+/// How to mount a filesystem, like mount(8)'s -t and -o
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MountOptions {
+    /// Filesystem type. Needed for anything but a bind mount.
+    pub fstype: Option<String>,
+    pub bind: bool,
+    /// With `bind`, the mounts under the source too
+    pub recursive: bool,
+    pub read_only: bool,
+    /// Filesystem specific options, like "errors=remount-ro"
+    pub data: Option<String>,
+}
+
+impl MountOptions {
+    pub fn fstype(fstype: &str) -> Self {
+        Self {
+            fstype: Some(fstype.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn bind() -> Self {
+        Self {
+            bind: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn data(mut self, data: impl Into<String>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// The mount(8) arguments that would do the same
+    pub fn mount_args(&self, source: &str, target: &str) -> Vec<String> {
+        let mut args = vec![];
+
+        if let Some(fstype) = &self.fstype {
+            args.push("-t".into());
+            args.push(fstype.clone());
+        }
+
+        if self.bind && self.recursive {
+            args.push("--rbind".into());
+        } else if self.bind {
+            args.push("--bind".into());
+        }
+
+        let options: Vec<&str> = self
+            .read_only
+            .then_some("ro")
+            .into_iter()
+            .chain(self.data.as_deref())
+            .collect();
+
+        if !options.is_empty() {
+            args.push("-o".into());
+            args.push(options.join(","));
+        }
+
+        args.push(source.into());
+        args.push(target.into());
+        args
+    }
+}
+
+pub struct HostExecutor;
+
+/// Read `reader` to the end, tracing each line as it arrives
+fn stream_lines(name: &'static str, reader: impl Read) -> std::io::Result<Vec<u8>> {
+    let span = tracing::Span::current();
+    let _entered = span.enter();
+
+    let mut reader = BufReader::new(reader);
+    let mut output = vec![];
+
+    loop {
+        let start = output.len();
+        if reader.read_until(b'\n', &mut output)? == 0 {
+            break;
+        }
+
+        trace!(
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(&output[start..]).trim_end()
+        );
+    }
+
+    Ok(output)
+}
+
+/// A C string for a path or option passed to a syscall
+fn c_string(value: &str) -> Result<CString> {
+    Ok(CString::new(value)?)
+}
+
+impl Executor for HostExecutor {
+    fn mount(&self, source: &str, target: &str, options: &MountOptions) -> Result<()> {
+        debug!(
+            "mount {}",
+            command_line("", &options.mount_args(source, target)).trim_start()
+        );
+
+        if options.fstype.is_none() && !options.bind {
+            bail!("mounting {} needs a filesystem type", source);
+        }
+
+        let c_source = c_string(source)?;
+        let c_target = c_string(target)?;
+        let fstype = options.fstype.as_deref().map(c_string).transpose()?;
+        let data = options.data.as_deref().map(c_string).transpose()?;
+
+        let mut flags: libc::c_ulong = 0;
+        if options.bind {
+            flags |= libc::MS_BIND;
+        }
+        if options.recursive {
+            flags |= libc::MS_REC;
+        }
+        if options.read_only {
+            flags |= libc::MS_RDONLY;
+        }
+
+        // SAFETY: every pointer is to a live CString or null
+        let result = unsafe {
+            libc::mount(
+                c_source.as_ptr(),
+                c_target.as_ptr(),
+                fstype.as_ref().map_or(std::ptr::null(), |x| x.as_ptr()),
+                flags,
+                data.as_ref()
+                    .map_or(std::ptr::null(), |x| x.as_ptr() as *const libc::c_void),
+            )
+        };
+        if result != 0 {
+            bail!(
+                "could not mount {} on {}: {}",
+                source,
+                target,
+                std::io::Error::last_os_error()
+            );
+        }
+
+        // The kernel ignores MS_RDONLY when creating a bind mount, it has to
+        // be remounted read-only after
+        if options.bind && options.read_only {
+            // SAFETY: as above
+            let result = unsafe {
+                libc::mount(
+                    std::ptr::null(),
+                    c_target.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+                    std::ptr::null(),
+                )
+            };
+            if result != 0 {
+                bail!(
+                    "could not make {} read-only: {}",
+                    target,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn attach_loop(&self, image: &str, options: &LoopOptions) -> Result<String> {
+        let device = loopdev::attach(image, options.read_only, options.partscan)?;
+        debug!("attached {} to {}", image, device);
+        Ok(device)
+    }
+
+    fn detach_loop(&self, device: &str) -> Result<()> {
+        debug!("detach {}", device);
+        loopdev::detach(device)
+    }
+
+    fn find_free_loop(&self) -> Result<String> {
+        loopdev::find_free()
+    }
+
+    fn partition_device(&self, device: &str, number: u32) -> Result<String> {
+        loopdev::partition_device(device, number)
+    }
+
+    fn unpack_tar(&self, archive: &str, dest: &str) -> Result<()> {
+        debug!("unpack {} into {}", archive, dest);
+
+        let file = File::open(archive)?;
+        let bar = progress_bar(
+            Some(file.metadata()?.len()),
+            "{msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+            "extract",
+        );
+
+        let mut events = ProgressEvents::new("extract", Some(file.metadata()?.len()));
+
+        let result = untar::unpack(BufReader::new(file), Path::new(dest), |x| {
+            bar.set_position(x);
+            events.set_position(x);
+        });
+        bar.finish_and_clear();
+        events.finish(bar.position());
+
+        result.map_err(|e| e.context(format!("could not unpack {}", archive)))
+    }
+
+    fn probe_filesystem(&self, device: &str) -> Result<Filesystem> {
+        probe::probe(Path::new(device))
+    }
+
+    fn mounts_of(&self, device: &str) -> Result<Vec<String>> {
+        loopdev::mounts_of(device)
+    }
+
+    fn capabilities(&self) -> Result<u64> {
+        parse_cap_eff(&std::fs::read_to_string("/proc/self/status")?)
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
+
+    fn umount(&self, target: &str) -> Result<()> {
+        debug!("umount {}", target);
+
+        let c_target = c_string(target)?;
+
+        // SAFETY: c_target is a live CString
+        if unsafe { libc::umount2(c_target.as_ptr(), 0) } != 0 {
+            // Kept as an io::Error so EBUSY can be told apart
+            return Err(anyhow::Error::new(std::io::Error::last_os_error())
+                .context(format!("could not umount {}", target)));
+        }
+
+        Ok(())
+    }
+
+    fn umount_lazy(&self, target: &str) -> Result<()> {
+        debug!("umount -l {}", target);
+
+        let c_target = c_string(target)?;
+
+        // SAFETY: c_target is a live CString
+        if unsafe { libc::umount2(c_target.as_ptr(), libc::MNT_DETACH) } != 0 {
+            bail!(
+                "could not lazily umount {}: {}",
+                target,
+                std::io::Error::last_os_error()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn execute(
+        &self,
+        exe: &str,
+        args: &[String],
+        env_vars: &[(String, String)],
+        stdin: Option<&str>,
+        stop: &StopWhen,
+    ) -> Result<Output> {
+        let mut cmd = Command::new(exe);
+
+        for arg in args {
+            cmd.arg(arg);
+        }
+
+        for env_var in env_vars {
+            cmd.env(&env_var.0, &env_var.1);
+        }
+
+        // In its own process group, so that stopping it also stops anything
+        // it started (apt's dpkg, say), and so Ctrl-C is left to us
+        cmd.process_group(0);
+
+        debug!("running {:?}", cmd);
+
+        cmd.stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+
+        let mut child = cmd.spawn()?;
+
+        if let Some(stdin) = stdin {
+            child.stdin.take().unwrap().write_all(stdin.as_bytes())?;
+        }
+
+        let child_stdout = child.stdout.take().unwrap();
+        let child_stderr = child.stderr.take().unwrap();
+
+        // Log each line as it comes, so a long apt install isn't silent until
+        // it finishes, and keep all of it for the caller
+        std::thread::scope(|scope| {
+            let stdout = scope.spawn(|| stream_lines("stdout", child_stdout));
+            let stderr = scope.spawn(|| stream_lines("stderr", child_stderr));
+
+            let start = Instant::now();
+            let mut poll = Duration::from_millis(1);
+
+            let status = loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+
+                let cancelled = stop.cancel.as_ref().is_some_and(CancelToken::is_cancelled);
+                let timed_out = stop.timeout.is_some_and(|x| start.elapsed() >= x);
+
+                if cancelled || timed_out {
+                    // SAFETY: kill has no memory safety requirements
+                    unsafe {
+                        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                    }
+                    child.wait()?;
+
+                    return Err(if cancelled {
+                        Error::Cancelled
+                    } else {
+                        Error::TimedOut {
+                            cmd: command_line(exe, args),
+                            timeout: stop.timeout.unwrap(),
+                        }
+                    }
+                    .into());
+                }
+
+                std::thread::sleep(poll);
+                poll = (poll * 2).min(Duration::from_millis(50));
+            };
+
+            Ok(Output {
+                status,
+                stdout: stdout.join().unwrap()?,
+                stderr: stderr.join().unwrap()?,
+            })
+        })
+    }
+
+    fn execute_interactive(&self, exe: &str, args: &[String]) -> Result<ExitStatus> {
+        let mut cmd = Command::new(exe);
+        cmd.args(args);
+
+        debug!("running {:?}", cmd);
+
+        Ok(cmd.status()?)
+    }
+
+    fn execute_until(
+        &self,
+        exe: &str,
+        args: &[String],
+        marker: &str,
+        timeout: Duration,
+    ) -> Result<(bool, String)> {
+        let mut cmd = Command::new(exe);
+        cmd.args(args);
+        cmd.stdin(Stdio::null());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::null());
+
+        debug!("running {:?}", cmd);
+
+        let mut child = cmd.spawn()?;
+        let mut stdout = child.stdout.take().unwrap();
+
+        // Read on another thread so the wait below can time out
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = stdout.read(&mut buf) {
+                if n == 0 || tx.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let deadline = std::time::Instant::now() + timeout;
+        let mut output = vec![];
+        let mut found = false;
+
+        while let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()) {
+            match rx.recv_timeout(remaining) {
+                Ok(bytes) => {
+                    output.extend_from_slice(&bytes);
+                    if String::from_utf8_lossy(&output).contains(marker) {
+                        found = true;
+                        break;
+                    }
+                }
+
+                // Timed out, or the command exited
+                Err(_) => break,
+            }
+        }
+
+        let _ = child.kill();
+        child.wait()?;
+
+        Ok((found, String::from_utf8_lossy(&output).to_string()))
+    }
+}
+
+/// A command seen by a `RecordingExecutor`
+#[derive(Debug, Clone)]
+pub struct RecordedCommand {
+    pub exe: String,
+    pub args: Vec<String>,
+    pub env_vars: Vec<(String, String)>,
+}
+
+impl std::fmt::Display for RecordedCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (k, v) in &self.env_vars {
+            write!(f, "{}={} ", k, shell_quote(v))?;
+        }
+
+        write!(f, "{}", command_line(&self.exe, &self.args))
+    }
+}
+
+pub(crate) fn shell_quote(x: &str) -> String {
+    if x.is_empty() || x.contains(|c: char| c.is_whitespace() || "\"'|$*;&<>()".contains(c)) {
+        format!("'{}'", x.replace('\'', "'\\''"))
+    } else {
+        x.to_string()
+    }
+}
+
+/// `exe` and `args` as one line that a shell would run the same way
+pub fn command_line(exe: &str, args: &[String]) -> String {
+    std::iter::once(exe)
+        .chain(args.iter().map(String::as_str))
+        .map(shell_quote)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+type Responder = Box<dyn Fn(&str, &[String]) -> Result<String>>;
+
+/// Records commands instead of running them. The responder decides what each
+/// command prints to stdout (and can simulate side effects).
+pub struct RecordingExecutor {
+    commands: RefCell<Vec<RecordedCommand>>,
+    responder: Responder,
+}
+
+impl RecordingExecutor {
+    pub fn new(responder: impl Fn(&str, &[String]) -> Result<String> + 'static) -> Self {
+        Self {
+            commands: RefCell::new(vec![]),
+            responder: Box::new(responder),
+        }
+    }
+
+    pub fn commands(&self) -> Vec<RecordedCommand> {
+        self.commands.borrow().clone()
+    }
+}
+
+impl Executor for RecordingExecutor {
+    fn execute(
+        &self,
+        exe: &str,
+        args: &[String],
+        env_vars: &[(String, String)],
+        _stdin: Option<&str>,
+        _stop: &StopWhen,
+    ) -> Result<Output> {
+        self.commands.borrow_mut().push(RecordedCommand {
+            exe: exe.to_string(),
+            args: args.to_vec(),
+            env_vars: env_vars.to_vec(),
+        });
+
+        let stdout = (self.responder)(exe, args)?;
+
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.into_bytes(),
+            stderr: vec![],
+        })
+    }
+
+    fn execute_interactive(&self, exe: &str, args: &[String]) -> Result<ExitStatus> {
+        self.commands.borrow_mut().push(RecordedCommand {
+            exe: exe.to_string(),
+            args: args.to_vec(),
+            env_vars: vec![],
+        });
+
+        Ok(ExitStatus::from_raw(0))
+    }
+
+    fn execute_until(
+        &self,
+        exe: &str,
+        args: &[String],
+        marker: &str,
+        _timeout: Duration,
+    ) -> Result<(bool, String)> {
+        self.commands.borrow_mut().push(RecordedCommand {
+            exe: exe.to_string(),
+            args: args.to_vec(),
+            env_vars: vec![],
+        });
+
+        let stdout = (self.responder)(exe, args)?;
+
+        Ok((stdout.contains(marker), stdout))
+    }
+}
+
+/// Passes commands through to another executor, keeping a transcript of each
+/// one with its exit status and output
+pub struct TranscriptExecutor {
+    inner: Rc<dyn Executor>,
+    transcript: RefCell<String>,
+}
+
+impl TranscriptExecutor {
+    pub fn new(inner: Rc<dyn Executor>) -> Self {
+        Self {
+            inner,
+            transcript: RefCell::new(String::new()),
+        }
+    }
+
+    pub fn transcript(&self) -> String {
+        self.transcript.borrow().clone()
+    }
+
+    fn record(&self, exe: &str, args: &[String], env_vars: &[(String, String)], result: String) {
+        let command = RecordedCommand {
+            exe: exe.to_string(),
+            args: args.to_vec(),
+            env_vars: env_vars.to_vec(),
+        };
+
+        let mut transcript = self.transcript.borrow_mut();
+        transcript.push_str(&format!("$ {}\n{}\n", command, result));
+    }
+}
+
+impl Executor for TranscriptExecutor {
+    fn mount(&self, source: &str, target: &str, options: &MountOptions) -> Result<()> {
+        let result = self.inner.mount(source, target, options);
+
+        self.record(
+            "mount",
+            &options.mount_args(source, target),
+            &[],
+            match &result {
+                Ok(()) => "ok\n".into(),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn attach_loop(&self, image: &str, options: &LoopOptions) -> Result<String> {
+        let result = self.inner.attach_loop(image, options);
+
+        self.record(
+            "losetup",
+            &options.losetup_args(image),
+            &[],
+            match &result {
+                Ok(device) => format!("{}\n", device),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn detach_loop(&self, device: &str) -> Result<()> {
+        let result = self.inner.detach_loop(device);
+
+        self.record(
+            "losetup",
+            &["-d".into(), device.into()],
+            &[],
+            match &result {
+                Ok(()) => "ok\n".into(),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn find_free_loop(&self) -> Result<String> {
+        self.inner.find_free_loop()
+    }
+
+    fn partition_device(&self, device: &str, number: u32) -> Result<String> {
+        self.inner.partition_device(device, number)
+    }
+
+    fn unpack_tar(&self, archive: &str, dest: &str) -> Result<()> {
+        let result = self.inner.unpack_tar(archive, dest);
+
+        self.record(
+            "tar",
+            &tar_args(archive, dest),
+            &[],
+            match &result {
+                Ok(()) => "ok\n".into(),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn probe_filesystem(&self, device: &str) -> Result<Filesystem> {
+        let result = self.inner.probe_filesystem(device);
+
+        self.record(
+            "blkid",
+            &blkid_args(device),
+            &[],
+            match &result {
+                Ok(filesystem) => filesystem.blkid_export(),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn capabilities(&self) -> Result<u64> {
+        let result = self.inner.capabilities();
+
+        self.record(
+            "grep",
+            &cap_eff_args(),
+            &[],
+            match &result {
+                Ok(mask) => format!("CapEff:\t{:016x}\n", mask),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn mounts_of(&self, device: &str) -> Result<Vec<String>> {
+        let result = self.inner.mounts_of(device);
+
+        self.record(
+            "lsblk",
+            &lsblk_args(device),
+            &[],
+            match &result {
+                Ok(mounts) => mounts.iter().rev().map(|x| format!("{}\n", x)).collect(),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn umount(&self, target: &str) -> Result<()> {
+        let result = self.inner.umount(target);
+
+        self.record(
+            "umount",
+            &[target.to_string()],
+            &[],
+            match &result {
+                Ok(()) => "ok\n".into(),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn umount_lazy(&self, target: &str) -> Result<()> {
+        let result = self.inner.umount_lazy(target);
+
+        self.record(
+            "umount",
+            &["-l".into(), target.to_string()],
+            &[],
+            match &result {
+                Ok(()) => "ok\n".into(),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn execute(
+        &self,
+        exe: &str,
+        args: &[String],
+        env_vars: &[(String, String)],
+        stdin: Option<&str>,
+        stop: &StopWhen,
+    ) -> Result<Output> {
+        let start = Instant::now();
+        let result = self.inner.execute(exe, args, env_vars, stdin, stop);
+
+        self.record(
+            exe,
+            args,
+            env_vars,
+            match &result {
+                Ok(output) => format!(
+                    "{} after {:.2?}\n--- stdout\n{}\n--- stderr\n{}\n",
+                    output.status,
+                    start.elapsed(),
+                    output_string(&output.stdout),
+                    output_string(&output.stderr)
+                ),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn execute_interactive(&self, exe: &str, args: &[String]) -> Result<ExitStatus> {
+        let result = self.inner.execute_interactive(exe, args);
+
+        self.record(
+            exe,
+            args,
+            &[],
+            match &result {
+                Ok(status) => format!("{} (interactive)\n", status),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+
+    fn execute_until(
+        &self,
+        exe: &str,
+        args: &[String],
+        marker: &str,
+        timeout: Duration,
+    ) -> Result<(bool, String)> {
+        let result = self.inner.execute_until(exe, args, marker, timeout);
+
+        self.record(
+            exe,
+            args,
+            &[],
+            match &result {
+                Ok((found, output)) => format!(
+                    "{:?} {}\n--- stdout\n{}\n",
+                    marker,
+                    if *found { "seen" } else { "not seen" },
+                    output
+                ),
+                Err(e) => format!("error: {}\n", e),
+            },
+        );
+
+        result
+    }
+}
+
+thread_local! {
+    static EXECUTOR: RefCell<Rc<dyn Executor>> = RefCell::new(Rc::new(HostExecutor));
+    static STOP: RefCell<StopWhen> = RefCell::new(StopWhen::default());
+    static CLEANING_UP: Cell<bool> = const { Cell::new(false) };
+    static RETRY: Cell<RetryPolicy> = const { Cell::new(RetryPolicy::DEFAULT) };
+}
+
+/// Stops commands run through `run` and friends once cancelled. Clones share
+/// the same state, and cancelling is safe from a signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// How to retry things that fail for reasons that tend to go away: a
+/// mirror that's briefly unreachable, or a mount that's still busy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts after the first
+    pub retries: u32,
+
+    /// Before the first retry, doubling for each one after
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub const DEFAULT: RetryPolicy = RetryPolicy {
+        retries: 3,
+        delay: Duration::from_secs(2),
+    };
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Retry this thread's flaky steps as `policy` says, returning the previous
+/// policy
+pub fn set_retry_policy(policy: RetryPolicy) -> RetryPolicy {
+    RETRY.with(|x| x.replace(policy))
+}
+
+/// Run `f`, running it again as this thread's retry policy allows while it
+/// fails with an error `retryable` accepts. Cancelling stops the retries.
+pub fn retry<T>(
+    what: &str,
+    retryable: impl Fn(&anyhow::Error) -> bool,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let policy = RETRY.with(Cell::get);
+    let mut delay = policy.delay;
+
+    for attempt in 0.. {
+        match f() {
+            Ok(x) => return Ok(x),
+
+            Err(e) if attempt < policy.retries && retryable(&e) => {
+                warn!(
+                    "{} failed, retrying in {:?} ({} of {}): {:#}",
+                    what,
+                    delay,
+                    attempt + 1,
+                    policy.retries,
+                    e
+                );
+                std::thread::sleep(delay);
+                check_cancelled()?;
+                delay *= 2;
+            }
+
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!();
+}
+
+/// Whether a failure might not happen again: anything but being cancelled.
+/// For steps that go over the network, where a failed command is most
+/// likely a mirror or registry having a bad moment.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    !matches!(error.downcast_ref::<Error>(), Some(Error::Cancelled))
+}
+
+#[test]
+fn test_retry() {
+    let previous = set_retry_policy(RetryPolicy {
+        retries: 2,
+        delay: Duration::from_millis(1),
+    });
+
+    let mut attempts = 0;
+    let result = retry("flaky", is_transient, || {
+        attempts += 1;
+        if attempts < 3 {
+            bail!("not yet");
+        }
+        Ok(attempts)
+    });
+    assert_eq!(result.unwrap(), 3);
+
+    // Out of retries
+    let mut attempts = 0;
+    let result: Result<()> = retry("broken", is_transient, || {
+        attempts += 1;
+        bail!("never")
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 3);
+
+    // Not worth retrying
+    let mut attempts = 0;
+    let result: Result<()> = retry("cancelled", is_transient, || {
+        attempts += 1;
+        Err(Error::Cancelled.into())
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+
+    set_retry_policy(previous);
+}
+
+/// When a running command should be killed
+#[derive(Debug, Clone, Default)]
+pub struct StopWhen {
+    pub cancel: Option<CancelToken>,
+    /// How long any one command may run
+    pub timeout: Option<Duration>,
+}
+
+/// Stop this thread's commands as `stop` says, returning the previous
+/// setting
+pub fn set_stop_when(stop: StopWhen) -> StopWhen {
+    STOP.with(|x| x.replace(stop))
+}
+
+/// Run `f` ignoring cancellation and timeouts. Unmounting and detaching
+/// loop devices has to happen even, or especially, when a build was
+/// cancelled.
+pub fn cleanup<T>(f: impl FnOnce() -> T) -> T {
+    let previous = CLEANING_UP.with(|x| x.replace(true));
+    let result = f();
+    CLEANING_UP.with(|x| x.set(previous));
+    result
+}
+
+/// Whether the build should stop: fails with `Error::Cancelled` once this
+/// thread's cancel token has been cancelled
+pub fn check_cancelled() -> Result<()> {
+    let cancelled = STOP.with(|x| {
+        x.borrow()
+            .cancel
+            .as_ref()
+            .is_some_and(CancelToken::is_cancelled)
+    });
+
+    if cancelled && !CLEANING_UP.with(Cell::get) {
+        return Err(Error::Cancelled.into());
+    }
+
+    Ok(())
+}
+
+/// Install an executor for this thread, returning the previous one.
+pub fn set_executor(executor: Rc<dyn Executor>) -> Rc<dyn Executor> {
+    EXECUTOR.with(|x| x.replace(executor))
+}
+
+/// The executor installed for this thread
+pub fn executor() -> Rc<dyn Executor> {
+    EXECUTOR.with(|x| x.borrow().clone())
+}
+
+enum TaskState<T> {
+    Running(std::thread::JoinHandle<Result<T>>),
+    Deferred(Box<dyn FnOnce() -> Result<T>>),
+}
+
+/// Work started with [`spawn_task`], running alongside the build until it
+/// is joined
+pub struct Task<T> {
+    name: String,
+    state: Option<TaskState<T>>,
+}
+
+/// Start `f` on its own thread, with this thread's cancel token, timeout,
+/// retry policy and event subscriber. If the executor isn't
+/// [`concurrent`](Executor::concurrent), `f` runs when the task is joined
+/// instead, so commands are seen in the same order as without tasks.
+pub fn spawn_task<T: Send + 'static>(
+    name: &str,
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<Task<T>> {
+    if !executor().concurrent() {
+        return Ok(Task {
+            name: name.into(),
+            state: Some(TaskState::Deferred(Box::new(f))),
+        });
+    }
+
+    let stop = STOP.with(|x| x.borrow().clone());
+    let retry_policy = RETRY.with(Cell::get);
+    let subscriber = events::events();
+
+    debug!("starting {} in the background", name);
+    let handle = std::thread::Builder::new()
+        .name(name.into())
+        .spawn(move || {
+            set_stop_when(stop);
+            set_retry_policy(retry_policy);
+            events::set_events(subscriber);
+            f()
+        })?;
+
+    Ok(Task {
+        name: name.into(),
+        state: Some(TaskState::Running(handle)),
+    })
+}
+
+impl<T> Task<T> {
+    /// Wait for the task to finish, returning what it returned
+    pub fn join(mut self) -> Result<T> {
+        match self.state.take().unwrap() {
+            TaskState::Running(handle) => match handle.join() {
+                Ok(result) => result,
+                Err(panic) => std::panic::resume_unwind(panic),
+            },
+            TaskState::Deferred(f) => f(),
+        }
+    }
+}
+
+/// Dropped without being joined means the build failed elsewhere. A running
+/// task is waited for, so it's done with the working directory before that
+/// is cleaned up; a deferred one never starts.
+impl<T> Drop for Task<T> {
+    fn drop(&mut self) {
+        if let Some(TaskState::Running(handle)) = self.state.take() {
+            debug!("waiting for {} to finish", self.name);
+            if let Ok(Err(e)) = handle.join() {
+                warn!("{} failed: {:#}", self.name, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_spawn_task() -> Result<()> {
+    // Both sleeps overlap, and the task sees this thread's retry policy
+    let policy = RetryPolicy {
+        retries: 7,
+        delay: Duration::from_millis(1),
+    };
+    let previous = set_retry_policy(policy);
+    let start = Instant::now();
+    let task = spawn_task("test", || {
+        std::thread::sleep(Duration::from_millis(300));
+        Ok(RETRY.with(Cell::get))
+    })?;
+    std::thread::sleep(Duration::from_millis(300));
+    let seen = task.join();
+    set_retry_policy(previous);
+
+    assert_eq!(seen?, policy);
+    assert!(start.elapsed() < Duration::from_millis(550));
+
+    // Recorded commands stay in the order they'd run in without tasks
+    let recorder = Rc::new(RecordingExecutor::new(|_, _| Ok(String::new())));
+    let previous = set_executor(recorder.clone());
+    let result = spawn_task("test", || run("second".into(), &[]).map(|_| ())).and_then(|task| {
+        run("first".into(), &[])?;
+        task.join()?;
+
+        // Never joined, never run
+        spawn_task("test", || run("third".into(), &[]).map(|_| ()))
+    });
+    set_executor(previous);
+    drop(result?);
+
+    let commands: Vec<String> = recorder.commands().iter().map(|x| x.to_string()).collect();
+    assert_eq!(commands, ["first", "second"]);
+
+    Ok(())
+}
+
+pub fn run(exe: String, args: &[String]) -> Result<CommandOutput> {
+    run_with_env(exe, args, &[])
+}
+
+pub fn run_with_env(
+    exe: String,
+    args: &[String],
+    env_vars: &[(String, String)],
+) -> Result<CommandOutput> {
+    execute(exe, args, env_vars, None)
+}
+
+/// Run a command, feeding it `stdin`. Useful for things like passwd that
+/// prompt for input.
+pub fn run_with_stdin(exe: String, args: &[String], stdin: String) -> Result<CommandOutput> {
+    execute(exe, args, &[], Some(&stdin))
+}
+
+/// Mount `source` on `target` through this thread's executor
+pub fn mount_fs(source: &str, target: &str, options: &MountOptions) -> Result<()> {
+    check_cancelled()?;
+    executor().mount(source, target, options)
+}
+
+pub fn umount_fs(target: &str) -> Result<()> {
+    executor().umount(target)
+}
+
+pub fn umount_fs_lazy(target: &str) -> Result<()> {
+    executor().umount_lazy(target)
+}
+
+/// Sync and unmount `target`. A mount that stays busy (a process still
+/// running in the chroot, say) is retried as the retry policy says, then
+/// detached lazily so the rest of the cleanup can go on.
+pub fn unmount(target: &str) -> Result<()> {
+    if let Err(e) = run("sync".into(), &[]) {
+        warn!("could not sync: {:#}", e);
+    }
+
+    match retry(&format!("umount {}", target), is_busy, || umount_fs(target)) {
+        Err(e) if is_busy(&e) => {
+            warn!("{} is still busy, detaching it lazily", target);
+            umount_fs_lazy(target)
+        }
+
+        result => result,
+    }
+}
+
+#[test]
+fn test_unmount_busy() -> Result<()> {
+    let recorder = Rc::new(RecordingExecutor::new(|exe, args| {
+        if exe == "umount" && args[0] != "-l" {
+            return Err(std::io::Error::from_raw_os_error(libc::EBUSY).into());
+        }
+        Ok(String::new())
+    }));
+    let previous = set_executor(recorder.clone());
+    let previous_policy = set_retry_policy(RetryPolicy {
+        retries: 4,
+        delay: Duration::from_millis(1),
+    });
+    let result = unmount("/mnt");
+    set_retry_policy(previous_policy);
+    set_executor(previous);
+    result?;
+
+    // sync, the first attempt and four retries, then the lazy unmount
+    let commands: Vec<String> = recorder.commands().iter().map(|x| x.to_string()).collect();
+    assert_eq!(commands.len(), 1 + 5 + 1);
+    assert_eq!(commands[1], "umount /mnt");
+    assert_eq!(commands.last().unwrap(), "umount -l /mnt");
+
+    Ok(())
+}
+
+/// Whether a failed umount was EBUSY, from umount2 or from umount(8)
+fn is_busy(error: &anyhow::Error) -> bool {
+    error.chain().any(|x| {
+        if let Some(e) = x.downcast_ref::<std::io::Error>() {
+            return e.raw_os_error() == Some(libc::EBUSY);
+        }
+
+        matches!(
+            x.downcast_ref::<Error>(),
+            Some(Error::CommandFailed { stderr, .. }) if stderr.contains("busy")
+        )
+    })
+}
+
+/// Attach `image` to a free loop device through this thread's executor
+pub fn attach_loop(image: &str, options: &LoopOptions) -> Result<String> {
+    check_cancelled()?;
+    executor().attach_loop(image, options)
+}
+
+/// Detach the loop device `device`, retrying while it's busy
+pub fn detach_loop(device: &str) -> Result<()> {
+    retry(&format!("detach {}", device), is_busy, || {
+        executor().detach_loop(device)
+    })
+}
+
+pub fn find_free_loop() -> Result<String> {
+    executor().find_free_loop()
+}
+
+/// The device node for partition `number` of `device`
+pub fn partition_device(device: &str, number: u32) -> Result<String> {
+    executor().partition_device(device, number)
+}
+
+/// Unpack the tar archive `archive` into `dest` through this thread's
+/// executor
+pub fn unpack_tar(archive: &str, dest: &str) -> Result<()> {
+    check_cancelled()?;
+    executor().unpack_tar(archive, dest)
+}
+
+/// The type and UUID of the filesystem on `device`
+pub fn probe_filesystem(device: &str) -> Result<Filesystem> {
+    executor().probe_filesystem(device)
+}
+
+/// This process's effective capabilities, as a bitmask of CAP_* numbers
+pub fn capabilities() -> Result<u64> {
+    executor().capabilities()
+}
+
+/// Unmount whatever else has mounted `device` or its partitions. Desktop
+/// hosts running udisks automount filesystems they find on new loop
+/// devices, and the ESP has one as soon as the disk is attached; left
+/// mounted, it would be written behind the kernel's back, and the loop
+/// device couldn't be detached.
+pub fn unmount_automounts(device: &str) -> Result<()> {
+    for mount in executor().mounts_of(device)? {
+        warn!(
+            "{} is mounted on {}, probably by an automounter; unmounting it",
+            device, mount
+        );
+        unmount(&mount)?;
+    }
+
+    Ok(())
+}
+
+/// Run a command with the terminal's stdin/stdout/stderr. Its exit status
+/// is returned rather than checked.
+pub fn run_interactive(exe: String, args: &[String]) -> Result<ExitStatus> {
+    let executor = EXECUTOR.with(|x| x.borrow().clone());
+
+    executor.execute_interactive(&exe, args)
+}
+
+/// Run a command until `marker` appears in its stdout, killing it then or
+/// after `timeout`. Returns whether the marker was seen, and the output.
+pub fn run_until(
+    exe: String,
+    args: &[String],
+    marker: &str,
+    timeout: Duration,
+) -> Result<(bool, String)> {
+    let executor = EXECUTOR.with(|x| x.borrow().clone());
+
+    let (found, output) = executor.execute_until(&exe, args, marker, timeout)?;
+
+    trace!("stdout: {}", output);
+
+    Ok((found, output))
+}
+
+fn execute(
+    exe: String,
+    args: &[String],
+    env_vars: &[(String, String)],
+    stdin: Option<&str>,
+) -> Result<CommandOutput> {
+    let executor = EXECUTOR.with(|x| x.borrow().clone());
+
+    check_cancelled()?;
+
+    let stop = if CLEANING_UP.with(Cell::get) {
+        StopWhen::default()
+    } else {
+        STOP.with(|x| x.borrow().clone())
+    };
+
+    let command = command_line(&exe, args);
+    emit(BuildEvent::CommandStarted {
+        command: command.clone(),
+    });
+
+    let start = Instant::now();
+    let result = executor.execute(&exe, args, env_vars, stdin, &stop);
+
+    emit(BuildEvent::CommandFinished {
+        command: command.clone(),
+        elapsed: start.elapsed(),
+        succeeded: result.as_ref().is_ok_and(|x| x.status.success()),
+    });
+    let result = result?;
+
+    let output = CommandOutput {
+        command,
+        status: result.status,
+        stdout: String::from_utf8_lossy(&result.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+        duration: start.elapsed(),
+    };
+
+    debug!(
+        "{} exited with {} after {:.2?}",
+        exe, output.status, output.duration
+    );
+
+    if !output.status.success() {
+        return Err(Error::CommandFailed {
+            stderr: output_stderr_string(&output),
+            cmd: output.command,
+            status: output.status,
+        }
+        .into());
+    }
+
+    Ok(output)
+}
+
+#[test]
+fn test_run_until() -> Result<()> {
+    let (found, output) = run_until(
+        "sh".into(),
+        &["-c".into(), "echo booting; echo 'login:'; sleep 60".into()],
+        "login:",
+        Duration::from_secs(30),
+    )?;
+    assert!(found);
+    assert!(output.starts_with("booting\n"));
+
+    let start = std::time::Instant::now();
+    let (found, _) = run_until(
+        "sleep".into(),
+        &["60".into()],
+        "login:",
+        Duration::from_millis(100),
+    )?;
+    assert!(!found);
+    assert!(start.elapsed() < Duration::from_secs(30));
+
+    Ok(())
+}
+
+#[test]
+fn test_run() -> Result<()> {
+    let result = run("ls".into(), &["-al".into()])?;
+
+    println!("{:?}", result.status);
+    assert!(result.status.success());
+    assert_eq!(result.status.code().unwrap(), 0);
+    assert_eq!(result.command, "ls -al");
+
+    println!("{}", result.stdout);
+
+    Ok(())
+}
+
+#[test]
+fn test_run_utf8() -> Result<()> {
+    let result = run("printf".into(), &["caf\\303\\251\\n\\377\\n".into()])?;
+    assert_eq!(result.stdout, "café\n\u{fffd}\n");
+    assert_eq!(output_stdout_string(&result), "café\n\u{fffd}");
+
+    Ok(())
+}
 
-use anyhow::{bail, Result};
-use tempfile::tempdir;
+#[test]
+fn test_stop_when() -> Result<()> {
+    let previous = set_stop_when(StopWhen {
+        cancel: None,
+        timeout: Some(Duration::from_millis(100)),
+    });
+    let result = run("sh".into(), &["-c".into(), "sleep 60 & wait".into()]);
+    set_stop_when(previous);
+
+    let error = result.unwrap_err();
+    assert!(
+        matches!(error.downcast_ref::<Error>(), Some(Error::TimedOut { .. })),
+        "{}",
+        error
+    );
+
+    let token = CancelToken::new();
+    let previous = set_stop_when(StopWhen {
+        cancel: Some(token.clone()),
+        timeout: None,
+    });
+
+    let canceller = {
+        let token = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        })
+    };
+    let result = run("sleep".into(), &["60".into()]);
+    canceller.join().unwrap();
+
+    // Nothing new starts once cancelled, except for cleanup
+    let after = run("true".into(), &[]);
+    let during_cleanup = cleanup(|| run("true".into(), &[]));
+    set_stop_when(previous);
+
+    for result in [result.map(|_| ()), after.map(|_| ())] {
+        let error = result.unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<Error>(), Some(Error::Cancelled)),
+            "{}",
+            error
+        );
+    }
+    during_cleanup?;
 
-pub fn output_stdout_string(output: &Output) -> String {
-    let mut text = output
-        .stdout
-        .iter()
-        .map(|x| (*x as char).to_string())
-        .collect::<String>();
+    Ok(())
+}
 
-    if text.ends_with('\n') {
-        text.pop();
+#[test]
+fn test_mount_args() {
+    assert_eq!(
+        MountOptions::fstype("ext4")
+            .read_only(true)
+            .data("errors=remount-ro")
+            .mount_args("/dev/loop0p3", "/mnt"),
+        [
+            "-t",
+            "ext4",
+            "-o",
+            "ro,errors=remount-ro",
+            "/dev/loop0p3",
+            "/mnt"
+        ]
+    );
+    assert_eq!(
+        MountOptions::bind().mount_args("/dev", "/mnt/dev"),
+        ["--bind", "/dev", "/mnt/dev"]
+    );
+}
+
+#[test]
+fn test_command_failed() {
+    let error = run("sh".into(), &["-c".into(), "echo oops >&2; exit 3".into()]).unwrap_err();
+
+    let Some(Error::CommandFailed {
+        cmd,
+        status,
+        stderr,
+    }) = error.downcast_ref::<Error>()
+    else {
+        panic!("expected CommandFailed, got {}", error);
+    };
+
+    assert_eq!(cmd, "sh -c 'echo oops >&2; exit 3'");
+    assert_eq!(status.code(), Some(3));
+    assert_eq!(stderr, "oops");
+}
+
+/// Turn an image reference like `registry.example.com/team/mongo:4` into
+/// something usable as a hostname (`mongo`).
+pub fn hostname_from_image_name(image_name: &str) -> String {
+    let name = image_name.split('@').next().unwrap();
+    let name = name.rsplit('/').next().unwrap();
+    let name = name.split(':').next().unwrap();
+
+    let hostname: String = name
+        .to_ascii_lowercase()
+        .chars()
+        .map(|x| if x.is_ascii_alphanumeric() { x } else { '-' })
+        .take(63)
+        .collect();
+
+    let hostname = hostname.trim_matches('-');
+
+    if hostname.is_empty() {
+        "localhost".into()
+    } else {
+        hostname.into()
     }
+}
 
-    text
+#[test]
+fn test_hostname_from_image_name() {
+    assert_eq!(hostname_from_image_name("debian"), "debian");
+    assert_eq!(hostname_from_image_name("mongo:4"), "mongo");
+    assert_eq!(
+        hostname_from_image_name("registry.example.com:5000/team/crucible_tester:latest"),
+        "crucible-tester"
+    );
+    assert_eq!(hostname_from_image_name("alpine@sha256:abcd"), "alpine");
+    assert_eq!(hostname_from_image_name("__"), "localhost");
 }
 
-pub fn output_stderr_string(output: &Output) -> String {
-    let mut text = output
-        .stderr
+/// The kernel versions with modules installed in a root filesystem
+pub fn kernel_versions(root: &str) -> Result<Vec<String>> {
+    let mut versions: Vec<String> = std::fs::read_dir(format!("{}/lib/modules/", root))?
+        .collect::<Result<Vec<std::fs::DirEntry>, std::io::Error>>()?
+        .into_iter()
+        .map(|x| x.file_name().to_string_lossy().to_string())
+        .collect();
+
+    versions.sort();
+
+    Ok(versions)
+}
+
+/// Hex encoded SHA-256 of a file's contents
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(hasher
+        .finalize()
         .iter()
-        .map(|x| (*x as char).to_string())
-        .collect::<String>();
+        .map(|x| format!("{:02x}", x))
+        .collect())
+}
 
-    if text.ends_with('\n') {
-        text.pop();
+static PROGRESS: AtomicBool = AtomicBool::new(true);
+
+/// Turn progress bars and spinners on or off. They are also hidden
+/// whenever stderr is not a terminal.
+pub fn set_progress_enabled(enabled: bool) {
+    PROGRESS.store(enabled, Ordering::Relaxed);
+}
+
+fn progress_bar(len: Option<u64>, template: &str, message: &str) -> ProgressBar {
+    if !PROGRESS.load(Ordering::Relaxed) {
+        return ProgressBar::hidden();
     }
 
-    text
+    let bar = match len {
+        Some(len) => ProgressBar::new(len),
+        None => ProgressBar::new_spinner(),
+    };
+
+    bar.set_style(ProgressStyle::with_template(template).unwrap());
+    bar.set_message(message.to_string());
+    bar.enable_steady_tick(Duration::from_millis(100));
+
+    bar
 }
 
-pub fn run(exe: String, args: &[String]) -> Result<Output> {
-    run_with_env(exe, args, &[])
+/// Show a spinner with the elapsed time while `f` runs
+pub fn with_spinner<T>(message: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let bar = progress_bar(None, "{spinner} {msg} [{elapsed}]", message);
+    let result = f();
+    bar.finish_and_clear();
+    result
+}
+
+/// Show the size of the file at `path` growing towards `total` bytes while
+/// `f` runs, for commands that write a file we can watch. With no `total`
+/// only the bytes written so far are shown.
+pub fn with_file_progress<T>(
+    message: &str,
+    path: &Path,
+    total: Option<u64>,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let bar = match total {
+        Some(total) => progress_bar(
+            Some(total),
+            "{msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+            message,
+        ),
+        None => progress_bar(None, "{spinner} {msg} {bytes} ({bytes_per_sec})", message),
+    };
+
+    let done = AtomicBool::new(false);
+    let mut events = ProgressEvents::new(message, total);
+
+    let result = std::thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                if let Ok(metadata) = std::fs::metadata(path) {
+                    bar.set_position(metadata.len());
+                    events.set_position(metadata.len());
+                }
+                std::thread::sleep(Duration::from_millis(200));
+            }
+
+            if let Ok(metadata) = std::fs::metadata(path) {
+                events.finish(metadata.len());
+            }
+        });
+
+        let result = f();
+        done.store(true, Ordering::Relaxed);
+        result
+    });
+
+    bar.finish_and_clear();
+    result
 }
 
-pub fn run_with_env(exe: String, args: &[String], env_vars: &[(String, String)]) -> Result<Output> {
-    let mut cmd = Command::new(exe);
+/// A progress bar and progress events for `total` bytes of work done in
+/// pieces, like a copy or the parts of an upload
+pub struct ByteProgress {
+    bar: ProgressBar,
+    events: ProgressEvents,
+}
 
-    for arg in args {
-        cmd.arg(arg);
+impl ByteProgress {
+    pub fn new(message: &str, total: u64) -> Self {
+        Self {
+            bar: progress_bar(
+                Some(total),
+                "{msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                message,
+            ),
+            events: ProgressEvents::new(message, Some(total)),
+        }
     }
 
-    for env_var in env_vars {
-        cmd.env(&env_var.0, &env_var.1);
+    pub fn set_position(&mut self, done: u64) {
+        self.bar.set_position(done);
+        self.events.set_position(done);
     }
 
-    // no stdin
-    cmd.stdin(Stdio::null());
+    pub fn finish(mut self, done: u64) {
+        self.bar.finish_and_clear();
+        self.events.finish(done);
+    }
+}
 
-    // Debug: print what is about to run
-    println!("# {:?} {:?}", cmd, env_vars);
+/// Copy `src` to `dst` showing progress. Blocks of zeros are skipped rather
+/// than written so a sparse disk image stays sparse.
+pub fn copy_with_progress(message: &str, src: &Path, dst: &Path) -> Result<u64> {
+    const BLOCK: usize = 1024 * 1024;
 
-    let result = cmd.output()?;
+    let mut input = File::open(src)?;
+    let len = input.metadata()?.len();
+    let mut output = File::create(dst)?;
 
-    // Debug: print output
-    println!("O# {}", output_stdout_string(&result));
-    println!("E# {}", output_stderr_string(&result));
+    let mut progress = ByteProgress::new(message, len);
 
-    if !result.status.success() {
-        bail!("Command failed!");
-    }
+    let mut buf = vec![0u8; BLOCK];
+    let mut copied = 0;
 
-    Ok(result)
-}
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
 
-#[test]
-fn test_run() -> Result<()> {
-    let result = run("ls".into(), &["-al".into()])?;
+        if buf[..n].iter().all(|x| *x == 0) {
+            output.seek(SeekFrom::Current(n as i64))?;
+        } else {
+            output.write_all(&buf[..n])?;
+        }
 
-    println!("{:?}", result.status);
-    assert!(result.status.success());
-    assert_eq!(result.status.code().unwrap(), 0);
+        copied += n as u64;
+        progress.set_position(copied);
+    }
 
-    let text = result
-        .stdout
-        .iter()
-        .map(|x| (*x as char).to_string())
-        .collect::<Vec<String>>()
-        .join("");
-    println!("{}", text);
+    output.set_len(copied)?;
+    progress.finish(copied);
 
-    Ok(())
+    Ok(copied)
 }
 
 #[test]
-fn grep() -> Result<()> {
-    let result = run(
-        "blkid".into(),
-        &["-o".into(), "export".into(), "/dev/nvme0n1p1".into()],
-    )?;
+fn test_copy_with_progress() -> Result<()> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    let dst = dir.path().join("dst");
 
-    let text = output_stdout_string(&result);
+    let mut data = vec![0u8; 3 * 1024 * 1024 + 17];
+    data[1024 * 1024 + 5] = 1;
+    data[3 * 1024 * 1024 + 16] = 2;
+    std::fs::write(&src, &data)?;
 
-    let text: Vec<&str> = text
-        .split("\n")
-        .filter(|x| x.starts_with("UUID="))
-        .collect();
+    assert_eq!(copy_with_progress("copy", &src, &dst)?, data.len() as u64);
+    assert_eq!(std::fs::read(&dst)?, data);
 
-    assert_eq!(text, vec!["UUID=D466-DF85"]);
+    // Trailing zeros still count towards the length
+    std::fs::write(&src, vec![0u8; 4096])?;
+    copy_with_progress("copy", &src, &dst)?;
+    assert_eq!(std::fs::read(&dst)?, vec![0u8; 4096]);
 
     Ok(())
 }
 
 pub struct LoopbackDevice {
     path: String,
+    attached: bool,
 }
 
 impl LoopbackDevice {
     pub fn new(source_path: String) -> Result<Self> {
-        let output = run(
-            "losetup".into(),
-            &["--show".into(), "--find".into(), source_path],
-        )?;
+        Self::attach(&source_path, &LoopOptions::default())
+    }
+
+    pub fn attach(image: &str, options: &LoopOptions) -> Result<Self> {
+        Ok(Self {
+            path: attach_loop(image, options)?,
+            attached: true,
+        })
+    }
+
+    /// Stands in for a loop device when `image` is built without one
+    pub fn unattached(image: &str) -> Self {
+        Self {
+            path: image.into(),
+            attached: false,
+        }
+    }
 
-        let path: String = output_stdout_string(&output);
+    /// The device node for partition `number`, or nothing when the image
+    /// isn't attached
+    pub fn partition(&self, number: u32) -> Result<String> {
+        if !self.attached {
+            return Ok(String::new());
+        }
 
-        Ok(Self { path })
+        partition_device(&self.path, number)
     }
 
     pub fn path(&self) -> String {
@@ -131,75 +2075,415 @@ impl LoopbackDevice {
     }
 }
 
+impl Cleanup for LoopbackDevice {
+    fn cleanup(&mut self) -> Result<()> {
+        if self.attached {
+            self.attached = false;
+
+            // Anything of the build's own is unmounted by now
+            unmount_automounts(&self.path)?;
+            detach_loop(&self.path)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl Drop for LoopbackDevice {
     fn drop(&mut self) {
-        println!("# Dropping {}", self.path);
-
-        // XXX if your OS auto-mounted this, need a umount
-        run("losetup".into(), &["-d".into(), self.path.clone()]).expect("could not drop!");
+        debug!("dropping {}", self.path);
+        cleanup_or_warn(self);
     }
 }
 
 pub struct Mount {
     dest: String,
+    mounted: bool,
+    /// Mounts were made under it, so it's detached with them all
+    recursive: bool,
 }
 
 impl Mount {
-    pub fn new(source: String, dest: String) -> Result<Self> {
-        run("mkdir".into(), &["-p".into(), dest.clone()])?;
-
-        println!(">> mount {} {}", source, dest);
-        run("mount".into(), &[source, dest.clone()])?;
+    /// Mount `source` on `dest`, creating `dest` if needed
+    pub fn new(source: String, dest: String, options: &MountOptions) -> Result<Self> {
+        std::fs::create_dir_all(&dest)?;
+        mount_fs(&source, &dest, options)?;
 
-        Ok(Self { dest })
+        Ok(Self {
+            dest,
+            mounted: true,
+            recursive: options.recursive,
+        })
     }
 
     pub fn bind(source: String, dest: String) -> Result<Self> {
-        run("mkdir".into(), &["-p".into(), dest.clone()])?;
-
-        println!(">> mount --bind {} {}", source, dest);
-        run("mount".into(), &["--bind".into(), source, dest.clone()])?;
+        Self::new(source, dest, &MountOptions::bind())
+    }
 
-        Ok(Self { dest })
+    /// Bind `source` and the mounts under it. In a user namespace the
+    /// mounts under /dev, /proc and /sys are locked to them, so binding
+    /// them takes this.
+    pub fn rbind(source: String, dest: String) -> Result<Self> {
+        Self::new(source, dest, &MountOptions::bind().recursive(true))
     }
 
     pub fn dest(&self) -> String {
         self.dest.clone()
     }
+
+    /// Don't unmount this when it's cleaned up or dropped
+    pub fn leave_mounted(&mut self) {
+        self.mounted = false;
+    }
+}
+
+impl Cleanup for Mount {
+    fn cleanup(&mut self) -> Result<()> {
+        if self.mounted {
+            self.mounted = false;
+
+            if self.recursive {
+                umount_fs_lazy(&self.dest)?;
+            } else {
+                unmount(&self.dest)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for Mount {
     fn drop(&mut self) {
-        println!("# Umount {}", self.dest);
-        run("sync".into(), &[]).expect("could not sync!");
-        run("umount".into(), &[self.dest.clone()]).expect("could not umount!");
+        debug!("umount {}", self.dest);
+        cleanup_or_warn(self);
+    }
+}
+
+/// Something to undo at the end of a build, like a mount. Undoing it twice
+/// does nothing the second time, so it can be undone early and dropped
+/// later.
+pub trait Cleanup {
+    fn cleanup(&mut self) -> Result<()>;
+}
+
+/// Undo `x` from a Drop impl, where failing can't stop anything: it's
+/// logged rather than panicking, which mid-unwind would abort and leave
+/// every other mount and loop device behind
+fn cleanup_or_warn(x: &mut dyn Cleanup) {
+    if let Err(e) = cleanup(|| x.cleanup()) {
+        warn!("{:#}", e);
+    }
+}
+
+/// Mounts and the like, undone newest first: bind mounts pushed after the
+/// filesystem they're in go before it. A failure doesn't stop the rest from
+/// being undone; every failure is returned by `unwind`, or logged if the
+/// stack is just dropped.
+#[derive(Default)]
+pub struct CleanupStack {
+    items: Vec<Box<dyn Cleanup>>,
+}
+
+impl CleanupStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, item: impl Cleanup + 'static) {
+        self.items.push(Box::new(item));
+    }
+
+    /// Undo everything, newest first
+    pub fn unwind(&mut self) -> Result<()> {
+        let mut failed = vec![];
+
+        while let Some(mut item) = self.items.pop() {
+            if let Err(e) = cleanup(|| item.cleanup()) {
+                failed.push(format!("{:#}", e));
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(Error::CleanupFailed(failed).into());
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_cleanup_stack() {
+    struct Undo(&'static str, Rc<RefCell<Vec<&'static str>>>);
+
+    impl Cleanup for Undo {
+        fn cleanup(&mut self) -> Result<()> {
+            self.1.borrow_mut().push(self.0);
+            if self.0 == "proc" {
+                bail!("could not umount proc");
+            }
+            Ok(())
+        }
+    }
+
+    let undone = Rc::new(RefCell::new(vec![]));
+    let mut stack = CleanupStack::new();
+    for name in ["root", "proc", "sys"] {
+        stack.push(Undo(name, undone.clone()));
+    }
+
+    let error = stack.unwind().unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<Error>(),
+        Some(Error::CleanupFailed(failed)) if failed == &["could not umount proc"]
+    ));
+
+    // The failure didn't stop root from being unmounted
+    assert_eq!(*undone.borrow(), ["sys", "proc", "root"]);
+}
+
+impl Drop for CleanupStack {
+    fn drop(&mut self) {
+        if let Err(e) = self.unwind() {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Label on every container a build starts, so ones left behind by a
+/// crashed run can be found
+pub const CONTAINER_LABEL: &str = "docker_to_uefi_bootable_image";
+
+/// A container started from an image to export its filesystem, stopped and
+/// removed when dropped
+pub struct Container {
+    name: String,
+    running: bool,
+}
+
+impl Container {
+    /// Start `image` with a shell as the entrypoint, so an image that runs a
+    /// service or exits right away can still be exported
+    pub fn run(image: &str) -> Result<Self> {
+        let name = uuid::Uuid::new_v4().to_string();
+
+        run(
+            "docker".into(),
+            &[
+                "run".into(),
+                "-d".into(),
+                "--entrypoint=/bin/sh".into(),
+                format!("--label={}", CONTAINER_LABEL),
+                "--name".into(),
+                name.clone(),
+                image.into(),
+            ],
+        )?;
+
+        Ok(Self {
+            name,
+            running: true,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Stop and remove the container now, failing if that fails
+    pub fn remove(mut self) -> Result<()> {
+        cleanup(|| Cleanup::cleanup(&mut self))
+    }
+
+    /// Containers left behind by earlier runs that crashed or were killed
+    pub fn leftovers() -> Result<Vec<String>> {
+        let output = run(
+            "docker".into(),
+            &[
+                "ps".into(),
+                "--all".into(),
+                "--filter".into(),
+                format!("label={}", CONTAINER_LABEL),
+                "--format".into(),
+                "{{.Names}}".into(),
+            ],
+        )?;
+
+        Ok(output_stdout_string(&output)
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    /// Stop and remove a leftover container by name
+    pub fn remove_leftover(name: &str) -> Result<()> {
+        run("docker".into(), &["rm".into(), "-f".into(), name.into()])?;
+        Ok(())
+    }
+}
+
+impl Cleanup for Container {
+    fn cleanup(&mut self) -> Result<()> {
+        if self.running {
+            self.running = false;
+            Container::remove_leftover(&self.name)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        debug!("remove container {}", self.name);
+        cleanup_or_warn(self);
+    }
+}
+
+#[test]
+fn test_container_removed_on_failure() -> Result<()> {
+    let recorder = Rc::new(RecordingExecutor::new(|exe, args| match exe {
+        "docker" if args[0] == "export" => bail!("export failed"),
+        "docker" if args[0] == "ps" => Ok("leftover-1\nleftover-2\n".into()),
+        _ => Ok(String::new()),
+    }));
+    let previous = set_executor(recorder.clone());
+
+    let container = Container::run("debian")?;
+    let name = container.name().to_string();
+    let exported = run("docker".into(), &["export".into(), name.clone()]);
+
+    // As if the build returned the export's error
+    drop(container);
+    let leftovers = Container::leftovers();
+
+    set_executor(previous);
+    assert!(exported.is_err());
+    assert_eq!(leftovers?, ["leftover-1", "leftover-2"]);
+
+    let commands: Vec<String> = recorder.commands().iter().map(|x| x.to_string()).collect();
+    assert_eq!(
+        commands[0],
+        format!(
+            "docker run -d --entrypoint=/bin/sh --label={} --name {} debian",
+            CONTAINER_LABEL, name
+        )
+    );
+    assert_eq!(commands[2], format!("docker rm -f {}", name));
+
+    Ok(())
+}
+
+/// A directory for a build's files, removed when dropped unless kept. Unlike
+/// a TempDir, one left behind by an earlier run can be opened again.
+pub struct WorkingDir {
+    path: PathBuf,
+    keep: bool,
+    tmpfs: Option<Mount>,
+}
+
+impl WorkingDir {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            path: tempdir()?.keep(),
+            keep: false,
+            tmpfs: None,
+        })
+    }
+
+    /// A new working directory on a tmpfs of `size`, anything tmpfs(5)
+    /// takes like "16G" or "50%", so nothing touches the disk until the
+    /// image is copied out
+    pub fn tmpfs(size: &str) -> Result<Self> {
+        let mut working_dir = Self::new()?;
+
+        working_dir.tmpfs = Some(Mount::new(
+            "tmpfs".into(),
+            working_dir.path.to_string_lossy().to_string(),
+            &MountOptions::fstype("tmpfs").data(format!("size={},mode=0700", size)),
+        )?);
+
+        Ok(working_dir)
+    }
+
+    /// Take over an existing directory. It is kept unless told otherwise.
+    pub fn open(path: &Path) -> Result<Self> {
+        if !path.is_dir() {
+            bail!("{:?} is not a directory", path);
+        }
+
+        Ok(Self {
+            path: path.canonicalize()?,
+            keep: true,
+            tmpfs: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn keep(&mut self, keep: bool) {
+        self.keep = keep;
+    }
+}
+
+impl Drop for WorkingDir {
+    fn drop(&mut self) {
+        if self.keep {
+            // Unmounting a tmpfs would throw away what's being kept
+            if let Some(tmpfs) = &mut self.tmpfs {
+                warn!(
+                    "{:?} is a tmpfs, left mounted to keep it; unmount it when done",
+                    self.path
+                );
+                tmpfs.leave_mounted();
+            }
+            return;
+        }
+
+        if let Some(tmpfs) = &mut self.tmpfs {
+            cleanup_or_warn(tmpfs);
+        }
+
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("could not remove {:?}: {}", self.path, e);
+        }
     }
 }
 
+/// Fields are dropped in order, so the loop device is detached before the
+/// working directory holding its image goes
 pub struct LoopbackDisk {
-    working_dir: tempfile::TempDir,
-    img_path: String,
     root_device: LoopbackDevice,
+    working_dir: WorkingDir,
+    img_path: String,
+}
+
+/// Create a sparse output.img of `size_in_gb` in `working_dir`
+fn create_image(working_dir: WorkingDir, size_in_gb: usize) -> Result<(WorkingDir, String)> {
+    let img_path = {
+        let mut img_path = working_dir.path().to_path_buf();
+        img_path.push("output.img");
+        if let Ok(s) = img_path.into_os_string().into_string() {
+            s
+        } else {
+            bail!("img_path.into_os_string().into_string()");
+        }
+    };
+
+    let img = File::create(&img_path)?;
+    img.set_len((size_in_gb * 1024 * 1024 * 1024).try_into()?)?;
+    drop(img);
+
+    Ok((working_dir, img_path))
 }
 
 impl LoopbackDisk {
     pub fn new(size_in_gb: usize) -> Result<Self> {
-        let working_dir = tempdir()?;
-
-        // Create blank file
-        let img_path = {
-            let mut img_path = working_dir.path().to_path_buf();
-            img_path.push("output.img");
-            if let Ok(s) = img_path.into_os_string().into_string() {
-                s
-            } else {
-                bail!("img_path.into_os_string().into_string()");
-            }
-        };
-
-        let img = File::create(&img_path)?;
-        img.set_len((size_in_gb * 1024 * 1024 * 1024).try_into()?)?;
-        drop(img);
+        let (working_dir, img_path) = create_image(WorkingDir::new()?, size_in_gb)?;
 
         let root_device = LoopbackDevice::new(img_path.clone())?;
 
@@ -210,6 +2494,32 @@ impl LoopbackDisk {
         })
     }
 
+    /// Attach the image a previous LoopbackDisk left in `working_dir`, with
+    /// a device node per partition
+    pub fn open(working_dir: &Path) -> Result<Self> {
+        let working_dir = WorkingDir::open(working_dir)?;
+
+        let img_path = working_dir.path().join("output.img");
+        if !img_path.is_file() {
+            bail!("no disk image in {:?}", working_dir.path());
+        }
+        let img_path = img_path.to_string_lossy().to_string();
+
+        let root_device = LoopbackDevice::attach(
+            &img_path,
+            &LoopOptions {
+                read_only: false,
+                partscan: true,
+            },
+        )?;
+
+        Ok(Self {
+            working_dir,
+            img_path,
+            root_device,
+        })
+    }
+
     pub fn path(&self) -> String {
         self.root_device.path()
     }
@@ -217,97 +2527,220 @@ impl LoopbackDisk {
     pub fn img_path(&self) -> String {
         self.img_path.clone()
     }
+
+    /// Leave the working directory behind when this is dropped
+    pub fn keep_working_dir(&mut self, keep: bool) {
+        self.working_dir.keep(keep);
+    }
 }
 
 pub struct PartitionedLoopbackDisk {
     loopback_disk: LoopbackDisk,
+    gpt: gpt::Gpt,
+}
+
+/// Where a second ESP, a copy of the first, is mounted, though only by hand
+pub const ESP_MIRROR_MOUNTPOINT: &str = "/boot/efi2";
+
+/// A partition of a [`PartitionedLoopbackDisk`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    /// The device node, e.g. /dev/loop0p2
+    pub device: String,
+    pub number: u32,
+    pub type_guid: uuid::Uuid,
+    pub unique_guid: uuid::Uuid,
+
+    /// Where the partition is mounted in the booted image, if anywhere
+    pub mountpoint: Option<&'static str>,
 }
 
 impl PartitionedLoopbackDisk {
-    /// Consume a LoopbackDisk, produce a PartitionedLoopbackDisk
-    pub fn from(loopback_disk: LoopbackDisk) -> Result<Self> {
-        // BIOS boot
-        // XXX not used!
-        run(
-            "sgdisk".into(),
-            &[
-                "-n".into(),
-                "0:0:+2M".into(),
-                "-c".into(),
-                "0:\"BIOS Boot Partition\"".into(),
-                "-t".into(),
-                "0:ef02".into(),
-                loopback_disk.path(),
-            ],
-        )?;
+    /// Create a disk of `size_in_gb`, write `gpt` into it, then attach it so
+    /// the kernel finds the partitions as it does. Anything else that has to
+    /// be in the image before the kernel sees it, like a filesystem written
+    /// without the loop device, is written by `prepare`.
+    pub fn new(
+        size_in_gb: usize,
+        gpt: &gpt::Gpt,
+        prepare: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<Self> {
+        Self::new_in(WorkingDir::new()?, size_in_gb, gpt, prepare)
+    }
 
-        // EFI partition
-        run(
-            "sgdisk".into(),
-            &[
-                "-n".into(),
-                "0:0:+512M".into(),
-                "-c".into(),
-                "0:\"EFI System Partition\"".into(),
-                "-t".into(),
-                "0:ef00".into(),
-                loopback_disk.path(),
-            ],
-        )?;
+    /// As [`PartitionedLoopbackDisk::new`], in `working_dir`
+    pub fn new_in(
+        working_dir: WorkingDir,
+        size_in_gb: usize,
+        gpt: &gpt::Gpt,
+        prepare: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<Self> {
+        Self::partitioned_by(working_dir, size_in_gb, |image| {
+            gpt.write(image)?;
+            prepare(image)
+        })
+    }
 
-        // main install
-        run(
-            "sgdisk".into(),
-            &[
-                "-n".into(),
-                "0:0:-100M".into(),
-                "-c".into(),
-                "0:\"Root Partition\"".into(),
-                loopback_disk.path(),
-            ],
-        )?;
+    /// Create a disk of `size_in_gb` in `working_dir`, have `partition`
+    /// write a partition table into it, then attach it
+    pub fn partitioned_by(
+        working_dir: WorkingDir,
+        size_in_gb: usize,
+        partition: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<Self> {
+        Self::create(working_dir, size_in_gb, partition, true)
+    }
+
+    /// As [`PartitionedLoopbackDisk::partitioned_by`], but without a loop
+    /// device: the partitions have no device nodes, and are written through
+    /// the image file
+    pub fn unattached_in(
+        working_dir: WorkingDir,
+        size_in_gb: usize,
+        partition: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<Self> {
+        Self::create(working_dir, size_in_gb, partition, false)
+    }
 
-        // swap
-        // XXX cloud-init cannot grow root partition if swap is right after!
-        //run(
-        //    "sgdisk".into(),
-        //    &[
-        //        "-n".into(),
-        //        "0:0:".into(),
-        //        "-c".into(),
-        //        "0:\"Swap Partition\"".into(),
-        //        "-t".into(),
-        //        "8200".into(),
-        //        loopback_disk.path(),
-        //    ],
-        //)?;
+    fn create(
+        working_dir: WorkingDir,
+        size_in_gb: usize,
+        partition: impl FnOnce(&Path) -> Result<()>,
+        attach: bool,
+    ) -> Result<Self> {
+        let (working_dir, img_path) = create_image(working_dir, size_in_gb)?;
+
+        partition(Path::new(&img_path))?;
+        let gpt = gpt::Gpt::read(Path::new(&img_path))?;
+
+        let root_device = if attach {
+            LoopbackDevice::attach(
+                &img_path,
+                &LoopOptions {
+                    read_only: false,
+                    partscan: true,
+                },
+            )?
+        } else {
+            LoopbackDevice::unattached(&img_path)
+        };
+
+        Ok(Self {
+            loopback_disk: LoopbackDisk {
+                working_dir,
+                img_path,
+                root_device,
+            },
+            gpt,
+        })
+    }
 
-        run("partprobe".into(), &[loopback_disk.path()])?;
+    /// Wrap a LoopbackDisk that was already partitioned
+    pub fn open(loopback_disk: LoopbackDisk) -> Result<Self> {
+        let gpt = gpt::Gpt::read(Path::new(&loopback_disk.img_path()))?;
 
-        Ok(Self { loopback_disk })
+        Ok(Self { loopback_disk, gpt })
     }
 
     pub fn path(&self) -> String {
         self.loopback_disk.path()
     }
 
-    pub fn working_dir(&self) -> &tempfile::TempDir {
+    /// Partition `number`, with its device node as the kernel named it.
+    /// The first ESP is mounted on /boot/efi, a mirror of it on
+    /// /boot/efi2, and the first partition of a root type is root.
+    pub fn partition(&self, number: u32) -> Result<Partition> {
+        let Some(entry) = self.gpt.partition(number) else {
+            bail!("{} has no partition {}", self.path(), number);
+        };
+
+        let mountpoint = if entry.type_guid == gpt::EFI_SYSTEM {
+            if self.gpt.first_of(&[gpt::EFI_SYSTEM]) == Some(number) {
+                Some("/boot/efi")
+            } else {
+                Some(ESP_MIRROR_MOUNTPOINT)
+            }
+        } else if self.gpt.first_of(gpt::ROOT_TYPES) == Some(number) {
+            Some("/")
+        } else {
+            None
+        };
+
+        Ok(Partition {
+            device: self.loopback_disk.root_device.partition(number)?,
+            number,
+            type_guid: entry.type_guid,
+            unique_guid: entry.unique_guid,
+            mountpoint,
+        })
+    }
+
+    /// The partition mounted on `mountpoint`
+    pub fn partition_at(&self, mountpoint: &str) -> Result<Partition> {
+        for number in self.gpt.partitions.keys() {
+            let partition = self.partition(*number)?;
+            if partition.mountpoint == Some(mountpoint) {
+                return Ok(partition);
+            }
+        }
+
+        bail!("{} has no partition for {}", self.path(), mountpoint);
+    }
+
+    pub fn working_dir(&self) -> &WorkingDir {
         &self.loopback_disk.working_dir
     }
 
+    /// The partition table as written
+    pub fn gpt(&self) -> &gpt::Gpt {
+        &self.gpt
+    }
+
     pub fn img_path(&self) -> String {
         self.loopback_disk.img_path()
     }
+
+    /// Leave the working directory behind when this is dropped
+    pub fn keep_working_dir(&mut self, keep: bool) {
+        self.loopback_disk.keep_working_dir(keep);
+    }
 }
 
-/*
-// TODO needs root
 #[test]
-fn partition_disk() {
-    let dev = LoopbackDisk::new(1).unwrap();
-    let partitioned_disk = PartitionedLoopbackDisk::from(dev).unwrap();
+fn test_partition_handles() -> Result<()> {
+    let recorder = Rc::new(RecordingExecutor::new(|exe, _| match exe {
+        "losetup" => Ok("/dev/loop7".into()),
+        _ => Ok(String::new()),
+    }));
+    let previous = set_executor(recorder);
+
+    let layout = gpt::Gpt::default_layout(1024 * 1024 * 1024)?;
+    let disk = PartitionedLoopbackDisk::new(1, &layout, |_| Ok(()))?;
+
+    let esp = disk.partition_at("/boot/efi")?;
+    assert_eq!(esp.device, "/dev/loop7p2");
+    assert_eq!(esp.number, 2);
+    assert_eq!(esp.type_guid, gpt::EFI_SYSTEM);
+    assert_eq!(esp.unique_guid, layout.partition(2).unwrap().unique_guid);
+
+    assert_eq!(disk.partition_at("/")?.number, 3);
+    assert_eq!(disk.partition(1)?.mountpoint, None);
+    assert!(disk.partition(4).is_err());
+    drop(disk);
+
+    // A discoverable root is still root, ahead of a later Linux partition
+    let mut layout = layout;
+    layout.split_end(3, "data", gpt::LINUX_FILESYSTEM, 100 * 1024 * 1024)?;
+    layout.change(&["3:type=root-x86-64".parse()?])?;
+    let disk = PartitionedLoopbackDisk::new(1, &layout, |_| Ok(()))?;
+    assert_eq!(disk.partition_at("/")?.number, 3);
+    assert_eq!(disk.partition(4)?.mountpoint, None);
+
+    drop(disk);
+    set_executor(previous);
+
+    Ok(())
 }
-*/
 
 pub struct DropCommand {
     pub command: String,
@@ -322,6 +2755,8 @@ impl DropCommand {
 
 impl Drop for DropCommand {
     fn drop(&mut self) {
-        run(self.command.clone(), &self.args).expect("could not drop!");
+        if let Err(e) = cleanup(|| run(self.command.clone(), &self.args)) {
+            warn!("{:#}", e);
+        }
     }
 }
@@ -0,0 +1,343 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Loop devices through /dev/loop-control and the loop ioctls, as the host
+//! executor does them. See loop(4).
+
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+// From <linux/loop.h>
+const LOOP_SET_FD: libc::Ioctl = 0x4C00;
+const LOOP_CLR_FD: libc::Ioctl = 0x4C01;
+const LOOP_SET_STATUS64: libc::Ioctl = 0x4C04;
+const LOOP_CTL_GET_FREE: libc::Ioctl = 0x4C82;
+
+const LO_FLAGS_PARTSCAN: u32 = 8;
+
+/// How long a partition gets to show up after the partition table is scanned
+const PARTITION_TIMEOUT: Duration = Duration::from_secs(10);
+const LO_NAME_SIZE: usize = 64;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+fn loop_control() -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/loop-control")?)
+}
+
+/// The first free loop device, creating one if need be
+pub fn find_free() -> Result<String> {
+    let control = loop_control()?;
+
+    // SAFETY: LOOP_CTL_GET_FREE takes no argument
+    let number = unsafe { libc::ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE) };
+    if number < 0 {
+        bail!("no free loop device: {}", std::io::Error::last_os_error());
+    }
+
+    Ok(format!("/dev/loop{}", number))
+}
+
+/// Attach `image` to a free loop device and return the device's path.
+/// Read-only comes from how the image is opened; with `partscan` the kernel
+/// creates a device per partition.
+pub fn attach(image: &str, read_only: bool, partscan: bool) -> Result<String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(image)?;
+
+    let mut info = LoopInfo64 {
+        lo_device: 0,
+        lo_inode: 0,
+        lo_rdevice: 0,
+        lo_offset: 0,
+        lo_sizelimit: 0,
+        lo_number: 0,
+        lo_encrypt_type: 0,
+        lo_encrypt_key_size: 0,
+        lo_flags: if partscan { LO_FLAGS_PARTSCAN } else { 0 },
+        lo_file_name: [0; LO_NAME_SIZE],
+        lo_crypt_name: [0; LO_NAME_SIZE],
+        lo_encrypt_key: [0; 32],
+        lo_init: [0; 2],
+    };
+
+    // Shown by losetup --list, so keep the end of a long path
+    let name = image.as_bytes();
+    let name = &name[name.len().saturating_sub(LO_NAME_SIZE - 1)..];
+    info.lo_file_name[..name.len()].copy_from_slice(name);
+
+    // Someone else can take the free device between asking for it and
+    // attaching to it, so try again if it turns out to be busy
+    for _ in 0..10 {
+        let path = find_free()?;
+        let device = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(&path)?;
+
+        // SAFETY: LOOP_SET_FD takes the backing file's descriptor
+        if unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_FD, file.as_raw_fd()) } < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EBUSY) {
+                continue;
+            }
+            bail!("could not attach {} to {}: {}", image, path, e);
+        }
+
+        // SAFETY: info is a live loop_info64
+        if unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_STATUS64, &info) } < 0 {
+            let e = std::io::Error::last_os_error();
+
+            // SAFETY: LOOP_CLR_FD takes no argument
+            unsafe { libc::ioctl(device.as_raw_fd(), LOOP_CLR_FD, 0) };
+            bail!("could not configure {}: {}", path, e);
+        }
+
+        return Ok(path);
+    }
+
+    bail!("could not find a free loop device for {}", image);
+}
+
+pub fn detach(device: &str) -> Result<()> {
+    let device_file = OpenOptions::new().read(true).open(device)?;
+
+    // SAFETY: LOOP_CLR_FD takes no argument
+    if unsafe { libc::ioctl(device_file.as_raw_fd(), LOOP_CLR_FD, 0) } < 0 {
+        // Kept as an io::Error so EBUSY can be told apart
+        return Err(anyhow::Error::new(std::io::Error::last_os_error())
+            .context(format!("could not detach {}", device)));
+    }
+
+    Ok(())
+}
+
+/// The device node for partition `number` of `device`, found in sysfs
+/// rather than guessed from the device's name. The partition and its node
+/// can take a moment to appear after the device is attached, longer on a
+/// loaded machine, so they're waited for rather than raced by mkfs.
+pub fn partition_device(device: &str, number: u32) -> Result<String> {
+    wait_for(PARTITION_TIMEOUT, || {
+        let node = partition_device_in(Path::new("/sys/class/block"), device, number)?;
+
+        if !std::fs::metadata(&node).is_ok_and(|x| x.file_type().is_block_device()) {
+            bail!("{} has no device node yet", node);
+        }
+
+        Ok(node)
+    })
+}
+
+/// Retry `f` every 50ms until it succeeds, or until `timeout` has passed
+/// and its last error is returned
+fn wait_for<T>(timeout: Duration, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let start = Instant::now();
+
+    loop {
+        match f() {
+            Ok(x) => return Ok(x),
+
+            Err(e) if start.elapsed() >= timeout => {
+                return Err(e.context(format!("gave up after {:?}", timeout)));
+            }
+
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+#[test]
+fn test_wait_for() {
+    let mut attempts = 0;
+    let result = wait_for(Duration::from_secs(10), || {
+        attempts += 1;
+        if attempts < 3 {
+            bail!("not yet");
+        }
+        Ok(attempts)
+    });
+    assert_eq!(result.unwrap(), 3);
+
+    let result: Result<()> = wait_for(Duration::from_millis(120), || bail!("never"));
+    let error = format!("{:#}", result.unwrap_err());
+    assert!(error.starts_with("gave up after"));
+    assert!(error.ends_with("never"));
+}
+
+fn partition_device_in(sys_block: &Path, device: &str, number: u32) -> Result<String> {
+    let Some(name) = Path::new(device).file_name() else {
+        bail!("{} is not a device path", device);
+    };
+
+    for entry in std::fs::read_dir(sys_block.join(name))? {
+        let entry = entry?;
+
+        // Only partitions have a partition file, holding their number
+        let Ok(partition) = std::fs::read_to_string(entry.path().join("partition")) else {
+            continue;
+        };
+
+        if partition.trim() == number.to_string() {
+            return Ok(format!("/dev/{}", entry.file_name().to_string_lossy()));
+        }
+    }
+
+    bail!("{} has no partition {}", device, number);
+}
+
+/// Where `device` and any of its partitions are mounted, newest first,
+/// matched by device number from /proc/self/mountinfo
+pub fn mounts_of(device: &str) -> Result<Vec<String>> {
+    mounts_of_in(
+        Path::new("/sys/class/block"),
+        &std::fs::read_to_string("/proc/self/mountinfo")?,
+        device,
+    )
+}
+
+fn mounts_of_in(sys_block: &Path, mountinfo: &str, device: &str) -> Result<Vec<String>> {
+    let Some(name) = Path::new(device).file_name() else {
+        bail!("{} is not a device path", device);
+    };
+    let dir = sys_block.join(name);
+
+    let mut numbers = vec![std::fs::read_to_string(dir.join("dev"))?.trim().to_string()];
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.join("partition").exists() {
+            numbers.push(
+                std::fs::read_to_string(path.join("dev"))?
+                    .trim()
+                    .to_string(),
+            );
+        }
+    }
+
+    // See proc(5): the third field is major:minor and the fifth is the
+    // mount point, with spaces and the like octal escaped
+    let mut mounts: Vec<String> = mountinfo
+        .lines()
+        .map(|x| x.split(' ').collect::<Vec<&str>>())
+        .filter(|x| x.len() > 4 && numbers.iter().any(|n| n == x[2]))
+        .map(|x| unescape_mount_point(x[4]))
+        .collect();
+
+    mounts.reverse();
+
+    Ok(mounts)
+}
+
+fn unescape_mount_point(escaped: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = escaped.as_bytes();
+
+    while let Some((&first, tail)) = rest.split_first() {
+        let octal = tail
+            .get(..3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 8).ok());
+
+        match (first, octal) {
+            (b'\\', Some(byte)) => {
+                bytes.push(byte);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(first);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[test]
+fn test_mounts_of() -> Result<()> {
+    let sys = tempfile::tempdir()?;
+
+    for (dir, dev, partition) in [
+        ("loop7", "7:7", None),
+        ("loop7/loop7p1", "259:0", Some("1")),
+        ("loop7/loop7p2", "259:1", Some("2")),
+        ("loop8", "7:8", None),
+    ] {
+        let dir = sys.path().join(dir);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("dev"), format!("{}\n", dev))?;
+        if let Some(partition) = partition {
+            std::fs::write(dir.join("partition"), partition)?;
+        }
+    }
+
+    let mountinfo = "\
+22 1 259:9 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+95 22 259:0 / /media/user/EFI\\040System rw shared:50 - vfat /dev/loop7p1 rw
+96 22 259:1 / /media/user/root rw shared:51 - ext4 /dev/loop7p2 rw
+97 22 7:8 / /mnt/other rw shared:52 - ext4 /dev/loop8 rw
+";
+
+    assert_eq!(
+        mounts_of_in(sys.path(), mountinfo, "/dev/loop7")?,
+        ["/media/user/root", "/media/user/EFI System"]
+    );
+    assert_eq!(
+        mounts_of_in(sys.path(), mountinfo, "/dev/loop8")?,
+        ["/mnt/other"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_partition_device() -> Result<()> {
+    let sys = tempfile::tempdir()?;
+
+    for (dir, partition) in [
+        ("loop7/loop7p1", Some("1\n")),
+        ("loop7/loop7p12", Some("12\n")),
+        ("loop7/queue", None),
+    ] {
+        let dir = sys.path().join(dir);
+        std::fs::create_dir_all(&dir)?;
+        if let Some(partition) = partition {
+            std::fs::write(dir.join("partition"), partition)?;
+        }
+    }
+
+    assert_eq!(
+        partition_device_in(sys.path(), "/dev/loop7", 12)?,
+        "/dev/loop7p12"
+    );
+    assert!(partition_device_in(sys.path(), "/dev/loop7", 2).is_err());
+
+    Ok(())
+}
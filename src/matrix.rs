@@ -0,0 +1,157 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Matrix builds: several variants of an image from one `create`, one for
+//! each combination of the values given for some of its options, like
+//! `--matrix flavor=debian,ubuntu --matrix disk-size=4,16`. Each variant's
+//! output file is named after its values.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+/// One option and the values to build with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Axis {
+    /// The long option, without the leading --
+    pub option: String,
+    pub values: Vec<String>,
+}
+
+impl FromStr for Axis {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((option, values)) = s.split_once('=') else {
+            bail!("expected OPTION=VALUE[,VALUE...], not {:?}", s);
+        };
+
+        let option = option.trim_start_matches("--");
+        let values: Vec<String> = values
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(String::from)
+            .collect();
+
+        if option.is_empty() || values.is_empty() {
+            bail!("expected OPTION=VALUE[,VALUE...], not {:?}", s);
+        }
+
+        Ok(Self {
+            option: option.into(),
+            values,
+        })
+    }
+}
+
+/// One combination of values, an option and value for each axis
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub options: Vec<(String, String)>,
+}
+
+impl Variant {
+    /// Its values joined, for file names: debian-4
+    pub fn name(&self) -> String {
+        self.options
+            .iter()
+            .map(|(_, value)| {
+                value
+                    .chars()
+                    .map(|x| {
+                        if x.is_ascii_alphanumeric() || x == '.' {
+                            x
+                        } else {
+                            '_'
+                        }
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// `path` with the variant's name before its extension, so out.img
+    /// becomes out-debian-4.img
+    pub fn path(&self, path: &Path) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+        let name = match path.extension() {
+            Some(extension) => format!("{}-{}.{}", stem, self.name(), extension.to_string_lossy()),
+            None => format!("{}-{}", stem, self.name()),
+        };
+
+        path.with_file_name(name)
+    }
+}
+
+/// Every combination of the axes' values, the first axis varying slowest
+pub fn variants(axes: &[Axis]) -> Result<Vec<Variant>> {
+    for (i, axis) in axes.iter().enumerate() {
+        if axes[..i].iter().any(|x| x.option == axis.option) {
+            bail!("--matrix {} is given more than once", axis.option);
+        }
+    }
+
+    let mut variants = vec![Variant { options: vec![] }];
+
+    for axis in axes {
+        variants = variants
+            .into_iter()
+            .flat_map(|variant| {
+                axis.values.iter().map(move |value| {
+                    let mut variant = variant.clone();
+                    variant.options.push((axis.option.clone(), value.clone()));
+                    variant
+                })
+            })
+            .collect();
+    }
+
+    Ok(variants)
+}
+
+#[test]
+fn test_variants() -> Result<()> {
+    let axes: Vec<Axis> = vec!["flavor=debian,ubuntu".parse()?, "--disk-size=4,16".parse()?];
+
+    let built = variants(&axes)?;
+    let names: Vec<String> = built.iter().map(Variant::name).collect();
+    assert_eq!(names, ["debian-4", "debian-16", "ubuntu-4", "ubuntu-16"]);
+
+    assert_eq!(
+        built[1].options,
+        [
+            ("flavor".to_string(), "debian".to_string()),
+            ("disk-size".to_string(), "16".to_string())
+        ]
+    );
+
+    assert_eq!(
+        built[0].path(Path::new("/out/vm.img")),
+        Path::new("/out/vm-debian-4.img")
+    );
+
+    assert!(variants(&[axes[0].clone(), axes[0].clone()]).is_err());
+    assert!("flavor".parse::<Axis>().is_err());
+    assert!("flavor=".parse::<Axis>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_variant_name() -> Result<()> {
+    let variants = variants(&["image-name=debian:12,ubuntu:24.04".parse()?])?;
+
+    assert_eq!(variants[0].name(), "debian_12");
+    assert_eq!(
+        variants[1].path(Path::new("vm")),
+        Path::new("vm-ubuntu_24.04")
+    );
+
+    Ok(())
+}
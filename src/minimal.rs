@@ -0,0 +1,126 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! A smaller root for appliances: no documentation, man pages or
+//! translations. dpkg is told not to unpack them, and what the container
+//! image already had is deleted. Copyright files stay.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Where the dpkg config goes in the image
+pub const DPKG_CFG: &str = "/etc/dpkg/dpkg.cfg.d/01-minimal";
+
+/// Directories emptied, relative to the root
+const PURGED: &[&str] = &[
+    "usr/share/doc",
+    "usr/share/man",
+    "usr/share/info",
+    "usr/share/locale",
+    "usr/share/lintian",
+];
+
+/// dpkg's path-exclude and path-include lines for `PURGED`, and what's kept
+pub fn dpkg_cfg() -> String {
+    let mut cfg = String::from("# Written by docker_to_uefi_bootable_image --minimal\n");
+
+    for dir in PURGED {
+        cfg.push_str(&format!("path-exclude=/{}/*\n", dir));
+    }
+
+    cfg.push_str("path-include=/usr/share/doc/*/copyright\n");
+    cfg.push_str("path-include=/usr/share/locale/locale.alias\n");
+
+    cfg
+}
+
+/// Kept as the dpkg config's path-include says
+fn kept(relative: &Path) -> bool {
+    let parts: Vec<_> = relative.iter().collect();
+
+    matches!(
+        parts[..],
+        [_, _, doc, _, name] if doc == "doc" && name == "copyright"
+    ) || relative == Path::new("usr/share/locale/locale.alias")
+}
+
+/// Delete what dpkg would now leave out from the image at `root`, returning
+/// the number of files deleted
+pub fn purge(root: &Path) -> Result<usize> {
+    let mut deleted = 0;
+
+    for dir in PURGED {
+        let dir = root.join(dir);
+        if dir.is_dir() {
+            deleted += purge_dir(root, &dir)?;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Empty directories left behind go too, but not `dir` itself
+fn purge_dir(root: &Path, dir: &Path) -> Result<usize> {
+    let mut deleted = 0;
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        // Symlinks are deleted like files, never followed out of the image
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            deleted += purge_dir(root, &path)?;
+            if std::fs::read_dir(&path)?.next().is_none() {
+                std::fs::remove_dir(&path)?;
+            }
+        } else if !kept(path.strip_prefix(root)?) {
+            std::fs::remove_file(&path)?;
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+#[test]
+fn test_dpkg_cfg() {
+    let cfg = dpkg_cfg();
+    assert!(cfg.contains("path-exclude=/usr/share/doc/*\n"));
+    assert!(cfg.contains("path-exclude=/usr/share/man/*\n"));
+    assert!(cfg.contains("path-include=/usr/share/doc/*/copyright\n"));
+}
+
+#[test]
+fn test_purge() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let root = root.path();
+
+    for file in [
+        "usr/share/doc/bash/copyright",
+        "usr/share/doc/bash/README.gz",
+        "usr/share/man/man1/bash.1.gz",
+        "usr/share/locale/locale.alias",
+        "usr/share/locale/de/LC_MESSAGES/bash.mo",
+        "usr/share/zoneinfo/UTC",
+    ] {
+        std::fs::create_dir_all(root.join(file).parent().unwrap())?;
+        std::fs::write(root.join(file), "")?;
+    }
+    std::os::unix::fs::symlink("/etc", root.join("usr/share/doc/link"))?;
+
+    assert_eq!(purge(root)?, 4);
+
+    assert!(root.join("usr/share/doc/bash/copyright").exists());
+    assert!(!root.join("usr/share/doc/bash/README.gz").exists());
+    assert!(!root.join("usr/share/doc/link").exists());
+    assert!(root.join("usr/share/locale/locale.alias").exists());
+    assert!(!root.join("usr/share/locale/de").exists());
+    assert!(root.join("usr/share/man").is_dir());
+    assert!(!root.join("usr/share/man/man1").exists());
+    assert!(root.join("usr/share/zoneinfo/UTC").exists());
+
+    Ok(())
+}
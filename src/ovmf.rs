@@ -0,0 +1,278 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Finding OVMF (UEFI firmware for QEMU) on the host, or fetching a pinned
+//! build of it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use tracing::{debug, info};
+
+use crate::{run, sha256_file};
+
+/// Where distros install OVMF, as (code, vars) pairs, best first
+pub const OVMF_CANDIDATES: &[(&str, &str)] = &[
+    // Debian, Ubuntu
+    (
+        "/usr/share/OVMF/OVMF_CODE_4M.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.fd",
+    ),
+    // Older Debian, Ubuntu
+    (
+        "/usr/share/OVMF/OVMF_CODE.fd",
+        "/usr/share/OVMF/OVMF_VARS.fd",
+    ),
+    // Fedora, RHEL
+    (
+        "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.fd",
+    ),
+    // Arch
+    (
+        "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+        "/usr/share/edk2/x64/OVMF_VARS.4m.fd",
+    ),
+    // Older Arch
+    (
+        "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+        "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd",
+    ),
+    // openSUSE
+    (
+        "/usr/share/qemu/ovmf-x86_64-code.bin",
+        "/usr/share/qemu/ovmf-x86_64-vars.bin",
+    ),
+    // The build that ships with QEMU itself
+    (
+        "/usr/share/qemu/edk2-x86_64-code.fd",
+        "/usr/share/qemu/edk2-i386-vars.fd",
+    ),
+];
+
+/// UEFI firmware for QEMU. Split builds have a read-only code image and a
+/// template for the writable variable store; combined builds are a single
+/// image passed with -bios.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ovmf {
+    pub code: PathBuf,
+    pub vars: Option<PathBuf>,
+}
+
+impl Ovmf {
+    /// The first known OVMF install under `root` ("/" for the host)
+    pub fn find(root: &Path) -> Option<Self> {
+        OVMF_CANDIDATES.iter().find_map(|(code, vars)| {
+            let code = root.join(code.trim_start_matches('/'));
+            let vars = root.join(vars.trim_start_matches('/'));
+
+            debug!("looking for OVMF at {:?}", code);
+
+            if code.exists() && vars.exists() {
+                Some(Self {
+                    code,
+                    vars: Some(vars),
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// QEMU arguments for this firmware. The variable store is copied into
+    /// `work_dir` first so that each run starts from a clean, writable copy
+    /// and the installed template is never modified.
+    pub fn qemu_args(&self, work_dir: &Path) -> Result<Vec<String>> {
+        let Some(vars) = &self.vars else {
+            return Ok(vec![
+                "-bios".into(),
+                self.code.to_string_lossy().to_string(),
+            ]);
+        };
+
+        let vars_copy = work_dir.join("OVMF_VARS.fd");
+        std::fs::copy(vars, &vars_copy)?;
+
+        Ok(vec![
+            "-drive".into(),
+            format!(
+                "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                self.code.display()
+            ),
+            "-drive".into(),
+            format!("if=pflash,format=raw,unit=1,file={}", vars_copy.display()),
+        ])
+    }
+
+    /// Fetch OVMF from `url` into `cache_dir`, checking it against `sha256`.
+    /// `url` is either a Debian `ovmf` package, which is unpacked, or a
+    /// combined firmware image. Nothing is downloaded if the cache already
+    /// has it.
+    pub fn download(url: &str, sha256: &str, cache_dir: &Path) -> Result<Self> {
+        let dir = cache_dir.join(sha256);
+        let download = dir.join(if url.ends_with(".deb") {
+            "ovmf.deb"
+        } else {
+            "OVMF.fd"
+        });
+
+        if !download.exists() || sha256_file(&download)? != sha256 {
+            info!("download OVMF from {}", url);
+
+            std::fs::create_dir_all(&dir)?;
+            let partial = dir.join("partial");
+
+            run(
+                "curl".into(),
+                &[
+                    "-fsSL".into(),
+                    "-o".into(),
+                    partial.to_string_lossy().to_string(),
+                    url.to_string(),
+                ],
+            )?;
+
+            let actual = sha256_file(&partial)?;
+            if actual != sha256 {
+                std::fs::remove_file(&partial)?;
+                bail!("{} has SHA-256 {}, expected {}", url, actual, sha256);
+            }
+
+            std::fs::rename(&partial, &download)?;
+        }
+
+        if download.extension().is_some_and(|x| x == "fd") {
+            return Ok(Self {
+                code: download,
+                vars: None,
+            });
+        }
+
+        let root = dir.join("root");
+        if let Some(ovmf) = Self::find(&root) {
+            return Ok(ovmf);
+        }
+
+        // A .deb is an ar archive holding a data.tar.* of the files
+        let deb = std::fs::read(&download)?;
+        let (name, data) = ar_member(&deb, "data.tar")?;
+
+        let data_path = dir.join(name);
+        std::fs::write(&data_path, data)?;
+        std::fs::create_dir_all(&root)?;
+
+        run(
+            "tar".into(),
+            &[
+                "-C".into(),
+                root.to_string_lossy().to_string(),
+                "-xf".into(),
+                data_path.to_string_lossy().to_string(),
+            ],
+        )?;
+
+        match Self::find(&root) {
+            Some(ovmf) => Ok(ovmf),
+            None => bail!("no OVMF firmware found in {}", url),
+        }
+    }
+}
+
+/// The first member of an ar archive whose name starts with `prefix`
+fn ar_member<'a>(archive: &'a [u8], prefix: &str) -> Result<(String, &'a [u8])> {
+    const MAGIC: &[u8] = b"!<arch>\n";
+    const HEADER: usize = 60;
+
+    if !archive.starts_with(MAGIC) {
+        bail!("not an ar archive");
+    }
+
+    let mut offset = MAGIC.len();
+
+    while offset + HEADER <= archive.len() {
+        let header = &archive[offset..offset + HEADER];
+        let name = String::from_utf8_lossy(&header[0..16])
+            .trim_end()
+            .trim_end_matches('/')
+            .to_string();
+        let size: usize = String::from_utf8_lossy(&header[48..58]).trim().parse()?;
+
+        let start = offset + HEADER;
+        let Some(data) = archive.get(start..start + size) else {
+            bail!("ar member {} is truncated", name);
+        };
+
+        if name.starts_with(prefix) {
+            return Ok((name, data));
+        }
+
+        // Members are padded to an even length
+        offset = start + size + size % 2;
+    }
+
+    bail!("no {}* in ar archive", prefix);
+}
+
+#[test]
+fn test_ar_member() -> Result<()> {
+    let mut archive = b"!<arch>\n".to_vec();
+    for (name, data) in [("debian-binary/", "2.0\n"), ("data.tar.xz/", "abc")] {
+        archive.extend(
+            format!(
+                "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                name,
+                0,
+                0,
+                0,
+                100644,
+                data.len()
+            )
+            .bytes(),
+        );
+        archive.extend(data.bytes());
+        if data.len() % 2 == 1 {
+            archive.push(b'\n');
+        }
+    }
+
+    let (name, data) = ar_member(&archive, "data.tar")?;
+    assert_eq!(name, "data.tar.xz");
+    assert_eq!(data, b"abc");
+
+    assert!(ar_member(&archive, "control.tar").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_find() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    assert_eq!(Ovmf::find(root.path()), None);
+
+    // Fedora's layout, with only the code image from Debian's
+    for path in [
+        "usr/share/OVMF/OVMF_CODE_4M.fd",
+        "usr/share/edk2/ovmf/OVMF_CODE.fd",
+        "usr/share/edk2/ovmf/OVMF_VARS.fd",
+    ] {
+        let path = root.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, "")?;
+    }
+
+    let ovmf = Ovmf::find(root.path()).unwrap();
+    assert_eq!(
+        ovmf.code,
+        root.path().join("usr/share/edk2/ovmf/OVMF_CODE.fd")
+    );
+
+    let work_dir = tempfile::tempdir()?;
+    let args = ovmf.qemu_args(work_dir.path())?;
+    assert!(args[3].ends_with(&format!("file={}/OVMF_VARS.fd", work_dir.path().display())));
+    assert!(work_dir.path().join("OVMF_VARS.fd").exists());
+
+    Ok(())
+}
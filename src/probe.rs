@@ -0,0 +1,323 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Filesystem types and UUIDs read straight from superblocks, as the host
+//! executor does them, so blkid isn't needed. Only the filesystems the
+//! builder makes are recognized: ext2/3/4 and FAT.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use uuid::Uuid;
+
+// From the ext4 disk layout: the superblock is 1024 bytes in
+const EXT_SUPERBLOCK: u64 = 1024;
+const EXT_MAGIC: u16 = 0xEF53;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+
+/// Incompatible and read-only features ext3 knows about; anything else
+/// makes it ext4, as blkid decides
+const EXT3_INCOMPAT: u32 = 0x2 | 0x4 | 0x10;
+const EXT3_RO_COMPAT: u32 = 0x1 | 0x2 | 0x4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    Ext2,
+    Ext3,
+    Ext4,
+    Vfat,
+}
+
+impl FsType {
+    /// The name mount(8), fstab and blkid use
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FsType::Ext2 => "ext2",
+            FsType::Ext3 => "ext3",
+            FsType::Ext4 => "ext4",
+            FsType::Vfat => "vfat",
+        }
+    }
+}
+
+impl std::fmt::Display for FsType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for FsType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "ext2" => FsType::Ext2,
+            "ext3" => FsType::Ext3,
+            "ext4" => FsType::Ext4,
+            "vfat" => FsType::Vfat,
+            _ => bail!("unsupported filesystem type {:?}", s),
+        })
+    }
+}
+
+/// What fstab's `UUID=` refers to, which isn't always a UUID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsUuid {
+    /// ext2/3/4
+    Uuid(Uuid),
+
+    /// FAT's 32-bit volume ID, written like "1A2B-3C4D"
+    VolumeId(u32),
+}
+
+impl std::fmt::Display for FsUuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsUuid::Uuid(uuid) => write!(f, "{}", uuid.to_hyphenated_ref()),
+            FsUuid::VolumeId(id) => write!(f, "{:04X}-{:04X}", id >> 16, id & 0xFFFF),
+        }
+    }
+}
+
+impl FsUuid {
+    /// Parse the UUID of a `fs_type` filesystem as blkid prints it
+    pub fn parse(fs_type: FsType, s: &str) -> Result<Self> {
+        match fs_type {
+            FsType::Ext2 | FsType::Ext3 | FsType::Ext4 => {
+                Ok(FsUuid::Uuid(Uuid::parse_str(s).with_context(|| {
+                    format!("bad {} UUID {:?}", fs_type, s)
+                })?))
+            }
+
+            FsType::Vfat => {
+                let id = match s.split_once('-') {
+                    Some((high, low)) if high.len() == 4 && low.len() == 4 => {
+                        u16::from_str_radix(high, 16).and_then(|high| {
+                            u16::from_str_radix(low, 16).map(|low| (high as u32) << 16 | low as u32)
+                        })
+                    }
+                    _ => bail!("bad vfat volume ID {:?}", s),
+                };
+
+                Ok(FsUuid::VolumeId(
+                    id.with_context(|| format!("bad vfat volume ID {:?}", s))?,
+                ))
+            }
+        }
+    }
+}
+
+/// A filesystem's type and UUID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Filesystem {
+    pub fs_type: FsType,
+    pub uuid: FsUuid,
+}
+
+impl Filesystem {
+    /// Parse `blkid -o export` output. Missing or malformed TYPE or UUID
+    /// tags are an error rather than something to skip.
+    pub fn from_blkid(output: &str) -> Result<Self> {
+        let tag = |name: &str| {
+            output
+                .lines()
+                .filter_map(|x| x.split_once('='))
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.trim())
+        };
+
+        let Some(fs_type) = tag("TYPE") else {
+            bail!("blkid found no filesystem type");
+        };
+        let fs_type: FsType = fs_type.parse()?;
+
+        let Some(uuid) = tag("UUID") else {
+            bail!("blkid found no UUID for the {} filesystem", fs_type);
+        };
+
+        Ok(Filesystem {
+            fs_type,
+            uuid: FsUuid::parse(fs_type, uuid)?,
+        })
+    }
+
+    /// How `blkid -o export` would describe it
+    pub fn blkid_export(&self) -> String {
+        format!("TYPE={}\nUUID={}\n", self.fs_type, self.uuid)
+    }
+}
+
+/// Read the filesystem on the device or image file at `path`
+pub fn probe(path: &Path) -> Result<Filesystem> {
+    probe_reader(&mut File::open(path)?).with_context(|| format!("could not probe {:?}", path))
+}
+
+fn probe_reader(reader: &mut (impl Read + Seek)) -> Result<Filesystem> {
+    // Enough for the FAT boot sector and the ext superblock after it
+    let mut block = vec![0u8; 2048];
+    reader.seek(SeekFrom::Start(0))?;
+    let mut len = 0;
+    while len < block.len() {
+        match reader.read(&mut block[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    block.truncate(len);
+
+    if let Some(filesystem) = ext(&block) {
+        return Ok(filesystem);
+    }
+
+    if let Some(filesystem) = fat(&block) {
+        return Ok(filesystem);
+    }
+
+    bail!("no ext or FAT filesystem found");
+}
+
+fn u16_at(block: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([block[offset], block[offset + 1]])
+}
+
+fn u32_at(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+fn ext(block: &[u8]) -> Option<Filesystem> {
+    let sb = block.get(EXT_SUPERBLOCK as usize..EXT_SUPERBLOCK as usize + 1024)?;
+
+    if u16_at(sb, 0x38) != EXT_MAGIC {
+        return None;
+    }
+
+    let compat = u32_at(sb, 0x5C);
+    let incompat = u32_at(sb, 0x60);
+    let ro_compat = u32_at(sb, 0x64);
+
+    let fs_type = if incompat & !EXT3_INCOMPAT != 0 || ro_compat & !EXT3_RO_COMPAT != 0 {
+        FsType::Ext4
+    } else if compat & EXT_COMPAT_HAS_JOURNAL != 0 {
+        FsType::Ext3
+    } else {
+        FsType::Ext2
+    };
+
+    Some(Filesystem {
+        fs_type,
+        uuid: FsUuid::Uuid(Uuid::from_slice(&sb[0x68..0x78]).ok()?),
+    })
+}
+
+fn fat(block: &[u8]) -> Option<Filesystem> {
+    let boot = block.get(..512)?;
+
+    if boot[510..512] != [0x55, 0xAA] || !matches!(boot[0], 0xEB | 0xE9) {
+        return None;
+    }
+
+    // FAT32 has no 16-bit FAT size, and keeps its extended boot record
+    // further in to make room for its own fields
+    let volume_id = if u16_at(boot, 22) == 0 {
+        if &boot[82..87] != b"FAT32" {
+            return None;
+        }
+        u32_at(boot, 67)
+    } else {
+        if &boot[54..57] != b"FAT" {
+            return None;
+        }
+        u32_at(boot, 39)
+    };
+
+    Some(Filesystem {
+        fs_type: FsType::Vfat,
+        uuid: FsUuid::VolumeId(volume_id),
+    })
+}
+
+#[test]
+fn test_probe_fat32() -> Result<()> {
+    use crate::fat::Fat32;
+
+    let image = tempfile::NamedTempFile::new()?;
+    image.as_file().set_len(64 * 1024 * 1024)?;
+    let fat = Fat32::new(0, 64 * 1024 * 1024, 0x1A2B_3C4D);
+    fat.write(image.path(), None)?;
+
+    let filesystem = probe(image.path())?;
+    assert_eq!(filesystem.fs_type, FsType::Vfat);
+    assert_eq!(filesystem.uuid, FsUuid::VolumeId(0x1A2B_3C4D));
+    assert_eq!(filesystem.uuid.to_string(), fat.uuid());
+
+    Ok(())
+}
+
+#[test]
+fn test_probe_ext() -> Result<()> {
+    let uuid = Uuid::parse_str("0f3a9c1e-52d4-4b7a-9e61-3c2d8b5a7f10")?;
+
+    let superblock = |compat: u32, incompat: u32| {
+        let mut block = vec![0u8; 4096];
+        let sb = &mut block[1024..2048];
+        sb[0x38..0x3A].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+        sb[0x5C..0x60].copy_from_slice(&compat.to_le_bytes());
+        sb[0x60..0x64].copy_from_slice(&incompat.to_le_bytes());
+        sb[0x68..0x78].copy_from_slice(uuid.as_bytes());
+        std::io::Cursor::new(block)
+    };
+
+    // extents and 64bit
+    let filesystem = probe_reader(&mut superblock(EXT_COMPAT_HAS_JOURNAL, 0x2 | 0x40 | 0x80))?;
+    assert_eq!(filesystem.fs_type, FsType::Ext4);
+    assert_eq!(
+        filesystem.uuid.to_string(),
+        "0f3a9c1e-52d4-4b7a-9e61-3c2d8b5a7f10"
+    );
+
+    let filesystem = probe_reader(&mut superblock(EXT_COMPAT_HAS_JOURNAL, 0x2))?;
+    assert_eq!(filesystem.fs_type, FsType::Ext3);
+
+    let filesystem = probe_reader(&mut superblock(0, 0x2))?;
+    assert_eq!(filesystem.fs_type, FsType::Ext2);
+
+    assert!(probe_reader(&mut std::io::Cursor::new(vec![0u8; 4096])).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_from_blkid() -> Result<()> {
+    let filesystem = Filesystem::from_blkid(
+        "DEVNAME=/dev/loop0p2\nUUID=1A2B-3C4D\nBLOCK_SIZE=512\nTYPE=vfat\nPARTUUID=1a2b3c4d-0000-0000-0000-000000000002\n",
+    )?;
+    assert_eq!(filesystem.fs_type, FsType::Vfat);
+    assert_eq!(filesystem.uuid, FsUuid::VolumeId(0x1A2B_3C4D));
+    assert_eq!(
+        Filesystem::from_blkid(&filesystem.blkid_export())?,
+        filesystem
+    );
+
+    // UUID_SUB and friends aren't the filesystem's UUID
+    let filesystem = Filesystem::from_blkid(
+        "UUID_SUB=11111111-2222-3333-4444-555555555555\nUUID=0f3a9c1e-52d4-4b7a-9e61-3c2d8b5a7f10\nTYPE=ext4\n",
+    )?;
+    assert_eq!(
+        filesystem.uuid.to_string(),
+        "0f3a9c1e-52d4-4b7a-9e61-3c2d8b5a7f10"
+    );
+
+    assert!(Filesystem::from_blkid("TYPE=ext4\n").is_err());
+    assert!(Filesystem::from_blkid("UUID=1A2B-3C4D\n").is_err());
+    assert!(Filesystem::from_blkid("UUID=0f3a9c1e\nTYPE=ext4\n").is_err());
+    assert!(Filesystem::from_blkid("UUID=1A2B3C4D\nTYPE=vfat\n").is_err());
+    assert!(Filesystem::from_blkid("UUID=1A2B-3C4D\nTYPE=ntfs\n").is_err());
+
+    Ok(())
+}
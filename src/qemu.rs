@@ -0,0 +1,177 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Booting images under QEMU with OVMF
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tracing::info;
+
+use crate::error::Error;
+use crate::ovmf::Ovmf;
+use crate::*;
+
+/// How to run an image under QEMU. The serial console goes to stdio.
+#[derive(Debug, Clone)]
+pub struct QemuOptions {
+    pub image: PathBuf,
+    /// Guest memory, in QEMU's syntax
+    pub memory: String,
+    pub cpus: usize,
+    /// Host TCP ports forwarded to guest ports
+    pub forward: Vec<(u16, u16)>,
+    /// Keep changes the guest makes to the image
+    pub persist: bool,
+    /// Use software emulation instead of KVM
+    pub no_kvm: bool,
+}
+
+impl QemuOptions {
+    /// 2G of memory and 2 CPUs, discarding writes, with KVM if /dev/kvm
+    /// exists
+    pub fn new(image: impl Into<PathBuf>) -> Self {
+        Self {
+            image: image.into(),
+            memory: "2G".into(),
+            cpus: 2,
+            forward: vec![],
+            persist: false,
+            no_kvm: !Path::new("/dev/kvm").exists(),
+        }
+    }
+
+    /// Arguments for qemu-system-x86_64, given the firmware's arguments
+    pub fn args(&self, firmware: &[String]) -> Vec<String> {
+        let accel = if self.no_kvm { "tcg" } else { "kvm" };
+
+        let mut qemu_args: Vec<String> = vec![
+            "-machine".into(),
+            format!("q35,accel={}", accel),
+            "-m".into(),
+            self.memory.clone(),
+            "-smp".into(),
+            self.cpus.to_string(),
+        ];
+
+        qemu_args.extend_from_slice(firmware);
+
+        qemu_args.extend([
+            "-drive".into(),
+            format!("file={},if=virtio,format=raw", self.image.display()),
+            "-nographic".into(),
+        ]);
+
+        if !self.persist {
+            qemu_args.push("-snapshot".into());
+        }
+
+        let mut netdev = String::from("user,id=net0");
+        for (host, guest) in &self.forward {
+            netdev.push_str(&format!(",hostfwd=tcp::{}-:{}", host, guest));
+        }
+
+        qemu_args.extend([
+            "-netdev".into(),
+            netdev,
+            "-device".into(),
+            "virtio-net-pci,netdev=net0".into(),
+        ]);
+
+        qemu_args
+    }
+}
+
+/// Boot with the serial console on this terminal, until the guest powers
+/// off or QEMU is quit
+pub fn boot(options: &QemuOptions, ovmf: &Ovmf) -> Result<()> {
+    if !options.image.exists() {
+        return Err(Error::MissingFile(options.image.clone()).into());
+    }
+
+    let work_dir = tempfile::tempdir()?;
+    let firmware = ovmf.qemu_args(work_dir.path())?;
+
+    info!("booting {:?}, press Ctrl-A X to quit", options.image);
+
+    let status = run_interactive("qemu-system-x86_64".into(), &options.args(&firmware))?;
+    if !status.success() {
+        bail!("qemu-system-x86_64 failed: {}", status);
+    }
+
+    Ok(())
+}
+
+/// Boot headless and wait for `marker` on the serial console, failing with
+/// the end of the console output if it doesn't show up within `timeout`
+pub fn verify_boots(
+    options: &QemuOptions,
+    ovmf: &Ovmf,
+    marker: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let image = &options.image;
+    let work_dir = tempfile::tempdir()?;
+    let firmware = ovmf.qemu_args(work_dir.path())?;
+
+    info!(
+        "boot {:?} and wait up to {:?} for {:?}",
+        image, timeout, marker
+    );
+
+    let (found, console) = run_until(
+        "qemu-system-x86_64".into(),
+        &options.args(&firmware),
+        marker,
+        timeout,
+    )?;
+
+    if !found {
+        let tail: Vec<&str> = console.lines().rev().take(20).collect();
+        let tail: Vec<&str> = tail.into_iter().rev().collect();
+
+        return Err(Error::BootFailed {
+            image: image.clone(),
+            marker: marker.to_string(),
+            timeout,
+            console_tail: tail.join("\n"),
+        }
+        .into());
+    }
+
+    info!("{:?} booted", image);
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_boots_needs_marker() -> Result<()> {
+    let console = |exe: &str, _args: &[String]| -> Result<String> {
+        assert_eq!(exe, "qemu-system-x86_64");
+        Ok("GRUB loading.\nerror: no such device: root.\n".into())
+    };
+
+    let previous = set_executor(std::rc::Rc::new(RecordingExecutor::new(console)));
+    let result = verify_boots(
+        &QemuOptions::new("debian.img"),
+        &Ovmf {
+            code: "OVMF.fd".into(),
+            vars: None,
+        },
+        "login:",
+        Duration::from_secs(1),
+    );
+    set_executor(previous);
+
+    let error = result.unwrap_err();
+    let Some(Error::BootFailed { console_tail, .. }) = error.downcast_ref::<Error>() else {
+        panic!("expected BootFailed, got {}", error);
+    };
+    assert!(console_tail.ends_with("error: no such device: root."));
+
+    Ok(())
+}
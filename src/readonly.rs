@@ -0,0 +1,153 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! A read-only root for appliances that get switched off without warning.
+//! Nothing on disk is written after the build: on Debian and Ubuntu an
+//! initramfs-tools script puts overlays kept in memory over /etc and /var
+//! before switching to the root, and on Alpine the initramfs's own
+//! `overlaytmpfs` does the same for the whole root.
+//!
+//! With a persistence partition, for live USB sticks, the overlays are kept
+//! on it instead and cover everything that would be written, so changes
+//! survive a reboot while the root itself is still never written.
+
+use std::io::Write;
+
+use anyhow::Result;
+
+/// Where the initramfs-tools script goes in the image
+pub const INITRAMFS_SCRIPT: &str = "/etc/initramfs-tools/scripts/init-bottom/overlay-etc-var";
+
+/// The directories given a writable layer on Debian and Ubuntu
+pub const OVERLAY_DIRS: &[&str] = &["etc", "var"];
+
+/// The directories given a layer on the persistence partition, any that
+/// are in the image
+pub const PERSISTENT_OVERLAY_DIRS: &[&str] = &["etc", "home", "opt", "root", "srv", "usr", "var"];
+
+/// The persistence partition's GPT name and filesystem label
+pub const PERSISTENCE_LABEL: &str = "persistence";
+
+/// Runs after the root is mounted read-only at ${rootmnt}. The tmpfs, or
+/// the persistence partition with `persistent`, is under /run, which init
+/// moves onto the root after this.
+pub fn initramfs_script(persistent: bool) -> String {
+    let (dirs, kept, upper) = if persistent {
+        (
+            PERSISTENT_OVERLAY_DIRS,
+            "on the persistence partition",
+            format!(
+                "wait_for_udev 10\n\
+                 if ! mount -t ext4 /dev/disk/by-label/{label} /run/overlay; then\n\
+                 \tlog_warning_msg \"no {label} partition, changes are kept in memory\"\n\
+                 \tmount -t tmpfs -o mode=0755 overlay /run/overlay || panic \"no tmpfs for the overlays\"\n\
+                 fi\n",
+                label = PERSISTENCE_LABEL,
+            ),
+        )
+    } else {
+        (
+            OVERLAY_DIRS,
+            "kept in memory",
+            "mount -t tmpfs -o mode=0755 overlay /run/overlay || panic \"no tmpfs for the overlays\"\n"
+                .to_string(),
+        )
+    };
+
+    format!(
+        "#!/bin/sh\n\
+         # Writable {dirs} over the read-only root, {kept}\n\
+         \n\
+         PREREQ=\"\"\n\
+         prereqs() {{\n\
+         \techo \"$PREREQ\"\n\
+         }}\n\
+         case \"$1\" in\n\
+         prereqs)\n\
+         \tprereqs\n\
+         \texit 0\n\
+         \t;;\n\
+         esac\n\
+         \n\
+         . /scripts/functions\n\
+         \n\
+         mkdir -p /run/overlay\n\
+         {upper}\
+         \n\
+         for dir in {dirs}; do\n\
+         {skip}\
+         \tmkdir -p /run/overlay/$dir/upper /run/overlay/$dir/work\n\
+         \tmount -t overlay \\\n\
+         \t\t-o lowerdir=${{rootmnt}}/$dir,upperdir=/run/overlay/$dir/upper,workdir=/run/overlay/$dir/work \\\n\
+         \t\toverlay ${{rootmnt}}/$dir || panic \"no overlay for /$dir\"\n\
+         done\n",
+        dirs = dirs.join(" "),
+        skip = match persistent {
+            true => "\t[ -d ${rootmnt}/$dir ] || continue\n",
+            false => "",
+        },
+    )
+}
+
+/// Add the script, and the overlay module it needs, to the initramfs-tools
+/// config in the image at `root`. update-initramfs still has to run after.
+pub fn install_initramfs_script(root: &str, persistent: bool) -> Result<()> {
+    let path = format!("{}{}", root, INITRAMFS_SCRIPT);
+    std::fs::create_dir_all(std::path::Path::new(&path).parent().unwrap())?;
+    std::fs::write(&path, initramfs_script(persistent))?;
+    std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+
+    let mut modules = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{}/etc/initramfs-tools/modules", root))?;
+    writeln!(modules, "overlay")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_initramfs_script() {
+    let script = initramfs_script(false);
+    assert!(script.starts_with("#!/bin/sh\n"));
+    assert!(script.contains("for dir in etc var; do\n\tmkdir"));
+    assert!(script.contains("-o lowerdir=${rootmnt}/$dir,upperdir=/run/overlay/$dir/upper,"));
+    assert!(script.contains("\t\toverlay ${rootmnt}/$dir || panic \"no overlay for /$dir\"\n"));
+    assert!(!script.contains("persistence"));
+}
+
+#[test]
+fn test_persistent_initramfs_script() {
+    let script = initramfs_script(true);
+    assert!(
+        script.contains("if ! mount -t ext4 /dev/disk/by-label/persistence /run/overlay; then\n")
+    );
+    assert!(script.contains("for dir in etc home opt root srv usr var; do\n"));
+    assert!(script.contains("\t[ -d ${rootmnt}/$dir ] || continue\n"));
+}
+
+#[test]
+fn test_install_initramfs_script() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    std::fs::create_dir_all(root.path().join("etc/initramfs-tools"))?;
+    std::fs::write(
+        root.path().join("etc/initramfs-tools/modules"),
+        "virtio_blk\n",
+    )?;
+
+    install_initramfs_script(root.path().to_str().unwrap(), false)?;
+
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("etc/initramfs-tools/modules"))?,
+        "virtio_blk\noverlay\n"
+    );
+    assert!(root
+        .path()
+        .join("etc/initramfs-tools/scripts/init-bottom/overlay-etc-var")
+        .is_file());
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! `create --in-container`: run the same command again in a privileged
+//! container of the builder image, which has every tool a build needs, so
+//! the host only needs docker. The container uses the host's daemon through
+//! its socket, and sees the host's /dev, as partitions of loop devices only
+//! show up there. The directories the build reads and writes are mounted at
+//! the same paths, so every path on the command line still works.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use anyhow::Result;
+
+use crate::*;
+
+/// Built from Dockerfile.builder and published with each release
+pub const BUILDER_IMAGE: &str = "ghcr.io/jmpesp/docker_to_uefi_bootable_image:latest";
+
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Options that only mean something outside the container, with whether
+/// each takes a value. --config's options were already added to the
+/// command line, with --set's values in them.
+const HOST_OPTIONS: &[(&str, bool)] = &[
+    ("--in-container", false),
+    ("--builder-image", true),
+    ("--config", true),
+    ("--set", true),
+];
+
+/// `argv` without the program name or any of `HOST_OPTIONS`
+pub fn container_args(argv: &[String]) -> Vec<String> {
+    let mut args = vec![];
+    let mut iter = argv.iter().skip(1);
+
+    while let Some(arg) = iter.next() {
+        let host_option = HOST_OPTIONS.iter().find(|(name, _)| {
+            arg == name || arg.strip_prefix(name).is_some_and(|x| x.starts_with('='))
+        });
+
+        match host_option {
+            Some((name, true)) if arg == name => {
+                iter.next();
+            }
+            Some(_) => {}
+            None => args.push(arg.clone()),
+        }
+    }
+
+    args
+}
+
+/// The directories to mount for `paths`, absolute, leaving out any under
+/// another
+fn mounted_dirs(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let cwd = std::env::current_dir()?;
+
+    let mut dirs: Vec<PathBuf> = paths
+        .iter()
+        .map(|x| cwd.join(x).components().collect())
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+
+    let all = dirs.clone();
+    dirs.retain(|x| !all.iter().any(|y| y != x && x.starts_with(y)));
+
+    Ok(dirs)
+}
+
+/// The `docker run` arguments for running `args` in `image`, with `dirs`
+/// mounted and the current directory the same
+pub fn docker_run_args(image: &str, args: &[String], dirs: &[PathBuf]) -> Result<Vec<String>> {
+    let mut docker_args: Vec<String> = vec![
+        "run".into(),
+        "--rm".into(),
+        "--privileged".into(),
+        "--volume".into(),
+        format!("{0}:{0}", DOCKER_SOCKET),
+        "--volume".into(),
+        "/dev:/dev".into(),
+    ];
+
+    for dir in mounted_dirs(dirs)? {
+        docker_args.push("--volume".into());
+        docker_args.push(format!("{0}:{0}", dir.display()));
+    }
+
+    docker_args.extend([
+        "--workdir".into(),
+        std::env::current_dir()?.display().to_string(),
+        image.into(),
+    ]);
+    docker_args.extend_from_slice(args);
+
+    Ok(docker_args)
+}
+
+/// Run `argv` again in a container of `image`, with `dirs` mounted, and
+/// return how it exited
+pub fn relaunch(image: &str, argv: &[String], dirs: &[PathBuf]) -> Result<ExitStatus> {
+    let args = docker_run_args(image, &container_args(argv), dirs)?;
+    run_interactive("docker".into(), &args)
+}
+
+/// The directory `path` is in, for mounting
+pub fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[test]
+fn test_container_args() {
+    let argv: Vec<String> = [
+        "docker_to_uefi_bootable_image",
+        "-v",
+        "create",
+        "--in-container",
+        "--builder-image",
+        "builder:dev",
+        "--config=build.toml",
+        "--image-name",
+        "debian:12",
+        "--flavor",
+        "debian",
+    ]
+    .iter()
+    .map(|x| x.to_string())
+    .collect();
+
+    assert_eq!(
+        container_args(&argv),
+        [
+            "-v",
+            "create",
+            "--image-name",
+            "debian:12",
+            "--flavor",
+            "debian"
+        ]
+    );
+}
+
+#[test]
+fn test_docker_run_args() -> Result<()> {
+    let cwd = std::env::current_dir()?;
+
+    let args = docker_run_args(
+        "builder:dev",
+        &["create".into()],
+        &[cwd.join("out"), cwd.clone()],
+    )?;
+
+    assert_eq!(
+        args,
+        [
+            "run".to_string(),
+            "--rm".into(),
+            "--privileged".into(),
+            "--volume".into(),
+            "/var/run/docker.sock:/var/run/docker.sock".into(),
+            "--volume".into(),
+            "/dev:/dev".into(),
+            "--volume".into(),
+            format!("{0}:{0}", cwd.display()),
+            "--workdir".into(),
+            cwd.display().to_string(),
+            "builder:dev".into(),
+            "create".into(),
+        ]
+    );
+
+    Ok(())
+}
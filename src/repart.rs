@@ -0,0 +1,178 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Partitioning with systemd-repart instead of writing the GPT here: the
+//! layout is described by repart.d definitions, and repart creates the
+//! partitions and formats root. The ESPs are still written here, as they
+//! are rewritten with the boot files at the end of the build anyway.
+//!
+//! On systemd flavors the image gets a definition for root too, so
+//! systemd-repart grows it on boot to fill a disk that was made bigger.
+
+use std::path::Path;
+
+use anyhow::Result;
+use uuid::Uuid;
+
+use crate::gpt::BIOS_BOOT;
+use crate::*;
+
+/// The definition installed in the image for growing root on boot
+pub const ROOT_DEFINITION: &str = "/etc/repart.d/40-root.conf";
+
+/// Root's definition, without formatting it, which repart only does to
+/// partitions it creates
+fn root_definition() -> String {
+    "[Partition]\n\
+     Type=linux-generic\n\
+     Label=Root Partition\n"
+        .into()
+}
+
+/// The same layout as `Gpt::default_layout`, by file name, except that the
+/// mirror of the ESP takes partition 3 and root comes last as 4: repart
+/// numbers partitions in the order it places them
+pub fn definitions(esp_mirror: bool) -> Vec<(&'static str, String)> {
+    let fixed = |type_: &str, label: &str, size: &str| {
+        format!(
+            "[Partition]\n\
+             Type={}\n\
+             Label={}\n\
+             SizeMinBytes={}\n\
+             SizeMaxBytes={}\n",
+            type_, label, size, size
+        )
+    };
+
+    let mut definitions = vec![
+        (
+            "10-bios.conf",
+            fixed(
+                &BIOS_BOOT.to_hyphenated_ref().to_string(),
+                "BIOS Boot Partition",
+                "2M",
+            ),
+        ),
+        ("20-esp.conf", fixed("esp", "EFI System Partition", "512M")),
+    ];
+
+    if esp_mirror {
+        definitions.push((
+            "30-esp-mirror.conf",
+            fixed("esp", "EFI System Partition Mirror", "512M"),
+        ));
+    }
+
+    definitions.push((
+        "40-root.conf",
+        format!("{}Format=ext4\n", root_definition()),
+    ));
+
+    definitions
+}
+
+pub fn write_definitions(dir: &Path, esp_mirror: bool) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    for (name, definition) in definitions(esp_mirror) {
+        std::fs::write(dir.join(name), definition)?;
+    }
+
+    Ok(())
+}
+
+/// Partition the empty disk image at `image` as the definitions in
+/// `definitions` say. `seed` makes the GUIDs and filesystem UUIDs the same
+/// each time.
+pub fn partition(image: &Path, definitions: &Path, seed: Option<Uuid>) -> Result<()> {
+    let mut args = vec![
+        "--dry-run=no".into(),
+        "--empty=force".into(),
+        "--no-pager".into(),
+        format!("--definitions={}", definitions.display()),
+    ];
+
+    if let Some(seed) = seed {
+        args.push(format!("--seed={}", seed.to_hyphenated_ref()));
+    }
+
+    args.push(image.display().to_string());
+
+    run("systemd-repart".into(), &args)?;
+
+    Ok(())
+}
+
+/// Write root's definition into the image at `root`. systemd-repart has to
+/// be installed there too.
+pub fn install_grow_on_boot(root: &str) -> Result<()> {
+    let path = format!("{}{}", root, ROOT_DEFINITION);
+    std::fs::create_dir_all(Path::new(&path).parent().unwrap())?;
+    std::fs::write(&path, root_definition())?;
+    Ok(())
+}
+
+#[test]
+fn test_definitions() {
+    let names: Vec<&str> = definitions(true).iter().map(|x| x.0).collect();
+    assert_eq!(
+        names,
+        [
+            "10-bios.conf",
+            "20-esp.conf",
+            "30-esp-mirror.conf",
+            "40-root.conf"
+        ]
+    );
+
+    let definitions = definitions(false);
+    assert_eq!(definitions.len(), 3);
+    assert_eq!(
+        definitions[0].1,
+        "[Partition]\n\
+         Type=21686148-6449-6e6f-744e-656564454649\n\
+         Label=BIOS Boot Partition\n\
+         SizeMinBytes=2M\n\
+         SizeMaxBytes=2M\n"
+    );
+
+    // Root takes the rest of the disk
+    assert_eq!(
+        definitions[2].1,
+        "[Partition]\nType=linux-generic\nLabel=Root Partition\nFormat=ext4\n"
+    );
+}
+
+#[test]
+fn test_partition() -> Result<()> {
+    use std::rc::Rc;
+
+    let executor = Rc::new(RecordingExecutor::new(|_, _| Ok(String::new())));
+    let previous = set_executor(executor.clone());
+    let result = partition(
+        Path::new("/work/output.img"),
+        Path::new("/work/repart.d"),
+        Some(Uuid::from_u128(1)),
+    );
+    set_executor(previous);
+    result?;
+
+    let commands = executor.commands();
+    assert_eq!(commands[0].exe, "systemd-repart");
+    assert_eq!(
+        commands[0].args,
+        [
+            "--dry-run=no",
+            "--empty=force",
+            "--no-pager",
+            "--definitions=/work/repart.d",
+            "--seed=00000000-0000-0000-0000-000000000001",
+            "/work/output.img",
+        ]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,391 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Building without root or loop devices. The build runs as root in a user
+//! namespace, mapped to the invoking user and their subordinate IDs from
+//! /etc/subuid and /etc/subgid, so the image's files keep their owners.
+//!
+//! Nothing is mounted from the disk image: root is populated as a plain
+//! directory and written into its partition at the end by `mkfs.ext4 -d`,
+//! with a UUID chosen up front. The ESP is written here as always. GRUB is
+//! made with grub-mkimage and given a grub.cfg written here, as
+//! grub-install and grub-mkconfig both look for root's block device.
+
+use std::cmp::Ordering;
+use std::ffi::CStr;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use uuid::Uuid;
+
+use crate::builder::Flavor;
+use crate::console::{self, Console};
+use crate::gpt::{self, SECTOR};
+use crate::*;
+
+/// Where the files of the booted image's GRUB go, as grub-install puts them
+const GRUB_DIR: &str = "/boot/grub";
+
+/// The modules built into GRUB's EFI image, enough to find root and read
+/// the rest of them from it
+const GRUB_CORE_MODULES: &[&str] = &["part_gpt", "ext2", "search_fs_uuid"];
+
+/// The first range of subordinate IDs for `user` (or `id`) in an
+/// /etc/subuid or /etc/subgid style `file`, as (start, count)
+fn subordinate_ids(file: &str, user: &str, id: u32) -> Result<(u32, u32)> {
+    for line in file.lines() {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+
+        if let [owner, start, count] = fields[..] {
+            if owner == user || owner == id.to_string() {
+                return Ok((start.parse()?, count.parse()?));
+            }
+        }
+    }
+
+    bail!("no subordinate IDs for {}", user);
+}
+
+/// Unless this process is root already, move it into a new user and mount
+/// namespace as root. Has to be called before any threads are started.
+pub fn enter_user_namespace() -> Result<()> {
+    // SAFETY: these can't fail
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if uid == 0 {
+        return Ok(());
+    }
+
+    // SAFETY: the process is single threaded, and the name is copied out
+    // before anything else could call getpwuid
+    let user = unsafe {
+        let passwd = libc::getpwuid(uid);
+        if passwd.is_null() {
+            bail!("uid {} has no user name", uid);
+        }
+        CStr::from_ptr((*passwd).pw_name)
+            .to_string_lossy()
+            .to_string()
+    };
+
+    let (uid_start, uid_count) = subordinate_ids(
+        &std::fs::read_to_string("/etc/subuid").context("reading /etc/subuid")?,
+        &user,
+        uid,
+    )?;
+    let (gid_start, gid_count) = subordinate_ids(
+        &std::fs::read_to_string("/etc/subgid").context("reading /etc/subgid")?,
+        &user,
+        uid,
+    )?;
+
+    // The maps have to be written from outside the namespace, by setuid
+    // helpers started now and told to go once it exists
+    let pid = std::process::id();
+    let mut helper = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "read _ && newuidmap {pid} 0 {uid} 1 1 {uid_start} {uid_count} \
+             && newgidmap {pid} 0 {gid} 1 1 {gid_start} {gid_count}"
+        ))
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("could not run newuidmap and newgidmap")?;
+
+    // SAFETY: no pointers
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+        let error = std::io::Error::last_os_error();
+        let _ = helper.kill();
+        let _ = helper.wait();
+        bail!("could not create a user namespace: {}", error);
+    }
+
+    helper.stdin.take().unwrap().write_all(b"\n")?;
+    if !helper.wait()?.success() {
+        bail!("newuidmap or newgidmap could not map the user namespace's IDs");
+    }
+
+    // Keep the build's mounts from propagating anywhere
+    let root = c"/";
+    // SAFETY: every pointer is to a live CStr or null
+    let result = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            root.as_ptr(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        bail!(
+            "could not make mounts private: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok(())
+}
+
+/// Compare kernel versions like GRUB's version sort, numbers by value
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let chunks = |s: &str| -> Vec<(bool, String)> {
+        let mut chunks: Vec<(bool, String)> = vec![];
+        for c in s.chars() {
+            let digit = c.is_ascii_digit();
+            match chunks.last_mut() {
+                Some((d, chunk)) if *d == digit => chunk.push(c),
+                _ => chunks.push((digit, c.to_string())),
+            }
+        }
+        chunks
+    };
+
+    for (x, y) in chunks(a).iter().zip(chunks(b).iter()) {
+        let order = match (x, y) {
+            ((true, x), (true, y)) => x
+                .parse::<u64>()
+                .unwrap_or(0)
+                .cmp(&y.parse::<u64>().unwrap_or(0)),
+            ((_, x), (_, y)) => x.cmp(y),
+        };
+
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+
+    a.len().cmp(&b.len())
+}
+
+/// The config built into GRUB's EFI image, finding root by its UUID
+pub fn load_cfg(root_uuid: Uuid) -> String {
+    format!(
+        "search.fs_uuid {} root\n\
+         set prefix=($root){}\n",
+        root_uuid.to_hyphenated_ref(),
+        GRUB_DIR
+    )
+}
+
+/// A grub.cfg with an entry for each of `kernels`, newest first, booting
+/// root by UUID with `cmdline`, and GRUB's menu on `consoles`
+pub fn grub_cfg(
+    flavor: &Flavor,
+    root_uuid: Uuid,
+    kernels: &[String],
+    cmdline: &str,
+    consoles: &[Console],
+) -> String {
+    let root_uuid = root_uuid.to_hyphenated_ref().to_string();
+
+    let mut kernels = kernels.to_vec();
+    kernels.sort_by(|a, b| compare_versions(b, a));
+
+    let mut cfg = "# Written by docker_to_uefi_bootable_image --rootless, as grub-mkconfig\n\
+                   # needs root's block device. update-grub in the booted image replaces it.\n\
+                   \n"
+    .to_string();
+
+    if let Some(serial) = console::grub_serial_command(consoles) {
+        cfg.push_str(&format!("{}\n", serial));
+    }
+
+    let terminals = console::grub_terminals(consoles).join(" ");
+    cfg.push_str(&format!(
+        "terminal_input {terminals}\n\
+         terminal_output {terminals}\n\
+         set timeout=5\n\
+         set default=0\n\
+         \n\
+         insmod part_gpt\n\
+         insmod ext2\n\
+         search --no-floppy --fs-uuid --set=root {root_uuid}\n"
+    ));
+
+    for kernel in kernels {
+        // Alpine names them by the kernel's flavor, like vmlinuz-lts
+        let (vmlinuz, initrd) = match flavor {
+            Flavor::Debian | Flavor::Ubuntu => (
+                format!("/boot/vmlinuz-{}", kernel),
+                format!("/boot/initrd.img-{}", kernel),
+            ),
+            Flavor::Alpine => {
+                let name = kernel.rsplit('-').next().unwrap_or(&kernel);
+                (
+                    format!("/boot/vmlinuz-{}", name),
+                    format!("/boot/initramfs-{}", name),
+                )
+            }
+        };
+
+        cfg.push_str(&format!(
+            "\nmenuentry 'Linux {kernel}' {{\n\
+             \tlinux {vmlinuz} root=UUID={root_uuid} ro {cmdline}\n\
+             \tinitrd {initrd}\n\
+             }}\n"
+        ));
+    }
+
+    cfg
+}
+
+/// Put GRUB in the image at `root`: its EFI image on the ESP's removable
+/// media path, its modules, and a grub.cfg. Uses the image's own GRUB.
+pub fn install_grub(
+    root: &str,
+    flavor: &Flavor,
+    root_uuid: Uuid,
+    kernels: &[String],
+    cmdline: &str,
+    consoles: &[Console],
+) -> Result<()> {
+    let chroot = |args: &[&str]| {
+        let args: Vec<String> = std::iter::once(root)
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        run("chroot".into(), &args)
+    };
+
+    std::fs::create_dir_all(format!("{}{}", root, GRUB_DIR))?;
+    std::fs::create_dir_all(format!("{}/boot/efi/EFI/BOOT", root))?;
+
+    chroot(&["cp", "-r", "/usr/lib/grub/x86_64-efi", GRUB_DIR])?;
+
+    let load_cfg_path = format!("{}/load.cfg", GRUB_DIR);
+    std::fs::write(format!("{}{}", root, load_cfg_path), load_cfg(root_uuid))?;
+
+    let mut args = vec![
+        "grub-mkimage",
+        "-O",
+        "x86_64-efi",
+        "-p",
+        GRUB_DIR,
+        "-c",
+        &load_cfg_path,
+        "-o",
+        "/boot/efi/EFI/BOOT/BOOTX64.EFI",
+    ];
+    args.extend(GRUB_CORE_MODULES);
+    chroot(&args)?;
+
+    std::fs::remove_file(format!("{}{}", root, load_cfg_path))?;
+
+    std::fs::write(
+        format!("{}{}/grub.cfg", root, GRUB_DIR),
+        grub_cfg(flavor, root_uuid, kernels, cmdline, consoles),
+    )?;
+
+    Ok(())
+}
+
+/// Make an ext4 filesystem of what's in `dir`, in `partition` of the disk
+/// image at `image`. `hash_seed` makes directories the same each time.
+pub fn make_root_filesystem(
+    image: &Path,
+    partition: &gpt::Partition,
+    dir: &str,
+    uuid: Uuid,
+    hash_seed: Option<Uuid>,
+) -> Result<()> {
+    let mut extended = format!("offset={}", partition.first_lba * SECTOR);
+    if let Some(hash_seed) = hash_seed {
+        extended.push_str(&format!(",hash_seed={}", hash_seed.to_hyphenated_ref()));
+    }
+
+    run(
+        "mkfs.ext4".into(),
+        &[
+            // It's a file, with a partition table where mkfs looks first
+            "-F".into(),
+            "-d".into(),
+            dir.into(),
+            "-U".into(),
+            uuid.to_hyphenated_ref().to_string(),
+            "-E".into(),
+            extended,
+            image.display().to_string(),
+            format!("{}k", partition.size() / 1024),
+        ],
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn test_subordinate_ids() -> Result<()> {
+    let file = "alice:100000:65536\n1001:165536:65536\n";
+
+    assert_eq!(subordinate_ids(file, "alice", 1000)?, (100000, 65536));
+    assert_eq!(subordinate_ids(file, "bob", 1001)?, (165536, 65536));
+    assert!(subordinate_ids(file, "carol", 1002).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_compare_versions() {
+    let mut versions = vec![
+        "6.1.0-9-amd64".to_string(),
+        "6.1.0-18-amd64".to_string(),
+        "5.10.0-28-amd64".to_string(),
+    ];
+    versions.sort_by(|a, b| compare_versions(b, a));
+
+    assert_eq!(
+        versions,
+        ["6.1.0-18-amd64", "6.1.0-9-amd64", "5.10.0-28-amd64"]
+    );
+}
+
+#[test]
+fn test_grub_cfg() {
+    let uuid = Uuid::from_u128(1);
+
+    let cfg = grub_cfg(
+        &Flavor::Debian,
+        uuid,
+        &["6.1.0-9-amd64".into(), "6.1.0-18-amd64".into()],
+        "console=ttyS0,115200",
+        &console::defaults(),
+    );
+    assert!(cfg.contains(
+        "serial --unit=0 --speed=115200\n\
+         terminal_input serial\n\
+         terminal_output serial\n"
+    ));
+    assert!(cfg.contains(
+        "search --no-floppy --fs-uuid --set=root 00000000-0000-0000-0000-000000000001\n"
+    ));
+
+    // The newest is the default
+    let newest = cfg.find("menuentry 'Linux 6.1.0-18-amd64'").unwrap();
+    let older = cfg.find("menuentry 'Linux 6.1.0-9-amd64'").unwrap();
+    assert!(newest < older);
+    assert!(cfg.contains(
+        "\tlinux /boot/vmlinuz-6.1.0-18-amd64 root=UUID=00000000-0000-0000-0000-000000000001 ro console=ttyS0,115200\n\
+         \tinitrd /boot/initrd.img-6.1.0-18-amd64\n"
+    ));
+
+    let cfg = grub_cfg(
+        &Flavor::Alpine,
+        uuid,
+        &["6.6.14-0-lts".into()],
+        "",
+        &["tty0".parse().unwrap()],
+    );
+    assert!(cfg.contains("terminal_output console\n"));
+    assert!(!cfg.contains("serial"));
+    assert!(cfg.contains("\tlinux /boot/vmlinuz-lts root=UUID="));
+    assert!(cfg.contains("\tinitrd /boot/initramfs-lts\n"));
+
+    assert_eq!(
+        load_cfg(uuid),
+        "search.fs_uuid 00000000-0000-0000-0000-000000000001 root\nset prefix=($root)/boot/grub\n"
+    );
+}
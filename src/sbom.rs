@@ -0,0 +1,414 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Software bills of materials for built images: the packages installed in
+//! the image and the container image it came from, as SPDX 2.3 or CycloneDX
+//! 1.5 JSON. Build with
+//! [`ImageBuilder::list_packages`](crate::builder::ImageBuilder::list_packages)
+//! so the image's packages are known.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::builder::{BuiltImage, Flavor};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SbomFormat {
+    Spdx,
+    Cyclonedx,
+}
+
+impl SbomFormat {
+    /// What the SBOM of `output_file` is called, next to it, e.g.
+    /// debian.img.spdx.json
+    pub fn path(&self, output_file: &Path) -> PathBuf {
+        let mut path = output_file.as_os_str().to_os_string();
+        path.push(match self {
+            SbomFormat::Spdx => ".spdx.json",
+            SbomFormat::Cyclonedx => ".cdx.json",
+        });
+        PathBuf::from(path)
+    }
+}
+
+/// A package installed in an image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub architecture: String,
+}
+
+/// The packages installed in the root filesystem at `root`, read from the
+/// package manager's database the way dpkg-query and apk would
+pub fn installed_packages(root: &str, flavor: &Flavor) -> Result<Vec<Package>> {
+    let database = match flavor {
+        Flavor::Debian | Flavor::Ubuntu => "var/lib/dpkg/status",
+        Flavor::Alpine => "lib/apk/db/installed",
+    };
+
+    let contents = match std::fs::read_to_string(format!("{}/{}", root, database)) {
+        Ok(contents) => contents,
+        Err(e) => bail!("could not read the package database {}: {}", database, e),
+    };
+
+    let mut packages = match flavor {
+        Flavor::Debian | Flavor::Ubuntu => dpkg_packages(&contents),
+        Flavor::Alpine => apk_packages(&contents),
+    };
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(packages)
+}
+
+/// Packages in a dpkg status file that are installed, rather than removed
+/// with their config left behind
+fn dpkg_packages(status: &str) -> Vec<Package> {
+    status
+        .split("\n\n")
+        .filter_map(|stanza| {
+            let field = |name: &str| {
+                stanza
+                    .lines()
+                    .find_map(|x| x.strip_prefix(name)?.strip_prefix(':'))
+                    .map(|x| x.trim().to_string())
+            };
+
+            if !field("Status")?.ends_with(" installed") {
+                return None;
+            }
+
+            Some(Package {
+                name: field("Package")?,
+                version: field("Version")?,
+                architecture: field("Architecture")?,
+            })
+        })
+        .collect()
+}
+
+/// Packages in apk's installed database, one per blank-line separated
+/// record of single letter fields
+fn apk_packages(installed: &str) -> Vec<Package> {
+    installed
+        .split("\n\n")
+        .filter_map(|record| {
+            let field = |name: &str| {
+                record
+                    .lines()
+                    .find_map(|x| x.strip_prefix(name)?.strip_prefix(':'))
+                    .map(String::from)
+            };
+
+            Some(Package {
+                name: field("P")?,
+                version: field("V")?,
+                architecture: field("A")?,
+            })
+        })
+        .collect()
+}
+
+/// The package URL of `package`, which scanners match against advisories
+pub fn purl(flavor: &Flavor, package: &Package) -> String {
+    let (kind, namespace) = match flavor {
+        Flavor::Debian => ("deb", "debian"),
+        Flavor::Ubuntu => ("deb", "ubuntu"),
+        Flavor::Alpine => ("apk", "alpine"),
+    };
+
+    // Debian versions may have an epoch, and colons need escaping
+    format!(
+        "pkg:{}/{}/{}@{}?arch={}",
+        kind,
+        namespace,
+        package.name,
+        package.version.replace(':', "%3A"),
+        package.architecture
+    )
+}
+
+/// `image`'s file name, for naming the document and its main component
+fn image_file_name(image: &BuiltImage) -> String {
+    image
+        .path
+        .file_name()
+        .unwrap_or(image.path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The hex digest of the container image, from its ID like "sha256:..."
+fn container_digest(image: &BuiltImage) -> Option<&str> {
+    image.image_id.as_deref()?.strip_prefix("sha256:")
+}
+
+pub fn spdx(image: &BuiltImage, created: SystemTime) -> Value {
+    let name = image_file_name(image);
+
+    let mut container = json!({
+        "SPDXID": "SPDXRef-Container",
+        "name": image.image_name,
+        "downloadLocation": "NOASSERTION",
+        "primaryPackagePurpose": "CONTAINER",
+    });
+    if let Some(digest) = container_digest(image) {
+        container["checksums"] = json!([{ "algorithm": "SHA256", "checksumValue": digest }]);
+    }
+
+    let mut packages = vec![
+        json!({
+            "SPDXID": "SPDXRef-Image",
+            "name": name,
+            "downloadLocation": "NOASSERTION",
+            "primaryPackagePurpose": "OPERATING-SYSTEM",
+        }),
+        container,
+    ];
+
+    let mut relationships = vec![
+        json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": "SPDXRef-Image",
+        }),
+        json!({
+            "spdxElementId": "SPDXRef-Image",
+            "relationshipType": "GENERATED_FROM",
+            "relatedSpdxElement": "SPDXRef-Container",
+        }),
+    ];
+
+    for (i, package) in image.packages.iter().enumerate() {
+        let id = format!("SPDXRef-Package-{}", i + 1);
+
+        packages.push(json!({
+            "SPDXID": id,
+            "name": package.name,
+            "versionInfo": package.version,
+            "downloadLocation": "NOASSERTION",
+            "licenseConcluded": "NOASSERTION",
+            "licenseDeclared": "NOASSERTION",
+            "copyrightText": "NOASSERTION",
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": purl(&image.flavor, package),
+            }],
+        }));
+
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-Image",
+            "relationshipType": "CONTAINS",
+            "relatedSpdxElement": id,
+        }));
+    }
+
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!(
+            "https://spdx.org/spdxdocs/{}-{}",
+            name,
+            uuid::Uuid::new_v4().to_hyphenated_ref()
+        ),
+        "creationInfo": {
+            "created": rfc3339(created),
+            "creators": [format!("Tool: {}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    })
+}
+
+pub fn cyclonedx(image: &BuiltImage, created: SystemTime) -> Value {
+    let mut container = json!({
+        "type": "container",
+        "bom-ref": "container",
+        "name": image.image_name,
+    });
+    if let Some(digest) = container_digest(image) {
+        container["hashes"] = json!([{ "alg": "SHA-256", "content": digest }]);
+    }
+
+    let mut components = vec![container];
+    components.extend(image.packages.iter().map(|package| {
+        let purl = purl(&image.flavor, package);
+
+        json!({
+            "type": "library",
+            "bom-ref": purl,
+            "name": package.name,
+            "version": package.version,
+            "purl": purl,
+        })
+    }));
+
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", uuid::Uuid::new_v4().to_hyphenated_ref()),
+        "version": 1,
+        "metadata": {
+            "timestamp": rfc3339(created),
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                }],
+            },
+            "component": {
+                "type": "operating-system",
+                "bom-ref": "image",
+                "name": image_file_name(image),
+            },
+        },
+        "components": components,
+        "dependencies": [{
+            "ref": "image",
+            "dependsOn": std::iter::once("container".to_string())
+                .chain(image.packages.iter().map(|x| purl(&image.flavor, x)))
+                .collect::<Vec<_>>(),
+        }],
+    })
+}
+
+/// Write the SBOM of `image` to `path`
+pub fn write(format: SbomFormat, image: &BuiltImage, path: &Path) -> Result<()> {
+    let sbom = match format {
+        SbomFormat::Spdx => spdx(image, SystemTime::now()),
+        SbomFormat::Cyclonedx => cyclonedx(image, SystemTime::now()),
+    };
+
+    std::fs::write(path, serde_json::to_string_pretty(&sbom)?)?;
+
+    Ok(())
+}
+
+/// A UTC timestamp like 2024-02-29T12:00:00Z, as both formats want
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Days to a civil date, from Howard Hinnant's days_from_civil inverse
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[test]
+fn test_rfc3339() {
+    let at = |secs| rfc3339(UNIX_EPOCH + std::time::Duration::from_secs(secs));
+
+    assert_eq!(at(0), "1970-01-01T00:00:00Z");
+    assert_eq!(at(951782400), "2000-02-29T00:00:00Z");
+    assert_eq!(at(1709208000), "2024-02-29T12:00:00Z");
+    assert_eq!(at(1735689599), "2024-12-31T23:59:59Z");
+}
+
+#[test]
+fn test_dpkg_packages() {
+    let status = "\
+Package: libc6
+Status: install ok installed
+Architecture: amd64
+Version: 2.36-9+deb12u4
+Description: GNU C Library
+ with a continuation line
+
+Package: old-thing
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 1.0
+
+Package: tzdata
+Status: install ok installed
+Architecture: all
+Version: 2024a-0+deb12u1
+";
+
+    assert_eq!(
+        dpkg_packages(status),
+        [
+            Package {
+                name: "libc6".into(),
+                version: "2.36-9+deb12u4".into(),
+                architecture: "amd64".into(),
+            },
+            Package {
+                name: "tzdata".into(),
+                version: "2024a-0+deb12u1".into(),
+                architecture: "all".into(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_apk_packages() {
+    let installed = "\
+C:Q1abc=
+P:musl
+V:1.2.4-r2
+A:x86_64
+L:MIT
+
+C:Q1def=
+P:busybox
+V:1.36.1-r15
+A:x86_64
+";
+
+    let packages = apk_packages(installed);
+    assert_eq!(packages.len(), 2);
+    assert_eq!(packages[1].name, "busybox");
+    assert_eq!(packages[1].version, "1.36.1-r15");
+
+    assert_eq!(
+        purl(&Flavor::Alpine, &packages[0]),
+        "pkg:apk/alpine/musl@1.2.4-r2?arch=x86_64"
+    );
+}
+
+#[test]
+fn test_purl_escapes_epoch() {
+    let package = Package {
+        name: "openssh-server".into(),
+        version: "1:9.2p1-2".into(),
+        architecture: "amd64".into(),
+    };
+
+    assert_eq!(
+        purl(&Flavor::Debian, &package),
+        "pkg:deb/debian/openssh-server@1%3A9.2p1-2?arch=amd64"
+    );
+}
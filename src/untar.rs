@@ -0,0 +1,932 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Unpacking tar archives in process, as the host executor does. Reads what
+//! docker export and GNU tar write: ustar, GNU long names and sparse files,
+//! and PAX headers, including xattrs. See tar(5).
+
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use tracing::warn;
+
+use crate::check_cancelled;
+
+const BLOCK: usize = 512;
+
+/// Runs of zeros at least this long are left as holes
+const HOLE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    File,
+    HardLink,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    Fifo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    path: String,
+    kind: Kind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    /// Bytes of data in the archive
+    size: u64,
+    link: String,
+    device: (u32, u32),
+    xattrs: Vec<(String, Vec<u8>)>,
+    /// For sparse files, the (offset, length) of each run of data and the
+    /// file's real size
+    sparse: Option<(Vec<(u64, u64)>, u64)>,
+}
+
+/// A tar stream, keeping count of where it's up to for errors and progress
+struct Archive<R> {
+    reader: R,
+    offset: u64,
+}
+
+impl<R: Read> Archive<R> {
+    /// Fill `buf`, explaining a short read in terms of `what` was being read
+    fn read_exact(&mut self, buf: &mut [u8], what: &dyn Fn() -> String) -> Result<()> {
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            let n = self.reader.read(&mut buf[filled..])?;
+            if n == 0 {
+                bail!(
+                    "archive ends at byte {} in the middle of {}",
+                    self.offset,
+                    what()
+                );
+            }
+            filled += n;
+            self.offset += n as u64;
+        }
+
+        Ok(())
+    }
+
+    /// The next header block, or None at the end of the archive
+    fn read_header_block(&mut self) -> Result<Option<[u8; BLOCK]>> {
+        let mut block = [0; BLOCK];
+
+        // An archive may end without the two zero blocks
+        let n = self.reader.read(&mut block)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        self.offset += n as u64;
+        if n < BLOCK {
+            self.read_exact(&mut block[n..], &|| "a header".into())?;
+        }
+
+        if block.iter().all(|x| *x == 0) {
+            return Ok(None);
+        }
+
+        Ok(Some(block))
+    }
+
+    fn read_data(&mut self, size: u64, what: &dyn Fn() -> String) -> Result<Vec<u8>> {
+        let mut data = vec![0; size as usize];
+        self.read_exact(&mut data, what)?;
+        self.skip_padding(size, what)?;
+        Ok(data)
+    }
+
+    fn skip(&mut self, mut size: u64, what: &dyn Fn() -> String) -> Result<()> {
+        let mut buf = [0; 8192];
+
+        while size > 0 {
+            let n = size.min(buf.len() as u64) as usize;
+            self.read_exact(&mut buf[..n], what)?;
+            size -= n as u64;
+        }
+
+        Ok(())
+    }
+
+    fn skip_padding(&mut self, size: u64, what: &dyn Fn() -> String) -> Result<()> {
+        self.skip((BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64, what)
+    }
+
+    /// The next entry, with any extended headers for it applied
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        let mut long_name = None;
+        let mut long_link = None;
+        let mut pax = BTreeMap::new();
+
+        loop {
+            let Some(header) = self.read_header_block()? else {
+                return Ok(None);
+            };
+
+            check_header(&header, self.offset)?;
+
+            let size = number(&header[124..136])?;
+            let at = self.offset;
+            let what = || format!("an extended header at byte {}", at);
+
+            match header[156] {
+                b'L' => long_name = Some(c_str(&self.read_data(size, &what)?)),
+                b'K' => long_link = Some(c_str(&self.read_data(size, &what)?)),
+                b'x' => pax.extend(pax_records(&self.read_data(size, &what)?)?),
+
+                // Global headers say nothing docker export relies on
+                b'g' => self.skip(size.div_ceil(BLOCK as u64) * BLOCK as u64, &what)?,
+
+                _ => {
+                    return self
+                        .entry(&header, size, long_name, long_link, pax)
+                        .map(Some)
+                }
+            }
+        }
+    }
+
+    fn entry(
+        &mut self,
+        header: &[u8; BLOCK],
+        size: u64,
+        long_name: Option<String>,
+        long_link: Option<String>,
+        pax: BTreeMap<String, Vec<u8>>,
+    ) -> Result<Entry> {
+        let pax_str = |key: &str| pax.get(key).map(|x| String::from_utf8_lossy(x).to_string());
+        let pax_number = |key: &str| -> Result<Option<u64>> {
+            match pax_str(key) {
+                Some(x) => {
+                    Ok(Some(x.split('.').next().unwrap().parse().with_context(
+                        || format!("bad {} {:?} in PAX header", key, x),
+                    )?))
+                }
+                None => Ok(None),
+            }
+        };
+
+        let path = match pax_str("path").or(long_name) {
+            Some(path) => path,
+            None => {
+                let name = c_str(&header[0..100]);
+                let prefix = c_str(&header[345..500]);
+                if &header[257..263] == b"ustar\0" && !prefix.is_empty() {
+                    format!("{}/{}", prefix, name)
+                } else {
+                    name
+                }
+            }
+        };
+
+        let kind = match header[156] {
+            b'0' | b'\0' | b'7' | b'S' => Kind::File,
+            b'1' => Kind::HardLink,
+            b'2' => Kind::Symlink,
+            b'3' => Kind::CharDevice,
+            b'4' => Kind::BlockDevice,
+            b'5' => Kind::Directory,
+            b'6' => Kind::Fifo,
+            x => bail!("{} has unsupported tar entry type {:?}", path, x as char),
+        };
+
+        let xattrs = pax
+            .iter()
+            .filter_map(|(k, v)| {
+                k.strip_prefix("SCHILY.xattr.")
+                    .map(|name| (name.to_string(), v.clone()))
+            })
+            .collect();
+
+        let mut entry = Entry {
+            kind,
+            mode: number(&header[100..108])? as u32,
+            uid: pax_number("uid")?.map_or(number(&header[108..116]), Ok)? as u32,
+            gid: pax_number("gid")?.map_or(number(&header[116..124]), Ok)? as u32,
+            mtime: pax_number("mtime")?.map_or(number(&header[136..148]), Ok)? as i64,
+            size: pax_number("size")?.unwrap_or(size),
+            link: pax_str("linkpath")
+                .or(long_link)
+                .unwrap_or_else(|| c_str(&header[157..257])),
+            device: (
+                number(&header[329..337])? as u32,
+                number(&header[337..345])? as u32,
+            ),
+            xattrs,
+            sparse: None,
+            path,
+        };
+
+        if header[156] == b'S' {
+            entry.sparse = Some(self.old_gnu_sparse_map(header, &entry.path)?);
+        } else if pax_str("GNU.sparse.major").as_deref() == Some("1") {
+            if let Some(name) = pax_str("GNU.sparse.name") {
+                entry.path = name;
+            }
+            let real_size = pax_number("GNU.sparse.realsize")?.unwrap_or(0);
+            let (map, map_size) = self.pax_sparse_map(&entry.path)?;
+            entry.size -= map_size;
+            entry.sparse = Some((map, real_size));
+        } else if pax.keys().any(|x| x.starts_with("GNU.sparse.")) {
+            bail!("{} is in an old PAX sparse format", entry.path);
+        }
+
+        Ok(entry)
+    }
+
+    /// The map in an old GNU sparse header, and any extension blocks after
+    fn old_gnu_sparse_map(
+        &mut self,
+        header: &[u8; BLOCK],
+        path: &str,
+    ) -> Result<(Vec<(u64, u64)>, u64)> {
+        let mut map = vec![];
+
+        let mut push = |entries: &[u8]| -> Result<()> {
+            for pair in entries.chunks(24) {
+                let (offset, len) = (number(&pair[0..12])?, number(&pair[12..24])?);
+                if offset != 0 || len != 0 {
+                    map.push((offset, len));
+                }
+            }
+            Ok(())
+        };
+
+        push(&header[386..482])?;
+        let real_size = number(&header[483..495])?;
+
+        let mut extended = header[482] != 0;
+        while extended {
+            let mut block = [0; BLOCK];
+            self.read_exact(&mut block, &|| format!("the sparse map of {}", path))?;
+            push(&block[0..504])?;
+            extended = block[504] != 0;
+        }
+
+        Ok((map, real_size))
+    }
+
+    /// The map at the start of a PAX 1.0 sparse file's data, and the bytes
+    /// it took
+    fn pax_sparse_map(&mut self, path: &str) -> Result<(Vec<(u64, u64)>, u64)> {
+        let what = || format!("the sparse map of {}", path);
+        let mut text = vec![];
+        let mut numbers = vec![];
+        let mut count = None;
+
+        while count.is_none_or(|count| numbers.len() < 1 + count * 2) {
+            let mut block = [0; BLOCK];
+            self.read_exact(&mut block, &what)?;
+            text.extend_from_slice(&block);
+
+            numbers = text
+                .split(|x| *x == b'\n')
+                .collect::<Vec<_>>()
+                .split_last()
+                .map(|(_, complete)| complete.to_vec())
+                .unwrap_or_default()
+                .iter()
+                .map(|x| String::from_utf8_lossy(x).parse::<u64>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .with_context(what)?;
+
+            count = numbers.first().map(|x| *x as usize);
+        }
+
+        let map = numbers[1..]
+            .chunks(2)
+            .take(count.unwrap())
+            .map(|x| (x[0], x[1]))
+            .collect();
+
+        Ok((map, text.len() as u64))
+    }
+}
+
+/// Check a header's checksum, which is taken with its own field as spaces
+fn check_header(header: &[u8; BLOCK], offset: u64) -> Result<()> {
+    let expected = number(&header[148..156])?;
+
+    let sum = |signed: bool| -> i64 {
+        header
+            .iter()
+            .enumerate()
+            .map(|(i, x)| match (148..156).contains(&i) {
+                true => b' ' as i64,
+                false if signed => *x as i8 as i64,
+                false => *x as i64,
+            })
+            .sum()
+    };
+
+    // Some old tars summed signed bytes
+    if sum(false) != expected as i64 && sum(true) != expected as i64 {
+        bail!(
+            "bad tar header checksum before byte {}: not a tar archive, or a corrupt one",
+            offset
+        );
+    }
+
+    Ok(())
+}
+
+/// A numeric field: octal text, or big-endian binary flagged by the top bit
+fn number(field: &[u8]) -> Result<u64> {
+    if field.first().is_some_and(|x| x & 0x80 != 0) {
+        let mut value: u64 = (field[0] & 0x7F) as u64;
+        for byte in &field[1..] {
+            value = value << 8 | *byte as u64;
+        }
+        return Ok(value);
+    }
+
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+
+    u64::from_str_radix(text, 8).with_context(|| format!("bad number {:?} in tar header", text))
+}
+
+/// Text up to the first NUL
+fn c_str(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|x| *x == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+/// "<length> <key>=<value>\n" records, where the value can be binary
+fn pax_records(data: &[u8]) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut records = BTreeMap::new();
+    let mut rest = data;
+
+    while !rest.is_empty() && rest[0] != 0 {
+        let Some(space) = rest.iter().position(|x| *x == b' ') else {
+            bail!("bad PAX header record");
+        };
+        let len: usize = String::from_utf8_lossy(&rest[..space])
+            .parse()
+            .context("bad PAX header record length")?;
+        if len <= space + 1 || len > rest.len() {
+            bail!("bad PAX header record length {}", len);
+        }
+
+        let record = &rest[space + 1..len - 1];
+        let Some(equals) = record.iter().position(|x| *x == b'=') else {
+            bail!("bad PAX header record");
+        };
+
+        records.insert(
+            String::from_utf8_lossy(&record[..equals]).to_string(),
+            record[equals + 1..].to_vec(),
+        );
+        rest = &rest[len..];
+    }
+
+    Ok(records)
+}
+
+/// Where `name` goes under `dest`, refusing anything that would land
+/// outside it
+fn entry_path(dest: &Path, name: &str) -> Result<PathBuf> {
+    let mut path = dest.to_path_buf();
+
+    for component in Path::new(name.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(x) => path.push(x),
+            Component::CurDir => {}
+            _ => bail!("{:?} would be unpacked outside {:?}", name, dest),
+        }
+    }
+
+    Ok(path)
+}
+
+/// Unpack `archive` into `dest`, keeping ownership, permissions, mtimes,
+/// xattrs (file capabilities among them), hard links and holes. Nothing is
+/// written outside `dest`, even through a symlink the archive made.
+/// `progress` is told how many bytes of the archive have been read.
+pub fn unpack(archive: impl Read, dest: &Path, mut progress: impl FnMut(u64)) -> Result<()> {
+    let mut archive = Archive {
+        reader: archive,
+        offset: 0,
+    };
+
+    // Directories known not to be symlinks
+    let mut checked: HashSet<PathBuf> = HashSet::new();
+    checked.insert(dest.to_path_buf());
+
+    // Directory permissions and mtimes are set at the end, so a read-only
+    // directory can still be filled and its mtime isn't changed by filling it
+    let mut directories = vec![];
+
+    while let Some(entry) = archive.next_entry()? {
+        check_cancelled()?;
+
+        let path = entry_path(dest, &entry.path)?;
+        let result = unpack_entry(&mut archive, &entry, dest, &path, &mut checked).and_then(|_| {
+            match entry.kind {
+                Kind::Directory => {
+                    directories.push((path.clone(), entry.mode, entry.mtime));
+                    Ok(())
+                }
+
+                // Shares its target's inode, and chowning it again would
+                // clear the target's capabilities
+                Kind::HardLink => Ok(()),
+
+                // Left out without CAP_MKNOD
+                Kind::CharDevice | Kind::BlockDevice
+                    if std::fs::symlink_metadata(&path).is_err() =>
+                {
+                    Ok(())
+                }
+
+                _ => set_metadata(&path, &entry),
+            }
+        });
+        result.with_context(|| format!("could not unpack {}", entry.path))?;
+
+        progress(archive.offset);
+    }
+
+    for (path, mode, mtime) in directories.into_iter().rev() {
+        // A later entry may have replaced it, or a directory above it, with
+        // a symlink, which chmod would follow out of `dest`
+        if !is_real_dir(dest, &path)? {
+            continue;
+        }
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+        set_mtime(&path, mtime)?;
+    }
+
+    Ok(())
+}
+
+/// Whether `path`, under `dest`, is a directory with no symlink on the way
+/// from `dest` to it
+fn is_real_dir(dest: &Path, path: &Path) -> Result<bool> {
+    let mut current = dest.to_path_buf();
+
+    for component in path.strip_prefix(dest)?.components() {
+        current.push(component);
+        match std::fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.is_dir() => {}
+            _ => return Ok(false),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Create the parents of `path` that don't exist, refusing to go through a
+/// symlink
+fn make_parents(dest: &Path, path: &Path, checked: &mut HashSet<PathBuf>) -> Result<()> {
+    let Some(parent) = path.parent() else {
+        return Ok(());
+    };
+
+    if checked.contains(parent) || !parent.starts_with(dest) {
+        return Ok(());
+    }
+
+    make_parents(dest, parent, checked)?;
+
+    match std::fs::symlink_metadata(parent) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => bail!("{:?} is not a directory", parent),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir(parent)?,
+        Err(e) => return Err(e.into()),
+    }
+
+    checked.insert(parent.to_path_buf());
+
+    Ok(())
+}
+
+/// Make way for a new entry at `path`. Like tar, an existing directory is
+/// kept for a directory entry, and anything else is replaced.
+fn clear(path: &Path, kind: Kind, checked: &mut HashSet<PathBuf>) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            if kind != Kind::Directory {
+                std::fs::remove_dir(path)?;
+                checked.retain(|x| !x.starts_with(path));
+            }
+        }
+        Ok(_) => {
+            std::fs::remove_file(path)?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+fn unpack_entry<R: Read>(
+    archive: &mut Archive<R>,
+    entry: &Entry,
+    dest: &Path,
+    path: &Path,
+    checked: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    make_parents(dest, path, checked)?;
+    clear(path, entry.kind, checked)?;
+
+    match entry.kind {
+        Kind::File => write_file(archive, entry, path)?,
+
+        Kind::HardLink => {
+            let target = entry_path(dest, &entry.link)?;
+            make_parents(dest, &target, checked)?;
+            std::fs::hard_link(&target, path)?;
+        }
+
+        Kind::Symlink => std::os::unix::fs::symlink(&entry.link, path)?,
+
+        Kind::Directory => {
+            if !path.is_dir() {
+                std::fs::create_dir(path)?;
+            }
+            checked.insert(path.to_path_buf());
+        }
+
+        Kind::CharDevice | Kind::BlockDevice | Kind::Fifo => {
+            let file_type = match entry.kind {
+                Kind::CharDevice => libc::S_IFCHR,
+                Kind::BlockDevice => libc::S_IFBLK,
+                _ => libc::S_IFIFO,
+            };
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            let device = libc::makedev(entry.device.0, entry.device.1);
+
+            // SAFETY: c_path is a live CString
+            if unsafe { libc::mknod(c_path.as_ptr(), file_type | (entry.mode & 0o7777), device) }
+                != 0
+            {
+                let error = std::io::Error::last_os_error();
+
+                // Devices can't be made in a user namespace, and devtmpfs
+                // makes them on boot anyway
+                if error.kind() != std::io::ErrorKind::PermissionDenied || entry.kind == Kind::Fifo
+                {
+                    return Err(error.into());
+                }
+                warn!("left out device {}: {}", entry.path, error);
+            }
+        }
+    }
+
+    // Whatever data is left, like a hard link's in some archives
+    if entry.kind != Kind::File {
+        archive.skip(entry.size, &|| format!("the data of {}", entry.path))?;
+        archive.skip_padding(entry.size, &|| format!("the data of {}", entry.path))?;
+    }
+
+    Ok(())
+}
+
+/// Copy a file's data out of the archive, seeking over zeros so holes stay
+/// holes
+fn write_file<R: Read>(archive: &mut Archive<R>, entry: &Entry, path: &Path) -> Result<()> {
+    let what = || format!("the data of {}", entry.path);
+
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+
+    let default_map = [(0, entry.size)];
+    let (map, real_size) = match &entry.sparse {
+        Some((map, real_size)) => (&map[..], *real_size),
+        None => (&default_map[..], entry.size),
+    };
+
+    let mut buf = vec![0; 64 * 1024];
+    for (offset, len) in map {
+        file.seek(SeekFrom::Start(*offset))?;
+
+        let mut left = *len;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            archive.read_exact(&mut buf[..n], &what)?;
+
+            for chunk in buf[..n].chunks(HOLE) {
+                if chunk.len() == HOLE && chunk.iter().all(|x| *x == 0) {
+                    file.seek(SeekFrom::Current(HOLE as i64))?;
+                } else {
+                    file.write_all(chunk)?;
+                }
+            }
+
+            left -= n as u64;
+        }
+    }
+
+    archive.skip_padding(entry.size, &what)?;
+
+    // Trailing holes need the length set
+    file.set_len(real_size)?;
+
+    Ok(())
+}
+
+/// Ownership, then permissions and xattrs, as changing the owner clears
+/// setuid bits and file capabilities
+fn set_metadata(path: &Path, entry: &Entry) -> Result<()> {
+    std::os::unix::fs::lchown(path, Some(entry.uid), Some(entry.gid))?;
+
+    if entry.kind != Kind::Symlink {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode & 0o7777))?;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    for (name, value) in &entry.xattrs {
+        let c_name = CString::new(name.as_str())?;
+
+        // SAFETY: c_path and c_name are live CStrings, value is value.len()
+        // bytes
+        let result = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if result != 0 {
+            bail!(
+                "could not set xattr {}: {}",
+                name,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    set_mtime(path, entry.mtime)
+}
+
+fn set_mtime(path: &Path, mtime: i64) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let time = libc::timespec {
+        tv_sec: mtime as libc::time_t,
+        tv_nsec: 0,
+    };
+
+    // SAFETY: c_path is a live CString, and the times are two timespecs
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            [time, time].as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        bail!(
+            "could not set the mtime of {:?}: {}",
+            path,
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok(())
+}
+
+/// A ustar header for the tests, with the checksum filled in
+#[cfg(test)]
+fn test_header(name: &str, kind: u8, size: usize, link: &str) -> Vec<u8> {
+    let mut header = vec![0; BLOCK];
+    let uid = format!("{:07o}", unsafe { libc::getuid() });
+    let gid = format!("{:07o}", unsafe { libc::getgid() });
+
+    for (offset, value) in [
+        (0, name),
+        (100, "0000644"),
+        (108, uid.as_str()),
+        (116, gid.as_str()),
+        (124, &format!("{:011o}", size)),
+        (136, "00000001750"),
+        (157, link),
+        (257, "ustar\x0000"),
+    ] {
+        header[offset..offset + value.len()].copy_from_slice(value.as_bytes());
+    }
+    header[156] = kind;
+
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|x| *x as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+
+    header
+}
+
+#[cfg(test)]
+fn test_member(archive: &mut Vec<u8>, name: &str, kind: u8, data: &[u8], link: &str) {
+    archive.extend(test_header(name, kind, data.len(), link));
+    archive.extend(data);
+    archive.resize(archive.len().div_ceil(BLOCK) * BLOCK, 0);
+}
+
+#[cfg(test)]
+fn test_pax(archive: &mut Vec<u8>, records: &[(&str, &[u8])]) {
+    let mut data = vec![];
+    for (key, value) in records {
+        // The length counts itself
+        let base = key.len() + value.len() + 3;
+        let mut len = base + 1;
+        while len != base + len.to_string().len() {
+            len = base + len.to_string().len();
+        }
+        data.extend(format!("{} {}=", len, key).as_bytes());
+        data.extend(*value);
+        data.push(b'\n');
+    }
+    test_member(archive, "PaxHeader", b'x', &data, "");
+}
+
+#[test]
+fn test_unpack() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::tempdir()?;
+    let long_name = format!("etc/{}", "x".repeat(150));
+
+    let mut archive = vec![];
+    test_member(&mut archive, "etc/", b'5', b"", "");
+    test_member(&mut archive, "etc/hostname", b'0', b"box\n", "");
+    test_member(&mut archive, "etc/hostname.bak", b'1', b"", "etc/hostname");
+    test_member(
+        &mut archive,
+        "etc/localtime",
+        b'2',
+        b"",
+        "/usr/share/zoneinfo/UTC",
+    );
+    test_pax(&mut archive, &[("path", long_name.as_bytes())]);
+    test_member(&mut archive, "short", b'0', b"long", "");
+    // Parents that aren't in the archive are made
+    test_member(&mut archive, "usr/bin/ping", b'0', b"ping", "");
+    archive.extend([0; BLOCK * 2]);
+
+    let dest = dir.path().join("root");
+    std::fs::create_dir(&dest)?;
+    let read = std::cell::Cell::new(0);
+    unpack(&archive[..], &dest, |x| read.set(x))?;
+    assert_eq!(read.get(), archive.len() as u64 - BLOCK as u64 * 2);
+
+    assert_eq!(std::fs::read_to_string(dest.join("etc/hostname"))?, "box\n");
+    assert_eq!(
+        std::fs::metadata(dest.join("etc/hostname"))?.ino(),
+        std::fs::metadata(dest.join("etc/hostname.bak"))?.ino()
+    );
+    assert_eq!(
+        std::fs::read_link(dest.join("etc/localtime"))?,
+        Path::new("/usr/share/zoneinfo/UTC")
+    );
+    assert_eq!(std::fs::read_to_string(dest.join(&long_name))?, "long");
+    assert_eq!(std::fs::read_to_string(dest.join("usr/bin/ping"))?, "ping");
+
+    let metadata = std::fs::metadata(dest.join("etc/hostname"))?;
+    assert_eq!(metadata.mode() & 0o7777, 0o644);
+    assert_eq!(metadata.mtime(), 1000);
+
+    Ok(())
+}
+
+#[test]
+fn test_sparse() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // Two runs of data in a 1 MiB file, in the PAX 1.0 format
+    let map = b"2\n0\n3\n524288\n4\n";
+    let mut data = map.to_vec();
+    data.resize(BLOCK, 0);
+    data.extend(b"abcdefg");
+
+    let mut archive = vec![];
+    test_pax(
+        &mut archive,
+        &[
+            ("GNU.sparse.major", b"1"),
+            ("GNU.sparse.minor", b"0"),
+            ("GNU.sparse.name", b"var/lib/sparse"),
+            ("GNU.sparse.realsize", b"1048576"),
+        ],
+    );
+    test_member(&mut archive, "GNUSparseFile.0/sparse", b'0', &data, "");
+
+    let dest = dir.path();
+    unpack(&archive[..], dest, |_| {})?;
+
+    let unpacked = std::fs::read(dest.join("var/lib/sparse"))?;
+    assert_eq!(unpacked.len(), 1024 * 1024);
+    assert_eq!(&unpacked[0..3], b"abc");
+    assert_eq!(&unpacked[524288..524292], b"defg");
+    assert_eq!(unpacked.iter().filter(|x| **x != 0).count(), 7);
+
+    Ok(())
+}
+
+#[test]
+fn test_pax_xattrs() -> Result<()> {
+    let mut archive = vec![];
+    test_pax(
+        &mut archive,
+        &[("SCHILY.xattr.security.capability", b"\x01\x00\x00\x02")],
+    );
+    test_member(&mut archive, "usr/bin/ping", b'0', b"ping", "");
+
+    let entry = Archive {
+        reader: &archive[..],
+        offset: 0,
+    }
+    .next_entry()?
+    .unwrap();
+
+    assert_eq!(entry.path, "usr/bin/ping");
+    assert_eq!(
+        entry.xattrs,
+        [(
+            "security.capability".to_string(),
+            b"\x01\x00\x00\x02".to_vec()
+        )]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_unpack_refuses_to_escape() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dest = dir.path().join("root");
+    std::fs::create_dir(&dest)?;
+
+    let mut archive = vec![];
+    test_member(&mut archive, "../escaped", b'0', b"", "");
+    assert!(unpack(&archive[..], &dest, |_| {}).is_err());
+
+    // A symlink out of the root, then a file through it
+    let mut archive = vec![];
+    test_member(&mut archive, "etc", b'2', b"", dir.path().to_str().unwrap());
+    test_member(&mut archive, "etc/escaped", b'0', b"", "");
+    assert!(unpack(&archive[..], &dest, |_| {}).is_err());
+
+    assert!(!dir.path().join("escaped").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_unpack_directory_replaced_by_symlink() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dest = dir.path().join("root");
+    let outside = dir.path().join("outside");
+    std::fs::create_dir(&dest)?;
+    std::fs::create_dir(&outside)?;
+    std::fs::set_permissions(&outside, std::fs::Permissions::from_mode(0o700))?;
+
+    // The directory's mode is set last, after it became a symlink out
+    let mut archive = vec![];
+    test_member(&mut archive, "x/", b'5', b"", "");
+    test_member(&mut archive, "x", b'2', b"", outside.to_str().unwrap());
+    unpack(&archive[..], &dest, |_| {})?;
+
+    assert!(std::fs::symlink_metadata(dest.join("x"))?.is_symlink());
+    assert_eq!(
+        std::fs::metadata(&outside)?.permissions().mode() & 0o7777,
+        0o700
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_short_read() {
+    let mut archive = vec![];
+    test_member(&mut archive, "etc/hostname", b'0', b"box\n", "");
+
+    let dir = tempfile::tempdir().unwrap();
+    let error = unpack(&archive[..BLOCK + 2], dir.path(), |_| {}).unwrap_err();
+
+    assert_eq!(
+        format!("{:#}", error),
+        "could not unpack etc/hostname: archive ends at byte 514 in the middle of the \
+         data of etc/hostname"
+    );
+}
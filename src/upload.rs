@@ -0,0 +1,986 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Uploading finished images to S3 and S3-compatible object storage such as
+//! MinIO, with the aws CLI. Images go up as a multipart upload, one part at
+//! a time, so progress can be shown and a failed part is retried on its own
+//! rather than starting over.
+//!
+//! Also registering them as Google Compute Engine images, with gcloud, as
+//! images in an Oxide silo, with the oxide CLI, and as Proxmox VE VM
+//! templates, with pvesh on the node.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context as _, Result};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::image::with_suffix;
+use crate::*;
+
+const MIB: u64 = 1024 * 1024;
+
+/// S3 refuses smaller parts, except for the last one
+pub const MIN_PART_SIZE: u64 = 5 * MIB;
+
+/// Parts in one upload, at most
+const MAX_PARTS: u64 = 10_000;
+
+/// Where an object goes: s3://bucket/key
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3Url {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Url {
+    /// The object next to this one with `suffix` added to its key, e.g.
+    /// debian.img.sha256
+    pub fn with_suffix(&self, suffix: &str) -> S3Url {
+        S3Url {
+            bucket: self.bucket.clone(),
+            key: format!("{}{}", self.key, suffix),
+        }
+    }
+}
+
+impl FromStr for S3Url {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some(path) = s.strip_prefix("s3://") else {
+            bail!("{:?} is not an s3://bucket/key URL", s);
+        };
+
+        match path.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() && !key.ends_with('/') => {
+                Ok(S3Url {
+                    bucket: bucket.into(),
+                    key: key.into(),
+                })
+            }
+            _ => bail!("{:?} needs both a bucket and an object key", s),
+        }
+    }
+}
+
+impl std::fmt::Display for S3Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Which S3 to talk to and as whom. Credentials otherwise come from
+/// wherever the aws CLI looks: AWS_ACCESS_KEY_ID and friends, the default
+/// profile, or an instance role.
+#[derive(Debug, Clone)]
+pub struct S3Options {
+    /// For S3-compatibles, e.g. http://minio.internal:9000
+    pub endpoint_url: Option<String>,
+    pub region: Option<String>,
+    /// A profile in the aws CLI's config and credentials files
+    pub profile: Option<String>,
+    /// A credentials file to use instead of ~/.aws/credentials
+    pub credentials_file: Option<PathBuf>,
+    /// Bytes per part, raised if needed to stay within S3's 10,000 parts
+    pub part_size: u64,
+}
+
+impl S3Options {
+    /// AWS itself, with 64M parts
+    pub fn new() -> Self {
+        Self {
+            endpoint_url: None,
+            region: None,
+            profile: None,
+            credentials_file: None,
+            part_size: 64 * MIB,
+        }
+    }
+
+    /// The part size for uploading `len` bytes
+    fn part_size_for(&self, len: u64) -> u64 {
+        self.part_size
+            .max(len.div_ceil(MAX_PARTS).div_ceil(MIB) * MIB)
+    }
+
+    /// Run `aws s3api <command> <args>` with these options, returning its
+    /// JSON output
+    fn s3api(&self, command: &str, args: &[&[String]]) -> Result<Value> {
+        let mut aws_args: Vec<String> = vec![];
+
+        if let Some(endpoint_url) = &self.endpoint_url {
+            aws_args.extend(["--endpoint-url".into(), endpoint_url.clone()]);
+        }
+        if let Some(region) = &self.region {
+            aws_args.extend(["--region".into(), region.clone()]);
+        }
+        if let Some(profile) = &self.profile {
+            aws_args.extend(["--profile".into(), profile.clone()]);
+        }
+        aws_args.extend(["--output".into(), "json".into()]);
+        aws_args.extend(["s3api".into(), command.into()]);
+        aws_args.extend(args.concat());
+
+        let env_vars: Vec<(String, String)> = self
+            .credentials_file
+            .iter()
+            .map(|x| {
+                (
+                    "AWS_SHARED_CREDENTIALS_FILE".into(),
+                    x.to_string_lossy().to_string(),
+                )
+            })
+            .collect();
+
+        let output = output_stdout_string(&run_with_env("aws".into(), &aws_args, &env_vars)?);
+
+        if output.trim().is_empty() {
+            return Ok(Value::Null);
+        }
+
+        serde_json::from_str(&output).with_context(|| format!("aws printed {:?}", output))
+    }
+}
+
+impl Default for S3Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Upload `file` to `url`. If it fails part way, the upload is aborted so
+/// the parts already sent aren't left behind, taking up space.
+pub fn upload_s3(file: &Path, url: &S3Url, options: &S3Options) -> Result<()> {
+    if options.part_size < MIN_PART_SIZE {
+        bail!(
+            "S3 parts must be at least 5M, not {} bytes",
+            options.part_size
+        );
+    }
+
+    info!("upload {:?} to {}", file, url);
+
+    let object = [
+        "--bucket".to_string(),
+        url.bucket.clone(),
+        "--key".to_string(),
+        url.key.clone(),
+    ];
+
+    let created = options.s3api("create-multipart-upload", &[&object])?;
+    let Some(upload_id) = created["UploadId"].as_str() else {
+        bail!("creating the upload to {} returned no UploadId", url);
+    };
+    let upload_id = ["--upload-id".to_string(), upload_id.to_string()];
+
+    let result = upload_parts(file, url, options, &object, &upload_id).and_then(|parts| {
+        let parts = json!({ "Parts": parts }).to_string();
+        options.s3api(
+            "complete-multipart-upload",
+            &[&object, &upload_id, &["--multipart-upload".into(), parts]],
+        )
+    });
+
+    if let Err(e) = result {
+        let aborted = cleanup(|| options.s3api("abort-multipart-upload", &[&object, &upload_id]));
+        if let Err(abort_error) = aborted {
+            warn!("could not abort the upload to {}: {:#}", url, abort_error);
+        }
+
+        return Err(e.context(format!("uploading {:?} to {} failed", file, url)));
+    }
+
+    Ok(())
+}
+
+/// Send each part of `file`, returning their ETags and numbers for
+/// completing the upload
+fn upload_parts(
+    file: &Path,
+    url: &S3Url,
+    options: &S3Options,
+    object: &[String],
+    upload_id: &[String],
+) -> Result<Vec<Value>> {
+    let mut input = File::open(file)?;
+    let len = input.metadata()?.len();
+    let part_size = options.part_size_for(len);
+
+    // The aws CLI only reads a part's body from a file, so each part is
+    // written out next to the image while it is sent
+    let part_path = with_suffix(file, ".part");
+
+    let mut progress = ByteProgress::new(&format!("upload {}", url), len);
+    let mut parts = vec![];
+    let mut offset = 0;
+
+    // An empty file is still one (empty) part
+    while offset < len || parts.is_empty() {
+        check_cancelled()?;
+
+        let size = part_size.min(len - offset);
+        let number = parts.len() + 1;
+
+        let mut part = vec![0u8; size as usize];
+        input.seek(SeekFrom::Start(offset))?;
+        input.read_exact(&mut part)?;
+        std::fs::write(&part_path, &part)?;
+        drop(part);
+
+        let uploaded = retry(&format!("upload part {}", number), is_transient, || {
+            options.s3api(
+                "upload-part",
+                &[
+                    object,
+                    upload_id,
+                    &[
+                        "--part-number".into(),
+                        number.to_string(),
+                        "--body".into(),
+                        part_path.to_string_lossy().to_string(),
+                    ],
+                ],
+            )
+        });
+        let _ = std::fs::remove_file(&part_path);
+
+        let Some(etag) = uploaded?["ETag"].as_str().map(String::from) else {
+            bail!("uploading part {} returned no ETag", number);
+        };
+        parts.push(json!({ "ETag": etag, "PartNumber": number }));
+
+        offset += size;
+        progress.set_position(offset);
+    }
+
+    progress.finish(len);
+
+    Ok(parts)
+}
+
+/// Check a name the way Compute Engine and Oxide both do
+fn check_name(what: &str, name: &str) -> Result<()> {
+    let valid = name.len() <= 63
+        && name.starts_with(|x: char| x.is_ascii_lowercase())
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-');
+
+    if !valid {
+        bail!(
+            "{} name {:?} must be up to 63 lowercase letters, digits and \
+             hyphens, starting with a letter and not ending with a hyphen",
+            what,
+            name
+        );
+    }
+
+    Ok(())
+}
+
+/// A Compute Engine image to create from a built image
+#[derive(Debug, Clone)]
+pub struct GceImage {
+    /// The image's name, like debian-12-20240229
+    pub name: String,
+    /// Where the tarball is staged: gs://bucket or gs://bucket/prefix
+    pub bucket: String,
+    /// gcloud's configured project if None
+    pub project: Option<String>,
+    pub family: Option<String>,
+    pub description: Option<String>,
+    /// KEY=VALUE
+    pub labels: Vec<String>,
+    /// Besides UEFI_COMPATIBLE, which every image built here is
+    pub guest_os_features: Vec<String>,
+    /// A region or multi-region like "us", instead of the nearest one
+    pub storage_location: Option<String>,
+    /// Leave the tarball in the bucket once the image exists
+    pub keep_tarball: bool,
+}
+
+impl GceImage {
+    pub fn new(name: impl Into<String>, bucket: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            bucket: bucket.into(),
+            project: None,
+            family: None,
+            description: None,
+            labels: vec![],
+            guest_os_features: vec![],
+            storage_location: None,
+            keep_tarball: false,
+        }
+    }
+
+    /// Check what Compute Engine would refuse, before packing and
+    /// uploading gigabytes
+    pub fn validate(&self) -> Result<()> {
+        check_name("image", &self.name)?;
+        if let Some(family) = &self.family {
+            check_name("family", family)?;
+        }
+
+        if !self.bucket.starts_with("gs://") || self.bucket.len() == "gs://".len() {
+            bail!("{:?} is not a gs://bucket URL", self.bucket);
+        }
+
+        for label in &self.labels {
+            if !matches!(label.split_once('='), Some((key, _)) if !key.is_empty()) {
+                bail!("label {:?} is not KEY=VALUE", label);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Where the tarball goes in the bucket
+    fn tarball_url(&self) -> String {
+        format!("{}/{}.tar.gz", self.bucket.trim_end_matches('/'), self.name)
+    }
+
+    fn project_args(&self) -> Vec<String> {
+        match &self.project {
+            Some(project) => vec!["--project".into(), project.clone()],
+            None => vec![],
+        }
+    }
+}
+
+/// Create `gce` from the raw disk `image`: pack it as the disk.raw in a
+/// gzipped tarball as Compute Engine wants, stage that in Cloud Storage and
+/// create the image from it. The staged tarball is removed afterwards, even
+/// if creating the image fails, unless it is to be kept.
+pub fn upload_gce(image: &Path, gce: &GceImage) -> Result<()> {
+    gce.validate()?;
+
+    // disk.raw is hard linked next to the image rather than copied, so the
+    // directory for it has to be on the same filesystem
+    let Some(parent) = image.parent() else {
+        bail!("{:?} has no parent directory", image);
+    };
+    let staging = tempfile::tempdir_in(if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    })?;
+    let disk_raw = staging.path().join("disk.raw");
+    std::fs::hard_link(image, &disk_raw)?;
+
+    let tarball = staging.path().join(format!("{}.tar.gz", gce.name));
+    info!("pack {:?} into {:?}", image, tarball);
+
+    // Sparse, and in the old GNU format, as the import documentation says
+    with_file_progress("pack disk.raw", &tarball, None, || {
+        run(
+            "tar".into(),
+            &[
+                "--format=oldgnu".into(),
+                "-Sczf".into(),
+                tarball.to_string_lossy().to_string(),
+                "-C".into(),
+                staging.path().to_string_lossy().to_string(),
+                "disk.raw".into(),
+            ],
+        )
+    })?;
+
+    let tarball_url = gce.tarball_url();
+    info!("upload {:?} to {}", tarball, tarball_url);
+    with_spinner(&format!("upload {}", tarball_url), || {
+        retry(&format!("upload {}", tarball_url), is_transient, || {
+            run(
+                "gcloud".into(),
+                &[
+                    "storage".into(),
+                    "cp".into(),
+                    tarball.to_string_lossy().to_string(),
+                    tarball_url.clone(),
+                ],
+            )
+        })
+    })?;
+
+    let mut create_args: Vec<String> = vec![
+        "compute".into(),
+        "images".into(),
+        "create".into(),
+        gce.name.clone(),
+    ];
+    create_args.extend(gce.project_args());
+    create_args.extend([
+        "--source-uri".into(),
+        tarball_url.clone(),
+        "--guest-os-features".into(),
+        std::iter::once("UEFI_COMPATIBLE")
+            .chain(gce.guest_os_features.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(","),
+    ]);
+    if let Some(family) = &gce.family {
+        create_args.extend(["--family".into(), family.clone()]);
+    }
+    if let Some(description) = &gce.description {
+        create_args.extend(["--description".into(), description.clone()]);
+    }
+    if !gce.labels.is_empty() {
+        create_args.extend(["--labels".into(), gce.labels.join(",")]);
+    }
+    if let Some(storage_location) = &gce.storage_location {
+        create_args.extend(["--storage-location".into(), storage_location.clone()]);
+    }
+
+    info!("create image {}", gce.name);
+    let created = with_spinner(&format!("create image {}", gce.name), || {
+        run("gcloud".into(), &create_args)
+    });
+
+    if !gce.keep_tarball {
+        let removed = cleanup(|| {
+            run(
+                "gcloud".into(),
+                &["storage".into(), "rm".into(), tarball_url.clone()],
+            )
+        });
+        if let Err(e) = removed {
+            warn!("could not remove {}: {:#}", tarball_url, e);
+        }
+    }
+
+    created?;
+
+    Ok(())
+}
+
+/// An Oxide image to create from a built image
+#[derive(Debug, Clone)]
+pub struct OxideImage {
+    pub project: String,
+    pub name: String,
+    pub description: String,
+    /// The operating system and its version, which the console shows
+    pub os: String,
+    pub version: String,
+    /// Make the image available to every project in the silo
+    pub promote: bool,
+    /// A profile from the oxide CLI's config, instead of its default or
+    /// OXIDE_HOST and OXIDE_TOKEN
+    pub profile: Option<String>,
+}
+
+impl OxideImage {
+    fn oxide_args(&self, args: &[&str]) -> Vec<String> {
+        let mut oxide_args: Vec<String> = vec![];
+        if let Some(profile) = &self.profile {
+            oxide_args.extend(["--profile".into(), profile.clone()]);
+        }
+        oxide_args.extend(args.iter().map(|x| x.to_string()));
+        oxide_args
+    }
+}
+
+/// Create `oxide` from the raw disk `image`. `oxide disk import` does the
+/// work: it uploads the image into a new disk in chunks, several at once,
+/// then snapshots the disk and creates the image from the snapshot. The
+/// disk isn't needed after that, and is deleted.
+pub fn upload_oxide(image: &Path, oxide: &OxideImage) -> Result<()> {
+    check_name("project", &oxide.project)?;
+    check_name("image", &oxide.name)?;
+
+    let disk = format!("{}-import", oxide.name);
+    let image_path = image.to_string_lossy();
+
+    info!(
+        "import {:?} into {} as {}",
+        image, oxide.project, oxide.name
+    );
+    with_spinner(&format!("import {}", oxide.name), || {
+        run(
+            "oxide".into(),
+            &oxide.oxide_args(&[
+                "disk",
+                "import",
+                "--project",
+                &oxide.project,
+                "--path",
+                &image_path,
+                "--disk",
+                &disk,
+                "--description",
+                &format!("import of {}", oxide.name),
+                "--snapshot",
+                &oxide.name,
+                "--image",
+                &oxide.name,
+                "--image-description",
+                &oxide.description,
+                "--image-os",
+                &oxide.os,
+                "--image-version",
+                &oxide.version,
+            ]),
+        )
+    })?;
+
+    let deleted = cleanup(|| {
+        run(
+            "oxide".into(),
+            &oxide.oxide_args(&[
+                "disk",
+                "delete",
+                "--project",
+                &oxide.project,
+                "--disk",
+                &disk,
+            ]),
+        )
+    });
+    if let Err(e) = deleted {
+        warn!("could not delete the import disk {}: {:#}", disk, e);
+    }
+
+    if oxide.promote {
+        info!("promote {} to a silo image", oxide.name);
+        run(
+            "oxide".into(),
+            &oxide.oxide_args(&[
+                "image",
+                "promote",
+                "--project",
+                &oxide.project,
+                "--image",
+                &oxide.name,
+            ]),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// A Proxmox VE VM template to create from a built image
+#[derive(Debug, Clone)]
+pub struct ProxmoxTemplate {
+    pub vmid: u32,
+    pub name: String,
+    /// Where the VM's disks go
+    pub storage: String,
+    /// The node's name in the cluster, if it isn't the node's hostname
+    pub node: Option<String>,
+    /// ssh destination of the node, like root@pve1. Without it, this is the
+    /// node.
+    pub host: Option<String>,
+    /// In MiB
+    pub memory: u32,
+    pub cores: u32,
+    pub bridge: String,
+}
+
+impl ProxmoxTemplate {
+    /// 2G of memory and 2 cores on local-lvm, bridged to vmbr0
+    pub fn new(vmid: u32, name: impl Into<String>) -> Self {
+        Self {
+            vmid,
+            name: name.into(),
+            storage: "local-lvm".into(),
+            node: None,
+            host: None,
+            memory: 2048,
+            cores: 2,
+            bridge: "vmbr0".into(),
+        }
+    }
+
+    /// Run a command on the node, over ssh if it's another machine
+    fn on_node(&self, exe: &str, args: &[String]) -> Result<CommandOutput> {
+        match &self.host {
+            Some(host) => run(
+                "ssh".into(),
+                &[host.clone(), "--".into(), command_line(exe, args)],
+            ),
+            None => run(exe.into(), args),
+        }
+    }
+
+    /// Arguments for creating the VM through the API, with the disk
+    /// imported from `disk`. q35 with OVMF, since the images only boot with
+    /// UEFI, and no Secure Boot keys enrolled, since their GRUB isn't
+    /// signed. Their console is the serial port.
+    fn create_args(&self, node: &str, disk: &str) -> Vec<String> {
+        vec![
+            "create".into(),
+            format!("/nodes/{}/qemu", node),
+            "--vmid".into(),
+            self.vmid.to_string(),
+            "--name".into(),
+            self.name.clone(),
+            "--machine".into(),
+            "q35".into(),
+            "--bios".into(),
+            "ovmf".into(),
+            "--ostype".into(),
+            "l26".into(),
+            "--memory".into(),
+            self.memory.to_string(),
+            "--cores".into(),
+            self.cores.to_string(),
+            "--net0".into(),
+            format!("virtio,bridge={}", self.bridge),
+            "--scsihw".into(),
+            "virtio-scsi-single".into(),
+            "--efidisk0".into(),
+            format!("{}:1,efitype=4m,pre-enrolled-keys=0", self.storage),
+            "--scsi0".into(),
+            format!("{}:0,import-from={}", self.storage, disk),
+            "--boot".into(),
+            "order=scsi0".into(),
+            "--serial0".into(),
+            "socket".into(),
+            "--vga".into(),
+            "serial0".into(),
+        ]
+    }
+}
+
+/// Create `template` from `image`: copy the image to the node if it's
+/// another machine, create a VM importing it as its disk with pvesh, which
+/// goes through the node's API, and turn the VM into a template. A copy on
+/// the node is removed afterwards.
+pub fn upload_proxmox(image: &Path, template: &ProxmoxTemplate) -> Result<()> {
+    let node = match &template.node {
+        Some(node) => node.clone(),
+        None => output_stdout_string(&template.on_node("hostname", &[])?)
+            .trim()
+            .to_string(),
+    };
+
+    let disk = match &template.host {
+        Some(host) => {
+            let disk = format!("/var/tmp/{}-{}.raw", template.name, template.vmid);
+            info!("copy {:?} to {}:{}", image, host, disk);
+            with_spinner(&format!("copy to {}", host), || {
+                run(
+                    "scp".into(),
+                    &[
+                        "-q".into(),
+                        image.to_string_lossy().to_string(),
+                        format!("{}:{}", host, disk),
+                    ],
+                )
+            })?;
+            disk
+        }
+
+        None => image.canonicalize()?.to_string_lossy().to_string(),
+    };
+
+    info!("create VM {} on {}", template.vmid, node);
+    let result = with_spinner(&format!("import into VM {}", template.vmid), || {
+        template.on_node("pvesh", &template.create_args(&node, &disk))
+    })
+    .and_then(|_| {
+        info!("convert VM {} to a template", template.vmid);
+        template.on_node(
+            "pvesh",
+            &[
+                "create".into(),
+                format!("/nodes/{}/qemu/{}/template", node, template.vmid),
+            ],
+        )
+    });
+
+    if template.host.is_some() {
+        let removed = cleanup(|| template.on_node("rm", &["-f".into(), disk.clone()]));
+        if let Err(e) = removed {
+            warn!("could not remove {} from the node: {:#}", disk, e);
+        }
+    }
+
+    result?;
+
+    Ok(())
+}
+
+#[test]
+fn test_s3_url() -> Result<()> {
+    let url: S3Url = "s3://images/ci/debian.img".parse()?;
+    assert_eq!(url.bucket, "images");
+    assert_eq!(url.key, "ci/debian.img");
+    assert_eq!(
+        url.with_suffix(".sha256").to_string(),
+        "s3://images/ci/debian.img.sha256"
+    );
+
+    assert!("images/debian.img".parse::<S3Url>().is_err());
+    assert!("s3://images".parse::<S3Url>().is_err());
+    assert!("s3://images/".parse::<S3Url>().is_err());
+    assert!("s3:///debian.img".parse::<S3Url>().is_err());
+    assert!("s3://images/ci/".parse::<S3Url>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_part_size_for() {
+    let options = S3Options::new();
+    assert_eq!(options.part_size_for(10 * MIB), 64 * MIB);
+    assert_eq!(options.part_size_for(64 * 10_000 * MIB), 64 * MIB);
+
+    // A terabyte doesn't fit in 10,000 parts of 64M
+    assert_eq!(options.part_size_for(1024 * 1024 * MIB), 105 * MIB);
+}
+
+#[test]
+fn test_upload_s3() -> Result<()> {
+    use std::rc::Rc;
+
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("debian.img");
+    std::fs::write(&image, vec![1u8; (12 * MIB) as usize])?;
+
+    let upload = |fail_part: Option<&'static str>| -> (Result<()>, Vec<String>) {
+        let executor = Rc::new(RecordingExecutor::new(move |_, args| {
+            let part = args.iter().position(|x| x == "--part-number");
+
+            Ok(match args[5].as_str() {
+                "create-multipart-upload" => r#"{"UploadId": "u1"}"#.into(),
+                "upload-part" => {
+                    let number = &args[part.unwrap() + 1];
+                    if Some(number.as_str()) == fail_part {
+                        bail!("connection reset");
+                    }
+                    format!(r#"{{"ETag": "\"e{}\""}}"#, number)
+                }
+                _ => String::new(),
+            })
+        }));
+        let previous = set_executor(executor.clone());
+        let previous_retry = set_retry_policy(RetryPolicy {
+            retries: 0,
+            ..RetryPolicy::default()
+        });
+
+        let mut options = S3Options::new();
+        options.endpoint_url = Some("http://minio:9000".into());
+        options.part_size = MIN_PART_SIZE;
+        let result = upload_s3(&image, &"s3://images/debian.img".parse().unwrap(), &options);
+
+        set_retry_policy(previous_retry);
+        set_executor(previous);
+
+        let commands = executor
+            .commands()
+            .iter()
+            .map(|x| {
+                x.args[5..]
+                    .join(" ")
+                    .replace(dir.path().to_str().unwrap(), "")
+            })
+            .collect();
+        (result, commands)
+    };
+
+    let (result, commands) = upload(None);
+    result?;
+    assert_eq!(
+        commands,
+        [
+            "create-multipart-upload --bucket images --key debian.img",
+            "upload-part --bucket images --key debian.img --upload-id u1 --part-number 1 --body /debian.img.part",
+            "upload-part --bucket images --key debian.img --upload-id u1 --part-number 2 --body /debian.img.part",
+            "upload-part --bucket images --key debian.img --upload-id u1 --part-number 3 --body /debian.img.part",
+            r#"complete-multipart-upload --bucket images --key debian.img --upload-id u1 --multipart-upload {"Parts":[{"ETag":"\"e1\"","PartNumber":1},{"ETag":"\"e2\"","PartNumber":2},{"ETag":"\"e3\"","PartNumber":3}]}"#,
+        ]
+    );
+    assert!(!dir.path().join("debian.img.part").exists());
+
+    // A failed part aborts the upload
+    let (result, commands) = upload(Some("2"));
+    assert!(result.is_err());
+    assert_eq!(
+        commands.last().unwrap(),
+        "abort-multipart-upload --bucket images --key debian.img --upload-id u1"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_gce_image_validate() {
+    let gce = |name: &str, bucket: &str| GceImage::new(name, bucket);
+
+    assert!(gce("debian-12-20240229", "gs://images").validate().is_ok());
+    assert!(gce("debian-12", "gs://images/ci/").validate().is_ok());
+
+    assert!(gce("Debian-12", "gs://images").validate().is_err());
+    assert!(gce("12-debian", "gs://images").validate().is_err());
+    assert!(gce("debian-", "gs://images").validate().is_err());
+    assert!(gce(&"d".repeat(64), "gs://images").validate().is_err());
+    assert!(gce("debian", "images").validate().is_err());
+    assert!(gce("debian", "gs://").validate().is_err());
+
+    let mut labelled = gce("debian", "gs://images");
+    labelled.labels = vec!["built-by=ci".into(), "flavor".into()];
+    assert!(labelled.validate().is_err());
+
+    let mut family = gce("debian", "gs://images");
+    family.family = Some("Debian".into());
+    assert!(family.validate().is_err());
+}
+
+#[test]
+fn test_upload_gce() -> Result<()> {
+    use std::rc::Rc;
+
+    let dir = tempfile::tempdir()?;
+    let image = dir.path().join("debian.img");
+    std::fs::write(&image, vec![1u8; 4096])?;
+
+    let upload = |gce: GceImage, fail_create: bool| -> (Result<()>, Vec<String>) {
+        let executor = Rc::new(RecordingExecutor::new(move |_, args| {
+            if fail_create && args[..2] == ["compute", "images"] {
+                bail!("quota exceeded");
+            }
+            Ok(String::new())
+        }));
+        let previous = set_executor(executor.clone());
+        let result = upload_gce(&image, &gce);
+        set_executor(previous);
+
+        let commands = executor
+            .commands()
+            .iter()
+            .map(|x| {
+                // The staging directory is random
+                let staging = x.args.iter().find_map(|x| {
+                    Path::new(x)
+                        .parent()
+                        .filter(|x| x.parent() == Some(dir.path()))
+                });
+                let command = x.to_string();
+                match staging {
+                    Some(staging) => command.replace(staging.to_str().unwrap(), "{staging}"),
+                    None => command,
+                }
+            })
+            .collect();
+        (result, commands)
+    };
+
+    let mut gce = GceImage::new("debian-12-20240229", "gs://images/ci/");
+    gce.project = Some("builds".into());
+    gce.family = Some("debian-12".into());
+    gce.labels = vec!["built-by=ci".into(), "flavor=debian".into()];
+    gce.guest_os_features = vec!["GVNIC".into()];
+
+    let (result, commands) = upload(gce.clone(), false);
+    result?;
+    assert_eq!(
+        commands,
+        [
+            "tar --format=oldgnu -Sczf {staging}/debian-12-20240229.tar.gz -C {staging} disk.raw",
+            "gcloud storage cp {staging}/debian-12-20240229.tar.gz gs://images/ci/debian-12-20240229.tar.gz",
+            "gcloud compute images create debian-12-20240229 --project builds --source-uri gs://images/ci/debian-12-20240229.tar.gz --guest-os-features UEFI_COMPATIBLE,GVNIC --family debian-12 --labels built-by=ci,flavor=debian",
+            "gcloud storage rm gs://images/ci/debian-12-20240229.tar.gz",
+        ]
+    );
+
+    // The staging directory and its hard link are gone
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+    // The tarball is removed even when creating the image fails
+    let (result, commands) = upload(gce.clone(), true);
+    assert!(result.is_err());
+    assert_eq!(
+        commands.last().unwrap(),
+        "gcloud storage rm gs://images/ci/debian-12-20240229.tar.gz"
+    );
+
+    gce.keep_tarball = true;
+    let (result, commands) = upload(gce, false);
+    result?;
+    assert!(commands
+        .last()
+        .unwrap()
+        .starts_with("gcloud compute images create"));
+
+    Ok(())
+}
+
+#[test]
+fn test_upload_oxide() -> Result<()> {
+    use std::rc::Rc;
+
+    let executor = Rc::new(RecordingExecutor::new(|_, _| Ok(String::new())));
+    let previous = set_executor(executor.clone());
+
+    let oxide = OxideImage {
+        project: "builds".into(),
+        name: "debian-12".into(),
+        description: "Debian 12 from debian:12".into(),
+        os: "debian".into(),
+        version: "12".into(),
+        promote: true,
+        profile: Some("rack2".into()),
+    };
+    let result = upload_oxide(Path::new("/images/debian.img"), &oxide);
+
+    set_executor(previous);
+    result?;
+
+    let commands: Vec<String> = executor.commands().iter().map(|x| x.to_string()).collect();
+    assert_eq!(
+        commands,
+        [
+            "oxide --profile rack2 disk import --project builds --path /images/debian.img --disk debian-12-import --description 'import of debian-12' --snapshot debian-12 --image debian-12 --image-description 'Debian 12 from debian:12' --image-os debian --image-version 12",
+            "oxide --profile rack2 disk delete --project builds --disk debian-12-import",
+            "oxide --profile rack2 image promote --project builds --image debian-12",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_upload_proxmox() -> Result<()> {
+    use std::rc::Rc;
+
+    let executor = Rc::new(RecordingExecutor::new(|exe, args| {
+        Ok(if exe == "ssh" && args[2] == "hostname" {
+            "pve1\n".into()
+        } else {
+            String::new()
+        })
+    }));
+    let previous = set_executor(executor.clone());
+
+    let mut template = ProxmoxTemplate::new(9000, "debian-12");
+    template.host = Some("root@pve1.example.com".into());
+    let result = upload_proxmox(Path::new("/images/debian.img"), &template);
+
+    set_executor(previous);
+    result?;
+
+    let commands: Vec<String> = executor.commands().iter().map(|x| x.to_string()).collect();
+    assert_eq!(
+        commands,
+        [
+            "ssh root@pve1.example.com -- hostname",
+            "scp -q /images/debian.img root@pve1.example.com:/var/tmp/debian-12-9000.raw",
+            "ssh root@pve1.example.com -- 'pvesh create /nodes/pve1/qemu --vmid 9000 --name debian-12 --machine q35 --bios ovmf --ostype l26 --memory 2048 --cores 2 --net0 virtio,bridge=vmbr0 --scsihw virtio-scsi-single --efidisk0 local-lvm:1,efitype=4m,pre-enrolled-keys=0 --scsi0 local-lvm:0,import-from=/var/tmp/debian-12-9000.raw --boot order=scsi0 --serial0 socket --vga serial0'",
+            "ssh root@pve1.example.com -- 'pvesh create /nodes/pve1/qemu/9000/template'",
+            "ssh root@pve1.example.com -- 'rm -f /var/tmp/debian-12-9000.raw'",
+        ]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! End to end tests: build a real image for each flavor with the binary and
+//! boot it in QEMU with `--verify-boot`. They need root, loop devices, a
+//! docker daemon that can pull the base images, QEMU and OVMF, so they are
+//! ignored by default. Run them with `cargo xtask test-e2e`.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+const BINARY: &str = env!("CARGO_BIN_EXE_docker_to_uefi_bootable_image");
+
+/// Build `image_name` as `flavor` with `args`, and boot it. Returns the
+/// manifest the build wrote.
+fn build_and_boot(image_name: &str, flavor: &str, args: &[&str]) -> Result<Value> {
+    let output_dir = tempfile::tempdir()?;
+    let output_file = output_dir.path().join(format!("{}.img", flavor));
+    let manifest = output_dir.path().join("manifest.json");
+
+    let mut command = Command::new(BINARY);
+    command
+        .arg("create")
+        .args(["--image-name", image_name, "--flavor", flavor])
+        .arg("--output-file")
+        .arg(&output_file)
+        .args(["--disk-size", "2"])
+        .arg("--manifest")
+        .arg(&manifest)
+        .arg("--verify-boot")
+        .args(args);
+
+    eprintln!("+ {:?}", command);
+    let status = command.status()?;
+    if !status.success() {
+        bail!("building {} as {} failed: {}", image_name, flavor, status);
+    }
+
+    let manifest: Value = serde_json::from_str(&std::fs::read_to_string(manifest)?)?;
+    check_manifest(&manifest)?;
+
+    Ok(manifest)
+}
+
+/// Things about the image that booting it wouldn't show
+fn check_manifest(manifest: &Value) -> Result<()> {
+    if manifest["size_bytes"] != 2u64 * 1024 * 1024 * 1024 {
+        bail!("the image is {} bytes, not 2G", manifest["size_bytes"]);
+    }
+
+    if manifest["partitions"].as_array().map(Vec::len) != Some(2) {
+        bail!(
+            "expected an ESP and a root partition, not {}",
+            manifest["partitions"]
+        );
+    }
+
+    if manifest["kernel_versions"]
+        .as_array()
+        .is_none_or(Vec::is_empty)
+    {
+        bail!("no kernel was installed");
+    }
+
+    // The generated password is written next to the image, for a person
+    // to log in with
+    match manifest["root_passwd_file"].as_str() {
+        Some(path) if Path::new(path).is_file() => {}
+        _ => bail!("no password file {}", manifest["root_passwd_file"]),
+    }
+
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn debian() -> Result<()> {
+    let manifest = build_and_boot("debian:12", "debian", &[])?;
+    assert_eq!(manifest["flavor"], "debian");
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn debian_ssh() -> Result<()> {
+    build_and_boot(
+        "debian:12",
+        "debian",
+        &[
+            "--extra-packages",
+            "openssh-server",
+            "--enable-service",
+            "ssh",
+        ],
+    )?;
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn ubuntu() -> Result<()> {
+    let manifest = build_and_boot("ubuntu:24.04", "ubuntu", &[])?;
+    assert_eq!(manifest["flavor"], "ubuntu");
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn alpine() -> Result<()> {
+    let manifest = build_and_boot("alpine:3.20", "alpine", &[])?;
+    assert_eq!(manifest["flavor"], "alpine");
+    Ok(())
+}
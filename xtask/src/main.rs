@@ -0,0 +1,139 @@
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+//! Development tasks, run with `cargo xtask <task>`.
+//!
+//! test-e2e: run the ignored end to end tests in tests/e2e.rs, which build
+//! an image for each flavor and boot it in QEMU. They need root, loop
+//! devices and a docker daemon, so by default they run in a privileged
+//! container that uses the host's daemon. With --host they run right here
+//! under sudo, which is for a throwaway VM such as a CI runner.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Result};
+
+const USAGE: &str = "\
+usage: cargo xtask test-e2e [--host] [-- <test filter and libtest args>]
+
+    --host    run on this machine under sudo instead of in a privileged
+              container";
+
+/// The image the end to end tests run in, built from xtask/Dockerfile.e2e
+const E2E_IMAGE: &str = "docker_to_uefi_bootable_image-e2e";
+
+/// Kept between runs so the container doesn't download every crate again
+const CARGO_REGISTRY_VOLUME: &str = "docker_to_uefi_bootable_image-e2e-registry";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("test-e2e") => test_e2e(&args[1..]),
+
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn test_e2e(args: &[String]) -> Result<()> {
+    let (ours, test_args) = match args.iter().position(|x| x == "--") {
+        Some(i) => (&args[..i], &args[i + 1..]),
+        None => (args, &[][..]),
+    };
+
+    let mut host = false;
+    for arg in ours {
+        match arg.as_str() {
+            "--host" => host = true,
+            _ => bail!("unexpected argument {:?}\n\n{}", arg, USAGE),
+        }
+    }
+
+    let root = workspace_root();
+
+    // Built as root either way, so kept apart from the normal target
+    // directory rather than leaving root-owned files in it
+    let target_dir = root.join("target/e2e");
+
+    let mut cargo_test = vec![
+        "cargo".to_string(),
+        "test".to_string(),
+        "--test".to_string(),
+        "e2e".to_string(),
+        "--".to_string(),
+        "--ignored".to_string(),
+        // Each test already uses a loop device and a few GB of disk
+        "--test-threads=1".to_string(),
+    ];
+    cargo_test.extend_from_slice(test_args);
+
+    let mut command = if host {
+        let mut command = Command::new("sudo");
+        command
+            .arg("--preserve-env=PATH,CARGO_HOME,RUSTUP_HOME")
+            .arg("env")
+            .arg(format!("CARGO_TARGET_DIR={}", target_dir.display()))
+            .args(&cargo_test)
+            .current_dir(&root);
+        command
+    } else {
+        run(Command::new("docker").args([
+            "build",
+            "--tag",
+            E2E_IMAGE,
+            "--file",
+            &root.join("xtask/Dockerfile.e2e").display().to_string(),
+            &root.join("xtask").display().to_string(),
+        ]))?;
+
+        // The tree is mounted at the same path so paths in failures match
+        // the host's. /dev is the host's so partitions of loop devices
+        // appear, which they don't in the container's own /dev.
+        let mut command = Command::new("docker");
+        command
+            .args(["run", "--rm", "--privileged"])
+            .args(["--volume", "/dev:/dev"])
+            .args(["--volume", "/var/run/docker.sock:/var/run/docker.sock"])
+            .arg("--volume")
+            .arg(format!(
+                "{}:/usr/local/cargo/registry",
+                CARGO_REGISTRY_VOLUME
+            ))
+            .arg("--volume")
+            .arg(format!("{0}:{0}", root.display()))
+            .arg("--workdir")
+            .arg(&root)
+            .arg("--env")
+            .arg(format!("CARGO_TARGET_DIR={}", target_dir.display()))
+            .arg(E2E_IMAGE)
+            .args(&cargo_test);
+        command
+    };
+
+    run(&mut command)
+}
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    eprintln!("+ {:?}", command);
+
+    let status = command.status()?;
+    if !status.success() {
+        bail!("{:?} failed: {}", command.get_program(), status);
+    }
+
+    Ok(())
+}